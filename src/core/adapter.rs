@@ -1,32 +1,53 @@
-use std::{cell::RefCell, collections::HashMap, mem, ptr};
+use std::{cell::RefCell, collections::HashMap, env, mem, ptr};
 
 use comptr::ComPtr;
 
 use winapi::shared::d3d9caps::*;
 use winapi::shared::d3d9types::*;
 use winapi::shared::dxgi::*;
+use winapi::shared::dxgi1_4::{
+    IDXGIAdapter3, DXGI_MEMORY_SEGMENT_GROUP_LOCAL, DXGI_QUERY_VIDEO_MEMORY_INFO,
+};
 use winapi::shared::dxgitype::DXGI_MODE_DESC;
+use winapi::shared::ntdef::LUID;
 use winapi::shared::windef::HMONITOR;
 use winapi::um::{d3d11::*, d3dcommon};
 
 use super::{
-    fmt::{d3d_format_to_dxgi, is_display_mode_format},
+    fmt::{
+        d3d_format_to_dxgi, is_display_mode_format, is_null_format, is_paletted_format,
+        D3DFMT_ATOC, D3DFMT_RESZ,
+    },
+    msample::d3d9_to_dxgi_samples,
     *,
 };
+use crate::d3d11::query_interface;
 use crate::Error;
 
+/// Environment variable which enables D3D11's debug layer when set to `1`.
+const DEBUG_LAYER_VAR: &str = "D3D9TO11_DEBUG";
+
+/// Checks if the user asked for D3D11's debug layer to be enabled.
+fn debug_layer_enabled() -> bool {
+    env::var_os(DEBUG_LAYER_VAR).map_or(false, |value| value == "1")
+}
+
 /// This class represents a physical graphics adapter (GPU).
 pub struct Adapter {
     // Ordinal of this adapter in the list of GPUs.
     index: u32,
     // Caches this adapter's description.
     adapter_desc: DXGI_ADAPTER_DESC,
+    // Newer interface used to query the video memory budget, if the OS supports it.
+    adapter3: Option<ComPtr<IDXGIAdapter3>>,
     // The display attached to this device.
     output: Option<ComPtr<IDXGIOutput>>,
     // Cache the display's properties.
     output_desc: Option<DXGI_OUTPUT_DESC>,
     // Caches the supported display modes compatible with a certain format.
     mode_cache: RefCell<HashMap<D3DFORMAT, Box<[DXGI_MODE_DESC]>>>,
+    // Highest feature level supported by the device, which determines the shader model we expose.
+    feature_level: d3dcommon::D3D_FEATURE_LEVEL,
     // With D3D11, obtaining a device's capabilities or checking for texture format support
     // requires us to create the device first.
     device: ComPtr<ID3D11Device>,
@@ -47,6 +68,8 @@ impl Adapter {
             desc
         };
 
+        let adapter3 = query_interface(&adapter);
+
         // D3D9 only supports one monitor per adapter.
         // TODO: allow user to choose which monitor they want to use.
         let output = unsafe {
@@ -76,8 +99,17 @@ impl Adapter {
             })
             .ok();
 
-        // We need to also create the D3D11 device now.;
+        // We need to also create the D3D11 device now.
         let mut feature_level = 0;
+
+        // The debug layer is slow, and only installed with the Windows SDK.
+        let flags = if debug_layer_enabled() {
+            info!("Enabling the D3D11 debug layer");
+            D3D11_CREATE_DEVICE_DEBUG
+        } else {
+            0
+        };
+
         let device = unsafe {
             let mut device = ptr::null_mut();
             let result = D3D11CreateDevice(
@@ -85,8 +117,7 @@ impl Adapter {
                 adapter.as_mut(),
                 d3dcommon::D3D_DRIVER_TYPE_UNKNOWN,
                 ptr::null_mut(),
-                // No additional flags.
-                D3D11_CREATE_DEVICE_DEBUG,
+                flags,
                 // We will use whichever feature level is supported.
                 ptr::null_mut(),
                 0,
@@ -108,8 +139,10 @@ impl Adapter {
         let adapter = Self {
             index,
             adapter_desc,
+            adapter3,
             output,
             output_desc,
+            feature_level,
             mode_cache: RefCell::new(HashMap::new()),
             device,
         };
@@ -223,6 +256,25 @@ impl Adapter {
 
     /// Checks if a given format is supported for a specific resource usage.
     pub fn is_format_supported(&self, fmt: D3DFORMAT, rt: ResourceType, usage: UsageFlags) -> bool {
+        // Paletted formats are emulated, and can only be used for plain textures.
+        if is_paletted_format(fmt) {
+            let special = UsageFlags::RENDER_TARGET
+                | UsageFlags::DEPTH_STENCIL
+                | UsageFlags::AUTO_GEN_MIP_MAP;
+            return rt == ResourceType::Texture && !usage.intersects(special);
+        }
+
+        // Null render targets are never written to, so they have no DXGI format.
+        // The RESZ format is only checked for, to detect support for depth resolves.
+        if is_null_format(fmt) || fmt == D3DFMT_RESZ {
+            return rt == ResourceType::Surface && usage == UsageFlags::RENDER_TARGET;
+        }
+
+        // The ATOC format is only checked for, to detect support for alpha to coverage.
+        if fmt == D3DFMT_ATOC {
+            return rt == ResourceType::Surface;
+        }
+
         let fmt = d3d_format_to_dxgi(fmt);
 
         let support = unsafe {
@@ -257,14 +309,19 @@ impl Adapter {
     }
 
     /// Checks if we support multisampling for a given format.
-    /// Returns the maximum quality level supported for a given format.
+    /// Returns the number of quality levels supported for a given format,
+    /// or 0 if the sample count is not supported.
     pub fn is_multisampling_supported(&self, fmt: D3DFORMAT, ms: D3DMULTISAMPLE_TYPE) -> u32 {
         let fmt = d3d_format_to_dxgi(fmt);
+
+        // Not multisampling is the same as using a single sample.
+        let count = d3d9_to_dxgi_samples(ms, 0).Count;
+
         let mut quality = 0;
         unsafe {
             // Even if this fails, quality is initialized to 0.
             self.device
-                .CheckMultisampleQualityLevels(fmt, ms, &mut quality);
+                .CheckMultisampleQualityLevels(fmt, count, &mut quality);
         }
 
         quality
@@ -272,10 +329,21 @@ impl Adapter {
 
     /// Returns the capabilities of this device.
     pub fn caps(&self) -> D3DCAPS9 {
+        let shader_model = self.shader_model();
+
+        // Shader model 3 has no limits on the instruction count,
+        // and older GPUs only have to support the minimum required by SM2.
+        let (instruction_slots, sm3_instruction_slots) = if shader_model >= 3 {
+            (1 << 31, 32768)
+        } else {
+            (512, 0)
+        };
+
         D3DCAPS9 {
             DeviceType: D3DDEVTYPE_HAL,
             AdapterOrdinal: self.index,
-            Caps: 0,
+            // The scanline is estimated from the display's refresh rate.
+            Caps: D3DCAPS_READ_SCANLINE,
             // TODO: implement D3DCAPS2_CANSHARERESOURCE for D3D9Ex
             Caps2: D3DCAPS2_CANAUTOGENMIPMAP
                 | D3DCAPS2_CANCALIBRATEGAMMA
@@ -327,11 +395,11 @@ impl Adapter {
             StencilCaps: !0,
             FVFCaps: !0,
             TextureOpCaps: !0,
-            // These are set to the API limits.
+            // These are set to the API limits, which every feature level supports.
             MaxSimultaneousTextures: 8,
             MaxTextureBlendStages: 8,
             MaxActiveLights: 8,
-            MaxUserClipPlanes: 8,
+            MaxUserClipPlanes: 6,
             MaxPrimitiveCount: std::u32::MAX,
             MaxVertexIndex: std::u32::MAX,
             MaxVertexBlendMatrices: std::u32::MAX,
@@ -340,10 +408,10 @@ impl Adapter {
             MaxPointSize: 2048.0,
             MaxStreams: 16,
             MaxStreamStride: 1 << 31,
-            VertexShaderVersion: 0xFFFE_0000 | (3 << 8),
-            MaxVertexShaderConst: 1 << 16,
-            PixelShaderVersion: 0xFFFF_0000 | (3 << 8),
-            PixelShader1xMaxValue: 8.0,
+            VertexShaderVersion: 0xFFFE_0000 | (shader_model << 8),
+            MaxVertexShaderConst: crate::shader::MAX_FLOAT_CONSTANTS,
+            PixelShaderVersion: 0xFFFF_0000 | (shader_model << 8),
+            PixelShader1xMaxValue: crate::shader::PS_1X_MAX_VALUE,
             DevCaps2: !0,
             MaxNpatchTessellationLevel: 256.0,
             Reserved5: 0,
@@ -351,7 +419,8 @@ impl Adapter {
             MasterAdapterOrdinal: self.index,
             NumberOfAdaptersInGroup: 1,
             AdapterOrdinalInGroup: 0,
-            DeclTypes: !0,
+            // Signed 10-bit vertex elements cannot be read by D3D11.
+            DeclTypes: !D3DDTCAPS_DEC3N,
             NumSimultaneousRTs: 8,
             StretchRectFilterCaps: !0,
             VS20Caps: D3DVSHADERCAPS2_0 {
@@ -365,16 +434,33 @@ impl Adapter {
                 DynamicFlowControlDepth: 24,
                 NumTemps: !16384,
                 StaticFlowControlDepth: 1 << 31,
-                NumInstructionSlots: 1 << 31,
+                NumInstructionSlots: instruction_slots,
             },
             VertexTextureFilterCaps: !0,
             MaxVShaderInstructionsExecuted: !0,
             MaxPShaderInstructionsExecuted: !0,
-            MaxVertexShader30InstructionSlots: 32768,
-            MaxPixelShader30InstructionSlots: 32768,
+            MaxVertexShader30InstructionSlots: sm3_instruction_slots,
+            MaxPixelShader30InstructionSlots: sm3_instruction_slots,
+        }
+    }
+
+    /// Returns the major version of the highest shader model we can translate to.
+    ///
+    /// Feature level 10 and above can run any SM3 shader,
+    /// while the 9.x levels are limited to SM2.
+    fn shader_model(&self) -> u32 {
+        if self.feature_level >= d3dcommon::D3D_FEATURE_LEVEL_10_0 {
+            3
+        } else {
+            2
         }
     }
 
+    /// Returns the locally unique identifier of this adapter.
+    pub fn luid(&self) -> LUID {
+        self.adapter_desc.AdapterLuid
+    }
+
     /// Returns the (primary) monitor of this adapter.
     pub fn monitor(&self) -> HMONITOR {
         self.output_desc
@@ -382,18 +468,33 @@ impl Adapter {
             .unwrap_or(ptr::null_mut())
     }
 
+    /// Clones this adapter's (primary) output, if it has one.
+    pub fn output(&self) -> Option<ComPtr<IDXGIOutput>> {
+        self.output.clone()
+    }
+
     /// Clones this adapter's D3D11 device.
     pub fn device(&self) -> ComPtr<ID3D11Device> {
         self.device.clone()
     }
 
-    /// Returns the amount of memory this adapter has.
+    /// Returns the amount of video memory still available to the app.
     pub fn available_memory(&self) -> u32 {
-        let desc = &self.adapter_desc;
+        // Windows 10 tells us how much memory the app can use, and how much it already does.
+        let budget = self.adapter3.as_ref().and_then(|adapter| unsafe {
+            let mut info: DXGI_QUERY_VIDEO_MEMORY_INFO = mem::zeroed();
+            let result =
+                adapter.QueryVideoMemoryInfo(0, DXGI_MEMORY_SEGMENT_GROUP_LOCAL, &mut info);
+
+            if result == 0 {
+                Some(info.Budget.saturating_sub(info.CurrentUsage) as usize)
+            } else {
+                None
+            }
+        });
 
-        // We return the sum of the VRAM and the amount of shared RAM.
-        // This is just a gross estimate.
-        let mem = desc.DedicatedVideoMemory + desc.DedicatedSystemMemory + desc.SharedSystemMemory;
+        // Otherwise, assume all of the VRAM is available.
+        let mem = budget.unwrap_or(self.adapter_desc.DedicatedVideoMemory);
 
         // Round to the nearest MiB.
         let mem = (mem / (1024 * 1024)) * (1024 * 1024);
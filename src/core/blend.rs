@@ -0,0 +1,63 @@
+//! Implements blending-related conversion functions.
+
+use winapi::shared::d3d9types::*;
+use winapi::um::d3d11::*;
+
+/// Converts a D3D9 blend factor to the equivalent D3D11 blend factor.
+///
+/// The deprecated `D3DBLEND_BOTH*` factors also affect the destination factor,
+/// and must be handled by the caller.
+pub fn d3d9_to_d3d11_blend(blend: D3DBLEND) -> D3D11_BLEND {
+    match blend {
+        D3DBLEND_ZERO => D3D11_BLEND_ZERO,
+        D3DBLEND_ONE => D3D11_BLEND_ONE,
+        D3DBLEND_SRCCOLOR => D3D11_BLEND_SRC_COLOR,
+        D3DBLEND_INVSRCCOLOR => D3D11_BLEND_INV_SRC_COLOR,
+        D3DBLEND_SRCALPHA | D3DBLEND_BOTHSRCALPHA => D3D11_BLEND_SRC_ALPHA,
+        D3DBLEND_INVSRCALPHA | D3DBLEND_BOTHINVSRCALPHA => D3D11_BLEND_INV_SRC_ALPHA,
+        D3DBLEND_DESTALPHA => D3D11_BLEND_DEST_ALPHA,
+        D3DBLEND_INVDESTALPHA => D3D11_BLEND_INV_DEST_ALPHA,
+        D3DBLEND_DESTCOLOR => D3D11_BLEND_DEST_COLOR,
+        D3DBLEND_INVDESTCOLOR => D3D11_BLEND_INV_DEST_COLOR,
+        D3DBLEND_SRCALPHASAT => D3D11_BLEND_SRC_ALPHA_SAT,
+        D3DBLEND_BLENDFACTOR => D3D11_BLEND_BLEND_FACTOR,
+        D3DBLEND_INVBLENDFACTOR => D3D11_BLEND_INV_BLEND_FACTOR,
+        D3DBLEND_SRCCOLOR2 => D3D11_BLEND_SRC1_COLOR,
+        D3DBLEND_INVSRCCOLOR2 => D3D11_BLEND_INV_SRC1_COLOR,
+        _ => {
+            error!("Unknown blend factor: {}", blend);
+            D3D11_BLEND_ONE
+        }
+    }
+}
+
+/// Converts a blend factor to one which can be used for blending the alpha channel.
+///
+/// D3D9 allows color factors to be used for alpha, where they are equivalent
+/// to the matching alpha factors. D3D11 does not accept them.
+pub fn color_to_alpha_blend(blend: D3D11_BLEND) -> D3D11_BLEND {
+    match blend {
+        D3D11_BLEND_SRC_COLOR => D3D11_BLEND_SRC_ALPHA,
+        D3D11_BLEND_INV_SRC_COLOR => D3D11_BLEND_INV_SRC_ALPHA,
+        D3D11_BLEND_DEST_COLOR => D3D11_BLEND_DEST_ALPHA,
+        D3D11_BLEND_INV_DEST_COLOR => D3D11_BLEND_INV_DEST_ALPHA,
+        D3D11_BLEND_SRC1_COLOR => D3D11_BLEND_SRC1_ALPHA,
+        D3D11_BLEND_INV_SRC1_COLOR => D3D11_BLEND_INV_SRC1_ALPHA,
+        blend => blend,
+    }
+}
+
+/// Converts a D3D9 blend operation to the equivalent D3D11 operation.
+pub fn d3d9_to_d3d11_blend_op(op: D3DBLENDOP) -> D3D11_BLEND_OP {
+    match op {
+        D3DBLENDOP_ADD => D3D11_BLEND_OP_ADD,
+        D3DBLENDOP_SUBTRACT => D3D11_BLEND_OP_SUBTRACT,
+        D3DBLENDOP_REVSUBTRACT => D3D11_BLEND_OP_REV_SUBTRACT,
+        D3DBLENDOP_MIN => D3D11_BLEND_OP_MIN,
+        D3DBLENDOP_MAX => D3D11_BLEND_OP_MAX,
+        _ => {
+            error!("Unknown blend operation: {}", op);
+            D3D11_BLEND_OP_ADD
+        }
+    }
+}
@@ -0,0 +1,82 @@
+//! Implements color-related conversion functions.
+
+use winapi::shared::d3d9types::*;
+
+/// Converts a packed ARGB D3DCOLOR to a normalized RGBA float vector.
+pub fn d3dcolor_to_rgba(color: D3DCOLOR) -> [f32; 4] {
+    let channel = |shift: u32| ((color >> shift) & 0xFF) as f32 / 255.0;
+
+    [channel(16), channel(8), channel(0), channel(24)]
+}
+
+/// Converts a D3DCOLOR to the pixel layout of a certain format.
+///
+/// Returns the bytes of a single pixel, or `None` if the format is not a simple color format.
+pub fn d3dcolor_to_pixel(color: D3DCOLOR, fmt: D3DFORMAT) -> Option<Vec<u8>> {
+    // Keeps the `bits` most significant bits of a channel, and moves them to `shift`.
+    let channel = |src_shift: u32, bits: u32, shift: u32| {
+        (((color >> src_shift) & 0xFF) >> (8 - bits)) << shift
+    };
+
+    let (pixel, size) = match fmt {
+        D3DFMT_A8R8G8B8 | D3DFMT_X8R8G8B8 => (color, 4),
+        D3DFMT_A8B8G8R8 | D3DFMT_X8B8G8R8 => (
+            channel(24, 8, 24) | channel(0, 8, 16) | channel(8, 8, 8) | channel(16, 8, 0),
+            4,
+        ),
+        D3DFMT_A2R10G10B10 => (
+            channel(24, 2, 30)
+                | (channel(16, 8, 22) | channel(16, 2, 20))
+                | (channel(8, 8, 12) | channel(8, 2, 10))
+                | (channel(0, 8, 2) | channel(0, 2, 0)),
+            4,
+        ),
+        D3DFMT_R5G6B5 => (channel(16, 5, 11) | channel(8, 6, 5) | channel(0, 5, 0), 2),
+        D3DFMT_A1R5G5B5 | D3DFMT_X1R5G5B5 => (
+            channel(24, 1, 15) | channel(16, 5, 10) | channel(8, 5, 5) | channel(0, 5, 0),
+            2,
+        ),
+        D3DFMT_A4R4G4B4 | D3DFMT_X4R4G4B4 => (
+            channel(24, 4, 12) | channel(16, 4, 8) | channel(8, 4, 4) | channel(0, 4, 0),
+            2,
+        ),
+        D3DFMT_A8 => (channel(24, 8, 0), 1),
+        // Luminance is taken from the red channel.
+        D3DFMT_L8 => (channel(16, 8, 0), 1),
+        _ => return None,
+    };
+
+    Some(pixel.to_le_bytes()[..size].to_vec())
+}
+
+/// Converts a 32-bit pixel of a certain format to a packed ARGB D3DCOLOR.
+///
+/// Formats without an alpha channel are treated as opaque.
+/// Returns `None` if the format is not one which a swap chain's buffers can have.
+pub fn pixel_to_d3dcolor(pixel: u32, fmt: D3DFORMAT) -> Option<D3DCOLOR> {
+    // Extracts a channel of `bits` bits at `shift`, and scales it to 8 bits.
+    let channel = |shift: u32, bits: u32| {
+        let max = (1 << bits) - 1;
+        ((pixel >> shift) & max) * 0xFF / max
+    };
+
+    let (a, r, g, b) = match fmt {
+        D3DFMT_A8R8G8B8 => return Some(pixel),
+        D3DFMT_X8R8G8B8 => return Some(pixel | 0xFF00_0000),
+        D3DFMT_A2R10G10B10 => (
+            channel(30, 2),
+            channel(20, 10),
+            channel(10, 10),
+            channel(0, 10),
+        ),
+        D3DFMT_A2B10G10R10 => (
+            channel(30, 2),
+            channel(0, 10),
+            channel(10, 10),
+            channel(20, 10),
+        ),
+        _ => return None,
+    };
+
+    Some((a << 24) | (r << 16) | (g << 8) | b)
+}
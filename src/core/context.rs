@@ -6,13 +6,11 @@ use winapi::shared::d3d9::*;
 use winapi::shared::d3d9caps::D3DCAPS9;
 use winapi::shared::d3d9types::*;
 use winapi::shared::dxgi;
+use winapi::shared::ntdef::LUID;
 use winapi::shared::windef::{HMONITOR, HWND};
+use winapi::um::unknwnbase::{IUnknown, IUnknownVtbl};
 use winapi::um::winuser;
 use winapi::Interface;
-use winapi::{
-    shared::d3d9::{IDirect3D9, IDirect3D9Vtbl},
-    um::unknwnbase::{IUnknown, IUnknownVtbl},
-};
 
 use com_impl::{implementation, interface, ComInterface};
 
@@ -25,16 +23,20 @@ use crate::{dev::Device, Error};
 /// D3D9 interface which stores all application context.
 ///
 /// Similar in role to a DXGI factory.
-#[interface(IDirect3D9)]
+#[interface(IDirect3D9Ex)]
 pub struct Context {
     refs: AtomicU32,
     factory: ComPtr<dxgi::IDXGIFactory>,
     adapters: Vec<Adapter>,
+    // Whether the app created this context through `Direct3DCreate9Ex`.
+    ex: bool,
 }
 
 impl Context {
     /// Creates a new D3D9 context.
-    pub fn new() -> Result<ComPtr<Context>, Error> {
+    ///
+    /// Contexts created for D3D9Ex also expose the extended interfaces.
+    pub fn new(ex: bool) -> Result<ComPtr<Context>, Error> {
         // We first have to create a factory, which is the equivalent of this interface in DXGI terms.
         let factory = unsafe {
             let uuid = dxgi::IDXGIFactory::uuidof();
@@ -64,6 +66,7 @@ impl Context {
             refs: AtomicU32::new(1),
             factory,
             adapters,
+            ex,
         };
 
         Ok(unsafe { new_com_interface(ctx) })
@@ -81,9 +84,20 @@ impl Context {
             _ => Err(Error::InvalidCall),
         }
     }
+
+    /// Checks that an extended display mode filter is valid, and retrieves the format it selects.
+    fn check_mode_filter(&self, filter: *const D3DDISPLAYMODEFILTER) -> Result<D3DFORMAT, Error> {
+        let filter = check_ref(filter)?;
+
+        if filter.Size != mem::size_of::<D3DDISPLAYMODEFILTER>() as u32 {
+            return Err(Error::InvalidCall);
+        }
+
+        Ok(filter.Format)
+    }
 }
 
-impl_iunknown!(struct Context: IUnknown, IDirect3D9);
+impl_iunknown!(struct Context: IUnknown, IDirect3D9; IDirect3D9Ex if ex);
 
 #[implementation(IDirect3D9)]
 impl Context {
@@ -222,16 +236,21 @@ impl Context {
         let adapter = if_error!(self.check_adapter(adapter));
         if_error!(self.check_devty(ty));
 
+        if mst > D3DMULTISAMPLE_16_SAMPLES {
+            error!("Invalid multisample type: {}", mst);
+            return Error::InvalidCall;
+        }
+
         let quality = check_mut_ref(quality);
 
         let q = adapter.is_multisampling_supported(surface_fmt, mst);
 
-        // Return the maximum quality level, if requested.
+        // Return the number of quality levels, if requested.
         if let Ok(quality) = quality {
             *quality = q;
         }
 
-        // Max quality of 0 would mean no support for MS.
+        // No quality levels means the sample count is not supported.
         if q == 0 {
             Error::NotAvailable
         } else {
@@ -354,9 +373,133 @@ impl Context {
             cp,
             pp,
             self.factory.clone(),
+            self.ex,
         ))
         .into();
 
         Error::Success
     }
 }
+
+#[implementation(IDirect3D9Ex)]
+impl Context {
+    /// Returns the number of display modes matching a filter which an adapter supports.
+    fn get_adapter_mode_count_ex(&self, adapter: u32, filter: *const D3DDISPLAYMODEFILTER) -> u32 {
+        self.check_mode_filter(filter)
+            .map(|fmt| self.get_adapter_mode_count(adapter, fmt))
+            .unwrap_or_default()
+    }
+
+    /// Retrieves the list of display modes matching a filter.
+    fn enum_adapter_modes_ex(
+        &self,
+        adapter: u32,
+        filter: *const D3DDISPLAYMODEFILTER,
+        i: u32,
+        mode: *mut D3DDISPLAYMODEEX,
+    ) -> Error {
+        let fmt = if_error!(self.check_mode_filter(filter));
+        let ret = if_error!(check_mut_ref(mode));
+
+        let mut dm = unsafe { mem::zeroed() };
+        if_not_success!(self.enum_adapter_modes(adapter, fmt, i, &mut dm));
+
+        *ret = display_mode_ex(&dm);
+
+        Error::Success
+    }
+
+    /// Retrieve the current display mode of the GPU, and the rotation of the screen.
+    fn get_adapter_display_mode_ex(
+        &self,
+        adapter: u32,
+        mode: *mut D3DDISPLAYMODEEX,
+        rotation: *mut D3DDISPLAYROTATION,
+    ) -> Error {
+        let mut dm = unsafe { mem::zeroed() };
+        if_not_success!(self.get_adapter_display_mode(adapter, &mut dm));
+
+        if let Ok(mode) = check_mut_ref(mode) {
+            *mode = display_mode_ex(&dm);
+        }
+
+        if let Ok(rotation) = check_mut_ref(rotation) {
+            // DXGI rotates the images for us.
+            *rotation = D3DDISPLAYROTATION_IDENTITY;
+        }
+
+        Error::Success
+    }
+
+    /// Creates a logical device from an adapter, which supports the extended interfaces.
+    fn create_device_ex(
+        &self,
+        adapter: u32,
+        ty: D3DDEVTYPE,
+        focus: HWND,
+        flags: u32,
+        pp: *mut D3DPRESENT_PARAMETERS,
+        mode: *mut D3DDISPLAYMODEEX,
+        device: *mut *mut Device,
+    ) -> Error {
+        let pp = if_error!(check_mut_ref(pp));
+        if_error!(check_fullscreen_mode(pp, mode));
+
+        self.create_device(adapter, ty, focus, flags, pp, device)
+    }
+
+    /// Retrieves the locally unique identifier of an adapter.
+    fn get_adapter_l_u_i_d(&self, adapter: u32, luid: *mut LUID) -> Error {
+        let adapter = if_error!(self.check_adapter(adapter));
+        let luid = if_error!(check_mut_ref(luid));
+
+        *luid = adapter.luid();
+
+        Error::Success
+    }
+}
+
+/// Converts a display mode to the equivalent extended display mode.
+pub fn display_mode_ex(dm: &D3DDISPLAYMODE) -> D3DDISPLAYMODEEX {
+    D3DDISPLAYMODEEX {
+        Size: mem::size_of::<D3DDISPLAYMODEEX>() as u32,
+        Width: dm.Width,
+        Height: dm.Height,
+        RefreshRate: dm.RefreshRate,
+        Format: dm.Format,
+        ScanLineOrdering: D3DSCANLINEORDERING_PROGRESSIVE,
+    }
+}
+
+/// Checks the display mode passed to the functions creating or resetting D3D9Ex devices.
+///
+/// Full-screen devices must be given a mode matching their presentation parameters,
+/// while windowed devices must not be given any.
+pub fn check_fullscreen_mode(
+    pp: &mut D3DPRESENT_PARAMETERS,
+    mode: *const D3DDISPLAYMODEEX,
+) -> Result<(), Error> {
+    if pp.Windowed != 0 {
+        return if mode.is_null() {
+            Ok(())
+        } else {
+            Err(Error::InvalidCall)
+        };
+    }
+
+    let mode = check_ref(mode)?;
+
+    let matches = mode.Size == mem::size_of::<D3DDISPLAYMODEEX>() as u32
+        && mode.Width == pp.BackBufferWidth
+        && mode.Height == pp.BackBufferHeight
+        && mode.Format == pp.BackBufferFormat;
+
+    if !matches {
+        error!("Full-screen display mode doesn't match the back buffer");
+        return Err(Error::InvalidCall);
+    }
+
+    pp.FullScreen_RefreshRateInHz = mode.RefreshRate;
+
+    Ok(())
+}
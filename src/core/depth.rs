@@ -0,0 +1,40 @@
+//! Implements depth/stencil-related conversion functions.
+
+use winapi::shared::d3d9types::*;
+use winapi::um::d3d11::*;
+
+/// Converts a D3D9 comparison function to the equivalent D3D11 function.
+pub fn d3d9_to_d3d11_comparison(func: D3DCMPFUNC) -> D3D11_COMPARISON_FUNC {
+    match func {
+        D3DCMP_NEVER => D3D11_COMPARISON_NEVER,
+        D3DCMP_LESS => D3D11_COMPARISON_LESS,
+        D3DCMP_EQUAL => D3D11_COMPARISON_EQUAL,
+        D3DCMP_LESSEQUAL => D3D11_COMPARISON_LESS_EQUAL,
+        D3DCMP_GREATER => D3D11_COMPARISON_GREATER,
+        D3DCMP_NOTEQUAL => D3D11_COMPARISON_NOT_EQUAL,
+        D3DCMP_GREATEREQUAL => D3D11_COMPARISON_GREATER_EQUAL,
+        D3DCMP_ALWAYS => D3D11_COMPARISON_ALWAYS,
+        _ => {
+            error!("Unknown comparison function: {}", func);
+            D3D11_COMPARISON_ALWAYS
+        }
+    }
+}
+
+/// Converts a D3D9 stencil operation to the equivalent D3D11 operation.
+pub fn d3d9_to_d3d11_stencil_op(op: D3DSTENCILOP) -> D3D11_STENCIL_OP {
+    match op {
+        D3DSTENCILOP_KEEP => D3D11_STENCIL_OP_KEEP,
+        D3DSTENCILOP_ZERO => D3D11_STENCIL_OP_ZERO,
+        D3DSTENCILOP_REPLACE => D3D11_STENCIL_OP_REPLACE,
+        D3DSTENCILOP_INCRSAT => D3D11_STENCIL_OP_INCR_SAT,
+        D3DSTENCILOP_DECRSAT => D3D11_STENCIL_OP_DECR_SAT,
+        D3DSTENCILOP_INVERT => D3D11_STENCIL_OP_INVERT,
+        D3DSTENCILOP_INCR => D3D11_STENCIL_OP_INCR,
+        D3DSTENCILOP_DECR => D3D11_STENCIL_OP_DECR,
+        _ => {
+            error!("Unknown stencil operation: {}", op);
+            D3D11_STENCIL_OP_KEEP
+        }
+    }
+}
@@ -8,7 +8,15 @@ use winapi::shared::d3d9types::*;
 use winapi::shared::dxgiformat::*;
 
 const ATI2: u32 = 843666497;
-const NULL: u32 = 0x4C4C554E;
+/// Format of render targets which are never written to,
+/// used with a depth buffer for depth-only rendering.
+pub const D3DFMT_NULL: D3DFORMAT = 0x4C4C554E;
+/// Format checked by apps to detect support for resolving multisampled depth buffers,
+/// by setting the point size to `RESZ_CODE`.
+pub const D3DFMT_RESZ: D3DFORMAT = 0x5A53_4552;
+/// Format checked by apps to detect support for enabling alpha to coverage,
+/// by setting `D3DRS_ADAPTIVETESS_Y` to this format.
+pub const D3DFMT_ATOC: D3DFORMAT = 0x434F_5441;
 const RAWZ: u32 = 1515667794;
 const INTZ: u32 = 1515474505;
 
@@ -49,21 +57,198 @@ pub fn is_depth_stencil_format(fmt: D3DFORMAT) -> bool {
     }
 }
 
+/// How the channels sampled from a texture must be rearranged to match D3D9.
+///
+/// D3D11 cannot swizzle views, so this is done in pixel shaders.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum TextureSwizzle {
+    /// The channels are used as they are.
+    None,
+    /// The red channel contains the luminance, which is copied to green and blue.
+    Luminance,
+    /// Like `Luminance`, but the alpha is stored in the green channel.
+    LuminanceAlpha,
+}
+
+/// Retrieves the swizzle needed when sampling textures created from a D3D9 format,
+/// based on the DXGI format the texture was created with.
+pub fn dxgi_format_swizzle(fmt: DXGI_FORMAT) -> TextureSwizzle {
+    match fmt {
+        // Luminance formats are stored in the red channel.
+        DXGI_FORMAT_R8_UNORM | DXGI_FORMAT_R16_UNORM => TextureSwizzle::Luminance,
+        // Depth textures like INTZ return the depth in every channel.
+        DXGI_FORMAT_R24_UNORM_X8_TYPELESS => TextureSwizzle::Luminance,
+        DXGI_FORMAT_R8G8_UNORM => TextureSwizzle::LuminanceAlpha,
+        _ => TextureSwizzle::None,
+    }
+}
+
+/// Retrieves the typeless format with which a depth / stencil texture must be created
+/// for it to be sampled from, and the format of its shader resource views.
+///
+/// Returns `None` for formats which are not depth / stencil formats.
+pub fn dxgi_depth_typeless_format(fmt: DXGI_FORMAT) -> Option<(DXGI_FORMAT, DXGI_FORMAT)> {
+    match fmt {
+        DXGI_FORMAT_D16_UNORM => Some((DXGI_FORMAT_R16_TYPELESS, DXGI_FORMAT_R16_UNORM)),
+        DXGI_FORMAT_D24_UNORM_S8_UINT => Some((
+            DXGI_FORMAT_R24G8_TYPELESS,
+            DXGI_FORMAT_R24_UNORM_X8_TYPELESS,
+        )),
+        DXGI_FORMAT_D32_FLOAT => Some((DXGI_FORMAT_R32_TYPELESS, DXGI_FORMAT_R32_FLOAT)),
+        _ => None,
+    }
+}
+
+/// Retrieves the formats of the depth / stencil views and shader resource views
+/// of a texture created with a typeless depth format.
+pub fn dxgi_typeless_depth_format(fmt: DXGI_FORMAT) -> Option<(DXGI_FORMAT, DXGI_FORMAT)> {
+    match fmt {
+        DXGI_FORMAT_R16_TYPELESS => Some((DXGI_FORMAT_D16_UNORM, DXGI_FORMAT_R16_UNORM)),
+        DXGI_FORMAT_R24G8_TYPELESS => Some((
+            DXGI_FORMAT_D24_UNORM_S8_UINT,
+            DXGI_FORMAT_R24_UNORM_X8_TYPELESS,
+        )),
+        DXGI_FORMAT_R32_TYPELESS => Some((DXGI_FORMAT_D32_FLOAT, DXGI_FORMAT_R32_FLOAT)),
+        _ => None,
+    }
+}
+
+/// Retrieves the typeless format with which a color texture must be created
+/// for it to also be viewed as sRGB, and the format of its sRGB views.
+///
+/// Returns `None` for formats which have no sRGB equivalent.
+pub fn dxgi_srgb_typeless_format(fmt: DXGI_FORMAT) -> Option<(DXGI_FORMAT, DXGI_FORMAT)> {
+    match fmt {
+        DXGI_FORMAT_R8G8B8A8_UNORM => Some((
+            DXGI_FORMAT_R8G8B8A8_TYPELESS,
+            DXGI_FORMAT_R8G8B8A8_UNORM_SRGB,
+        )),
+        DXGI_FORMAT_B8G8R8A8_UNORM => Some((
+            DXGI_FORMAT_B8G8R8A8_TYPELESS,
+            DXGI_FORMAT_B8G8R8A8_UNORM_SRGB,
+        )),
+        DXGI_FORMAT_B8G8R8X8_UNORM => Some((
+            DXGI_FORMAT_B8G8R8X8_TYPELESS,
+            DXGI_FORMAT_B8G8R8X8_UNORM_SRGB,
+        )),
+        DXGI_FORMAT_BC1_UNORM => Some((DXGI_FORMAT_BC1_TYPELESS, DXGI_FORMAT_BC1_UNORM_SRGB)),
+        DXGI_FORMAT_BC2_UNORM => Some((DXGI_FORMAT_BC2_TYPELESS, DXGI_FORMAT_BC2_UNORM_SRGB)),
+        DXGI_FORMAT_BC3_UNORM => Some((DXGI_FORMAT_BC3_TYPELESS, DXGI_FORMAT_BC3_UNORM_SRGB)),
+        _ => None,
+    }
+}
+
+/// Retrieves the formats of the linear views and sRGB views
+/// of a texture created with a typeless color format.
+pub fn dxgi_typeless_srgb_format(fmt: DXGI_FORMAT) -> Option<(DXGI_FORMAT, DXGI_FORMAT)> {
+    match fmt {
+        DXGI_FORMAT_R8G8B8A8_TYPELESS => {
+            Some((DXGI_FORMAT_R8G8B8A8_UNORM, DXGI_FORMAT_R8G8B8A8_UNORM_SRGB))
+        }
+        DXGI_FORMAT_B8G8R8A8_TYPELESS => {
+            Some((DXGI_FORMAT_B8G8R8A8_UNORM, DXGI_FORMAT_B8G8R8A8_UNORM_SRGB))
+        }
+        DXGI_FORMAT_B8G8R8X8_TYPELESS => {
+            Some((DXGI_FORMAT_B8G8R8X8_UNORM, DXGI_FORMAT_B8G8R8X8_UNORM_SRGB))
+        }
+        DXGI_FORMAT_BC1_TYPELESS => Some((DXGI_FORMAT_BC1_UNORM, DXGI_FORMAT_BC1_UNORM_SRGB)),
+        DXGI_FORMAT_BC2_TYPELESS => Some((DXGI_FORMAT_BC2_UNORM, DXGI_FORMAT_BC2_UNORM_SRGB)),
+        DXGI_FORMAT_BC3_TYPELESS => Some((DXGI_FORMAT_BC3_UNORM, DXGI_FORMAT_BC3_UNORM_SRGB)),
+        _ => None,
+    }
+}
+
+/// Retrieves the format of the linear views of a texture,
+/// which is the texture's own format unless it was created typeless for sRGB views.
+pub fn dxgi_linear_format(fmt: DXGI_FORMAT) -> DXGI_FORMAT {
+    dxgi_typeless_srgb_format(fmt).map_or(fmt, |(linear, _)| linear)
+}
+
+/// Checks if a format stores indices into a palette, instead of colors.
+///
+/// DXGI has no such formats, so textures using them are expanded to RGBA.
+pub fn is_paletted_format(fmt: D3DFORMAT) -> bool {
+    match fmt {
+        D3DFMT_P8 | D3DFMT_A8P8 => true,
+        _ => false,
+    }
+}
+
+/// Checks if a format is the `NULL` render target format, which has no storage.
+pub fn is_null_format(fmt: D3DFORMAT) -> bool {
+    fmt == D3DFMT_NULL
+}
+
+/// Retrieves the size in pixels of a format's blocks, and the number of bytes in every block.
+///
+/// Uncompressed formats have blocks of a single pixel.
+/// Returns `None` for formats whose layout is unknown.
+pub fn dxgi_format_block_info(fmt: DXGI_FORMAT) -> Option<((u32, u32), u32)> {
+    let pixel = |size| Some(((1, 1), size));
+
+    match fmt {
+        DXGI_FORMAT_A8_UNORM | DXGI_FORMAT_R8_UNORM => pixel(1),
+        DXGI_FORMAT_B5G6R5_UNORM
+        | DXGI_FORMAT_B4G4R4A4_UNORM
+        | DXGI_FORMAT_B5G5R5A1_UNORM
+        | DXGI_FORMAT_R8G8_UNORM
+        | DXGI_FORMAT_R8G8_SNORM
+        | DXGI_FORMAT_R16_UNORM
+        | DXGI_FORMAT_R16_FLOAT
+        | DXGI_FORMAT_D16_UNORM => pixel(2),
+        DXGI_FORMAT_R8G8B8A8_UNORM
+        | DXGI_FORMAT_R8G8B8A8_SNORM
+        | DXGI_FORMAT_B8G8R8X8_UNORM
+        | DXGI_FORMAT_B8G8R8A8_UNORM
+        | DXGI_FORMAT_R16G16_UNORM
+        | DXGI_FORMAT_R16G16_SNORM
+        | DXGI_FORMAT_R16G16_FLOAT
+        | DXGI_FORMAT_R10G10B10A2_UNORM
+        | DXGI_FORMAT_R32_FLOAT
+        | DXGI_FORMAT_D24_UNORM_S8_UINT
+        | DXGI_FORMAT_D32_FLOAT => pixel(4),
+        DXGI_FORMAT_R16G16B16A16_UNORM
+        | DXGI_FORMAT_R16G16B16A16_FLOAT
+        | DXGI_FORMAT_R32G32_FLOAT => pixel(8),
+        DXGI_FORMAT_R32G32B32A32_FLOAT => pixel(16),
+        // Pairs of pixels share their chroma.
+        DXGI_FORMAT_G8R8_G8B8_UNORM | DXGI_FORMAT_R8G8_B8G8_UNORM => Some(((2, 1), 4)),
+        DXGI_FORMAT_BC1_UNORM | DXGI_FORMAT_BC4_UNORM => Some(((4, 4), 8)),
+        DXGI_FORMAT_BC2_UNORM | DXGI_FORMAT_BC3_UNORM | DXGI_FORMAT_BC5_UNORM => Some(((4, 4), 16)),
+        _ => None,
+    }
+}
+
 // This macro is used to generate bi-directional mapping between D3D and DXGI formats.
+//
+// Formats with no DXGI equivalent are converted to `DXGI_FORMAT_UNKNOWN`,
+// which D3D11 will refuse to create resources with.
 macro_rules! format_conv {
-    ($($a:path => $b:path,)*) => {
+    ($($a:path => $b:path,)* unsupported: $($u:path,)*) => {
         /// Converts a general resource format to a DXGI format.
         pub fn d3d_format_to_dxgi(fmt: D3DFORMAT) -> DXGI_FORMAT {
             #[allow(non_upper_case_globals)]
             match fmt {
                 $($a => $b,)*
 
-                _ => panic!("Unknown D3D9 format: {}", fmt),
+                $($u)|* => {
+                    warn!("D3D9 format {} is not supported", fmt);
+                    DXGI_FORMAT_UNKNOWN
+                }
+                _ => {
+                    error!("Unknown D3D9 format: {}", fmt);
+                    DXGI_FORMAT_UNKNOWN
+                }
             }
         }
 
         /// Converts a DXGI format back into a Direct3D format.
         pub fn dxgi_format_to_d3d(fmt: DXGI_FORMAT) -> D3DFORMAT {
+            // Typeless depth textures have the format of their depth / stencil views.
+            let fmt = dxgi_typeless_depth_format(fmt).map_or(fmt, |(depth, _)| depth);
+            // Typeless color textures have the format of their linear views.
+            let fmt = dxgi_linear_format(fmt);
+
             // Some DXGI formats map to multiple D3D9 formats.
             #[allow(unreachable_patterns)]
             match fmt {
@@ -82,24 +267,30 @@ format_conv! {
     D3DFMT_L8 => DXGI_FORMAT_R8_UNORM,
 
     // 16 bit formats
+    // The formats with alpha come first, so that they are preferred when converting back.
     D3DFMT_R5G6B5 => DXGI_FORMAT_B5G6R5_UNORM,
-    D3DFMT_X4R4G4B4 => DXGI_FORMAT_B4G4R4A4_UNORM,
     D3DFMT_A4R4G4B4 => DXGI_FORMAT_B4G4R4A4_UNORM,
-    D3DFMT_X1R5G5B5 => DXGI_FORMAT_B5G5R5A1_UNORM,
+    D3DFMT_X4R4G4B4 => DXGI_FORMAT_B4G4R4A4_UNORM,
     D3DFMT_A1R5G5B5 => DXGI_FORMAT_B5G5R5A1_UNORM,
+    D3DFMT_X1R5G5B5 => DXGI_FORMAT_B5G5R5A1_UNORM,
+    // Luminance formats are swizzled when sampled, see `dxgi_format_swizzle`.
     D3DFMT_A8L8 => DXGI_FORMAT_R8G8_UNORM,
     D3DFMT_L16 => DXGI_FORMAT_R16_UNORM,
 
     // 32 bit formats
-    D3DFMT_X8B8G8R8 => DXGI_FORMAT_R8G8B8A8_UNORM,
-    D3DFMT_X8R8G8B8 => DXGI_FORMAT_B8G8R8X8_UNORM,
+    // D3D9 names the channels starting from the most significant bits,
+    // while DXGI starts from the least significant ones.
     D3DFMT_A8R8G8B8 => DXGI_FORMAT_B8G8R8A8_UNORM,
+    D3DFMT_X8R8G8B8 => DXGI_FORMAT_B8G8R8X8_UNORM,
     D3DFMT_A8B8G8R8 => DXGI_FORMAT_R8G8B8A8_UNORM,
+    D3DFMT_X8B8G8R8 => DXGI_FORMAT_R8G8B8A8_UNORM,
     D3DFMT_G16R16 => DXGI_FORMAT_R16G16_UNORM,
 
     // HDR formats
-    D3DFMT_A2R10G10B10 => DXGI_FORMAT_R10G10B10A2_UNORM,
     D3DFMT_A2B10G10R10 => DXGI_FORMAT_R10G10B10A2_UNORM,
+    // DXGI has no BGR version of this format. Rendering to it works as expected,
+    // but the red and blue channels are swapped in the memory seen by the app.
+    D3DFMT_A2R10G10B10 => DXGI_FORMAT_R10G10B10A2_UNORM,
 
     // Depth / stencil formats
     D3DFMT_D16_LOCKABLE => DXGI_FORMAT_D16_UNORM,
@@ -110,19 +301,19 @@ format_conv! {
     D3DFMT_D32F_LOCKABLE => DXGI_FORMAT_D32_FLOAT,
 
     // Compressed formats
+    // DXT2 and DXT4 have premultiplied alpha, which makes no difference to the GPU.
     D3DFMT_DXT1 => DXGI_FORMAT_BC1_UNORM,
+    D3DFMT_DXT3 => DXGI_FORMAT_BC2_UNORM,
     D3DFMT_DXT2 => DXGI_FORMAT_BC2_UNORM,
-    D3DFMT_DXT3 => DXGI_FORMAT_BC3_UNORM,
-    D3DFMT_DXT4 => DXGI_FORMAT_BC4_UNORM,
-    D3DFMT_DXT5 => DXGI_FORMAT_BC5_UNORM,
+    D3DFMT_DXT5 => DXGI_FORMAT_BC3_UNORM,
+    D3DFMT_DXT4 => DXGI_FORMAT_BC3_UNORM,
 
     // ATI2, TODO: https://aras-p.info/texts/D3D9GPUHacks.html red and green channel swap
     ATI2 => DXGI_FORMAT_BC5_UNORM,
-    // TODO: What is NULL even?
-    NULL => NULL,
-    // Depth Stencil (https://forum.beyond3d.com/threads/multisample-depth-buffer-resolve.38711/)
-    // claiming that RAWZ is different in terms of compression, maybe we should make RAWZ fail
-    // and only support INTZ
+    // Null render targets are never bound, they only need a texture with the right size.
+    D3DFMT_NULL => DXGI_FORMAT_R8_UNORM,
+    // Depth formats which can be sampled from, using a typeless texture.
+    // RAWZ returns the raw depth bits on old GPUs, but the same depth value is close enough.
     RAWZ => DXGI_FORMAT_D24_UNORM_S8_UINT,
     INTZ => DXGI_FORMAT_D24_UNORM_S8_UINT,
 
@@ -147,24 +338,24 @@ format_conv! {
     // Unknown format
     D3DFMT_UNKNOWN => DXGI_FORMAT_UNKNOWN,
 
-    // Unsupported formats
     // TODO: some formats have no support in modern DXGI.
     // We might still be able to approximate them with some other formats though.
-    D3DFMT_P8 => DXGI_FORMAT_UNKNOWN,
-    D3DFMT_A8P8 => DXGI_FORMAT_UNKNOWN,
-    D3DFMT_A4L4 => DXGI_FORMAT_UNKNOWN,
-    D3DFMT_R3G3B2 => DXGI_FORMAT_UNKNOWN,
-    D3DFMT_A8R3G3B2 => DXGI_FORMAT_UNKNOWN,
-    D3DFMT_R8G8B8 => DXGI_FORMAT_UNKNOWN,
-    D3DFMT_D15S1 => DXGI_FORMAT_UNKNOWN,
-    D3DFMT_D24FS8 => DXGI_FORMAT_UNKNOWN,
-    D3DFMT_D24X4S4 => DXGI_FORMAT_UNKNOWN,
-    D3DFMT_S8_LOCKABLE => DXGI_FORMAT_UNKNOWN,
-    D3DFMT_CxV8U8 => DXGI_FORMAT_UNKNOWN,
-    D3DFMT_L6V5U5 => DXGI_FORMAT_UNKNOWN,
-    D3DFMT_X8L8V8U8 => DXGI_FORMAT_UNKNOWN,
-    D3DFMT_A2W10V10U10 => DXGI_FORMAT_UNKNOWN,
-    D3DFMT_Q16W16V16U16 => DXGI_FORMAT_UNKNOWN,
-    D3DFMT_UYVY => DXGI_FORMAT_UNKNOWN,
-    D3DFMT_YUY2 => DXGI_FORMAT_UNKNOWN,
+    unsupported:
+    D3DFMT_P8,
+    D3DFMT_A8P8,
+    D3DFMT_A4L4,
+    D3DFMT_R3G3B2,
+    D3DFMT_A8R3G3B2,
+    D3DFMT_R8G8B8,
+    D3DFMT_D15S1,
+    D3DFMT_D24FS8,
+    D3DFMT_D24X4S4,
+    D3DFMT_S8_LOCKABLE,
+    D3DFMT_CxV8U8,
+    D3DFMT_L6V5U5,
+    D3DFMT_X8L8V8U8,
+    D3DFMT_A2W10V10U10,
+    D3DFMT_Q16W16V16U16,
+    D3DFMT_UYVY,
+    D3DFMT_YUY2,
 }
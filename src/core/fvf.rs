@@ -0,0 +1,102 @@
+//! Implements conversion of flexible vertex formats to vertex declarations.
+
+use winapi::shared::d3d9types::*;
+
+/// Builds the vertex elements equivalent to a flexible vertex format code.
+///
+/// The returned elements all read from the first stream, and do not include the end marker.
+pub fn fvf_to_vertex_elements(fvf: u32) -> Vec<D3DVERTEXELEMENT9> {
+    let mut elems = Vec::new();
+    let mut offset = 0;
+
+    let mut push = |ty: D3DDECLTYPE, size: u16, usage: D3DDECLUSAGE, index: u8| {
+        elems.push(D3DVERTEXELEMENT9 {
+            Stream: 0,
+            Offset: offset,
+            Type: ty as u8,
+            Method: D3DDECLMETHOD_DEFAULT as u8,
+            Usage: usage as u8,
+            UsageIndex: index,
+        });
+        offset += size;
+    };
+
+    // Number of blending weights stored after the position.
+    let betas = match fvf & D3DFVF_POSITION_MASK {
+        D3DFVF_XYZ => {
+            push(D3DDECLTYPE_FLOAT3, 12, D3DDECLUSAGE_POSITION, 0);
+            0
+        }
+        D3DFVF_XYZRHW => {
+            push(D3DDECLTYPE_FLOAT4, 16, D3DDECLUSAGE_POSITIONT, 0);
+            0
+        }
+        D3DFVF_XYZW => {
+            push(D3DDECLTYPE_FLOAT4, 16, D3DDECLUSAGE_POSITION, 0);
+            0
+        }
+        pos @ D3DFVF_XYZB1..=D3DFVF_XYZB5 => {
+            push(D3DDECLTYPE_FLOAT3, 12, D3DDECLUSAGE_POSITION, 0);
+            (pos - D3DFVF_XYZB1) / 2 + 1
+        }
+        _ => 0,
+    };
+
+    if betas > 0 {
+        let indexed = fvf & (D3DFVF_LASTBETA_UBYTE4 | D3DFVF_LASTBETA_D3DCOLOR) != 0;
+
+        // The last beta can store the indices of the matrices to blend.
+        let weights = if indexed { betas - 1 } else { betas };
+
+        // A single element holds at most 4 weights, the fifth one goes in a second element.
+        let first = weights.min(4);
+        if first > 0 {
+            let ty = D3DDECLTYPE_FLOAT1 + first - 1;
+            push(ty, 4 * first as u16, D3DDECLUSAGE_BLENDWEIGHT, 0);
+        }
+
+        if weights > 4 {
+            let rest = weights - 4;
+            let ty = D3DDECLTYPE_FLOAT1 + rest - 1;
+            push(ty, 4 * rest as u16, D3DDECLUSAGE_BLENDWEIGHT, 1);
+        }
+
+        if fvf & D3DFVF_LASTBETA_UBYTE4 != 0 {
+            push(D3DDECLTYPE_UBYTE4, 4, D3DDECLUSAGE_BLENDINDICES, 0);
+        } else if fvf & D3DFVF_LASTBETA_D3DCOLOR != 0 {
+            push(D3DDECLTYPE_D3DCOLOR, 4, D3DDECLUSAGE_BLENDINDICES, 0);
+        }
+    }
+
+    if fvf & D3DFVF_NORMAL != 0 {
+        push(D3DDECLTYPE_FLOAT3, 12, D3DDECLUSAGE_NORMAL, 0);
+    }
+
+    if fvf & D3DFVF_PSIZE != 0 {
+        push(D3DDECLTYPE_FLOAT1, 4, D3DDECLUSAGE_PSIZE, 0);
+    }
+
+    if fvf & D3DFVF_DIFFUSE != 0 {
+        push(D3DDECLTYPE_D3DCOLOR, 4, D3DDECLUSAGE_COLOR, 0);
+    }
+
+    if fvf & D3DFVF_SPECULAR != 0 {
+        push(D3DDECLTYPE_D3DCOLOR, 4, D3DDECLUSAGE_COLOR, 1);
+    }
+
+    let tex_count = (fvf & D3DFVF_TEXCOUNT_MASK) >> D3DFVF_TEXCOUNT_SHIFT;
+
+    for i in 0..tex_count {
+        // Each texture coordinate set has a 2-bit size code.
+        let (ty, size) = match (fvf >> (16 + i * 2)) & 0b11 {
+            D3DFVF_TEXTUREFORMAT1 => (D3DDECLTYPE_FLOAT1, 4),
+            D3DFVF_TEXTUREFORMAT3 => (D3DDECLTYPE_FLOAT3, 12),
+            D3DFVF_TEXTUREFORMAT4 => (D3DDECLTYPE_FLOAT4, 16),
+            _ => (D3DDECLTYPE_FLOAT2, 8),
+        };
+
+        push(ty, size, D3DDECLUSAGE_TEXCOORD, i as u8);
+    }
+
+    elems
+}
@@ -6,17 +6,29 @@
 mod enums;
 pub use self::enums::*;
 
+pub mod blend;
+
+pub mod color;
+
+pub mod depth;
+
 pub mod fmt;
 
+pub mod fvf;
+
 pub mod msample;
 
+pub mod prim;
+
+pub mod sampler;
+
 pub mod str;
 
 mod adapter;
 pub use self::adapter::Adapter;
 
 mod context;
-pub use self::context::Context;
+pub use self::context::{check_fullscreen_mode, display_mode_ex, Context};
 
 use comptr::ComPtr;
 
@@ -0,0 +1,31 @@
+//! Implements primitive-related conversion functions.
+
+use winapi::shared::d3d9types::*;
+use winapi::um::d3dcommon::*;
+
+/// Converts a D3D9 primitive type to the equivalent D3D11 primitive topology.
+///
+/// Returns `None` for primitive types which have no equivalent in D3D11.
+pub fn d3d9_to_d3d11_topology(ty: D3DPRIMITIVETYPE) -> Option<D3D_PRIMITIVE_TOPOLOGY> {
+    match ty {
+        D3DPT_POINTLIST => Some(D3D11_PRIMITIVE_TOPOLOGY_POINTLIST),
+        D3DPT_LINELIST => Some(D3D11_PRIMITIVE_TOPOLOGY_LINELIST),
+        D3DPT_LINESTRIP => Some(D3D11_PRIMITIVE_TOPOLOGY_LINESTRIP),
+        D3DPT_TRIANGLELIST => Some(D3D11_PRIMITIVE_TOPOLOGY_TRIANGLELIST),
+        D3DPT_TRIANGLESTRIP => Some(D3D11_PRIMITIVE_TOPOLOGY_TRIANGLESTRIP),
+        // Triangle fans were removed in D3D10.
+        _ => None,
+    }
+}
+
+/// Determines how many vertices (or indices) are required to draw a number of primitives.
+pub fn primitive_vertex_count(ty: D3DPRIMITIVETYPE, count: u32) -> u32 {
+    match ty {
+        D3DPT_POINTLIST => count,
+        D3DPT_LINELIST => count * 2,
+        D3DPT_LINESTRIP => count + 1,
+        D3DPT_TRIANGLELIST => count * 3,
+        D3DPT_TRIANGLESTRIP | D3DPT_TRIANGLEFAN => count + 2,
+        _ => 0,
+    }
+}
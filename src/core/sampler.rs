@@ -0,0 +1,55 @@
+//! Implements sampler-related conversion functions.
+
+use winapi::shared::d3d9types::*;
+use winapi::um::d3d11::*;
+
+/// Converts a set of D3D9 texture filters to the equivalent D3D11 filter.
+///
+/// Linear filtering is upgraded to anisotropic filtering if the app
+/// asked for a maximum anisotropy above 1.
+pub fn d3d9_to_d3d11_filter(
+    min: D3DTEXTUREFILTERTYPE,
+    mag: D3DTEXTUREFILTERTYPE,
+    mip: D3DTEXTUREFILTERTYPE,
+    max_anisotropy: u32,
+) -> D3D11_FILTER {
+    if min == D3DTEXF_ANISOTROPIC || mag == D3DTEXF_ANISOTROPIC {
+        return D3D11_FILTER_ANISOTROPIC;
+    }
+
+    // D3D11 has no equivalent of the quad filters, linear filtering is the closest match.
+    let is_linear = |filter| match filter {
+        D3DTEXF_LINEAR | D3DTEXF_PYRAMIDALQUAD | D3DTEXF_GAUSSIANQUAD => true,
+        _ => false,
+    };
+
+    if max_anisotropy > 1 && is_linear(min) && is_linear(mag) {
+        return D3D11_FILTER_ANISOTROPIC;
+    }
+
+    // D3D11's filters are a bit field, with one bit for each of the filtered dimensions.
+    let mut filter = D3D11_FILTER_MIN_MAG_MIP_POINT;
+
+    if is_linear(min) {
+        filter |= D3D11_FILTER_MIN_LINEAR_MAG_MIP_POINT;
+    }
+    if is_linear(mag) {
+        filter |= D3D11_FILTER_MIN_POINT_MAG_LINEAR_MIP_POINT;
+    }
+    if is_linear(mip) {
+        filter |= D3D11_FILTER_MIN_MAG_POINT_MIP_LINEAR;
+    }
+
+    filter
+}
+
+/// Converts a D3D9 texture addressing mode to the equivalent D3D11 mode.
+pub fn d3d9_to_d3d11_address_mode(mode: D3DTEXTUREADDRESS) -> D3D11_TEXTURE_ADDRESS_MODE {
+    match mode {
+        D3DTADDRESS_MIRROR => D3D11_TEXTURE_ADDRESS_MIRROR,
+        D3DTADDRESS_CLAMP => D3D11_TEXTURE_ADDRESS_CLAMP,
+        D3DTADDRESS_BORDER => D3D11_TEXTURE_ADDRESS_BORDER,
+        D3DTADDRESS_MIRRORONCE => D3D11_TEXTURE_ADDRESS_MIRROR_ONCE,
+        _ => D3D11_TEXTURE_ADDRESS_WRAP,
+    }
+}
@@ -0,0 +1,433 @@
+use std::ptr;
+
+use winapi::shared::{dxgiformat::DXGI_FORMAT, minwindef::BOOL, windef::RECT};
+use winapi::um::{d3d11::*, d3dcommon::D3D11_PRIMITIVE_TOPOLOGY_TRIANGLESTRIP};
+
+use comptr::ComPtr;
+
+use crate::core::{fmt::dxgi_linear_format, *};
+use crate::shader;
+use crate::Error;
+
+use super::{ConstantBuffer, Device, DeviceContext, Texture2D};
+
+/// Vertex shader which generates a quad covering the whole viewport.
+///
+/// The only constant is the region of the source texture to map to the quad,
+/// as an offset in `xy` and a size in `zw`, in texture coordinates.
+const BLIT_VERTEX_SHADER: &str = r#"
+cbuffer Blit : register(b0) {
+    float4 src_rect;
+};
+
+void main(uint id : SV_VertexID, out float4 pos : SV_Position, out float2 uv : TEXCOORD0) {
+    float2 corner = float2(id & 1, id >> 1);
+    pos = float4(corner.x * 2.0 - 1.0, 1.0 - corner.y * 2.0, 0.0, 1.0);
+    uv = src_rect.xy + corner * src_rect.zw;
+}
+"#;
+
+/// Pixel shader which samples the source texture.
+const BLIT_PIXEL_SHADER: &str = r#"
+Texture2D src : register(t0);
+SamplerState src_sampler : register(s0);
+
+float4 main(float4 pos : SV_Position, float2 uv : TEXCOORD0) : SV_Target {
+    return src.Sample(src_sampler, uv);
+}
+"#;
+
+/// Pixel shader which copies the first sample of a multisampled depth buffer.
+const DEPTH_RESOLVE_PIXEL_SHADER: &str = r#"
+Texture2DMS<float> src : register(t0);
+
+float main(float4 pos : SV_Position) : SV_Depth {
+    return src.Load(int2(pos.xy), 0);
+}
+"#;
+
+/// Copies regions of textures to render targets, by drawing textured quads.
+///
+/// This is used when a copy requires scaling or format conversion,
+/// which D3D11's copy functions cannot do.
+///
+/// Blitting overwrites the pipeline state, which the caller must restore.
+pub struct Blitter {
+    vs: ComPtr<ID3D11VertexShader>,
+    ps: ComPtr<ID3D11PixelShader>,
+    depth_ps: ComPtr<ID3D11PixelShader>,
+    // Depth / stencil state which writes every pixel's depth.
+    depth_state: ComPtr<ID3D11DepthStencilState>,
+    point_sampler: ComPtr<ID3D11SamplerState>,
+    linear_sampler: ComPtr<ID3D11SamplerState>,
+    consts: ConstantBuffer<f32>,
+}
+
+impl Blitter {
+    /// Compiles the blit shaders and creates the required state objects.
+    pub fn new(device: &Device) -> Result<Self, Error> {
+        let vs = shader::compile(
+            BLIT_VERTEX_SHADER,
+            shader::profile(shader::ShaderKind::Vertex),
+        )?;
+        let ps = shader::compile(
+            BLIT_PIXEL_SHADER,
+            shader::profile(shader::ShaderKind::Pixel),
+        )?;
+        let depth_ps = shader::compile(
+            DEPTH_RESOLVE_PIXEL_SHADER,
+            shader::profile(shader::ShaderKind::Pixel),
+        )?;
+
+        Ok(Self {
+            vs: device.create_vertex_shader(&vs)?,
+            ps: device.create_pixel_shader(&ps)?,
+            depth_ps: device.create_pixel_shader(&depth_ps)?,
+            depth_state: Self::create_depth_state(device)?,
+            point_sampler: Self::create_sampler(device, D3D11_FILTER_MIN_MAG_MIP_POINT)?,
+            linear_sampler: Self::create_sampler(device, D3D11_FILTER_MIN_MAG_MIP_LINEAR)?,
+            consts: ConstantBuffer::new(device, 1)?,
+        })
+    }
+
+    /// Creates a sampler which clamps texture coordinates.
+    fn create_sampler(
+        device: &ID3D11Device,
+        filter: D3D11_FILTER,
+    ) -> Result<ComPtr<ID3D11SamplerState>, Error> {
+        let desc = D3D11_SAMPLER_DESC {
+            Filter: filter,
+            AddressU: D3D11_TEXTURE_ADDRESS_CLAMP,
+            AddressV: D3D11_TEXTURE_ADDRESS_CLAMP,
+            AddressW: D3D11_TEXTURE_ADDRESS_CLAMP,
+            MipLODBias: 0.0,
+            MaxAnisotropy: 1,
+            ComparisonFunc: D3D11_COMPARISON_NEVER,
+            BorderColor: [0.0; 4],
+            MinLOD: 0.0,
+            MaxLOD: D3D11_FLOAT32_MAX,
+        };
+
+        let sampler = unsafe {
+            let mut ptr = ptr::null_mut();
+            let result = device.CreateSamplerState(&desc, &mut ptr);
+            if_not_success_err!(check_hresult(result, "Failed to create blit sampler state"));
+            ComPtr::new(ptr)
+        };
+
+        Ok(sampler)
+    }
+
+    /// Creates a depth / stencil state which overwrites the depth of every pixel.
+    fn create_depth_state(device: &ID3D11Device) -> Result<ComPtr<ID3D11DepthStencilState>, Error> {
+        let face = D3D11_DEPTH_STENCILOP_DESC {
+            StencilFailOp: D3D11_STENCIL_OP_KEEP,
+            StencilDepthFailOp: D3D11_STENCIL_OP_KEEP,
+            StencilPassOp: D3D11_STENCIL_OP_KEEP,
+            StencilFunc: D3D11_COMPARISON_ALWAYS,
+        };
+
+        let desc = D3D11_DEPTH_STENCIL_DESC {
+            DepthEnable: 1,
+            DepthWriteMask: D3D11_DEPTH_WRITE_MASK_ALL,
+            DepthFunc: D3D11_COMPARISON_ALWAYS,
+            StencilEnable: 0,
+            StencilReadMask: 0xff,
+            StencilWriteMask: 0xff,
+            FrontFace: face,
+            BackFace: face,
+        };
+
+        let state = unsafe {
+            let mut ptr = ptr::null_mut();
+            let result = device.CreateDepthStencilState(&desc, &mut ptr);
+            if_not_success_err!(check_hresult(
+                result,
+                "Failed to create depth resolve depth / stencil state"
+            ));
+            ComPtr::new(ptr)
+        };
+
+        Ok(state)
+    }
+
+    /// Draws a region of a subresource into a region of a render target.
+    ///
+    /// Multisampled sources are resolved first. The source is copied to a temporary texture,
+    /// since render targets usually cannot be sampled from directly.
+    #[allow(clippy::too_many_arguments)]
+    pub fn blit(
+        &mut self,
+        device: &Device,
+        ctx: &DeviceContext,
+        (src, src_subres): (&Texture2D, u32),
+        src_rect: &RECT,
+        dest: *mut ID3D11RenderTargetView,
+        dest_rect: &RECT,
+        linear: bool,
+    ) -> Result<(), Error> {
+        let desc = src.desc();
+        // Typeless sources are copied to a texture with the format of their linear views.
+        let format: DXGI_FORMAT = dxgi_linear_format(desc.Format);
+
+        let width = (src_rect.right - src_rect.left) as u32;
+        let height = (src_rect.bottom - src_rect.top) as u32;
+
+        // The region of the temporary texture which contains the source region.
+        let (temp, temp_size, offset) = if desc.SampleDesc.Count > 1 {
+            // Multisampled textures can only be resolved as a whole.
+            let size = (desc.Width, desc.Height);
+            let temp = Texture2D::new_sampled(device, size, format)?;
+
+            unsafe {
+                ctx.ResolveSubresource(
+                    temp.as_resource(),
+                    0,
+                    src.as_resource(),
+                    src_subres,
+                    format,
+                );
+            }
+
+            (temp, size, (src_rect.left as u32, src_rect.top as u32))
+        } else {
+            let size = (width, height);
+            let temp = Texture2D::new_sampled(device, size, format)?;
+
+            let src_box = D3D11_BOX {
+                left: src_rect.left as u32,
+                top: src_rect.top as u32,
+                front: 0,
+                right: src_rect.right as u32,
+                bottom: src_rect.bottom as u32,
+                back: 1,
+            };
+
+            unsafe {
+                ctx.CopySubresourceRegion(
+                    temp.as_resource(),
+                    0,
+                    0,
+                    0,
+                    0,
+                    src.as_resource(),
+                    src_subres,
+                    &src_box,
+                );
+            }
+
+            (temp, size, (0, 0))
+        };
+
+        let srv = temp.create_sr_view(device)?.ok_or(Error::InvalidCall)?;
+        let view: *mut ID3D11ShaderResourceView = srv.as_mut();
+
+        let (temp_width, temp_height) = (temp_size.0 as f32, temp_size.1 as f32);
+        self.consts.set(
+            0,
+            &[[
+                offset.0 as f32 / temp_width,
+                offset.1 as f32 / temp_height,
+                width as f32 / temp_width,
+                height as f32 / temp_height,
+            ]],
+        )?;
+        self.consts.upload(ctx)?;
+
+        let viewport = D3D11_VIEWPORT {
+            TopLeftX: dest_rect.left as f32,
+            TopLeftY: dest_rect.top as f32,
+            Width: (dest_rect.right - dest_rect.left) as f32,
+            Height: (dest_rect.bottom - dest_rect.top) as f32,
+            MinDepth: 0.0,
+            MaxDepth: 1.0,
+        };
+
+        let sampler: *mut ID3D11SamplerState = if linear {
+            self.linear_sampler.as_mut()
+        } else {
+            self.point_sampler.as_mut()
+        };
+
+        unsafe {
+            ctx.IASetInputLayout(ptr::null_mut());
+            ctx.IASetPrimitiveTopology(D3D11_PRIMITIVE_TOPOLOGY_TRIANGLESTRIP);
+
+            ctx.VSSetShader(self.vs.as_mut(), ptr::null(), 0);
+            ctx.VSSetConstantBuffers(0, 1, &self.consts.as_buffer());
+
+            // The point sprite geometry shader might still be bound.
+            ctx.GSSetShader(ptr::null_mut(), ptr::null(), 0);
+
+            ctx.PSSetShader(self.ps.as_mut(), ptr::null(), 0);
+            ctx.PSSetShaderResources(0, 1, &view);
+            ctx.PSSetSamplers(0, 1, &sampler);
+
+            // The default states disable blending and scissoring.
+            ctx.RSSetState(ptr::null_mut());
+            ctx.RSSetViewports(1, &viewport);
+            ctx.OMSetBlendState(ptr::null_mut(), &[0.0; 4], 0xffff_ffff);
+            ctx.OMSetDepthStencilState(ptr::null_mut(), 0);
+            ctx.OMSetRenderTargets(1, &dest, ptr::null_mut());
+
+            ctx.Draw(4, 0);
+
+            // Don't keep the temporary texture bound.
+            ctx.PSSetShaderResources(0, 1, &ptr::null_mut());
+        }
+
+        Ok(())
+    }
+
+    /// Copies the first sample of every pixel of a multisampled depth buffer
+    /// into a depth buffer with a single sample.
+    ///
+    /// D3D11 cannot resolve depth buffers, so the depth is written by a pixel shader.
+    pub fn resolve_depth(
+        &mut self,
+        device: &Device,
+        ctx: &DeviceContext,
+        src: &Texture2D,
+        dest: *mut ID3D11DepthStencilView,
+    ) -> Result<(), Error> {
+        let desc = src.desc();
+
+        let srv = src.create_sr_view(device)?.ok_or(Error::InvalidCall)?;
+        let view: *mut ID3D11ShaderResourceView = srv.as_mut();
+
+        // The vertex shader covers the whole viewport.
+        self.consts.set(0, &[[0.0, 0.0, 1.0, 1.0]])?;
+        self.consts.upload(ctx)?;
+
+        let viewport = D3D11_VIEWPORT {
+            TopLeftX: 0.0,
+            TopLeftY: 0.0,
+            Width: desc.Width as f32,
+            Height: desc.Height as f32,
+            MinDepth: 0.0,
+            MaxDepth: 1.0,
+        };
+
+        unsafe {
+            ctx.IASetInputLayout(ptr::null_mut());
+            ctx.IASetPrimitiveTopology(D3D11_PRIMITIVE_TOPOLOGY_TRIANGLESTRIP);
+
+            ctx.VSSetShader(self.vs.as_mut(), ptr::null(), 0);
+            ctx.VSSetConstantBuffers(0, 1, &self.consts.as_buffer());
+
+            // The point sprite geometry shader might still be bound.
+            ctx.GSSetShader(ptr::null_mut(), ptr::null(), 0);
+
+            ctx.PSSetShader(self.depth_ps.as_mut(), ptr::null(), 0);
+            ctx.PSSetShaderResources(0, 1, &view);
+
+            ctx.RSSetState(ptr::null_mut());
+            ctx.RSSetViewports(1, &viewport);
+            ctx.OMSetBlendState(ptr::null_mut(), &[0.0; 4], 0xffff_ffff);
+            ctx.OMSetDepthStencilState(self.depth_state.as_mut(), 0);
+            ctx.OMSetRenderTargets(0, ptr::null(), dest);
+
+            ctx.Draw(4, 0);
+
+            // Don't keep the depth buffer bound as a texture.
+            ctx.PSSetShaderResources(0, 1, &ptr::null_mut());
+        }
+
+        Ok(())
+    }
+
+    /// Clears some rectangles of a depth / stencil view, by drawing quads over them.
+    ///
+    /// D3D11 can only clear whole depth / stencil views. The depth is written
+    /// by a viewport whose depth range only contains the cleared value,
+    /// and the stencil value is written by the stencil test.
+    pub fn clear_depth_stencil(
+        &mut self,
+        device: &Device,
+        ctx: &DeviceContext,
+        dest: *mut ID3D11DepthStencilView,
+        rects: &[RECT],
+        depth: Option<f32>,
+        stencil: Option<u8>,
+    ) -> Result<(), Error> {
+        let state = Self::create_clear_state(device, depth.is_some(), stencil.is_some())?;
+        let depth = depth.unwrap_or(0.0).max(0.0).min(1.0);
+
+        // The vertex shader doesn't read any texture coordinates.
+        self.consts.set(0, &[[0.0; 4]])?;
+        self.consts.upload(ctx)?;
+
+        unsafe {
+            ctx.IASetInputLayout(ptr::null_mut());
+            ctx.IASetPrimitiveTopology(D3D11_PRIMITIVE_TOPOLOGY_TRIANGLESTRIP);
+
+            ctx.VSSetShader(self.vs.as_mut(), ptr::null(), 0);
+            ctx.VSSetConstantBuffers(0, 1, &self.consts.as_buffer());
+
+            ctx.GSSetShader(ptr::null_mut(), ptr::null(), 0);
+
+            // Only the depth / stencil buffer is written.
+            ctx.PSSetShader(ptr::null_mut(), ptr::null(), 0);
+
+            ctx.RSSetState(ptr::null_mut());
+            ctx.OMSetBlendState(ptr::null_mut(), &[0.0; 4], 0xffff_ffff);
+            ctx.OMSetDepthStencilState(state.as_mut(), u32::from(stencil.unwrap_or(0)));
+            ctx.OMSetRenderTargets(0, ptr::null(), dest);
+
+            for rect in rects {
+                let viewport = D3D11_VIEWPORT {
+                    TopLeftX: rect.left as f32,
+                    TopLeftY: rect.top as f32,
+                    Width: (rect.right - rect.left) as f32,
+                    Height: (rect.bottom - rect.top) as f32,
+                    MinDepth: depth,
+                    MaxDepth: depth,
+                };
+
+                ctx.RSSetViewports(1, &viewport);
+                ctx.Draw(4, 0);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Creates a depth / stencil state which overwrites the depth and / or the stencil
+    /// value of every pixel.
+    fn create_clear_state(
+        device: &ID3D11Device,
+        depth: bool,
+        stencil: bool,
+    ) -> Result<ComPtr<ID3D11DepthStencilState>, Error> {
+        let face = D3D11_DEPTH_STENCILOP_DESC {
+            StencilFailOp: D3D11_STENCIL_OP_REPLACE,
+            StencilDepthFailOp: D3D11_STENCIL_OP_REPLACE,
+            StencilPassOp: D3D11_STENCIL_OP_REPLACE,
+            StencilFunc: D3D11_COMPARISON_ALWAYS,
+        };
+
+        let desc = D3D11_DEPTH_STENCIL_DESC {
+            DepthEnable: depth as BOOL,
+            DepthWriteMask: D3D11_DEPTH_WRITE_MASK_ALL,
+            DepthFunc: D3D11_COMPARISON_ALWAYS,
+            StencilEnable: stencil as BOOL,
+            StencilReadMask: 0xff,
+            StencilWriteMask: 0xff,
+            FrontFace: face,
+            BackFace: face,
+        };
+
+        // D3D11 returns the same object for identical descriptions, so this is cheap.
+        let state = unsafe {
+            let mut ptr = ptr::null_mut();
+            let result = device.CreateDepthStencilState(&desc, &mut ptr);
+            if_not_success_err!(check_hresult(
+                result,
+                "Failed to create depth / stencil clear state"
+            ));
+            ComPtr::new(ptr)
+        };
+
+        Ok(state)
+    }
+}
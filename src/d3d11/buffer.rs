@@ -1,4 +1,6 @@
-use std::{mem, ptr};
+use std::cell::Cell;
+use std::mem::MaybeUninit;
+use std::ptr;
 
 use winapi::um::d3d11::*;
 
@@ -7,12 +9,18 @@ use comptr::ComPtr;
 use crate::core::*;
 
 use super::util::d3d_usage_to_d3d11;
+use super::DeviceContext;
 use crate::Error;
 
 /// Wrapper for a vertex/index buffer.
 #[derive(Clone)]
 pub struct Buffer {
     buffer: ComPtr<ID3D11Buffer>,
+    // Copy of a buffer which is not dynamic, which the CPU can read and write.
+    // Locks map this copy, and the locked range is uploaded to the GPU when unlocking.
+    staging: Option<ComPtr<ID3D11Buffer>>,
+    // Range which was locked for writing, and has to be uploaded when unlocking.
+    dirty: Cell<Option<(u32, u32)>>,
 }
 
 impl Buffer {
@@ -26,25 +34,46 @@ impl Buffer {
     ) -> Result<Self, Error> {
         let (usage, _, cpu_flags) = d3d_usage_to_d3d11(usage, pool)?;
 
-        let desc = D3D11_BUFFER_DESC {
-            ByteWidth: len,
-            Usage: usage,
-            BindFlags: bind_flags,
-            CPUAccessFlags: cpu_flags,
-            MiscFlags: 0,
-            StructureByteStride: 0,
-        };
+        // Dynamic buffers can be mapped directly. Others are stored in GPU memory,
+        // since buffers bound to the pipeline cannot be staging resources,
+        // but D3D9 apps still expect to be able to lock them.
+        let dynamic = usage == D3D11_USAGE_DYNAMIC;
 
-        let buffer = unsafe {
-            let mut ptr = ptr::null_mut();
+        let buffer = create_buffer(
+            device,
+            &D3D11_BUFFER_DESC {
+                ByteWidth: len,
+                Usage: if dynamic { usage } else { D3D11_USAGE_DEFAULT },
+                BindFlags: bind_flags,
+                CPUAccessFlags: if dynamic { cpu_flags } else { 0 },
+                MiscFlags: 0,
+                StructureByteStride: 0,
+            },
+        )?;
 
-            let result = device.CreateBuffer(&desc, ptr::null(), &mut ptr);
-            if_not_success_err!(check_hresult(result, "Failed to create buffer"));
+        let staging = if dynamic {
+            None
+        } else {
+            let staging = create_buffer(
+                device,
+                &D3D11_BUFFER_DESC {
+                    ByteWidth: len,
+                    Usage: D3D11_USAGE_STAGING,
+                    BindFlags: 0,
+                    CPUAccessFlags: D3D11_CPU_ACCESS_READ | D3D11_CPU_ACCESS_WRITE,
+                    MiscFlags: 0,
+                    StructureByteStride: 0,
+                },
+            )?;
 
-            ComPtr::new(ptr)
+            Some(staging)
         };
 
-        Ok(Self { buffer })
+        Ok(Self {
+            buffer,
+            staging,
+            dirty: Cell::new(None),
+        })
     }
 
     /// Retrieves this buffer as a resource.
@@ -55,13 +84,113 @@ impl Buffer {
     /// Retrieves the description of this buffer.
     pub fn desc(&self) -> D3D11_BUFFER_DESC {
         unsafe {
-            let mut buf = mem::uninitialized();
-            self.buffer.GetDesc(&mut buf);
-            buf
+            let mut buf = MaybeUninit::uninit();
+            self.buffer.GetDesc(buf.as_mut_ptr());
+            buf.assume_init()
         }
     }
 
     pub fn as_buffer(&self) -> *mut ID3D11Buffer {
         self.buffer.as_mut()
     }
+
+    /// Maps a range of this buffer.
+    ///
+    /// A `size` of 0 maps everything from `offset` to the end of the buffer.
+    pub fn lock(
+        &self,
+        ctx: &DeviceContext,
+        offset: u32,
+        size: u32,
+        flags: LockFlags,
+    ) -> Result<*mut u8, Error> {
+        let len = self.desc().ByteWidth;
+
+        let end = if size == 0 {
+            len
+        } else {
+            offset.saturating_add(size)
+        };
+        if offset > len || end > len {
+            error!("Tried to lock a buffer range out of bounds");
+            return Err(Error::InvalidCall);
+        }
+
+        let read_only = flags.intersects(LockFlags::READ_ONLY);
+
+        let data = if let Some(ref staging) = self.staging {
+            // The staging copy isn't used by the GPU, except when uploading it,
+            // so the discard and no-overwrite flags have no effect on it.
+            let map_type = if read_only {
+                D3D11_MAP_READ
+            } else {
+                D3D11_MAP_READ_WRITE
+            };
+
+            let mapped = ctx.map_with_type(staging.upcast().as_mut(), 0, map_type, flags)?;
+
+            if !read_only {
+                self.dirty.set(Some((offset, end)));
+            }
+
+            mapped.pData
+        } else {
+            if read_only {
+                error!("Dynamic buffers cannot be read from");
+                return Err(Error::InvalidCall);
+            }
+
+            // Dynamic buffers are either discarded, or mapped with no-overwrite if the app asks.
+            ctx.map(self.as_resource(), 0, flags, UsageFlags::DYNAMIC)?
+                .pBits
+        };
+
+        Ok(unsafe { (data as *mut u8).offset(offset as isize) })
+    }
+
+    /// Unmaps a buffer previously mapped with `lock`.
+    ///
+    /// For buffers which are not dynamic, the range which was written is uploaded to the GPU.
+    pub fn unlock(&self, ctx: &DeviceContext) {
+        let staging = match self.staging {
+            Some(ref staging) => staging.upcast().as_mut(),
+            None => return ctx.unmap(self.as_resource(), 0),
+        };
+
+        ctx.unmap(staging, 0);
+
+        if let Some((start, end)) = self.dirty.take() {
+            if start == end {
+                return;
+            }
+
+            let src_box = D3D11_BOX {
+                left: start,
+                top: 0,
+                front: 0,
+                right: end,
+                bottom: 1,
+                back: 1,
+            };
+
+            unsafe {
+                ctx.CopySubresourceRegion(self.as_resource(), 0, start, 0, 0, staging, 0, &src_box);
+            }
+        }
+    }
+}
+
+/// Creates a buffer with no initial data.
+fn create_buffer(
+    device: &ID3D11Device,
+    desc: &D3D11_BUFFER_DESC,
+) -> Result<ComPtr<ID3D11Buffer>, Error> {
+    unsafe {
+        let mut ptr = ptr::null_mut();
+
+        let result = device.CreateBuffer(desc, ptr::null(), &mut ptr);
+        if_not_success_err!(check_hresult(result, "Failed to create buffer"));
+
+        Ok(ComPtr::new(ptr))
+    }
 }
@@ -0,0 +1,156 @@
+use std::{cmp, mem, ptr, slice};
+
+use winapi::um::d3d11::*;
+
+use comptr::ComPtr;
+
+use crate::core::*;
+
+use super::DeviceContext;
+use crate::Error;
+
+/// A shader constant register, as seen by D3D9.
+pub type Vec4<T> = [T; 4];
+
+/// An array of shader constant registers, mirrored in a D3D11 constant buffer.
+///
+/// D3D9 apps update constants one register range at a time, so we keep a copy of them
+/// on the CPU and only upload the span of registers which was modified before a draw call.
+pub struct ConstantBuffer<T: Copy + Default> {
+    registers: Box<[Vec4<T>]>,
+    buffer: ComPtr<ID3D11Buffer>,
+    // The smallest range of registers containing every register modified since the last upload.
+    dirty: Option<(usize, usize)>,
+}
+
+impl<T: Copy + Default> ConstantBuffer<T> {
+    /// Creates a new constant buffer with a certain number of registers.
+    pub fn new(device: &ID3D11Device, count: usize) -> Result<Self, Error> {
+        // The buffer is updated with `UpdateSubresource`, which can update part of it.
+        let desc = D3D11_BUFFER_DESC {
+            ByteWidth: (count * mem::size_of::<Vec4<T>>()) as u32,
+            Usage: D3D11_USAGE_DEFAULT,
+            BindFlags: D3D11_BIND_CONSTANT_BUFFER,
+            CPUAccessFlags: 0,
+            MiscFlags: 0,
+            StructureByteStride: 0,
+        };
+
+        let buffer = unsafe {
+            let mut ptr = ptr::null_mut();
+            let result = device.CreateBuffer(&desc, ptr::null(), &mut ptr);
+            if_not_success_err!(check_hresult(result, "Failed to create constant buffer"));
+            ComPtr::new(ptr)
+        };
+
+        Ok(Self {
+            registers: vec![Vec4::<T>::default(); count].into_boxed_slice(),
+            buffer,
+            // Make sure the initial (zeroed) values get uploaded.
+            dirty: Some((0, count)),
+        })
+    }
+
+    /// Overwrites some registers, starting at `start`.
+    pub fn set(&mut self, start: u32, data: &[Vec4<T>]) -> Result<(), Error> {
+        let range = self.range(start, data.len() as u32)?;
+
+        self.dirty = Some(match self.dirty {
+            Some((start, end)) => (cmp::min(start, range.start), cmp::max(end, range.end)),
+            None => (range.start, range.end),
+        });
+
+        self.registers[range].copy_from_slice(data);
+        Ok(())
+    }
+
+    /// Retrieves `count` registers, starting at `start`.
+    pub fn get(&self, start: u32, count: u32) -> Result<&[Vec4<T>], Error> {
+        let range = self.range(start, count)?;
+        Ok(&self.registers[range])
+    }
+
+    /// Uploads the registers which have changed since the last upload to the GPU.
+    pub fn upload(&mut self, ctx: &DeviceContext) -> Result<(), Error> {
+        let (start, end) = match self.dirty.take() {
+            Some(range) => range,
+            None => return Ok(()),
+        };
+
+        let size = mem::size_of::<Vec4<T>>();
+        let data = unsafe {
+            let len = self.registers.len() * size;
+            slice::from_raw_parts(self.registers.as_ptr() as *const u8, len)
+        };
+
+        let resource = self.buffer.upcast().as_mut();
+        ctx.update_buffer(resource, data, (start * size) as u32, (end * size) as u32);
+
+        Ok(())
+    }
+
+    /// Retrieves the underlying D3D11 buffer.
+    pub fn as_buffer(&self) -> *mut ID3D11Buffer {
+        self.buffer.as_mut()
+    }
+
+    /// Checks that a range of registers is in bounds.
+    fn range(&self, start: u32, count: u32) -> Result<std::ops::Range<usize>, Error> {
+        let start = start as usize;
+        let end = start.saturating_add(count as usize);
+
+        if end > self.registers.len() {
+            error!(
+                "Shader constant range {}..{} is out of bounds (max {})",
+                start,
+                end,
+                self.registers.len()
+            );
+            return Err(Error::InvalidCall);
+        }
+
+        Ok(start..end)
+    }
+}
+
+/// All of the constants of a shader stage.
+///
+/// The buffers are meant to be bound to consecutive slots, in the order of the fields.
+pub struct ShaderConstants {
+    pub float: ConstantBuffer<f32>,
+    pub int: ConstantBuffer<i32>,
+    // Booleans are stored in the first component of each register.
+    pub bool: ConstantBuffer<u32>,
+}
+
+impl ShaderConstants {
+    /// Creates the constant buffers for a shader stage.
+    pub fn new(
+        device: &ID3D11Device,
+        float_count: usize,
+        int_count: usize,
+        bool_count: usize,
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            float: ConstantBuffer::new(device, float_count)?,
+            int: ConstantBuffer::new(device, int_count)?,
+            bool: ConstantBuffer::new(device, bool_count)?,
+        })
+    }
+
+    /// Uploads the modified constants to the GPU.
+    pub fn upload(&mut self, ctx: &DeviceContext) -> Result<(), Error> {
+        self.float.upload(ctx)?;
+        self.int.upload(ctx)?;
+        self.bool.upload(ctx)
+    }
+
+    /// Retrieves the underlying buffers, in slot order.
+    pub fn buffers(&self) -> [*mut ID3D11Buffer; 3] {
+        [
+            self.float.as_buffer(),
+            self.int.as_buffer(),
+            self.bool.as_buffer(),
+        ]
+    }
+}
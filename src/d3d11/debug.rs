@@ -0,0 +1,63 @@
+use std::{ffi::CStr, mem, ptr};
+
+use winapi::um::d3d11::ID3D11Device;
+use winapi::um::d3d11sdklayers::*;
+
+use comptr::ComPtr;
+
+use super::query_interface;
+
+/// Forwards the messages of D3D11's debug layer to our logger.
+pub struct InfoQueue {
+    queue: ComPtr<ID3D11InfoQueue>,
+}
+
+impl InfoQueue {
+    /// Retrieves the info queue of a device.
+    ///
+    /// Returns `None` if the device was created without the debug layer.
+    pub fn new(device: &ID3D11Device) -> Option<Self> {
+        query_interface(device).map(|queue| Self { queue })
+    }
+
+    /// Logs and removes all the messages stored in the queue.
+    pub fn flush(&self) {
+        let queue = &self.queue;
+
+        let count = unsafe { queue.GetNumStoredMessages() };
+
+        for index in 0..count {
+            unsafe {
+                let mut len = 0;
+                if queue.GetMessage(index, ptr::null_mut(), &mut len) != 0 {
+                    continue;
+                }
+
+                // The message's description is stored right after it,
+                // so the buffer must be aligned for the structure.
+                let words = (len + mem::size_of::<u64>() - 1) / mem::size_of::<u64>();
+                let mut buffer = vec![0u64; words];
+                let message = buffer.as_mut_ptr() as *mut D3D11_MESSAGE;
+
+                if queue.GetMessage(index, message, &mut len) != 0 {
+                    continue;
+                }
+
+                let message = &*message;
+                let text = CStr::from_ptr(message.pDescription).to_string_lossy();
+
+                match message.Severity {
+                    D3D11_MESSAGE_SEVERITY_CORRUPTION | D3D11_MESSAGE_SEVERITY_ERROR => {
+                        error!("D3D11: {}", text)
+                    }
+                    D3D11_MESSAGE_SEVERITY_WARNING => warn!("D3D11: {}", text),
+                    _ => info!("D3D11: {}", text),
+                }
+            }
+        }
+
+        unsafe {
+            queue.ClearStoredMessages();
+        }
+    }
+}
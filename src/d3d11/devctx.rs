@@ -1,17 +1,30 @@
-use std::{mem, ops, ptr};
+use std::mem::{self, MaybeUninit};
+use std::{cmp, ops, ptr};
 
-use winapi::shared::{d3d9types::*, winerror};
+use winapi::shared::{d3d9types::*, windef::RECT, winerror};
 use winapi::um::d3d11::*;
+use winapi::um::d3d11_1::{ID3D11DeviceContext1, ID3DUserDefinedAnnotation};
 
 use comptr::ComPtr;
 
-use crate::core::*;
+use crate::core::{
+    fmt::{dxgi_format_block_info, dxgi_linear_format},
+    *,
+};
 use crate::Error;
 
-/// Wrapper for a D3D11 immediate context.
+use super::{util::query_interface, Texture2D};
+
+/// Wrapper for a D3D11 immediate or deferred context.
 #[derive(Clone)]
 pub struct DeviceContext {
     ctx: ComPtr<ID3D11DeviceContext>,
+    // The D3D11.1 version of the context, if the runtime supports it.
+    ctx1: Option<ComPtr<ID3D11DeviceContext1>>,
+    // Whether the driver can update part of a constant buffer.
+    partial_cb_updates: bool,
+    // Interface used to annotate the commands, for graphics debuggers.
+    annotation: Option<ComPtr<ID3DUserDefinedAnnotation>>,
 }
 
 impl DeviceContext {
@@ -23,7 +36,125 @@ impl DeviceContext {
             ComPtr::new(ptr)
         };
 
-        Self { ctx }
+        Self::from_context(device, ctx)
+    }
+
+    /// Creates a deferred context, which records commands into command lists.
+    pub fn new_deferred(device: &ID3D11Device) -> Result<Self, Error> {
+        let ctx = unsafe {
+            let mut ptr = ptr::null_mut();
+            let result = device.CreateDeferredContext(0, &mut ptr);
+            if_not_success_err!(check_hresult(result, "Failed to create deferred context"));
+            ComPtr::new(ptr)
+        };
+
+        Ok(Self::from_context(device, ctx))
+    }
+
+    /// Wraps a context, retrieving its newer interfaces.
+    fn from_context(device: &ID3D11Device, ctx: ComPtr<ID3D11DeviceContext>) -> Self {
+        let ctx1 = query_interface(&ctx);
+
+        if ctx1.is_none() {
+            warn!("D3D11.1 is not supported, some operations will be emulated");
+        }
+
+        // Even with D3D11.1, the driver has to support partial constant buffer updates.
+        let partial_cb_updates = ctx1.is_some()
+            && unsafe {
+                let mut options: D3D11_FEATURE_DATA_D3D11_OPTIONS = mem::zeroed();
+                let result = device.CheckFeatureSupport(
+                    D3D11_FEATURE_D3D11_OPTIONS,
+                    &mut options as *mut _ as *mut _,
+                    mem::size_of_val(&options) as u32,
+                );
+                winerror::SUCCEEDED(result) && options.ConstantBufferPartialUpdate != 0
+            };
+
+        let annotation = query_interface(&ctx);
+
+        Self {
+            ctx,
+            ctx1,
+            partial_cb_updates,
+            annotation,
+        }
+    }
+
+    /// Finishes recording the commands of a deferred context into a command list.
+    ///
+    /// The context's state is reset afterwards.
+    pub fn finish_command_list(&self) -> Result<ComPtr<ID3D11CommandList>, Error> {
+        let list = unsafe {
+            let mut ptr = ptr::null_mut();
+            let result = self.ctx.FinishCommandList(0, &mut ptr);
+            if_not_success_err!(check_hresult(result, "Failed to finish command list"));
+            ComPtr::new(ptr)
+        };
+
+        Ok(list)
+    }
+
+    /// Runs the commands of a command list, then restores this context's state.
+    pub fn execute_command_list(&self, list: &ComPtr<ID3D11CommandList>) {
+        unsafe {
+            self.ctx.ExecuteCommandList(list.as_mut(), 1);
+        }
+    }
+
+    /// Retrieves the interface used to annotate the commands, if the runtime supports it.
+    pub fn annotation(&self) -> Option<&ComPtr<ID3DUserDefinedAnnotation>> {
+        self.annotation.as_ref()
+    }
+
+    /// Clears some rectangles of a render target view to a certain color.
+    ///
+    /// Returns false if partial clears are not supported by the runtime.
+    pub fn clear_rects(
+        &self,
+        view: *mut ID3D11RenderTargetView,
+        color: &[f32; 4],
+        rects: &[RECT],
+    ) -> bool {
+        if let Some(ref ctx1) = self.ctx1 {
+            unsafe {
+                ctx1.ClearView(view as *mut _, color, rects.as_ptr(), rects.len() as u32);
+            }
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Copies the range `start..end` of some data into the same range of a buffer.
+    ///
+    /// If the driver cannot update part of a constant buffer, the whole buffer is updated instead.
+    pub fn update_buffer(&self, buffer: *mut ID3D11Resource, data: &[u8], start: u32, end: u32) {
+        unsafe {
+            match self.ctx1 {
+                Some(ref ctx1) if self.partial_cb_updates => {
+                    let dest_box = D3D11_BOX {
+                        left: start,
+                        top: 0,
+                        front: 0,
+                        right: end,
+                        bottom: 1,
+                        back: 1,
+                    };
+
+                    let src = data[start as usize..].as_ptr() as *const _;
+                    ctx1.UpdateSubresource1(buffer, 0, &dest_box, src, 0, 0, 0);
+                }
+                _ => self.ctx.UpdateSubresource(
+                    buffer,
+                    0,
+                    ptr::null(),
+                    data.as_ptr() as *const _,
+                    0,
+                    0,
+                ),
+            }
+        }
     }
 
     /// Maps a resource.
@@ -34,7 +165,120 @@ impl DeviceContext {
         flags: LockFlags,
         usage: UsageFlags,
     ) -> Result<D3DLOCKED_RECT, Error> {
-        let map_flags = if usage.intersects(UsageFlags::WRITE_ONLY) {
+        let mapped = self.map_subresource(res, subres, flags, usage)?;
+
+        // For block compressed formats, D3D11's row pitch is already
+        // the size of a row of blocks, just like D3D9 expects.
+        let mapped = D3DLOCKED_RECT {
+            Pitch: mapped.RowPitch as i32,
+            pBits: mapped.pData,
+        };
+
+        Ok(mapped)
+    }
+
+    /// Maps a region of a subresource of a 2D texture.
+    ///
+    /// If there is a rect, the returned pointer points to its top-left corner,
+    /// and the pitch is left unchanged, so that the app writes within the rect.
+    /// D3D11 always maps the whole subresource, even when its contents are discarded.
+    /// For block compressed formats, the corner is rounded down to the start of its block.
+    pub fn map_rect(
+        &self,
+        texture: &Texture2D,
+        subres: u32,
+        rect: Option<&RECT>,
+        flags: LockFlags,
+        usage: UsageFlags,
+    ) -> Result<D3DLOCKED_RECT, Error> {
+        let res = texture.as_resource();
+
+        let rect = match rect {
+            Some(rect) => rect,
+            None => return self.map(res, subres, flags, usage),
+        };
+
+        let desc = texture.desc();
+        let fmt = dxgi_linear_format(desc.Format);
+
+        let level = subres % desc.MipLevels;
+        let width = cmp::max(desc.Width >> level, 1) as i32;
+        let height = cmp::max(desc.Height >> level, 1) as i32;
+
+        if rect.left < 0
+            || rect.top < 0
+            || rect.left >= rect.right
+            || rect.top >= rect.bottom
+            || rect.right > width
+            || rect.bottom > height
+        {
+            error!(
+                "Invalid lock rect: ({}, {}) - ({}, {})",
+                rect.left, rect.top, rect.right, rect.bottom
+            );
+            return Err(Error::InvalidCall);
+        }
+
+        let ((block_width, block_height), block_size) = match dxgi_format_block_info(fmt) {
+            Some(info) => info,
+            None => {
+                error!("Cannot lock a region of a surface with format {}", fmt);
+                return Err(Error::InvalidCall);
+            }
+        };
+
+        let mut mapped = self.map(res, subres, flags, usage)?;
+
+        let row = rect.top as usize / block_height as usize;
+        let column = rect.left as usize / block_width as usize;
+        let offset = row * mapped.Pitch as usize + column * block_size as usize;
+
+        mapped.pBits = unsafe { (mapped.pBits as *mut u8).add(offset) as *mut _ };
+
+        Ok(mapped)
+    }
+
+    /// Maps a subresource of a 3D resource.
+    pub fn map_box(
+        &self,
+        res: *mut ID3D11Resource,
+        subres: u32,
+        flags: LockFlags,
+        usage: UsageFlags,
+    ) -> Result<D3DLOCKED_BOX, Error> {
+        let mapped = self.map_subresource(res, subres, flags, usage)?;
+
+        let mapped = D3DLOCKED_BOX {
+            RowPitch: mapped.RowPitch as i32,
+            SlicePitch: mapped.DepthPitch as i32,
+            pBits: mapped.pData,
+        };
+
+        Ok(mapped)
+    }
+
+    /// Maps a subresource, converting the D3D9 lock flags to their D3D11 equivalent.
+    fn map_subresource(
+        &self,
+        res: *mut ID3D11Resource,
+        subres: u32,
+        flags: LockFlags,
+        usage: UsageFlags,
+    ) -> Result<D3D11_MAPPED_SUBRESOURCE, Error> {
+        let map_type = if usage.intersects(UsageFlags::DYNAMIC) {
+            // Dynamic resources can only be written to, either by discarding their contents,
+            // or by promising not to overwrite anything the GPU is using.
+            if flags.intersects(LockFlags::NO_OVERWRITE) {
+                D3D11_MAP_WRITE_NO_OVERWRITE
+            } else {
+                if !flags.intersects(LockFlags::DISCARD) {
+                    run_once!(|| warn!(
+                        "Dynamic resources lose their contents when locked without DISCARD"
+                    ));
+                }
+                D3D11_MAP_WRITE_DISCARD
+            }
+        } else if usage.intersects(UsageFlags::WRITE_ONLY) {
             // NOOVERWRITE must come first, since in D3D11 it's a superset of discard.
             if flags.intersects(LockFlags::NO_OVERWRITE) {
                 D3D11_MAP_WRITE_NO_OVERWRITE
@@ -58,6 +302,19 @@ impl DeviceContext {
             }
         };
 
+        self.map_with_type(res, subres, map_type, flags)
+    }
+
+    /// Maps a subresource with a certain D3D11 map type.
+    ///
+    /// Only the D3D9 lock flags which do not affect the map type are used.
+    pub fn map_with_type(
+        &self,
+        res: *mut ID3D11Resource,
+        subres: u32,
+        map_type: D3D11_MAP,
+        flags: LockFlags,
+    ) -> Result<D3D11_MAPPED_SUBRESOURCE, Error> {
         let gpu_flags = {
             let mut fl = 0;
 
@@ -69,26 +326,18 @@ impl DeviceContext {
         };
 
         // Try to map the subresource.
-        let mapped = unsafe {
-            let mut buf = mem::uninitialized();
-            let result = self.Map(res, subres, map_flags, gpu_flags, &mut buf);
+        // The mapped subresource is only written if the call succeeds.
+        unsafe {
+            let mut buf = MaybeUninit::uninit();
+            let result = self.Map(res, subres, map_type, gpu_flags, buf.as_mut_ptr());
 
             match result {
-                0 => Ok(buf),
+                0 => Ok(buf.assume_init()),
                 winerror::DXGI_ERROR_WAS_STILL_DRAWING => Err(Error::WasStillDrawing),
                 // Should never return Error::Success below
                 hr => Err(check_hresult(hr, "Failed to map resource")),
             }
-        }?;
-
-        // TODO: we need special handling for pitch with DXT texture formats.
-
-        let mapped = D3DLOCKED_RECT {
-            Pitch: mapped.RowPitch as i32,
-            pBits: mapped.pData,
-        };
-
-        Ok(mapped)
+        }
     }
 
     /// Unmaps a resource.
@@ -1,9 +1,15 @@
-use std::ops;
+use std::{ops, ptr};
 
-use winapi::um::d3d11::ID3D11Device;
+use winapi::ctypes::c_void;
+use winapi::um::d3d11::{
+    ID3D11Device, ID3D11GeometryShader, ID3D11PixelShader, ID3D11VertexShader,
+};
 
 use comptr::ComPtr;
 
+use crate::core::*;
+use crate::Error;
+
 /// Wraps a D3D11 device.
 #[derive(Clone)]
 pub struct Device {
@@ -15,6 +21,63 @@ impl Device {
     pub fn new(device: ComPtr<ID3D11Device>) -> Self {
         Self { device }
     }
+
+    /// Creates a vertex shader from its compiled bytecode.
+    pub fn create_vertex_shader(
+        &self,
+        bytecode: &[u8],
+    ) -> Result<ComPtr<ID3D11VertexShader>, Error> {
+        let shader = unsafe {
+            let mut ptr = ptr::null_mut();
+            let result = self.device.CreateVertexShader(
+                bytecode.as_ptr() as *const c_void,
+                bytecode.len(),
+                ptr::null_mut(),
+                &mut ptr,
+            );
+            if_not_success_err!(check_hresult(result, "Failed to create vertex shader"));
+            ComPtr::new(ptr)
+        };
+
+        Ok(shader)
+    }
+
+    /// Creates a pixel shader from its compiled bytecode.
+    pub fn create_pixel_shader(&self, bytecode: &[u8]) -> Result<ComPtr<ID3D11PixelShader>, Error> {
+        let shader = unsafe {
+            let mut ptr = ptr::null_mut();
+            let result = self.device.CreatePixelShader(
+                bytecode.as_ptr() as *const c_void,
+                bytecode.len(),
+                ptr::null_mut(),
+                &mut ptr,
+            );
+            if_not_success_err!(check_hresult(result, "Failed to create pixel shader"));
+            ComPtr::new(ptr)
+        };
+
+        Ok(shader)
+    }
+
+    /// Creates a geometry shader from its compiled bytecode.
+    pub fn create_geometry_shader(
+        &self,
+        bytecode: &[u8],
+    ) -> Result<ComPtr<ID3D11GeometryShader>, Error> {
+        let shader = unsafe {
+            let mut ptr = ptr::null_mut();
+            let result = self.device.CreateGeometryShader(
+                bytecode.as_ptr() as *const c_void,
+                bytecode.len(),
+                ptr::null_mut(),
+                &mut ptr,
+            );
+            if_not_success_err!(check_hresult(result, "Failed to create geometry shader"));
+            ComPtr::new(ptr)
+        };
+
+        Ok(shader)
+    }
 }
 
 impl ops::Deref for Device {
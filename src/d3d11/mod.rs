@@ -1,6 +1,10 @@
 //! Helper module wrapping D3D11's interfaces.
 
 mod util;
+pub use self::util::query_interface;
+
+mod debug;
+pub use self::debug::InfoQueue;
 
 mod device;
 pub use self::device::Device;
@@ -11,5 +15,16 @@ pub use self::devctx::DeviceContext;
 mod buffer;
 pub use self::buffer::Buffer;
 
+mod ring;
+pub use self::ring::RingBuffer;
+
 mod texture;
-pub use self::texture::Texture2D;
+pub use self::texture::{Texture2D, Texture3D};
+
+mod constants;
+pub use self::constants::{ConstantBuffer, ShaderConstants, Vec4};
+
+pub mod perf;
+
+mod blit;
+pub use self::blit::Blitter;
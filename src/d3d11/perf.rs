@@ -0,0 +1,81 @@
+//! Forwards the `D3DPERF_*` markers to D3D11's annotation interface,
+//! so that they show up in captures made by graphics debuggers.
+
+use std::sync::Mutex;
+
+use winapi::shared::ntdef::LPCWSTR;
+use winapi::um::d3d11_1::ID3DUserDefinedAnnotation;
+
+use comptr::ComPtr;
+
+use crate::dev::DeviceLock;
+
+/// The annotation interface which receives the markers, with the lock of its device.
+///
+/// The `D3DPERF_*` functions don't take a device, so the markers are sent
+/// to the most recently created device. The annotation interface belongs to
+/// the device's immediate context, so the device must be locked while using it.
+struct Target {
+    annotation: ComPtr<ID3DUserDefinedAnnotation>,
+    // The device unregisters itself before being destroyed, so this stays valid.
+    lock: *const DeviceLock,
+}
+
+// The target is only used while holding the device's lock.
+unsafe impl Send for Target {}
+
+/// The current target. The mutex is held while sending a marker,
+/// so that the target's device cannot be destroyed in the meantime.
+static TARGET: Mutex<Option<Target>> = Mutex::new(None);
+
+/// Makes an annotation interface receive the markers.
+pub fn set_target(annotation: &ComPtr<ID3DUserDefinedAnnotation>, lock: &DeviceLock) {
+    let target = Target {
+        annotation: annotation.clone(),
+        lock,
+    };
+
+    *TARGET.lock().unwrap_or_else(|err| err.into_inner()) = Some(target);
+}
+
+/// Stops an annotation interface from receiving markers, if it is the current target.
+pub fn clear_target(annotation: &ComPtr<ID3DUserDefinedAnnotation>) {
+    let mut target = TARGET.lock().unwrap_or_else(|err| err.into_inner());
+
+    let is_current = target
+        .as_ref()
+        .map_or(false, |t| t.annotation.as_mut() == annotation.as_mut());
+
+    if is_current {
+        *target = None;
+    }
+}
+
+/// Runs a function with the current target, if any, while holding its device's lock.
+fn with_target<T>(f: impl FnOnce(&ID3DUserDefinedAnnotation) -> T) -> Option<T> {
+    let target = TARGET.lock().unwrap_or_else(|err| err.into_inner());
+
+    target.as_ref().map(|target| {
+        let _lock = unsafe { (*target.lock).lock() };
+        f(&target.annotation)
+    })
+}
+
+/// Starts a named group of commands.
+///
+/// Returns the nesting level of the new group.
+pub fn begin_event(name: LPCWSTR) -> i32 {
+    with_target(|annotation| unsafe { annotation.BeginEvent(name) }).unwrap_or(0)
+}
+
+/// Ends the last group of commands which was started.
+///
+/// Returns the nesting level of the remaining groups.
+pub fn end_event() -> i32 {
+    with_target(|annotation| unsafe { annotation.EndEvent() }).unwrap_or(0)
+}
+
+/// Marks a point in the stream of commands.
+pub fn set_marker(name: LPCWSTR) {
+    with_target(|annotation| unsafe { annotation.SetMarker(name) });
+}
@@ -0,0 +1,98 @@
+use std::{cmp, ptr};
+
+use winapi::um::d3d11::{ID3D11Buffer, ID3D11Device};
+
+use crate::core::*;
+use crate::Error;
+
+use super::{Buffer, DeviceContext};
+
+/// All allocations start at a multiple of this, which is enough for any index or vertex format.
+const ALIGNMENT: u32 = 16;
+
+/// Dynamic buffer which is sub-allocated to stream data to the GPU.
+///
+/// Each allocation is placed after the previous one, mapping the buffer with no-overwrite,
+/// so the GPU can keep reading the older data. The buffer is only discarded once it fills up.
+pub struct RingBuffer {
+    buffer: Option<Buffer>,
+    bind_flags: u32,
+    // Size of the buffer, in bytes.
+    capacity: u32,
+    // Offset at which the next allocation starts.
+    offset: u32,
+}
+
+impl RingBuffer {
+    /// Creates a new ring buffer. The actual buffer is created the first time it is used.
+    pub fn new(bind_flags: u32) -> Self {
+        Self {
+            buffer: None,
+            bind_flags,
+            capacity: 0,
+            offset: 0,
+        }
+    }
+
+    /// Makes the next upload discard the buffer.
+    ///
+    /// Required when switching to another context, since a context can only map a buffer
+    /// with no-overwrite after it discarded it itself.
+    pub fn reset(&mut self) {
+        self.offset = self.capacity;
+    }
+
+    /// Copies some data into the ring, returning the buffer and the offset the data is at.
+    ///
+    /// The buffer is (re)created if it is too small to hold the data.
+    pub fn upload(
+        &mut self,
+        device: &ID3D11Device,
+        ctx: &DeviceContext,
+        data: &[u8],
+    ) -> Result<(*mut ID3D11Buffer, u32), Error> {
+        let len = data.len() as u32;
+
+        if self.buffer.is_none() || self.capacity < len {
+            // Grow the buffer geometrically, to avoid recreating it too often.
+            let capacity = cmp::max(len.next_power_of_two(), 1024 * 1024);
+            let usage = UsageFlags::DYNAMIC | UsageFlags::WRITE_ONLY;
+
+            let buffer = Buffer::new(
+                device,
+                capacity,
+                usage,
+                MemoryPool::Default,
+                self.bind_flags,
+            )?;
+
+            self.buffer = Some(buffer);
+            self.capacity = capacity;
+            // New buffers must be discarded before the first no-overwrite map.
+            self.offset = capacity;
+        }
+
+        // Once the ring wraps around, the old data is discarded.
+        let (offset, flags) = if self.offset.saturating_add(len) <= self.capacity {
+            (self.offset, LockFlags::NO_OVERWRITE)
+        } else {
+            (0, LockFlags::DISCARD)
+        };
+
+        let buffer = self.buffer.as_ref().unwrap();
+        let resource = buffer.as_resource();
+        let mapped = ctx.map(resource, 0, flags, UsageFlags::WRITE_ONLY)?;
+
+        unsafe {
+            let dest = (mapped.pBits as *mut u8).offset(offset as isize);
+            ptr::copy_nonoverlapping(data.as_ptr(), dest, data.len());
+        }
+
+        ctx.unmap(resource, 0);
+
+        let end = offset + len;
+        self.offset = (end + ALIGNMENT - 1) / ALIGNMENT * ALIGNMENT;
+
+        Ok((buffer.as_buffer(), offset))
+    }
+}
@@ -1,15 +1,53 @@
-use std::{mem, ptr};
+use std::mem::{self, MaybeUninit};
+use std::ptr;
 
-use winapi::shared::d3d9types::*;
+use winapi::shared::{d3d9types::*, dxgiformat::DXGI_FORMAT, dxgitype::DXGI_SAMPLE_DESC};
 use winapi::um::d3d11::*;
 
 use comptr::ComPtr;
 
-use crate::core::{fmt::d3d_format_to_dxgi, msample::d3d9_to_dxgi_samples, *};
+use crate::core::{
+    fmt::{
+        d3d_format_to_dxgi, dxgi_depth_typeless_format, dxgi_linear_format,
+        dxgi_srgb_typeless_format, dxgi_typeless_depth_format, dxgi_typeless_srgb_format,
+    },
+    msample::d3d9_to_dxgi_samples,
+    *,
+};
 
-use super::util::d3d_usage_to_d3d11;
+use super::util::{autogen_mip_flags, d3d_usage_to_d3d11};
 use crate::Error;
 
+/// Determines the format and bind flags of a texture which can be used as a depth buffer.
+///
+/// Depth textures are created with a typeless format, so that they can also be sampled from.
+/// Formats which have no such equivalent can only be used as depth buffers.
+fn depth_texture_format(
+    fmt: DXGI_FORMAT,
+    bind_flags: D3D11_BIND_FLAG,
+) -> (DXGI_FORMAT, D3D11_BIND_FLAG) {
+    if bind_flags & D3D11_BIND_DEPTH_STENCIL == 0 {
+        return (fmt, bind_flags);
+    }
+
+    match dxgi_depth_typeless_format(fmt) {
+        Some((typeless, _)) => (typeless, bind_flags),
+        None => (fmt, bind_flags & !D3D11_BIND_SHADER_RESOURCE),
+    }
+}
+
+/// Determines the format of a color texture.
+///
+/// Textures which can be bound to the pipeline are created with a typeless format
+/// if their format has an sRGB equivalent, so that they can also have sRGB views.
+fn color_texture_format(fmt: DXGI_FORMAT, bind_flags: D3D11_BIND_FLAG) -> DXGI_FORMAT {
+    if bind_flags & (D3D11_BIND_SHADER_RESOURCE | D3D11_BIND_RENDER_TARGET) == 0 {
+        return fmt;
+    }
+
+    dxgi_srgb_typeless_format(fmt).map_or(fmt, |(typeless, _)| typeless)
+}
+
 /// Wrapper for a D3D11 2D texture.
 #[derive(Clone)]
 pub struct Texture2D {
@@ -27,8 +65,11 @@ impl Texture2D {
         pool: MemoryPool,
     ) -> Result<Self, Error> {
         let (usage, bind_flags, cpu_flags) = d3d_usage_to_d3d11(uflags, pool)?;
+        let (bind_flags, misc_flags) = autogen_mip_flags(uflags, usage, bind_flags);
 
         let fmt = d3d_format_to_dxgi(fmt);
+        let (fmt, bind_flags) = depth_texture_format(fmt, bind_flags);
+        let fmt = color_texture_format(fmt, bind_flags);
 
         let desc = D3D11_TEXTURE2D_DESC {
             Width: width,
@@ -40,7 +81,7 @@ impl Texture2D {
             Usage: usage,
             BindFlags: bind_flags,
             CPUAccessFlags: cpu_flags,
-            MiscFlags: 0,
+            MiscFlags: misc_flags,
         };
 
         let texture = unsafe {
@@ -65,7 +106,8 @@ impl Texture2D {
         pool: MemoryPool,
     ) -> Result<Self, Error> {
         let (usage, bind_flags, cpu_flags) = d3d_usage_to_d3d11(uflags, pool)?;
-        let fmt = d3d_format_to_dxgi(fmt);
+        let (bind_flags, misc_flags) = autogen_mip_flags(uflags, usage, bind_flags);
+        let fmt = color_texture_format(d3d_format_to_dxgi(fmt), bind_flags);
 
         let desc = D3D11_TEXTURE2D_DESC {
             Width: dimension,
@@ -77,7 +119,7 @@ impl Texture2D {
             Usage: usage,
             BindFlags: bind_flags,
             CPUAccessFlags: cpu_flags,
-            MiscFlags: 0,
+            MiscFlags: misc_flags | D3D11_RESOURCE_MISC_TEXTURECUBE,
         };
 
         let texture = unsafe {
@@ -100,7 +142,7 @@ impl Texture2D {
         ms_ty: D3DMULTISAMPLE_TYPE,
         ms_qlt: u32,
     ) -> Result<Self, Error> {
-        let fmt = d3d_format_to_dxgi(fmt);
+        let fmt = color_texture_format(d3d_format_to_dxgi(fmt), D3D11_BIND_RENDER_TARGET);
 
         let desc = D3D11_TEXTURE2D_DESC {
             Width: width,
@@ -130,17 +172,206 @@ impl Texture2D {
         Ok(Self { texture })
     }
 
+    /// Creates a multisampled render target with the same size and format as this texture.
+    pub fn new_multisampled(
+        &self,
+        device: &ID3D11Device,
+        samples: DXGI_SAMPLE_DESC,
+    ) -> Result<Self, Error> {
+        let desc = self.desc();
+
+        let desc = D3D11_TEXTURE2D_DESC {
+            Width: desc.Width,
+            Height: desc.Height,
+            MipLevels: 1,
+            ArraySize: 1,
+            Format: color_texture_format(desc.Format, D3D11_BIND_RENDER_TARGET),
+            SampleDesc: samples,
+            Usage: D3D11_USAGE_DEFAULT,
+            BindFlags: D3D11_BIND_RENDER_TARGET,
+            CPUAccessFlags: 0,
+            MiscFlags: 0,
+        };
+
+        let texture = unsafe {
+            let mut ptr = ptr::null_mut();
+
+            let result = device.CreateTexture2D(&desc, ptr::null(), &mut ptr);
+            if_not_success_err!(check_hresult(
+                result,
+                "Failed to create multisampled render target"
+            ));
+
+            ComPtr::new(ptr)
+        };
+
+        Ok(Self { texture })
+    }
+
+    /// Creates a texture which can only be sampled from, used as a temporary copy of other textures.
+    pub fn new_sampled(
+        device: &ID3D11Device,
+        (width, height): (u32, u32),
+        fmt: DXGI_FORMAT,
+    ) -> Result<Self, Error> {
+        let desc = D3D11_TEXTURE2D_DESC {
+            Width: width,
+            Height: height,
+            MipLevels: 1,
+            ArraySize: 1,
+            Format: fmt,
+            SampleDesc: d3d9_to_dxgi_samples(0, 0),
+            Usage: D3D11_USAGE_DEFAULT,
+            BindFlags: D3D11_BIND_SHADER_RESOURCE,
+            CPUAccessFlags: 0,
+            MiscFlags: 0,
+        };
+
+        let texture = unsafe {
+            let mut ptr = ptr::null_mut();
+
+            let result = device.CreateTexture2D(&desc, ptr::null(), &mut ptr);
+            if_not_success_err!(check_hresult(result, "Failed to create sampled texture"));
+
+            ComPtr::new(ptr)
+        };
+
+        Ok(Self { texture })
+    }
+
+    /// Creates a CPU-readable texture, which can be used to read back GPU data.
+    pub fn new_staging(
+        device: &ID3D11Device,
+        (width, height): (u32, u32),
+        fmt: DXGI_FORMAT,
+    ) -> Result<Self, Error> {
+        let desc = D3D11_TEXTURE2D_DESC {
+            Width: width,
+            Height: height,
+            MipLevels: 1,
+            ArraySize: 1,
+            Format: fmt,
+            SampleDesc: d3d9_to_dxgi_samples(0, 0),
+            Usage: D3D11_USAGE_STAGING,
+            BindFlags: 0,
+            CPUAccessFlags: D3D11_CPU_ACCESS_READ,
+            MiscFlags: 0,
+        };
+
+        let texture = unsafe {
+            let mut ptr = ptr::null_mut();
+
+            let result = device.CreateTexture2D(&desc, ptr::null(), &mut ptr);
+            if_not_success_err!(check_hresult(result, "Failed to create staging texture"));
+
+            ComPtr::new(ptr)
+        };
+
+        Ok(Self { texture })
+    }
+
+    /// Creates a texture with the same size, format and mip levels as this one,
+    /// which the CPU can read from and write to.
+    pub fn new_staging_copy(&self, device: &ID3D11Device) -> Result<Self, Error> {
+        let desc = D3D11_TEXTURE2D_DESC {
+            Usage: D3D11_USAGE_STAGING,
+            BindFlags: 0,
+            CPUAccessFlags: D3D11_CPU_ACCESS_READ | D3D11_CPU_ACCESS_WRITE,
+            MiscFlags: 0,
+            ..self.desc()
+        };
+
+        let texture = unsafe {
+            let mut ptr = ptr::null_mut();
+
+            let result = device.CreateTexture2D(&desc, ptr::null(), &mut ptr);
+            if_not_success_err!(check_hresult(result, "Failed to create staging texture"));
+
+            ComPtr::new(ptr)
+        };
+
+        Ok(Self { texture })
+    }
+
     /// Creates a render target view from this texture.
     pub fn create_rt_view(
         &self,
         device: &ID3D11Device,
+    ) -> Result<ComPtr<ID3D11RenderTargetView>, Error> {
+        let fmt = dxgi_linear_format(self.desc().Format);
+        self.create_rt_view_with_format(device, 0, fmt)
+    }
+
+    /// Creates a render target view for a single subresource of this texture.
+    pub fn create_subresource_rt_view(
+        &self,
+        device: &ID3D11Device,
+        subresource: u32,
+    ) -> Result<ComPtr<ID3D11RenderTargetView>, Error> {
+        let fmt = dxgi_linear_format(self.desc().Format);
+        self.create_rt_view_with_format(device, subresource, fmt)
+    }
+
+    /// Creates a render target view for a single subresource of this texture,
+    /// which converts the written colors from linear to sRGB.
+    ///
+    /// Returns `None` if the texture's format has no sRGB equivalent.
+    pub fn create_srgb_rt_view(
+        &self,
+        device: &ID3D11Device,
+        subresource: u32,
+    ) -> Result<Option<ComPtr<ID3D11RenderTargetView>>, Error> {
+        let fmt = self.desc().Format;
+
+        // The buffers of swap chains are not typeless, but DXGI still allows them to have sRGB views.
+        let srgb = dxgi_typeless_srgb_format(fmt).or_else(|| dxgi_srgb_typeless_format(fmt));
+
+        match srgb {
+            Some((_, srgb)) => self
+                .create_rt_view_with_format(device, subresource, srgb)
+                .map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Creates a render target view with a certain format for a single subresource of this texture.
+    fn create_rt_view_with_format(
+        &self,
+        device: &ID3D11Device,
+        subresource: u32,
+        fmt: DXGI_FORMAT,
     ) -> Result<ComPtr<ID3D11RenderTargetView>, Error> {
         let resource = self.as_resource();
+        let desc = self.desc();
+
+        let level = subresource % desc.MipLevels;
+        let slice = subresource / desc.MipLevels;
+
+        let view_desc = unsafe {
+            let mut view_desc: D3D11_RENDER_TARGET_VIEW_DESC = mem::zeroed();
+            view_desc.Format = fmt;
+
+            // Cube maps are arrays of 2D textures, and each face is rendered to separately.
+            if desc.ArraySize > 1 {
+                view_desc.ViewDimension = D3D11_RTV_DIMENSION_TEXTURE2DARRAY;
+                let array = view_desc.u.Texture2DArray_mut();
+                array.MipSlice = level;
+                array.FirstArraySlice = slice;
+                array.ArraySize = 1;
+            } else if desc.SampleDesc.Count > 1 {
+                view_desc.ViewDimension = D3D11_RTV_DIMENSION_TEXTURE2DMS;
+            } else {
+                view_desc.ViewDimension = D3D11_RTV_DIMENSION_TEXTURE2D;
+                view_desc.u.Texture2D_mut().MipSlice = level;
+            }
+
+            view_desc
+        };
 
         let view = unsafe {
             let mut ptr = ptr::null_mut();
 
-            let result = device.CreateRenderTargetView(resource, ptr::null(), &mut ptr);
+            let result = device.CreateRenderTargetView(resource, &view_desc, &mut ptr);
             if_not_success_err!(check_hresult(result, "Failed to create render target view"));
 
             ComPtr::new(ptr)
@@ -149,13 +380,136 @@ impl Texture2D {
         Ok(view)
     }
 
+    /// Creates a shader resource view covering all of this texture's mip levels.
+    ///
+    /// Returns `None` if the texture cannot be bound to a shader, e.g. for staging textures.
+    pub fn create_sr_view(
+        &self,
+        device: &ID3D11Device,
+    ) -> Result<Option<ComPtr<ID3D11ShaderResourceView>>, Error> {
+        let desc = self.desc();
+
+        if desc.BindFlags & D3D11_BIND_SHADER_RESOURCE == 0 {
+            return Ok(None);
+        }
+
+        // Typeless textures need a view with a format which can be sampled.
+        let view_desc = dxgi_typeless_depth_format(desc.Format)
+            .map(|(_, fmt)| fmt)
+            .or_else(|| dxgi_typeless_srgb_format(desc.Format).map(|(fmt, _)| fmt))
+            // Without a description, cube maps would be viewed as texture arrays.
+            .or_else(|| {
+                if desc.MiscFlags & D3D11_RESOURCE_MISC_TEXTURECUBE != 0 {
+                    Some(desc.Format)
+                } else {
+                    None
+                }
+            })
+            .map(|fmt| self.sr_view_desc(fmt));
+
+        // Not passing a description creates a view with the texture's format and mip range.
+        let view_desc = view_desc
+            .as_ref()
+            .map_or(ptr::null(), |desc| desc as *const _);
+
+        self.create_sr_view_with_desc(device, view_desc).map(Some)
+    }
+
+    /// Creates a shader resource view covering all of this texture's mip levels,
+    /// which converts the sampled colors from sRGB to linear.
+    ///
+    /// Returns `None` if the texture cannot be bound to a shader,
+    /// or if its format has no sRGB equivalent.
+    pub fn create_srgb_sr_view(
+        &self,
+        device: &ID3D11Device,
+    ) -> Result<Option<ComPtr<ID3D11ShaderResourceView>>, Error> {
+        let desc = self.desc();
+
+        if desc.BindFlags & D3D11_BIND_SHADER_RESOURCE == 0 {
+            return Ok(None);
+        }
+
+        match dxgi_typeless_srgb_format(desc.Format) {
+            Some((_, srgb)) => {
+                let view_desc = self.sr_view_desc(srgb);
+                self.create_sr_view_with_desc(device, &view_desc).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Describes a shader resource view with a certain format,
+    /// covering all of this texture's mip levels and array slices, or faces for cube maps.
+    fn sr_view_desc(&self, fmt: DXGI_FORMAT) -> D3D11_SHADER_RESOURCE_VIEW_DESC {
+        let desc = self.desc();
+
+        unsafe {
+            let mut view_desc: D3D11_SHADER_RESOURCE_VIEW_DESC = mem::zeroed();
+            view_desc.Format = fmt;
+
+            if desc.MiscFlags & D3D11_RESOURCE_MISC_TEXTURECUBE != 0 {
+                view_desc.ViewDimension = D3D11_SRV_DIMENSION_TEXTURECUBE;
+                view_desc.u.TextureCube_mut().MipLevels = desc.MipLevels;
+            } else if desc.ArraySize > 1 {
+                view_desc.ViewDimension = D3D11_SRV_DIMENSION_TEXTURE2DARRAY;
+                let array = view_desc.u.Texture2DArray_mut();
+                array.MipLevels = desc.MipLevels;
+                array.ArraySize = desc.ArraySize;
+            } else if desc.SampleDesc.Count > 1 {
+                view_desc.ViewDimension = D3D11_SRV_DIMENSION_TEXTURE2DMS;
+            } else {
+                view_desc.ViewDimension = D3D11_SRV_DIMENSION_TEXTURE2D;
+                view_desc.u.Texture2D_mut().MipLevels = desc.MipLevels;
+            }
+
+            view_desc
+        }
+    }
+
+    /// Creates a shader resource view from a description, which may be null.
+    fn create_sr_view_with_desc(
+        &self,
+        device: &ID3D11Device,
+        view_desc: *const D3D11_SHADER_RESOURCE_VIEW_DESC,
+    ) -> Result<ComPtr<ID3D11ShaderResourceView>, Error> {
+        let resource = self.as_resource();
+
+        let view = unsafe {
+            let mut ptr = ptr::null_mut();
+
+            let result = device.CreateShaderResourceView(resource, view_desc, &mut ptr);
+            if_not_success_err!(check_hresult(
+                result,
+                "Failed to create shader resource view"
+            ));
+
+            ComPtr::new(ptr)
+        };
+
+        Ok(view)
+    }
+
     /// Creates a new depth/stencil buffer.
     pub fn new_ds(
         device: &ID3D11Device,
         (width, height): (u32, u32),
         fmt: D3DFORMAT,
+        ms_ty: D3DMULTISAMPLE_TYPE,
+        ms_qlt: u32,
     ) -> Result<Self, Error> {
         let fmt = d3d_format_to_dxgi(fmt);
+        let samples = d3d9_to_dxgi_samples(ms_ty, ms_qlt);
+
+        // Create the buffer with a typeless format, so that it can also be sampled from.
+        // Multisampled depth buffers are sampled from when resolving them.
+        let (fmt, bind_flags) = match dxgi_depth_typeless_format(fmt) {
+            Some((typeless, _)) => (
+                typeless,
+                D3D11_BIND_DEPTH_STENCIL | D3D11_BIND_SHADER_RESOURCE,
+            ),
+            None => (fmt, D3D11_BIND_DEPTH_STENCIL),
+        };
 
         let desc = D3D11_TEXTURE2D_DESC {
             Width: width,
@@ -163,9 +517,9 @@ impl Texture2D {
             MipLevels: 1,
             ArraySize: 1,
             Format: fmt,
-            SampleDesc: d3d9_to_dxgi_samples(0, 0),
+            SampleDesc: samples,
             Usage: D3D11_USAGE_DEFAULT,
-            BindFlags: D3D11_BIND_DEPTH_STENCIL,
+            BindFlags: bind_flags,
             CPUAccessFlags: 0,
             MiscFlags: 0,
         };
@@ -185,17 +539,79 @@ impl Texture2D {
         Ok(Self { texture })
     }
 
+    /// Creates a depth / stencil view for a single subresource of this texture.
+    pub fn create_subresource_ds_view(
+        &self,
+        device: &ID3D11Device,
+        subresource: u32,
+    ) -> Result<ComPtr<ID3D11DepthStencilView>, Error> {
+        let resource = self.as_resource();
+        let desc = self.desc();
+
+        let level = subresource % desc.MipLevels;
+        let slice = subresource / desc.MipLevels;
+
+        let view_desc = unsafe {
+            let mut view_desc: D3D11_DEPTH_STENCIL_VIEW_DESC = mem::zeroed();
+            view_desc.Format =
+                dxgi_typeless_depth_format(desc.Format).map_or(desc.Format, |(fmt, _)| fmt);
+
+            if desc.ArraySize > 1 {
+                view_desc.ViewDimension = D3D11_DSV_DIMENSION_TEXTURE2DARRAY;
+                let array = view_desc.u.Texture2DArray_mut();
+                array.MipSlice = level;
+                array.FirstArraySlice = slice;
+                array.ArraySize = 1;
+            } else {
+                view_desc.ViewDimension = D3D11_DSV_DIMENSION_TEXTURE2D;
+                view_desc.u.Texture2D_mut().MipSlice = level;
+            }
+
+            view_desc
+        };
+
+        let view = unsafe {
+            let mut ptr = ptr::null_mut();
+
+            let result = device.CreateDepthStencilView(resource, &view_desc, &mut ptr);
+            if_not_success_err!(check_hresult(
+                result,
+                "Failed to create depth / stencil view"
+            ));
+
+            ComPtr::new(ptr)
+        };
+
+        Ok(view)
+    }
+
     /// Creates a depth / stencil view from this texture.
     pub fn create_ds_view(
         &self,
         device: &ID3D11Device,
     ) -> Result<ComPtr<ID3D11DepthStencilView>, Error> {
         let resource = self.as_resource();
+        let desc = self.desc();
+
+        // Typeless textures need a view with the actual depth format.
+        let view_desc = dxgi_typeless_depth_format(desc.Format).map(|(fmt, _)| unsafe {
+            let mut view_desc: D3D11_DEPTH_STENCIL_VIEW_DESC = mem::zeroed();
+            view_desc.Format = fmt;
+            view_desc.ViewDimension = if desc.SampleDesc.Count > 1 {
+                D3D11_DSV_DIMENSION_TEXTURE2DMS
+            } else {
+                D3D11_DSV_DIMENSION_TEXTURE2D
+            };
+            view_desc
+        });
 
         let view = unsafe {
             let mut ptr = ptr::null_mut();
 
-            let result = device.CreateDepthStencilView(resource, ptr::null(), &mut ptr);
+            let view_desc = view_desc
+                .as_ref()
+                .map_or(ptr::null(), |desc| desc as *const _);
+            let result = device.CreateDepthStencilView(resource, view_desc, &mut ptr);
             if_not_success_err!(check_hresult(
                 result,
                 "Failed to create depth / stencil view"
@@ -232,3 +648,91 @@ impl From<ComPtr<ID3D11Texture2D>> for Texture2D {
         Self { texture }
     }
 }
+
+/// Wrapper for a D3D11 3D texture.
+#[derive(Clone)]
+pub struct Texture3D {
+    texture: ComPtr<ID3D11Texture3D>,
+}
+
+impl Texture3D {
+    /// Creates a new volume texture.
+    pub fn new(
+        device: &ID3D11Device,
+        (width, height, depth): (u32, u32, u32),
+        levels: u32,
+        uflags: UsageFlags,
+        fmt: D3DFORMAT,
+        pool: MemoryPool,
+    ) -> Result<Self, Error> {
+        let (usage, bind_flags, cpu_flags) = d3d_usage_to_d3d11(uflags, pool)?;
+        let (bind_flags, misc_flags) = autogen_mip_flags(uflags, usage, bind_flags);
+
+        let fmt = d3d_format_to_dxgi(fmt);
+
+        let desc = D3D11_TEXTURE3D_DESC {
+            Width: width,
+            Height: height,
+            Depth: depth,
+            MipLevels: levels,
+            Format: fmt,
+            Usage: usage,
+            BindFlags: bind_flags,
+            CPUAccessFlags: cpu_flags,
+            MiscFlags: misc_flags,
+        };
+
+        let texture = unsafe {
+            let mut ptr = ptr::null_mut();
+
+            let result = device.CreateTexture3D(&desc, ptr::null(), &mut ptr);
+            if_not_success_err!(check_hresult(result, "Failed to create 3D texture"));
+
+            ComPtr::new(ptr)
+        };
+
+        Ok(Self { texture })
+    }
+
+    /// Creates a shader resource view covering all of this texture's mip levels.
+    ///
+    /// Returns `None` if the texture cannot be bound to a shader, e.g. for staging textures.
+    pub fn create_sr_view(
+        &self,
+        device: &ID3D11Device,
+    ) -> Result<Option<ComPtr<ID3D11ShaderResourceView>>, Error> {
+        if self.desc().BindFlags & D3D11_BIND_SHADER_RESOURCE == 0 {
+            return Ok(None);
+        }
+
+        let resource = self.as_resource();
+
+        let view = unsafe {
+            let mut ptr = ptr::null_mut();
+
+            let result = device.CreateShaderResourceView(resource, ptr::null(), &mut ptr);
+            if_not_success_err!(check_hresult(
+                result,
+                "Failed to create shader resource view"
+            ));
+
+            ComPtr::new(ptr)
+        };
+
+        Ok(Some(view))
+    }
+
+    /// Retrieves this texture as a resource.
+    pub fn as_resource(&self) -> *mut ID3D11Resource {
+        self.texture.upcast().as_mut()
+    }
+
+    /// Retrieves the description of this texture.
+    pub fn desc(&self) -> D3D11_TEXTURE3D_DESC {
+        unsafe {
+            let mut desc = MaybeUninit::uninit();
+            self.texture.GetDesc(desc.as_mut_ptr());
+            desc.assume_init()
+        }
+    }
+}
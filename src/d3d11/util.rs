@@ -1,4 +1,10 @@
+use std::ptr;
+
 use winapi::um::d3d11::*;
+use winapi::um::unknwnbase::IUnknown;
+use winapi::Interface;
+
+use comptr::ComPtr;
 
 use crate::core::*;
 use crate::Error;
@@ -43,7 +49,7 @@ pub fn d3d_usage_to_d3d11(
         _ => error!("Unsupported memory pool: {:?}", pool),
     }
 
-    let bind_flags = if usage != D3D11_USAGE_STAGING {
+    let mut bind_flags = if usage != D3D11_USAGE_STAGING {
         // Even if the app doesn't end up using this in a shader,
         // this is the only bind flag we could choose for it.
         D3D11_BIND_SHADER_RESOURCE
@@ -51,5 +57,64 @@ pub fn d3d_usage_to_d3d11(
         0
     };
 
+    if uflags.intersects(UsageFlags::RENDER_TARGET) {
+        // The GPU can only render into resources which the CPU cannot access.
+        if pool != MemoryPool::Default || usage != D3D11_USAGE_DEFAULT {
+            error!("Render targets must be in the default pool, and cannot be dynamic");
+            return Err(Error::InvalidCall);
+        }
+
+        bind_flags |= D3D11_BIND_RENDER_TARGET;
+    }
+
+    if uflags.intersects(UsageFlags::DEPTH_STENCIL) {
+        if pool != MemoryPool::Default || usage != D3D11_USAGE_DEFAULT {
+            error!("Depth / stencil buffers must be in the default pool, and cannot be dynamic");
+            return Err(Error::InvalidCall);
+        }
+
+        bind_flags |= D3D11_BIND_DEPTH_STENCIL;
+    }
+
     Ok((usage, bind_flags, cpu_flags))
 }
+
+/// Determines the extra flags a texture needs for D3D11 to generate its mip maps,
+/// if the app requested automatic mip map generation.
+///
+/// Returns the new bind flags, and the misc flags.
+pub fn autogen_mip_flags(
+    uflags: UsageFlags,
+    usage: D3D11_USAGE,
+    bind_flags: D3D11_BIND_FLAG,
+) -> (D3D11_BIND_FLAG, D3D11_RESOURCE_MISC_FLAG) {
+    if !uflags.intersects(UsageFlags::AUTO_GEN_MIP_MAP) {
+        return (bind_flags, 0);
+    }
+
+    // D3D11 can only render into textures which the CPU cannot access.
+    if usage != D3D11_USAGE_DEFAULT {
+        warn!("Mip maps cannot be generated for textures which can be locked");
+        return (bind_flags, 0);
+    }
+
+    (
+        bind_flags | D3D11_BIND_RENDER_TARGET,
+        D3D11_RESOURCE_MISC_GENERATE_MIPS,
+    )
+}
+
+/// Queries a COM object for another interface it implements.
+///
+/// Returns `None` if the object does not support the requested interface.
+pub fn query_interface<T: Interface>(unknown: &IUnknown) -> Option<ComPtr<T>> {
+    let mut ptr: *mut T = ptr::null_mut();
+
+    let result = unsafe { unknown.QueryInterface(&T::uuidof(), &mut ptr as *mut _ as *mut _) };
+
+    if result == 0 && !ptr.is_null() {
+        Some(ComPtr::new(ptr))
+    } else {
+        None
+    }
+}
@@ -1,6 +1,6 @@
 use std::sync::atomic::{AtomicU32, Ordering};
 
-use winapi::shared::{d3d9::*, d3d9types::*};
+use winapi::shared::{d3d9::*, d3d9types::*, dxgiformat::*};
 use winapi::um::unknwnbase::{IUnknown, IUnknownVtbl};
 
 use com_impl::{implementation, interface, ComInterface};
@@ -72,7 +72,7 @@ impl VertexBuffer {
 
         ret.Type = ResourceType::VertexBuffer as u32;
         ret.Size = desc.ByteWidth;
-        ret.Format = D3DFMT_R32F;
+        ret.Format = D3DFMT_VERTEXDATA;
         ret.FVF = self.fvf;
         ret.Pool = self.pool() as u32;
         ret.Usage = self.usage().bits();
@@ -80,27 +80,19 @@ impl VertexBuffer {
         Error::Success
     }
 
-    fn lock(&self, offset: u32, _size: u32, ret: *mut *mut u8, flags: LockFlags) -> Error {
+    fn lock(&self, offset: u32, size: u32, ret: *mut *mut u8, flags: LockFlags) -> Error {
+        let _lock = self.device().lock();
         let ret = if_error!(check_mut_ref(ret));
 
-        let resource = self.buffer.as_resource();
-        let ctx = self.device_context();
-        let mapped = if_error!(ctx.map(resource, 0, flags, self.usage()));
-
         // TODO: allow buffers to be mapped multiple times.
-        info!("Mapped vertex buffer");
-        *ret = unsafe {
-            let addr = mapped.pBits as *mut u8;
-            addr.offset(offset as isize)
-        };
+        *ret = if_error!(self.buffer.lock(self.device_context(), offset, size, flags));
 
         Error::Success
     }
 
     fn unlock(&self) -> Error {
-        let resource = self.buffer.as_resource();
-        let ctx = self.device_context();
-        ctx.unmap(resource, 0);
+        let _lock = self.device().lock();
+        self.buffer.unlock(self.device_context());
         Error::Success
     }
 }
@@ -133,6 +125,19 @@ impl IndexBuffer {
 
         unsafe { new_com_interface(vb) }
     }
+
+    pub fn get_dx11(&self) -> &d3d11::Buffer {
+        &self.buffer
+    }
+
+    /// Retrieves the DXGI format of the indices stored in this buffer.
+    pub fn dxgi_format(&self) -> DXGI_FORMAT {
+        if self.fmt == D3DFMT_INDEX32 {
+            DXGI_FORMAT_R32_UINT
+        } else {
+            DXGI_FORMAT_R16_UINT
+        }
+    }
 }
 
 impl std::ops::Deref for IndexBuffer {
@@ -168,27 +173,19 @@ impl IndexBuffer {
         Error::Success
     }
 
-    fn lock(&self, offset: u32, _size: u32, ret: *mut *mut u8, flags: LockFlags) -> Error {
+    fn lock(&self, offset: u32, size: u32, ret: *mut *mut u8, flags: LockFlags) -> Error {
+        let _lock = self.device().lock();
         let ret = if_error!(check_mut_ref(ret));
 
-        let resource = self.buffer.as_resource();
-        let ctx = self.device_context();
-        let mapped = if_error!(ctx.map(resource, 0, flags, self.usage()));
-
         // TODO: allow buffers to be mapped multiple times.
-        info!("Mapped index buffer");
-        *ret = unsafe {
-            let addr = mapped.pBits as *mut u8;
-            addr.offset(offset as isize)
-        };
+        *ret = if_error!(self.buffer.lock(self.device_context(), offset, size, flags));
 
         Error::Success
     }
 
     fn unlock(&self) -> Error {
-        let resource = self.buffer.as_resource();
-        let ctx = self.device_context();
-        ctx.unmap(resource, 0);
+        let _lock = self.device().lock();
+        self.buffer.unlock(self.device_context());
         Error::Success
     }
 }
@@ -0,0 +1,133 @@
+use std::ptr;
+
+use winapi::shared::windef::HCURSOR;
+use winapi::um::{wingdi, winuser};
+
+use crate::Error;
+
+/// Emulates D3D9's hardware cursor with a Win32 cursor.
+///
+/// DXGI has no concept of a hardware cursor, but Windows draws its own cursors
+/// in hardware anyway, so the app's image is turned into a regular cursor.
+pub struct Cursor {
+    handle: HCURSOR,
+    visible: bool,
+}
+
+impl Cursor {
+    /// Replaces the cursor's image with an array of 32-bit ARGB pixels.
+    pub fn set_image(
+        &mut self,
+        pixels: &[u32],
+        (width, height): (u32, u32),
+        (x_hot, y_hot): (u32, u32),
+    ) -> Result<(), Error> {
+        // Monochrome bitmaps have rows aligned to 16 bits.
+        // The mask is empty, since the colors have an alpha channel.
+        let mask_pitch = ((width + 15) / 16 * 2) as usize;
+        let mask_bits = vec![0u8; mask_pitch * height as usize];
+
+        let handle = unsafe {
+            let color = wingdi::CreateBitmap(
+                width as i32,
+                height as i32,
+                1,
+                32,
+                pixels.as_ptr() as *const _,
+            );
+            let mask = wingdi::CreateBitmap(
+                width as i32,
+                height as i32,
+                1,
+                1,
+                mask_bits.as_ptr() as *const _,
+            );
+
+            let mut info = winuser::ICONINFO {
+                fIcon: 0,
+                xHotspot: x_hot,
+                yHotspot: y_hot,
+                hbmMask: mask,
+                hbmColor: color,
+            };
+
+            let handle = winuser::CreateIconIndirect(&mut info);
+
+            // The cursor keeps its own copies of the bitmaps.
+            wingdi::DeleteObject(color as *mut _);
+            wingdi::DeleteObject(mask as *mut _);
+
+            handle
+        };
+
+        if handle.is_null() {
+            error!("Failed to create cursor");
+            return Err(Error::InvalidCall);
+        }
+
+        self.destroy();
+        self.handle = handle;
+        self.update();
+
+        Ok(())
+    }
+
+    /// Shows or hides the cursor, returning whether it was previously visible.
+    pub fn show(&mut self, visible: bool) -> bool {
+        let previous = self.visible;
+        self.visible = visible;
+        self.update();
+        previous
+    }
+
+    /// Moves the cursor to a position on the screen.
+    pub fn set_position(&self, x: i32, y: i32) {
+        unsafe {
+            winuser::SetCursorPos(x, y);
+        }
+    }
+
+    /// Makes Windows use the current cursor.
+    fn update(&self) {
+        // Nothing is shown until the app sets an image.
+        if self.handle.is_null() {
+            return;
+        }
+
+        let handle = if self.visible {
+            self.handle
+        } else {
+            ptr::null_mut()
+        };
+
+        unsafe {
+            winuser::SetCursor(handle);
+        }
+    }
+
+    /// Destroys the cursor's image, if any.
+    fn destroy(&mut self) {
+        if !self.handle.is_null() {
+            unsafe {
+                winuser::DestroyCursor(self.handle);
+            }
+            self.handle = ptr::null_mut();
+        }
+    }
+}
+
+impl Default for Cursor {
+    fn default() -> Self {
+        Self {
+            handle: ptr::null_mut(),
+            // The D3D9 cursor starts out hidden.
+            visible: false,
+        }
+    }
+}
+
+impl Drop for Cursor {
+    fn drop(&mut self) {
+        self.destroy();
+    }
+}
@@ -1,25 +1,100 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::mem::MaybeUninit;
 use std::sync::atomic::AtomicU32;
-use std::{cmp, mem, ptr};
-
-use winapi::shared::{d3d9::*, d3d9caps::D3DCAPS9, d3d9types::*, dxgi::IDXGIFactory, windef::*};
+use std::time::{Duration, Instant};
+use std::{cmp, mem, ptr, slice};
+
+use winapi::ctypes::c_void;
+use winapi::shared::{
+    d3d9::*,
+    d3d9caps::D3DCAPS9,
+    d3d9types::*,
+    dxgi::{IDXGIDevice1, IDXGIFactory},
+    dxgiformat::*,
+    windef::*,
+};
 use winapi::um::{
     d3d11::*,
     unknwnbase::{IUnknown, IUnknownVtbl},
+    wingdi::PALETTEENTRY,
+    winuser,
 };
+use winapi::Interface;
 
 use com_impl::{implementation, interface, ComInterface};
 use comptr::ComPtr;
 
-use super::state::{DeviceState, StateBlock};
+use super::state::{DeviceState, RecordedState, StateBlock, StreamSource, MAX_STREAMS};
 use super::*;
 
-use crate::core::*;
+use crate::core::{
+    blend::{color_to_alpha_blend, d3d9_to_d3d11_blend, d3d9_to_d3d11_blend_op},
+    color::{d3dcolor_to_pixel, d3dcolor_to_rgba},
+    depth::{d3d9_to_d3d11_comparison, d3d9_to_d3d11_stencil_op},
+    fmt::{d3d_format_to_dxgi, is_null_format, is_paletted_format, TextureSwizzle, D3DFMT_ATOC},
+    fvf::fvf_to_vertex_elements,
+    prim::{d3d9_to_d3d11_topology, primitive_vertex_count},
+    sampler::{d3d9_to_d3d11_address_mode, d3d9_to_d3d11_filter},
+    *,
+};
 use crate::d3d11;
+use crate::shader;
 use crate::Error;
-use winapi::shared::minwindef::{DWORD, UINT};
+use winapi::shared::minwindef::{BOOL, DWORD, UINT};
+
+/// Number of samplers available to pixel shaders.
+const MAX_SAMPLERS: usize = 16;
+
+/// Number of samplers available to vertex shaders, starting at `D3DVERTEXTEXTURESAMPLER0`.
+const MAX_VERTEX_SAMPLERS: usize = 4;
+
+/// The first world matrix, which is the one used when vertex blending is disabled.
+const D3DTS_WORLD: D3DTRANSFORMSTATETYPE = 256;
+
+/// Point size which apps set to resolve the multisampled depth buffer
+/// into the depth texture bound to the first stage, if the `RESZ` format is supported.
+const RESZ_CODE: u32 = 0x7fa0_5000;
+
+/// Minimum time between two flushes of the context at the end of a scene.
+///
+/// Some games end thousands of scenes per frame, and flushing after every one would be slow.
+const SCENE_FLUSH_INTERVAL: Duration = Duration::from_millis(2);
+
+/// Whether a device can be used for rendering.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum DeviceStatus {
+    /// The device is working normally.
+    Ok,
+    /// The device was lost, and cannot be reset yet.
+    Lost,
+    /// The device was lost, and the app must reset it.
+    NotReset,
+}
+
+/// The pipeline state objects last bound to the device context.
+///
+/// Apps set the same states over and over, so binding them is skipped when nothing changed.
+/// `None` means the bound state is unknown, for example after the blitter overwrote it.
+#[derive(Default)]
+struct BoundState {
+    input_layout: Option<*mut ID3D11InputLayout>,
+    vertex_shader: Option<*mut ID3D11VertexShader>,
+    pixel_shader: Option<*mut ID3D11PixelShader>,
+    geometry_shader: Option<*mut ID3D11GeometryShader>,
+    samplers: Option<[*mut ID3D11SamplerState; MAX_SAMPLERS]>,
+    vertex_samplers: Option<[*mut ID3D11SamplerState; MAX_VERTEX_SAMPLERS]>,
+    // The blend state and the blend factor.
+    blend: Option<(*mut ID3D11BlendState, D3DCOLOR)>,
+    rasterizer: Option<*mut ID3D11RasterizerState>,
+    // The depth / stencil state and the stencil reference value.
+    depth_stencil: Option<(*mut ID3D11DepthStencilState, u32)>,
+    // The depth range of the viewport, which differs for pre-transformed vertices.
+    depth_range: Option<(f32, f32)>,
+}
 
 /// Structure representing a logical graphics device.
-#[interface(IDirect3DDevice9)]
+#[interface(IDirect3DDevice9Ex)]
 pub struct Device {
     refs: AtomicU32,
     // Interface which created this device.
@@ -33,6 +108,11 @@ pub struct Device {
     device: d3d11::Device,
     // The context in which commands are run.
     ctx: d3d11::DeviceContext,
+    // While recording a command list, `ctx` is a deferred context,
+    // and this stores the immediate context.
+    immediate_ctx: Option<d3d11::DeviceContext>,
+    // Messages from D3D11's debug layer, if it is enabled.
+    info_queue: Option<d3d11::InfoQueue>,
     // Store the creation params, since the app might request them later.
     creation_params: D3DDEVICE_CREATION_PARAMETERS,
     // The DXGI factory which was used to create this device.
@@ -40,6 +120,15 @@ pub struct Device {
     factory: ComPtr<IDXGIFactory>,
     // The window associated with this device.
     window: HWND,
+    // The window which must be active for a full-screen device not to be lost.
+    focus_window: HWND,
+    // Whether the device is usable, or must be reset first.
+    status: Cell<DeviceStatus>,
+    // Serializes the calls to this device, if the app asked for it to be thread-safe.
+    lock: DeviceLock,
+    // Whether the app created this device through the D3D9Ex interfaces.
+    // Extended devices are never lost.
+    ex: bool,
     // The implicit swap chain for the back buffer.
     // There is one for each device in an adapter group.
     swap_chains: Vec<ComPtr<SwapChain>>,
@@ -51,6 +140,59 @@ pub struct Device {
     // The current internal state of this device,
     // as it was last set by calling state functions.
     istate: DeviceState,
+    // The state set since the app called `BeginStateBlock`, if it is recording a state block.
+    recording: Option<RecordedState>,
+    // Whether the app is between a BeginScene / EndScene pair.
+    in_scene: bool,
+    // When the commands were last submitted to the GPU at the end of a scene.
+    last_scene_flush: Instant,
+    // Scratch buffers used for uploading data passed to the user pointer draw calls.
+    up_vertices: d3d11::RingBuffer,
+    up_indices: d3d11::RingBuffer,
+    // Vertex declarations created for the FVF codes the app has used.
+    fvf_decls: HashMap<u32, ComPtr<VertexDeclaration>>,
+    // Shaders which have already been translated.
+    shader_cache: shader::ShaderCache,
+    // Shaders generated to emulate the fixed function pipeline.
+    ff_vertex_shaders: HashMap<shader::FixedVertexKey, (ComPtr<ID3D11VertexShader>, Box<[u8]>)>,
+    ff_pixel_shaders: HashMap<shader::FixedPixelKey, ComPtr<ID3D11PixelShader>>,
+    // Geometry shader expanding points into quads, compiled the first time points are drawn.
+    point_sprite_shader: Option<ComPtr<ID3D11GeometryShader>>,
+    // Constants of the vertex and pixel shaders.
+    vs_consts: d3d11::ShaderConstants,
+    ps_consts: d3d11::ShaderConstants,
+    // Reference value of the alpha test, which is emulated in pixel shaders.
+    alpha_test_consts: d3d11::ConstantBuffer<f32>,
+    // Transforms and other state used by the fixed function vertex shader.
+    ff_consts: d3d11::ConstantBuffer<f32>,
+    // Equations of the enabled user clip planes.
+    clip_plane_consts: d3d11::ConstantBuffer<f32>,
+    // Point size state, used by the vertex shader and the point sprite geometry shader.
+    point_sprite_consts: d3d11::ConstantBuffer<f32>,
+    // Fog color and equation parameters, used by both vertex and pixel shaders.
+    fog_consts: d3d11::ConstantBuffer<f32>,
+    // Texture factor and per-stage constants used by the fixed function pixel shader.
+    texture_stage_consts: d3d11::ConstantBuffer<f32>,
+    // Sampler state objects, keyed by the D3D9 sampler states they were created from.
+    sampler_states: HashMap<[u32; 10], ComPtr<ID3D11SamplerState>>,
+    // Blend state objects, keyed by the D3D9 render states they were created from.
+    blend_states: HashMap<[u32; 13], ComPtr<ID3D11BlendState>>,
+    // Rasterizer state objects, keyed by the D3D9 render states they were created from.
+    rasterizer_states: HashMap<[u32; 5], ComPtr<ID3D11RasterizerState>>,
+    // Depth / stencil state objects, keyed by the D3D9 render states they were created from.
+    depth_stencil_states: HashMap<[u32; 15], ComPtr<ID3D11DepthStencilState>>,
+    // The state objects currently bound to the context.
+    bound: BoundState,
+    // The cursor set by the app, emulated with a Win32 cursor.
+    cursor: Cursor,
+    // The palettes used to expand paletted textures, and the index of the current one.
+    palettes: HashMap<u32, [PALETTEENTRY; 256]>,
+    current_palette: u32,
+    // Helper used to scale and convert surfaces, created the first time it is needed.
+    blitter: Option<d3d11::Blitter>,
+    // Number of times this device has been reset.
+    // Resources in the default pool created before the last reset are lost.
+    generation: u32,
 }
 
 impl Device {
@@ -61,13 +203,13 @@ impl Device {
         cp: D3DDEVICE_CREATION_PARAMETERS,
         pp: &mut D3DPRESENT_PARAMETERS,
         factory: ComPtr<IDXGIFactory>,
+        ex: bool,
     ) -> Result<ComPtr<Device>, Error> {
         let device = d3d11::Device::new(adapter.device());
         let ctx = d3d11::DeviceContext::new(&device);
+        let info_queue = d3d11::InfoQueue::new(&device);
 
         // Determine which window to render to.
-        // TODO: track the focus window and use it to disable rendering
-        // when the app loses focus. It is currently ignored.
         let window = unsafe {
             // We're supposed to use the device window if available, or
             // fall back to the focus window otherwise.
@@ -77,8 +219,34 @@ impl Device {
                 .ok_or(Error::InvalidCall)?
         };
 
+        // The focus window is used to detect when a full-screen app loses focus.
+        let focus_window = if cp.hFocusWindow.is_null() {
+            window
+        } else {
+            cp.hFocusWindow
+        };
+
         let istate = DeviceState::default();
 
+        let new_consts = || {
+            d3d11::ShaderConstants::new(
+                &device,
+                shader::MAX_FLOAT_CONSTANTS as usize,
+                shader::MAX_INT_CONSTANTS as usize,
+                shader::MAX_BOOL_CONSTANTS as usize,
+            )
+        };
+        let vs_consts = new_consts()?;
+        let ps_consts = new_consts()?;
+        let alpha_test_consts = d3d11::ConstantBuffer::new(&device, 1)?;
+        let ff_consts = d3d11::ConstantBuffer::new(&device, shader::FIXED_FUNCTION_REGISTERS)?;
+        let clip_plane_consts = d3d11::ConstantBuffer::new(&device, shader::MAX_CLIP_PLANES)?;
+        let point_sprite_consts =
+            d3d11::ConstantBuffer::new(&device, shader::POINT_SPRITE_REGISTERS)?;
+        let fog_consts = d3d11::ConstantBuffer::new(&device, shader::FOG_REGISTERS)?;
+        let texture_stage_consts =
+            d3d11::ConstantBuffer::new(&device, shader::TEXTURE_STAGES_REGISTERS)?;
+
         let device = Self {
             __vtable: Box::new(Self::create_vtable()),
             refs: AtomicU32::new(1),
@@ -86,38 +254,437 @@ impl Device {
             adapter,
             device,
             ctx,
+            immediate_ctx: None,
+            info_queue,
             creation_params: cp,
+            lock: DeviceLock::new(cp.BehaviorFlags & D3DCREATE_MULTITHREADED != 0),
             factory,
             window,
+            focus_window,
+            status: Cell::new(DeviceStatus::Ok),
+            ex,
             swap_chains: Vec::new(),
             render_targets: Vec::new(),
             depth_stencil: None,
             istate,
+            recording: None,
+            in_scene: false,
+            last_scene_flush: Instant::now(),
+            up_vertices: d3d11::RingBuffer::new(D3D11_BIND_VERTEX_BUFFER),
+            up_indices: d3d11::RingBuffer::new(D3D11_BIND_INDEX_BUFFER),
+            fvf_decls: HashMap::new(),
+            shader_cache: shader::ShaderCache::default(),
+            ff_vertex_shaders: HashMap::new(),
+            ff_pixel_shaders: HashMap::new(),
+            point_sprite_shader: None,
+            vs_consts,
+            ps_consts,
+            alpha_test_consts,
+            ff_consts,
+            clip_plane_consts,
+            point_sprite_consts,
+            fog_consts,
+            texture_stage_consts,
+            sampler_states: HashMap::new(),
+            blend_states: HashMap::new(),
+            rasterizer_states: HashMap::new(),
+            depth_stencil_states: HashMap::new(),
+            bound: BoundState::default(),
+            cursor: Cursor::default(),
+            palettes: HashMap::new(),
+            current_palette: 0,
+            blitter: None,
+            generation: 0,
         };
 
         let mut device: ComPtr<Device> = unsafe { new_com_interface(device) };
 
+        device.create_default_resources(pp)?;
+
+        // Send the app's markers to the newest device.
+        if let Some(annotation) = device.ctx.annotation() {
+            d3d11::perf::set_target(annotation, &device.lock);
+        }
+
+        Ok(device)
+    }
+
+    /// Locks this device for the current thread, until the returned guard is dropped.
+    ///
+    /// Does nothing unless the device was created with `D3DCREATE_MULTITHREADED`.
+    pub fn lock(&self) -> DeviceLockGuard {
+        self.lock.lock()
+    }
+
+    /// Retrieves the adapter of this device.
+    pub fn adapter(&self) -> &Adapter {
+        unsafe { &*self.adapter }
+    }
+
+    /// Retrieves a reference to the immediate device context.
+    pub fn device_context(&self) -> &d3d11::DeviceContext {
+        self.immediate_ctx.as_ref().unwrap_or(&self.ctx)
+    }
+
+    /// Starts recording the rendering commands into a command list, instead of running them.
+    ///
+    /// This lets engines build lists of commands off the main thread, or replay the same
+    /// commands cheaply. Resources are still locked and updated immediately while recording.
+    pub fn begin_command_list(&mut self) -> Result<(), Error> {
+        if self.immediate_ctx.is_some() || self.recording.is_some() {
+            error!("Cannot record a command list while already recording");
+            return Err(Error::InvalidCall);
+        }
+
+        let deferred = d3d11::DeviceContext::new_deferred(&self.device)?;
+        self.immediate_ctx = Some(mem::replace(&mut self.ctx, deferred));
+        self.reset_up_buffers();
+
+        // Deferred contexts start out with the default state.
+        self.rebind_state();
+
+        Ok(())
+    }
+
+    /// Stops recording commands, and returns the list of recorded commands.
+    pub fn end_command_list(&mut self) -> Result<ComPtr<ID3D11CommandList>, Error> {
+        let immediate = self.immediate_ctx.take().ok_or_else(|| {
+            error!("No command list is being recorded");
+            Error::InvalidCall
+        })?;
+
+        let deferred = mem::replace(&mut self.ctx, immediate);
+        self.reset_up_buffers();
+        let list = deferred.finish_command_list()?;
+
+        // The state set while recording only went to the deferred context.
+        self.rebind_state();
+
+        Ok(list)
+    }
+
+    /// Discards the commands recorded so far, if a command list is being recorded.
+    fn abort_command_list(&mut self) {
+        if let Some(immediate) = self.immediate_ctx.take() {
+            warn!("Discarding the command list being recorded");
+            // Releasing the deferred context drops its commands.
+            self.ctx = immediate;
+            self.reset_up_buffers();
+        }
+    }
+
+    /// Makes the next uploads of user pointer data discard the scratch buffers,
+    /// which is needed after switching between the immediate and the deferred context.
+    fn reset_up_buffers(&mut self) {
+        self.up_vertices.reset();
+        self.up_indices.reset();
+    }
+
+    /// Runs the commands of a recorded command list.
+    ///
+    /// The device's state is not changed by the commands in the list.
+    pub fn execute_command_list(&self, list: &ComPtr<ID3D11CommandList>) -> Result<(), Error> {
+        if self.immediate_ctx.is_some() {
+            error!("Cannot run a command list while recording another one");
+            return Err(Error::InvalidCall);
+        }
+
+        self.ctx.execute_command_list(list);
+
+        Ok(())
+    }
+
+    pub fn dx11_device(&self) -> &d3d11::Device {
+        &self.device
+    }
+
+    /// Checks if a full-screen device's window is hidden, because the app lost focus.
+    fn is_occluded(&self) -> bool {
+        let windowed = self.swap_chains.first().map_or(true, |sc| sc.is_windowed());
+        let has_focus = unsafe {
+            // The focus window might be a child of the app's top-level window.
+            let foreground = winuser::GetForegroundWindow();
+            let focus = winuser::GetAncestor(self.focus_window, winuser::GA_ROOT);
+            foreground == focus && winuser::IsIconic(focus) == 0
+        };
+
+        !windowed && !has_focus
+    }
+
+    /// Checks if the device has been lost, or if it can be reset after being lost.
+    fn update_status(&self) -> DeviceStatus {
+        let occluded = !self.ex && self.is_occluded();
+
+        // Full-screen devices are lost when the app loses focus,
+        // and can be reset once it regains it.
+        let status = match self.status.get() {
+            DeviceStatus::Ok if occluded => DeviceStatus::Lost,
+            DeviceStatus::Lost if !occluded => DeviceStatus::NotReset,
+            status => status,
+        };
+
+        if status != self.status.get() {
+            info!("Device status changed to {:?}", status);
+            self.status.set(status);
+        }
+
+        status
+    }
+
+    /// Checks if rendering should be skipped, because the device is lost.
+    ///
+    /// Like in D3D9, drawing while lost succeeds but does nothing.
+    fn is_lost(&self) -> bool {
+        self.status.get() != DeviceStatus::Ok
+    }
+
+    /// Retrieves the DXGI interface of the D3D11 device.
+    fn dxgi_device(&self) -> Result<ComPtr<IDXGIDevice1>, Error> {
+        d3d11::query_interface(&self.device).ok_or(Error::DriverInternalError)
+    }
+
+    /// Presents the back buffers of all the implicit swap chains.
+    fn present_swap_chains(
+        &self,
+        src: usize,
+        dest: usize,
+        wnd: HWND,
+        dirty: usize,
+        flags: u32,
+    ) -> Error {
+        for sc in &self.swap_chains {
+            match sc.present(src, dest, wnd, dirty, flags) {
+                Error::Success => (),
+                Error::DeviceLost => {
+                    // The swap chain is unusable until the device is reset.
+                    self.status.set(DeviceStatus::NotReset);
+                    return Error::DeviceLost;
+                }
+                err => return err,
+            }
+        }
+        Error::Success
+    }
+
+    /// Retrieves the number of times this device has been reset.
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+
+    /// Retrieves the colors of the current texture palette, if it was set.
+    pub fn current_palette(&self) -> Option<&[PALETTEENTRY; 256]> {
+        self.palettes.get(&self.current_palette)
+    }
+
+    /// Retrieves the cache of translated shaders.
+    pub fn shader_cache(&self) -> &shader::ShaderCache {
+        &self.shader_cache
+    }
+
+    /// Retrieves the indices of every light the app has set.
+    pub fn light_indices(&self) -> Vec<u32> {
+        self.istate.light_indices().collect()
+    }
+
+    /// Overwrites the values recorded in a state block with the current state.
+    ///
+    /// Only the state which is already in the block is captured.
+    pub fn capture_state(&self, rec: &mut RecordedState) -> Result<(), Error> {
+        if self.recording.is_some() {
+            error!("Cannot capture a state block while recording one");
+            return Err(Error::InvalidCall);
+        }
+
+        let state = &self.istate;
+
+        for (&ty, value) in &mut rec.render_states {
+            *value = state.get_render_state(ty);
+        }
+        for (&(sampler, ty), value) in &mut rec.sampler_states {
+            *value = state.get_sampler_state(sampler, ty);
+        }
+        for (&(stage, ty), value) in &mut rec.texture_stage_states {
+            *value = state.get_texture_stage_state(stage, ty);
+        }
+        for (&stage, texture) in &mut rec.textures {
+            *texture = state.get_texture(stage).cloned();
+        }
+        for (&ty, mat) in &mut rec.transforms {
+            *mat = state.get_transform(ty);
+        }
+
+        if rec.vertex_shader.is_some() {
+            rec.vertex_shader = Some(state.get_vertex_shader().cloned());
+        }
+        if rec.pixel_shader.is_some() {
+            rec.pixel_shader = Some(state.get_pixel_shader().cloned());
+        }
+
+        for (consts, recorded) in &mut [
+            (&self.vs_consts, &mut rec.vs_constants),
+            (&self.ps_consts, &mut rec.ps_constants),
+        ] {
+            for (&reg, value) in &mut recorded.float {
+                *value = consts.float.get(reg, 1)?[0];
+            }
+            for (&reg, value) in &mut recorded.int {
+                *value = consts.int.get(reg, 1)?[0];
+            }
+            for (&reg, value) in &mut recorded.bool {
+                *value = consts.bool.get(reg, 1)?[0];
+            }
+        }
+
+        if rec.vertex_decl.is_some() {
+            rec.vertex_decl = Some((state.get_vertex_declaration(), state.get_fvf()));
+        }
+        for (&stream, source) in &mut rec.streams {
+            *source = state.get_stream_source(stream).cloned().unwrap_or_default();
+        }
+        if rec.indices.is_some() {
+            rec.indices = Some(state.get_indices().cloned());
+        }
+
+        if rec.viewport.is_some() {
+            rec.viewport = Some(state.get_viewport());
+        }
+        if rec.scissor_rect.is_some() {
+            rec.scissor_rect = Some(state.get_scissor_rect());
+        }
+        if rec.material.is_some() {
+            rec.material = Some(state.get_material());
+        }
+
+        for (&index, light) in &mut rec.lights {
+            if let Some(current) = state.get_light(index) {
+                *light = current;
+            }
+        }
+        for (&index, enable) in &mut rec.light_enables {
+            *enable = state.is_light_enabled(index);
+        }
+        for (&index, plane) in &mut rec.clip_planes {
+            *plane = state.get_clip_plane(index as usize);
+        }
+
+        Ok(())
+    }
+
+    /// Sets the state recorded in a state block.
+    ///
+    /// This goes through the same functions as the app, so that the state
+    /// gets recorded if another state block is being recorded.
+    pub fn apply_state(&mut self, rec: &RecordedState) -> Error {
+        for (&ty, &value) in &rec.render_states {
+            if_not_success!(self.set_render_state(ty, value));
+        }
+        for (&(sampler, ty), &value) in &rec.sampler_states {
+            if_not_success!(self.set_sampler_state(sampler, ty, value));
+        }
+        for (&(stage, ty), &value) in &rec.texture_stage_states {
+            if_not_success!(self.set_texture_stage_state(stage, ty, value));
+        }
+        for (&stage, texture) in &rec.textures {
+            if_not_success!(self.set_texture(stage, com_ptr_or_null(texture)));
+        }
+        for (&ty, mat) in &rec.transforms {
+            let mat: D3DMATRIX = unsafe { mem::transmute(*mat) };
+            if_not_success!(self.set_transform(ty, &mat));
+        }
+
+        if let Some(vs) = &rec.vertex_shader {
+            if_not_success!(self.set_vertex_shader(com_ptr_or_null(vs)));
+        }
+        if let Some(ps) = &rec.pixel_shader {
+            if_not_success!(self.set_pixel_shader(com_ptr_or_null(ps)));
+        }
+
+        let vs = &rec.vs_constants;
+        for (&reg, value) in &vs.float {
+            if_not_success!(self.set_vertex_shader_constant_f(reg, value.as_ptr(), 1));
+        }
+        for (&reg, value) in &vs.int {
+            if_not_success!(self.set_vertex_shader_constant_i(reg, value.as_ptr(), 1));
+        }
+        for (&reg, value) in &vs.bool {
+            if_not_success!(self.set_vertex_shader_constant_b(reg, &(value[0] as BOOL), 1));
+        }
+
+        let ps = &rec.ps_constants;
+        for (&reg, value) in &ps.float {
+            if_not_success!(self.set_pixel_shader_constant_f(reg, value.as_ptr(), 1));
+        }
+        for (&reg, value) in &ps.int {
+            if_not_success!(self.set_pixel_shader_constant_i(reg, value.as_ptr(), 1));
+        }
+        for (&reg, value) in &ps.bool {
+            if_not_success!(self.set_pixel_shader_constant_b(reg, &(value[0] as BOOL), 1));
+        }
+
+        match rec.vertex_decl {
+            Some((_, fvf)) if fvf != 0 => if_not_success!(self.set_f_v_f(fvf)),
+            Some((decl, _)) => if_not_success!(self.set_vertex_declaration(decl)),
+            None => (),
+        }
+        for (&stream, source) in &rec.streams {
+            let vb = com_ptr_or_null(&source.buffer);
+            if_not_success!(self.set_stream_source(stream, vb, source.offset, source.stride));
+        }
+        if let Some(ib) = &rec.indices {
+            if_not_success!(self.set_indices(com_ptr_or_null(ib)));
+        }
+
+        if let Some(vp) = &rec.viewport {
+            if_not_success!(self.set_viewport(vp));
+        }
+        if let Some(rect) = &rec.scissor_rect {
+            if_not_success!(self.set_scissor_rect(rect));
+        }
+        if let Some(mat) = &rec.material {
+            if_not_success!(self.set_material(mat));
+        }
+
+        for (&index, light) in &rec.lights {
+            if_not_success!(self.set_light(index, light));
+        }
+        // Disable lights first, to make room for the ones being enabled.
+        let mut enables: Vec<_> = rec.light_enables.iter().collect();
+        enables.sort_by_key(|&(_, &enable)| enable);
+        for (&index, &enable) in enables {
+            if_not_success!(self.light_enable(index, enable as BOOL));
+        }
+        for (&index, plane) in &rec.clip_planes {
+            if_not_success!(self.set_clip_plane(index, plane.as_ptr()));
+        }
+
+        Error::Success
+    }
+
+    /// Creates the implicit swap chain, the default render target and the
+    /// automatic depth / stencil buffer, and binds them to the pipeline.
+    fn create_default_resources(&mut self, pp: &mut D3DPRESENT_PARAMETERS) -> Result<(), Error> {
         // Create the default swap chain for the adapter.
-        device.create_default_swap_chain(pp)?;
+        self.create_default_swap_chain(pp)?;
 
         // Create the default render target for the swap chain.
-        device.create_default_render_target()?;
+        self.create_default_render_target()?;
 
         // If the application requested it, we can automatically create
         // a depth/stencil buffer for it.
         if pp.EnableAutoDepthStencil != 0 {
-            device.depth_stencil = {
+            self.depth_stencil = {
                 let width = pp.BackBufferWidth;
                 let height = pp.BackBufferHeight;
                 let fmt = pp.AutoDepthStencilFormat;
                 let discard = pp.Flags & D3DPRESENTFLAG_DISCARD_DEPTHSTENCIL;
-                let ms_ty = 0;
-                let ms_qlt = 0;
+                // Must match the back buffer's multisampling.
+                let ms_ty = pp.MultiSampleType;
+                let ms_qlt = pp.MultiSampleQuality;
                 let shared_handle = 0;
 
                 let mut ptr = ptr::null_mut();
 
-                if_not_success_err!(device.create_depth_stencil_surface(
+                if_not_success_err!(self.create_depth_stencil_surface(
                     width,
                     height,
                     fmt,
@@ -133,23 +700,11 @@ impl Device {
         }
 
         // Now that we have an initial RT / DS buffer, we need to set D3D11's state.
-        device.update_render_targets();
-
-        Ok(device)
-    }
-
-    /// Retrieves the adapter of this device.
-    pub fn adapter(&self) -> &Adapter {
-        unsafe { &*self.adapter }
-    }
-
-    /// Retrieves a reference to the immediate device context.
-    pub fn device_context(&self) -> &d3d11::DeviceContext {
-        &self.ctx
-    }
+        self.update_render_targets();
+        self.reset_viewport_and_scissor()?;
+        self.bind_constant_buffers();
 
-    pub fn dx11_device(&self) -> &d3d11::Device {
-        &self.device
+        Ok(())
     }
 
     /// Creates the default swap chain for this device.
@@ -207,7 +762,7 @@ impl Device {
     /// Creates the default render target for this device.
     fn create_default_render_target(&mut self) -> Result<(), Error> {
         let sc = &self.swap_chains[0];
-        let bbuf = sc.buffer(0)?;
+        let bbuf = sc.back_buffer(0)?;
 
         let rt = self.create_render_target_helper(bbuf)?;
 
@@ -229,385 +784,1792 @@ impl Device {
         }
     }
 
-    /// Synchronises D3D9's render target views and depth / stencil view with D3D11.
-    fn update_render_targets(&mut self) {
-        let num = self.render_targets.len() as u32;
-
-        let mut rt_views = [ptr::null_mut(); 8];
-        for (i, rt) in self.render_targets.iter().enumerate() {
-            if let Some(rt) = rt {
-                rt_views[i] = rt.render_target_view().unwrap() as *mut _;
-            }
+    /// Checks that draw calls are allowed in the current state.
+    fn check_in_scene(&self) -> Result<(), Error> {
+        if self.in_scene {
+            Ok(())
+        } else {
+            error!("Application tried to draw outside of a scene");
+            Err(Error::InvalidCall)
         }
+    }
 
-        let ds_view = self
-            .depth_stencil
-            .as_ref()
-            .map(|ds| ds.depth_stencil_view().unwrap() as *mut _)
-            .unwrap_or(ptr::null_mut());
+    /// Sets the primitive topology used by the next draw call.
+    fn set_primitive_topology(&self, ty: D3DPRIMITIVETYPE) -> Result<(), Error> {
+        let topology = d3d9_to_d3d11_topology(ty).ok_or_else(|| {
+            error!("Unsupported primitive type: {}", ty);
+            Error::InvalidCall
+        })?;
 
         unsafe {
-            self.ctx.OMSetRenderTargets(num, rt_views.as_ptr(), ds_view);
+            self.ctx.IASetPrimitiveTopology(topology);
         }
 
-        // We also need to update the viewport.
-        let (width, height) = unsafe {
-            let rt = self.render_targets[0].as_ref().unwrap();
-            let mut desc = mem::uninitialized();
-            rt.get_desc(&mut desc);
-            (desc.Width, desc.Height)
+        Ok(())
+    }
+
+    /// Binds the current shaders and the matching input layout.
+    ///
+    /// If the app did not set a shader for a stage, the fixed function pipeline is emulated.
+    fn bind_shaders(&mut self, ty: D3DPRIMITIVETYPE) -> Result<(), Error> {
+        let decl = unsafe { self.istate.get_vertex_declaration().as_ref() }.ok_or_else(|| {
+            error!("Application tried to draw without a vertex declaration");
+            Error::InvalidCall
+        })?;
+
+        let (vs, signature) = match self.istate.get_vertex_shader() {
+            Some(vs) => (vs.get_dx11().clone(), vs.input_signature().into()),
+            None => self.fixed_vertex_shader(decl)?,
         };
 
-        let vp = D3DVIEWPORT9 {
-            X: 0,
-            Y: 0,
-            Width: width,
-            Height: height,
-            MinZ: 0.0,
-            MaxZ: 1.0,
+        let layout = decl.input_layout(&signature)?;
+
+        // D3D9 uses the depth of pre-transformed vertices as is, without mapping it
+        // to the viewport's depth range, while D3D11 always applies the viewport.
+        let vp = self.istate.get_viewport();
+        let depth_range = if self.istate.get_vertex_shader().is_none() && decl.is_pre_transformed()
+        {
+            (0.0, 1.0)
+        } else {
+            (vp.MinZ, vp.MaxZ)
         };
 
-        self.set_viewport(&vp);
-    }
-}
+        let variant = self.pixel_variant();
 
-impl_iunknown!(struct Device: IUnknown, IDirect3DDevice9);
+        let ps = match self.istate.get_pixel_shader() {
+            Some(ps) => ps.get_dx11(variant)?,
+            None => self.fixed_pixel_shader(variant)?,
+        };
 
-#[implementation(IDirect3DDevice9)]
-impl Device {
-    // -- Device status functions --
+        // D3D11 can only draw points one pixel wide, so they are expanded into quads.
+        let gs = if ty == D3DPT_POINTLIST {
+            self.point_sprite_shader()?.as_mut()
+        } else {
+            ptr::null_mut()
+        };
 
-    /// Resets the device, recreating all its state.
-    fn reset(&mut self) {
-        unimplemented!()
-    }
+        unsafe {
+            if changed(&mut self.bound.input_layout, layout.as_mut()) {
+                self.ctx.IASetInputLayout(layout.as_mut());
+            }
+            if changed(&mut self.bound.vertex_shader, vs.as_mut()) {
+                self.ctx.VSSetShader(vs.as_mut(), ptr::null(), 0);
+            }
+            if changed(&mut self.bound.pixel_shader, ps.as_mut()) {
+                self.ctx.PSSetShader(ps.as_mut(), ptr::null(), 0);
+            }
+            if changed(&mut self.bound.geometry_shader, gs) {
+                self.ctx.GSSetShader(gs, ptr::null(), 0);
+            }
+        }
 
-    /// Checks that the device has not yet been lost / reset.
-    fn test_cooperative_level(&self) -> Error {
-        // Even if the device were lost, we wouldn't be able to do much.
-        Error::Success
-    }
+        if changed(&mut self.bound.depth_range, depth_range) {
+            self.bind_viewport(&vp, depth_range);
+        }
 
-    /// Determines how much graphics memory is available.
-    fn get_available_texture_mem(&self) -> u32 {
-        self.adapter().available_memory()
+        Ok(())
     }
 
-    /// Asks the driver to evict all managed resources from VRAM.
-    fn evict_managed_resources(&self) -> Error {
-        // Do nothing. The D3D11 driver handles everything.
-        Error::Success
-    }
+    /// Determines which state must be emulated by the current pixel shader.
+    fn pixel_variant(&self) -> shader::PixelVariant {
+        // Alpha to coverage replaces the alpha test.
+        let alpha_test = self.istate.get_render_state(D3DRS_ALPHATESTENABLE) != 0;
+        let alpha_func = if alpha_test && !alpha_to_coverage(&self.istate) {
+            self.istate.get_render_state(D3DRS_ALPHAFUNC)
+        } else {
+            D3DCMP_ALWAYS
+        };
 
-    // -- Creation parameters functions --
+        let mut swizzles = [TextureSwizzle::None; 16];
+        for (stage, swizzle) in swizzles.iter_mut().enumerate() {
+            if let Some(tx) = self.istate.get_texture(stage as u32) {
+                *swizzle = BaseTexture::from_interface(tx).swizzle();
+            }
+        }
 
-    /// Returns a reference to the parent interface.
-    fn get_direct_3_d(&self, ptr: *mut *mut Context) -> Error {
-        let ptr = if_error!(check_mut_ref(ptr));
-        *ptr = com_ref(self.parent);
-        Error::Success
-    }
+        // Table fog takes precedence over vertex fog.
+        let table_mode = self.istate.get_render_state(D3DRS_FOGTABLEMODE);
+        let fog = if self.istate.get_render_state(D3DRS_FOGENABLE) == 0 {
+            shader::FogMode::None
+        } else if table_mode == D3DFOG_NONE {
+            shader::FogMode::Vertex
+        } else {
+            shader::FogMode::Table(table_mode)
+        };
 
-    /// Returns the caps of this device.
-    fn get_device_caps(&self, caps: *mut D3DCAPS9) -> Error {
-        let caps = if_error!(check_mut_ref(caps));
-        *caps = self.adapter().caps();
-        Error::Success
+        shader::PixelVariant {
+            alpha_func,
+            swizzles,
+            fog,
+        }
     }
 
-    /// Returns the creation parameters of this device.
-    fn get_creation_parameters(&self, params: *mut D3DDEVICE_CREATION_PARAMETERS) -> Error {
-        let params = if_error!(check_mut_ref(params));
-        *params = self.creation_params;
-        Error::Success
-    }
+    /// Rebinds the state which the blitter overwrote, and which isn't bound before each draw.
+    ///
+    /// The state objects will be bound again by the next draw.
+    fn restore_state_after_blit(&mut self) {
+        // The blitter unbinds the geometry shader, which the last draw might have used.
+        let gs = self.bound.geometry_shader.unwrap_or(ptr::null_mut());
 
-    // -- Swap chain functions --
+        self.bound = BoundState::default();
+        self.bind_constant_buffers();
+        self.update_render_targets();
 
-    /// Creates new swap chains for this device.
-    fn create_additional_swap_chain(
-        &mut self,
-        pp: *mut D3DPRESENT_PARAMETERS,
-        ret: *mut *mut SwapChain,
-    ) -> Error {
-        let factory = self.factory.as_mut();
-        let pp = if_error!(check_mut_ref(pp));
-        let window = self.window;
+        // The state isn't set again, since the app might be recording a state block.
+        let vp = self.istate.get_viewport();
+        self.bound.depth_range = Some((vp.MinZ, vp.MaxZ));
+        self.bind_viewport(&vp, (vp.MinZ, vp.MaxZ));
 
-        let ret = if_error!(check_mut_ref(ret));
+        self.bind_texture(0, self.istate.get_texture(0));
 
-        *ret = if_error!(SwapChain::new(self, &self.device, factory, pp, window)).into();
-        Error::Success
+        unsafe {
+            self.ctx.GSSetShader(gs, ptr::null(), 0);
+        }
+        self.bound.geometry_shader = Some(gs);
     }
 
-    /// Returns an implicit swap chain.
-    fn get_swap_chain(&self, sc: u32, ret: *mut *mut SwapChain) -> Error {
-        let sc = if_error!(self.check_swap_chain(sc));
-        let ret = if_error!(check_mut_ref(ret));
+    /// Binds a viewport, with a depth range which may differ from its own.
+    fn bind_viewport(&self, vp: &D3DVIEWPORT9, (min_depth, max_depth): (f32, f32)) {
+        let viewport = D3D11_VIEWPORT {
+            TopLeftX: vp.X as f32,
+            TopLeftY: vp.Y as f32,
+            Width: vp.Width as f32,
+            Height: vp.Height as f32,
+            MinDepth: min_depth,
+            MaxDepth: max_depth,
+        };
 
-        *ret = sc.clone().into();
-        Error::Success
+        unsafe {
+            self.ctx.RSSetViewports(1, &viewport);
+        }
     }
 
-    /// Returns the number of implicit swap chains.
-    fn get_number_of_swap_chains(&self) -> u32 {
-        // TODO: to have more than one implicit SC, we need multi-GPU support.
-        1
+    /// Binds all of the state which isn't bound before each draw.
+    fn rebind_state(&mut self) {
+        self.restore_state_after_blit();
+        self.update_scissor_rect();
+
+        let stages =
+            (0..MAX_SAMPLERS as u32).chain(D3DVERTEXTEXTURESAMPLER0..=D3DVERTEXTEXTURESAMPLER3);
+        for stage in stages {
+            self.bind_texture(stage, self.istate.get_texture(stage));
+        }
     }
 
-    // The functions below all operate on the implicit swap chains.
+    /// Binds the shader resource view of a texture to a stage.
+    ///
+    /// Textures are bound with their sRGB view if the stage's sampler requests it.
+    fn bind_texture(&self, stage: u32, texture: Option<&ComPtr<BaseTexture>>) {
+        let srgb = self.istate.get_sampler_state(stage, D3DSAMP_SRGBTEXTURE) != 0;
+
+        let view = texture
+            .and_then(|tx| {
+                let tx = BaseTexture::from_interface(tx);
+
+                let srgb_view = if srgb {
+                    let view = tx.srgb_shader_resource_view();
+                    if view.is_none() {
+                        run_once!(|| warn!("sRGB sampling is not supported for some formats"));
+                    }
+                    view
+                } else {
+                    None
+                };
+
+                let view = srgb_view.or_else(|| tx.shader_resource_view());
+                if view.is_none() {
+                    warn!("Texture bound to stage {} cannot be sampled from", stage);
+                }
+                view
+            })
+            .map(|view| view.as_mut() as *mut _)
+            .unwrap_or(ptr::null_mut());
 
-    fn present(&self, src: usize, dest: usize, wnd: HWND, dirty: usize) -> Error {
-        for sc in &self.swap_chains {
-            match sc.present(src, dest, wnd, dirty, 0) {
-                Error::Success => (),
-                err => return err,
+        unsafe {
+            if stage >= D3DVERTEXTEXTURESAMPLER0 {
+                let slot = stage - D3DVERTEXTEXTURESAMPLER0;
+                self.ctx.VSSetShaderResources(slot, 1, &view);
+            } else {
+                self.ctx.PSSetShaderResources(stage, 1, &view);
             }
         }
-        Error::Success
     }
 
-    fn get_front_buffer_data(&self, sc: u32, fb: *mut Surface) -> Error {
-        if_error!(self.check_swap_chain(sc)).get_front_buffer_data(fb)
-    }
+    /// Binds the shader constant buffers to their slots.
+    ///
+    /// The buffers are never recreated, so this only needs to be done once.
+    fn bind_constant_buffers(&self) {
+        let vs_buffers = self.vs_consts.buffers();
+        let ps_buffers = self.ps_consts.buffers();
 
-    fn get_back_buffer(
-        &self,
-        sc: u32,
-        bi: u32,
-        ty: D3DBACKBUFFER_TYPE,
-        ret: *mut *mut Surface,
-    ) -> Error {
-        if_error!(self.check_swap_chain(sc)).get_back_buffer(bi, ty, ret)
-    }
+        unsafe {
+            self.ctx
+                .VSSetConstantBuffers(0, vs_buffers.len() as u32, vs_buffers.as_ptr());
+            self.ctx
+                .PSSetConstantBuffers(0, ps_buffers.len() as u32, ps_buffers.as_ptr());
+            self.ctx.PSSetConstantBuffers(
+                shader::ALPHA_TEST_SLOT,
+                1,
+                &self.alpha_test_consts.as_buffer(),
+            );
+            self.ctx.VSSetConstantBuffers(
+                shader::FIXED_FUNCTION_SLOT,
+                1,
+                &self.ff_consts.as_buffer(),
+            );
+            self.ctx.VSSetConstantBuffers(
+                shader::CLIP_PLANES_SLOT,
+                1,
+                &self.clip_plane_consts.as_buffer(),
+            );
 
-    fn get_raster_status(&self, sc: u32, rs: *mut D3DRASTER_STATUS) -> Error {
-        if_error!(self.check_swap_chain(sc)).get_raster_status(rs)
+            let point_sprite = self.point_sprite_consts.as_buffer();
+            self.ctx
+                .VSSetConstantBuffers(shader::POINT_SPRITE_SLOT, 1, &point_sprite);
+            self.ctx
+                .GSSetConstantBuffers(shader::POINT_SPRITE_SLOT, 1, &point_sprite);
+
+            let fog = self.fog_consts.as_buffer();
+            self.ctx.VSSetConstantBuffers(shader::FOG_SLOT, 1, &fog);
+            self.ctx.PSSetConstantBuffers(shader::FOG_SLOT, 1, &fog);
+
+            self.ctx.PSSetConstantBuffers(
+                shader::TEXTURE_STAGES_SLOT,
+                1,
+                &self.texture_stage_consts.as_buffer(),
+            );
+        }
     }
 
-    fn get_display_mode(&self, sc: u32, dm: *mut D3DDISPLAYMODE) -> Error {
-        if_error!(self.check_swap_chain(sc)).get_display_mode(dm)
+    /// Applies the state the app has set which must be resolved right before drawing.
+    fn flush_state(&mut self, ty: D3DPRIMITIVETYPE) -> Result<(), Error> {
+        self.bind_shaders(ty)?;
+        self.upload_constants()?;
+        self.bind_samplers()?;
+        self.bind_blend_state()?;
+        self.bind_rasterizer_state()?;
+        self.bind_depth_stencil_state()
     }
 
-    // -- Gamma control functions --
+    /// Binds a blend state object matching the current blending-related render states.
+    fn bind_blend_state(&mut self) -> Result<(), Error> {
+        let state = self.blend_state()?;
+        let color = self.istate.get_render_state(D3DRS_BLENDFACTOR);
 
-    /// Sets the current gamma ramp.
-    fn set_gamma_ramp(&mut self, sc: u32, flags: u32, ramp: *const D3DGAMMARAMP) {
-        self.check_swap_chain(sc)
-            .and_then(|sc| check_ref(ramp).and_then(|ramp| sc.set_gamma_ramp(flags, ramp)))
-            .unwrap_or_else(|_| error!("Failed to set gamma ramp"));
-    }
+        if changed(&mut self.bound.blend, (state.as_mut(), color)) {
+            let factor = d3dcolor_to_rgba(color);
+            unsafe {
+                self.ctx
+                    .OMSetBlendState(state.as_mut(), &factor, 0xffff_ffff);
+            }
+        }
 
-    /// Retrieves the monitor's gamma ramp.
-    fn get_gamma_ramp(&self, sc: u32, ret: *mut D3DGAMMARAMP) {
-        self.check_swap_chain(sc)
-            .and_then(|sc| check_mut_ref(ret).and_then(|ret| sc.get_gamma_ramp(ret)))
-            .unwrap_or_else(|_| error!("Failed to retrieve gamma ramp"));
+        Ok(())
     }
 
-    // -- Render target functions --
+    /// Retrieves a blend state object matching the current render states.
+    fn blend_state(&mut self) -> Result<ComPtr<ID3D11BlendState>, Error> {
+        let istate = &self.istate;
+        let state = |ty| istate.get_render_state(ty);
+
+        let key = [
+            state(D3DRS_ALPHABLENDENABLE),
+            state(D3DRS_SRCBLEND),
+            state(D3DRS_DESTBLEND),
+            state(D3DRS_BLENDOP),
+            state(D3DRS_SEPARATEALPHABLENDENABLE),
+            state(D3DRS_SRCBLENDALPHA),
+            state(D3DRS_DESTBLENDALPHA),
+            state(D3DRS_BLENDOPALPHA),
+            state(D3DRS_COLORWRITEENABLE),
+            state(D3DRS_COLORWRITEENABLE1),
+            state(D3DRS_COLORWRITEENABLE2),
+            state(D3DRS_COLORWRITEENABLE3),
+            alpha_to_coverage(istate) as u32,
+        ];
+
+        if let Some(state) = self.blend_states.get(&key) {
+            return Ok(state.clone());
+        }
 
-    /// Creates a new render target.
-    fn create_render_target(
-        &mut self,
-        width: u32,
-        height: u32,
-        fmt: D3DFORMAT,
-        ms_ty: D3DMULTISAMPLE_TYPE,
-        ms_qlt: u32,
-        lockable: u32,
-        ret: *mut *mut Surface,
-        shared_handle: usize,
-    ) -> Error {
-        let ret = if_error!(check_mut_ref(ret));
+        let [enable, src, dest, op, separate_alpha, src_alpha, dest_alpha, op_alpha, ..] = key;
+        let write_masks = &key[8..12];
+        let alpha_to_coverage = key[12];
 
-        if lockable != 0 {
-            error!("Lockable render targets are not supported");
-        }
+        // The deprecated "both" factors set the destination factor as well.
+        let blend_factors = |src, dest| {
+            let dest = match src {
+                D3DBLEND_BOTHSRCALPHA => D3DBLEND_INVSRCALPHA,
+                D3DBLEND_BOTHINVSRCALPHA => D3DBLEND_SRCALPHA,
+                _ => dest,
+            };
+            (d3d9_to_d3d11_blend(src), d3d9_to_d3d11_blend(dest))
+        };
 
-        if shared_handle != 0 {
-            error!("Shared resources are not supported");
-            return Error::InvalidCall;
+        let (src_blend, dest_blend) = blend_factors(src, dest);
+
+        let (src_blend_alpha, dest_blend_alpha, blend_op_alpha) = if separate_alpha != 0 {
+            let (src, dest) = blend_factors(src_alpha, dest_alpha);
+            (src, dest, op_alpha)
+        } else {
+            (src_blend, dest_blend, op)
+        };
+
+        let mut desc: D3D11_BLEND_DESC = unsafe { mem::zeroed() };
+
+        // Only the first 4 render targets have their own write masks in D3D9.
+        // The blend state of the first render target applies to all of them
+        // unless their masks differ.
+        let independent = write_masks.iter().any(|&mask| mask != write_masks[0]);
+        desc.IndependentBlendEnable = independent as BOOL;
+        desc.AlphaToCoverageEnable = alpha_to_coverage as BOOL;
+
+        for (i, rt) in desc.RenderTarget.iter_mut().enumerate() {
+            *rt = D3D11_RENDER_TARGET_BLEND_DESC {
+                BlendEnable: (enable != 0) as BOOL,
+                SrcBlend: src_blend,
+                DestBlend: dest_blend,
+                BlendOp: d3d9_to_d3d11_blend_op(op),
+                SrcBlendAlpha: color_to_alpha_blend(src_blend_alpha),
+                DestBlendAlpha: color_to_alpha_blend(dest_blend_alpha),
+                BlendOpAlpha: d3d9_to_d3d11_blend_op(blend_op_alpha),
+                // D3D9's write mask bits match D3D11's.
+                RenderTargetWriteMask: write_masks
+                    .get(i)
+                    .map(|&mask| mask as u8 & 0xf)
+                    .unwrap_or(0xf),
+            };
         }
 
-        // First we need to create a texture we will render to.
-        let texture = if_error!(d3d11::Texture2D::new_rt(
-            &self.device,
-            (width, height),
-            fmt,
-            ms_ty,
-            ms_qlt
-        ));
+        let state = unsafe {
+            let mut ptr = ptr::null_mut();
+            let result = self.device.CreateBlendState(&desc, &mut ptr);
+            if_not_success_err!(check_hresult(result, "Failed to create blend state"));
+            ComPtr::new(ptr)
+        };
 
-        *ret = if_error!(self.create_render_target_helper(texture)).into();
+        self.blend_states.insert(key, state.clone());
 
-        Error::Success
+        Ok(state)
     }
 
-    /// Sets a new render target on this device.
-    fn set_render_target(&mut self, i: u32, rt: *mut Surface) -> Error {
-        if i >= D3D11_SIMULTANEOUS_RENDER_TARGET_COUNT {
-            return Error::InvalidCall;
-        }
+    /// Binds sampler state objects matching the state of each sampler.
+    fn bind_samplers(&mut self) -> Result<(), Error> {
+        let mut states = [ptr::null_mut(); MAX_SAMPLERS];
 
-        // The default render target is never allowed to be null.
-        if i == 0 && rt.is_null() {
-            return Error::InvalidCall;
+        for (stage, state) in states.iter_mut().enumerate() {
+            *state = self.sampler_state(stage as u32)?.as_mut();
         }
 
-        let i = i as usize;
+        if changed(&mut self.bound.samplers, states) {
+            unsafe {
+                self.ctx
+                    .PSSetSamplers(0, states.len() as u32, states.as_ptr());
+            }
+        }
 
-        // Ensure the RT vector contains at least as many entries as we need.
-        self.render_targets.resize(i + 1, None);
+        // Only shader model 3 vertex shaders can sample textures.
+        let sm3_vs = self
+            .istate
+            .get_vertex_shader()
+            .map_or(false, |vs| vs.version().major >= 3);
+        if !sm3_vs {
+            return Ok(());
+        }
 
-        self.render_targets[i] = if let Some(rt) = unsafe { rt.as_mut() } {
-            // Ensure this surface is indeed a render target.
-            if rt.render_target_view().is_none() {
-                return Error::InvalidCall;
-            }
+        let mut states = [ptr::null_mut(); MAX_VERTEX_SAMPLERS];
 
-            Some(ComPtr::new(rt))
-        } else {
-            None
-        };
+        for (i, state) in states.iter_mut().enumerate() {
+            *state = self
+                .sampler_state(D3DVERTEXTEXTURESAMPLER0 + i as u32)?
+                .as_mut();
+        }
 
-        self.update_render_targets();
+        if changed(&mut self.bound.vertex_samplers, states) {
+            unsafe {
+                self.ctx
+                    .VSSetSamplers(0, states.len() as u32, states.as_ptr());
+            }
+        }
 
-        Error::Success
+        Ok(())
     }
 
-    /// Retrieves a reference to a bound render target.
-    fn get_render_target(&self, i: u32, ret: *mut *mut Surface) -> Error {
-        let rt = if_error!(self.check_render_target(i));
-        let ret = if_error!(check_mut_ref(ret));
+    /// Retrieves a sampler state object matching the state of a sampler.
+    ///
+    /// Sampler state objects are cached, since apps tend to only use a few combinations of states.
+    fn sampler_state(&mut self, sampler: u32) -> Result<ComPtr<ID3D11SamplerState>, Error> {
+        let istate = &self.istate;
+        let state = |ty| istate.get_sampler_state(sampler, ty);
+
+        let key = [
+            state(D3DSAMP_ADDRESSU),
+            state(D3DSAMP_ADDRESSV),
+            state(D3DSAMP_ADDRESSW),
+            state(D3DSAMP_BORDERCOLOR),
+            state(D3DSAMP_MAGFILTER),
+            state(D3DSAMP_MINFILTER),
+            state(D3DSAMP_MIPFILTER),
+            state(D3DSAMP_MIPMAPLODBIAS),
+            state(D3DSAMP_MAXMIPLEVEL),
+            state(D3DSAMP_MAXANISOTROPY),
+        ];
+
+        if let Some(state) = self.sampler_states.get(&key) {
+            return Ok(state.clone());
+        }
 
-        *ret = rt.clone().into();
+        let [address_u, address_v, address_w, border_color, mag, min, mip, lod_bias, max_mip_level, max_anisotropy] =
+            key;
+
+        let desc = D3D11_SAMPLER_DESC {
+            Filter: d3d9_to_d3d11_filter(min, mag, mip, max_anisotropy),
+            AddressU: d3d9_to_d3d11_address_mode(address_u),
+            AddressV: d3d9_to_d3d11_address_mode(address_v),
+            AddressW: d3d9_to_d3d11_address_mode(address_w),
+            // Floating point states are passed in as their bit pattern.
+            MipLODBias: f32::from_bits(lod_bias),
+            MaxAnisotropy: cmp::min(cmp::max(max_anisotropy, 1), D3D11_REQ_MAXANISOTROPY),
+            ComparisonFunc: D3D11_COMPARISON_NEVER,
+            BorderColor: d3dcolor_to_rgba(border_color),
+            // D3D9's max mip level is the index of the largest mip level to use.
+            MinLOD: max_mip_level as f32,
+            // Without mip filtering, only that one level is used.
+            MaxLOD: if mip == D3DTEXF_NONE {
+                max_mip_level as f32
+            } else {
+                D3D11_FLOAT32_MAX
+            },
+        };
 
-        Error::Success
-    }
+        let state = unsafe {
+            let mut ptr = ptr::null_mut();
+            let result = self.device.CreateSamplerState(&desc, &mut ptr);
+            if_not_success_err!(check_hresult(result, "Failed to create sampler state"));
+            ComPtr::new(ptr)
+        };
 
-    /// Copies a render target's data into a surface.
-    fn get_render_target_data(&self, _rt: *mut Surface, _dest: *mut Surface) {
-        unimplemented!()
-    }
+        self.sampler_states.insert(key, state.clone());
 
-    // -- Depth / stencil buffer functions --
+        Ok(state)
+    }
 
-    /// Creates a new depth / stencil buffer.
-    fn create_depth_stencil_surface(
-        &self,
-        width: u32,
-        height: u32,
-        fmt: D3DFORMAT,
-        _ms_ty: D3DMULTISAMPLE_TYPE,
-        _ms_qlt: u32,
-        discard: u32,
-        ret: *mut *mut Surface,
-        shared_handle: usize,
-    ) -> Error {
-        let ret = if_error!(check_mut_ref(ret));
+    /// Uploads any shader constants which were modified since the last draw.
+    fn upload_constants(&mut self) -> Result<(), Error> {
+        // D3D9's alpha reference is an integer in the [0; 255] range.
+        let alpha_ref = (self.istate.get_render_state(D3DRS_ALPHAREF) & 0xff) as f32 / 255.0;
+        if self.alpha_test_consts.get(0, 1)?[0][0] != alpha_ref {
+            self.alpha_test_consts
+                .set(0, &[[alpha_ref, 0.0, 0.0, 0.0]])?;
+        }
 
-        if shared_handle != 0 {
-            error!("Shared resources are not supported");
-            return Error::InvalidCall;
+        if self.istate.get_vertex_shader().is_none() {
+            self.update_fixed_function_constants()?;
         }
 
-        if discard != 0 {
-            error!("Discarding depth/stencil buffer not supported");
+        // Disabled planes are zeroed, so that they never clip anything.
+        let enabled = self.istate.get_render_state(D3DRS_CLIPPLANEENABLE);
+        let mut planes = [[0.0; 4]; shader::MAX_CLIP_PLANES];
+        for (i, plane) in planes.iter_mut().enumerate() {
+            if enabled & (1 << i) != 0 {
+                *plane = self.istate.get_clip_plane(i);
+            }
+        }
+        set_constants_if_changed(&mut self.clip_plane_consts, 0, &planes)?;
+
+        let istate = &self.istate;
+        let state = |ty| f32::from_bits(istate.get_render_state(ty));
+        let enabled = |ty| (istate.get_render_state(ty) != 0) as u32 as f32;
+        let vp = istate.get_viewport();
+        let point_sprite = [
+            [
+                state(D3DRS_POINTSIZE),
+                state(D3DRS_POINTSIZE_MIN),
+                state(D3DRS_POINTSIZE_MAX),
+                enabled(D3DRS_POINTSCALEENABLE),
+            ],
+            [
+                state(D3DRS_POINTSCALE_A),
+                state(D3DRS_POINTSCALE_B),
+                state(D3DRS_POINTSCALE_C),
+                enabled(D3DRS_POINTSPRITEENABLE),
+            ],
+            [vp.Width as f32, vp.Height as f32, 0.0, 0.0],
+        ];
+        set_constants_if_changed(&mut self.point_sprite_consts, 0, &point_sprite)?;
+
+        let fog = [
+            d3dcolor_to_rgba(istate.get_render_state(D3DRS_FOGCOLOR)),
+            [
+                state(D3DRS_FOGSTART),
+                state(D3DRS_FOGEND),
+                state(D3DRS_FOGDENSITY),
+                0.0,
+            ],
+        ];
+        set_constants_if_changed(&mut self.fog_consts, 0, &fog)?;
+
+        if self.istate.get_pixel_shader().is_none() {
+            self.update_texture_stage_constants()?;
         }
 
-        let texture = if_error!(d3d11::Texture2D::new_ds(&self.device, (width, height), fmt));
+        self.vs_consts.upload(&self.ctx)?;
+        self.ps_consts.upload(&self.ctx)?;
+        self.alpha_test_consts.upload(&self.ctx)?;
+        self.ff_consts.upload(&self.ctx)?;
+        self.clip_plane_consts.upload(&self.ctx)?;
+        self.point_sprite_consts.upload(&self.ctx)?;
+        self.fog_consts.upload(&self.ctx)?;
+        self.texture_stage_consts.upload(&self.ctx)
+    }
 
-        let ds_view = if_error!(texture.create_ds_view(&self.device));
+    /// Updates the constants of the fixed function pixel shader from the current state.
+    fn update_texture_stage_constants(&mut self) -> Result<(), Error> {
+        let istate = &self.istate;
+        let stages = 0..shader::MAX_TEXTURE_STAGES as u32;
+        let state = |stage, ty| istate.get_texture_stage_state(stage, ty);
+        let float_state = |stage, ty| f32::from_bits(state(stage, ty));
+
+        let mut registers = vec![d3dcolor_to_rgba(
+            istate.get_render_state(D3DRS_TEXTUREFACTOR),
+        )];
+
+        registers.extend(
+            stages
+                .clone()
+                .map(|stage| d3dcolor_to_rgba(state(stage, D3DTSS_CONSTANT))),
+        );
 
-        let data = SurfaceData::DepthStencil(ds_view);
+        registers.extend(stages.clone().map(|stage| {
+            [
+                float_state(stage, D3DTSS_BUMPENVMAT00),
+                float_state(stage, D3DTSS_BUMPENVMAT01),
+                float_state(stage, D3DTSS_BUMPENVMAT10),
+                float_state(stage, D3DTSS_BUMPENVMAT11),
+            ]
+        }));
+
+        registers.extend(stages.map(|stage| {
+            [
+                float_state(stage, D3DTSS_BUMPENVLSCALE),
+                float_state(stage, D3DTSS_BUMPENVLOFFSET),
+                0.0,
+                0.0,
+            ]
+        }));
+
+        set_constants_if_changed(&mut self.texture_stage_consts, 0, &registers)
+    }
 
-        *ret = Surface::new(
-            self,
-            texture,
-            UsageFlags::DEPTH_STENCIL,
-            MemoryPool::Default,
-            data,
+    /// Updates the constants of the fixed function vertex shader from the current state.
+    fn update_fixed_function_constants(&mut self) -> Result<(), Error> {
+        let world = self.istate.get_transform(D3DTS_WORLD);
+        let view = self.istate.get_transform(D3DTS_VIEW);
+        let proj = self.istate.get_transform(D3DTS_PROJECTION);
+
+        // Our matrices are the transposes of D3D9's row-major matrices,
+        // so they have to be multiplied in reverse order.
+        let world_view_proj = proj * view * world;
+        set_constants_if_changed(
+            &mut self.ff_consts,
+            shader::WORLD_VIEW_PROJ_REGISTER,
+            &matrix_to_registers(&world_view_proj),
+        )?;
+
+        let vp = self.istate.get_viewport();
+        let (width, height) = (vp.Width as f32, vp.Height as f32);
+        // D3D9's pixel centers are at integer coordinates, while D3D11's are half a pixel further,
+        // so pre-transformed vertices are moved by half a pixel to cover the same pixels.
+        let screen_to_clip = [
+            2.0 / width,
+            -2.0 / height,
+            -1.0 - (2.0 * vp.X as f32 - 1.0) / width,
+            1.0 + (2.0 * vp.Y as f32 - 1.0) / height,
+        ];
+        set_constants_if_changed(
+            &mut self.ff_consts,
+            shader::SCREEN_TO_CLIP_REGISTER,
+            &[screen_to_clip],
+        )?;
+
+        let material = self.istate.get_material();
+        let color = |c: D3DCOLORVALUE| [c.r, c.g, c.b, c.a];
+        set_constants_if_changed(
+            &mut self.ff_consts,
+            shader::MATERIAL_REGISTER,
+            &[
+                color(material.Diffuse),
+                color(material.Ambient),
+                color(material.Specular),
+                color(material.Emissive),
+                [material.Power, 0.0, 0.0, 0.0],
+            ],
+        )?;
+
+        let ambient = d3dcolor_to_rgba(self.istate.get_render_state(D3DRS_AMBIENT));
+        set_constants_if_changed(&mut self.ff_consts, shader::AMBIENT_REGISTER, &[ambient])?;
+
+        set_constants_if_changed(
+            &mut self.ff_consts,
+            shader::WORLD_REGISTER,
+            &matrix_to_registers(&world),
+        )?;
+
+        // The camera is at the origin of view space.
+        let eye = view
+            .try_inverse()
+            .map(|inv| [inv[(0, 3)], inv[(1, 3)], inv[(2, 3)], 1.0])
+            .unwrap_or([0.0, 0.0, 0.0, 1.0]);
+        set_constants_if_changed(&mut self.ff_consts, shader::EYE_POSITION_REGISTER, &[eye])?;
+
+        let lights: Vec<_> = self
+            .istate
+            .enabled_lights()
+            .flat_map(|light| light_to_registers(light).to_vec())
+            .collect();
+        set_constants_if_changed(&mut self.ff_consts, shader::LIGHTS_REGISTER, &lights)?;
+
+        let texture_transforms: Vec<_> = (0..shader::MAX_TEXTURE_STAGES as u32)
+            .flat_map(|stage| {
+                let transform = self.istate.get_transform(D3DTS_TEXTURE0 + stage);
+                matrix_to_registers(&transform).to_vec()
+            })
+            .collect();
+        set_constants_if_changed(
+            &mut self.ff_consts,
+            shader::TEXTURE_TRANSFORMS_REGISTER,
+            &texture_transforms,
         )
-        .into();
-
-        Error::Success
     }
 
-    /// Sets the current depth / stencil buffer.
-    fn set_depth_stencil_surface(&mut self, ds: *mut Surface) -> Error {
-        self.depth_stencil = if let Some(ds) = unsafe { ds.as_mut() } {
-            if ds.depth_stencil_view().is_none() {
-                return Error::InvalidCall;
-            }
-
-            Some(ComPtr::new(ds))
+    /// Retrieves the fixed function vertex shader and its input signature for a vertex declaration.
+    fn fixed_vertex_shader(
+        &mut self,
+        decl: &VertexDeclaration,
+    ) -> Result<(ComPtr<ID3D11VertexShader>, Box<[u8]>), Error> {
+        let state = |ty| self.istate.get_render_state(ty);
+
+        let lighting = state(D3DRS_LIGHTING) != 0;
+
+        // Vertex colors are only used as material colors if COLORVERTEX is enabled.
+        let material_sources = if state(D3DRS_COLORVERTEX) != 0 {
+            [
+                state(D3DRS_DIFFUSEMATERIALSOURCE),
+                state(D3DRS_AMBIENTMATERIALSOURCE),
+                state(D3DRS_SPECULARMATERIALSOURCE),
+                state(D3DRS_EMISSIVEMATERIALSOURCE),
+            ]
         } else {
-            None
+            [D3DMCS_MATERIAL; 4]
         };
 
-        Error::Success
-    }
+        // Vertex fog is only computed if it is enabled, and table fog isn't used.
+        let fog_vertex_mode =
+            if state(D3DRS_FOGENABLE) != 0 && state(D3DRS_FOGTABLEMODE) == D3DFOG_NONE {
+                state(D3DRS_FOGVERTEXMODE)
+            } else {
+                D3DFOG_NONE
+            };
 
-    /// Retrieves the bound depth / stencil buffer.
-    fn get_depth_stencil_surface(&self, ret: *mut *mut Surface) -> Error {
-        let ret = if_error!(check_mut_ref(ret));
+        let mut texcoord_indices = [0; shader::MAX_TEXTURE_STAGES];
+        let mut transform_flags = [D3DTTFF_DISABLE; shader::MAX_TEXTURE_STAGES];
+        for stage in 0..shader::MAX_TEXTURE_STAGES {
+            let state = |ty| self.istate.get_texture_stage_state(stage as u32, ty);
 
-        *ret = self
-            .depth_stencil
-            .as_ref()
-            .map(|ds| ds.clone().into())
-            .unwrap_or(ptr::null_mut());
+            // The upper bits select a way to generate the coordinates.
+            let index = state(D3DTSS_TEXCOORDINDEX);
+            if index & 0xffff_0000 != 0 {
+                run_once!(|| warn!("Texture coordinate generation is not supported"));
+            }
 
-        Error::Success
-    }
+            texcoord_indices[stage] = index & 0xffff;
+            transform_flags[stage] = state(D3DTSS_TEXTURETRANSFORMFLAGS);
+        }
 
-    /// Creates an off-screen surface.
-    fn create_offscreen_plain_surface(
-        &self,
-        width: u32,
-        height: u32,
-        fmt: D3DFORMAT,
-        pool: MemoryPool,
-        ret: *mut *mut Surface,
-        shared_handle: usize,
-    ) -> Error {
-        let ret = if_error!(check_mut_ref(ret));
+        let key = shader::FixedVertexKey::new(
+            decl.elements(),
+            lighting,
+            material_sources,
+            self.istate.enabled_lights().count(),
+            state(D3DRS_SPECULARENABLE) != 0,
+            fog_vertex_mode,
+            state(D3DRS_RANGEFOGENABLE) != 0,
+            texcoord_indices,
+            transform_flags,
+        );
 
-        if shared_handle != 0 {
-            error!("Shared resources are not supported");
-            return Error::InvalidCall;
+        if let Some((vs, signature)) = self.ff_vertex_shaders.get(&key) {
+            return Ok((vs.clone(), signature.clone()));
         }
 
-        let texture = if_error!(d3d11::Texture2D::new(
-            &self.device,
-            (width, height),
-            1,
-            UsageFlags::empty(),
-            fmt,
-            // We ignore the pool, we need this surface to always be CPU-readable
-            // (i.e. D3D11_USAGE_STAGING), since that's its intended use.
-            MemoryPool::SystemMem,
-        ));
-
-        let data = SurfaceData::None;
+        let source = shader::vertex_shader_source(&key);
+        let bytecode = shader::compile(&source, shader::profile(shader::ShaderKind::Vertex))?;
+        let signature = shader::input_signature(&bytecode)?;
+
+        let vs = unsafe {
+            let mut ptr = ptr::null_mut();
+            let result = self.device.CreateVertexShader(
+                bytecode.as_ptr() as *const c_void,
+                bytecode.len(),
+                ptr::null_mut(),
+                &mut ptr,
+            );
+            if_not_success_err!(check_hresult(result, "Failed to create vertex shader"));
+            ComPtr::new(ptr)
+        };
 
-        // We pass in the correct pool here, for storage purposes.
-        *ret = Surface::new(self, texture, UsageFlags::empty(), pool, data).into();
+        self.ff_vertex_shaders
+            .insert(key, (vs.clone(), signature.clone()));
 
-        Error::Success
+        Ok((vs, signature))
     }
 
-    // -- Surface manipulation functions --
+    /// Retrieves the fixed function pixel shader matching the texture stage states.
+    fn fixed_pixel_shader(
+        &mut self,
+        variant: shader::PixelVariant,
+    ) -> Result<ComPtr<ID3D11PixelShader>, Error> {
+        let key = self.fixed_pixel_key(variant);
 
-    /// Copies a surface's region to another surface.
-    fn update_surface(
+        if let Some(ps) = self.ff_pixel_shaders.get(&key) {
+            return Ok(ps.clone());
+        }
+
+        let source = shader::pixel_shader_source(&key);
+        let bytecode = shader::compile(&source, shader::profile(shader::ShaderKind::Pixel))?;
+        let ps = self.device.create_pixel_shader(&bytecode)?;
+
+        self.ff_pixel_shaders.insert(key, ps.clone());
+
+        Ok(ps)
+    }
+
+    /// Builds the key of the fixed function pixel shader from the texture stage states.
+    fn fixed_pixel_key(&self, variant: shader::PixelVariant) -> shader::FixedPixelKey {
+        let istate = &self.istate;
+        let mut stages = Vec::with_capacity(shader::MAX_TEXTURE_STAGES);
+
+        for stage in 0..shader::MAX_TEXTURE_STAGES as u32 {
+            let state = |ty| istate.get_texture_stage_state(stage, ty);
+
+            // The stages after the first disabled one are ignored.
+            let color_op = state(D3DTSS_COLOROP);
+            if color_op == D3DTOP_DISABLE {
+                break;
+            }
+
+            let texture_type = match istate.get_texture(stage) {
+                Some(tx) => match BaseTexture::from_interface(tx).resource_type() {
+                    ResourceType::CubeTexture => D3DSTT_CUBE,
+                    ResourceType::VolumeTexture => D3DSTT_VOLUME,
+                    _ => D3DSTT_2D,
+                },
+                None => D3DSTT_UNKNOWN,
+            };
+
+            stages.push(shader::TextureStage {
+                color_op,
+                color_args: [
+                    state(D3DTSS_COLORARG0),
+                    state(D3DTSS_COLORARG1),
+                    state(D3DTSS_COLORARG2),
+                ],
+                alpha_op: state(D3DTSS_ALPHAOP),
+                alpha_args: [
+                    state(D3DTSS_ALPHAARG0),
+                    state(D3DTSS_ALPHAARG1),
+                    state(D3DTSS_ALPHAARG2),
+                ],
+                result_arg: state(D3DTSS_RESULTARG),
+                texture_type,
+                projected: state(D3DTSS_TEXTURETRANSFORMFLAGS) & D3DTTFF_PROJECTED != 0,
+            });
+        }
+
+        shader::FixedPixelKey {
+            variant,
+            stages,
+            specular: istate.get_render_state(D3DRS_SPECULARENABLE) != 0,
+        }
+    }
+
+    /// Retrieves the geometry shader which expands points into quads.
+    fn point_sprite_shader(&mut self) -> Result<ComPtr<ID3D11GeometryShader>, Error> {
+        if let Some(gs) = &self.point_sprite_shader {
+            return Ok(gs.clone());
+        }
+
+        let source = shader::geometry_shader_source();
+        let bytecode = shader::compile(&source, shader::GEOMETRY_PROFILE)?;
+        let gs = self.device.create_geometry_shader(&bytecode)?;
+
+        self.point_sprite_shader = Some(gs.clone());
+
+        Ok(gs)
+    }
+
+    /// Binds the app's vertex streams to the input assembler.
+    fn bind_vertex_buffers(&self) {
+        let mut buffers = [ptr::null_mut(); MAX_STREAMS];
+        let mut strides = [0; MAX_STREAMS];
+        let mut offsets = [0; MAX_STREAMS];
+
+        for (i, stream) in self.istate.stream_sources().iter().enumerate() {
+            if let Some(vb) = stream.buffer.as_ref() {
+                buffers[i] = vb.get_dx11().as_buffer();
+                strides[i] = stream.stride;
+                offsets[i] = stream.offset;
+            }
+        }
+
+        unsafe {
+            self.ctx.IASetVertexBuffers(
+                0,
+                MAX_STREAMS as u32,
+                buffers.as_ptr(),
+                strides.as_ptr(),
+                offsets.as_ptr(),
+            );
+        }
+    }
+
+    /// Resets the device, recreating the implicit swap chain and the default render targets.
+    ///
+    /// All of the device's state is reset to its default values, and all of
+    /// the resources in the default pool are lost.
+    ///
+    /// The D3D11 device itself is kept, so managed resources survive with their contents
+    /// intact, and don't need to be uploaded again like D3D9 drivers do.
+    ///
+    /// The swap effect must have been validated by the caller, since the allowed values
+    /// depend on the entry point.
+    fn reset_device(&mut self, pp: &mut D3DPRESENT_PARAMETERS) -> Result<(), Error> {
+        if self.update_status() == DeviceStatus::Lost {
+            return Err(Error::DeviceLost);
+        }
+
+        if pp.BackBufferCount > D3DPRESENT_BACK_BUFFERS_MAX {
+            return Err(Error::InvalidCall);
+        }
+
+        // Full-screen modes must specify the resolution.
+        if pp.Windowed == 0 && (pp.BackBufferWidth == 0 || pp.BackBufferHeight == 0) {
+            return Err(Error::InvalidCall);
+        }
+
+        // The commands being recorded might use the old back buffer.
+        self.abort_command_list();
+
+        // Unbind everything, to make D3D11 release its references to the old back buffer.
+        unsafe {
+            self.ctx.ClearState();
+        }
+        self.bound = BoundState::default();
+
+        self.render_targets.clear();
+        self.depth_stencil = None;
+        self.swap_chains.clear();
+
+        self.istate = DeviceState::default();
+        self.in_scene = false;
+        // Marks the resources in the default pool as lost, the other pools are unaffected.
+        self.generation += 1;
+
+        self.create_default_resources(pp)?;
+
+        self.status.set(DeviceStatus::Ok);
+
+        Ok(())
+    }
+
+    /// Retrieves the dimensions of the first render target.
+    fn render_target_size(&self) -> Result<(u32, u32), Error> {
+        let rt = self
+            .render_targets
+            .first()
+            .and_then(Option::as_ref)
+            .ok_or_else(|| {
+                error!("No render target is bound");
+                Error::InvalidCall
+            })?;
+
+        Ok(surface_size(rt))
+    }
+
+    /// Resolves the multisampled depth buffer into the depth texture bound to the first stage.
+    fn resolve_depth_stencil(&mut self) -> Result<(), Error> {
+        let src = match self.depth_stencil.as_ref() {
+            Some(ds) => ds.texture().clone(),
+            None => {
+                error!("Cannot resolve the depth buffer when none is bound");
+                return Err(Error::InvalidCall);
+            }
+        };
+
+        // The destination is the resource of the bound texture's view.
+        let dest = self
+            .istate
+            .get_texture(0)
+            .and_then(|tx| BaseTexture::from_interface(tx).shader_resource_view())
+            .and_then(|view| unsafe {
+                let mut resource = ptr::null_mut();
+                view.GetResource(&mut resource);
+                let resource = ComPtr::new(resource);
+                d3d11::query_interface::<ID3D11Texture2D>(&resource)
+            })
+            .map(d3d11::Texture2D::from);
+
+        let dest = match dest {
+            Some(dest) => dest,
+            None => {
+                error!("Cannot resolve the depth buffer without a depth texture bound to stage 0");
+                return Err(Error::InvalidCall);
+            }
+        };
+
+        let src_desc = src.desc();
+        let dest_desc = dest.desc();
+
+        if dest_desc.BindFlags & D3D11_BIND_DEPTH_STENCIL == 0 {
+            error!("Depth buffers can only be resolved into depth textures");
+            return Err(Error::InvalidCall);
+        }
+
+        if (src_desc.Width, src_desc.Height) != (dest_desc.Width, dest_desc.Height) {
+            error!("Depth buffers can only be resolved into textures of the same size");
+            return Err(Error::InvalidCall);
+        }
+
+        // Depth buffers with a single sample can simply be copied.
+        if src_desc.SampleDesc.Count == 1 {
+            unsafe {
+                self.ctx.CopySubresourceRegion(
+                    dest.as_resource(),
+                    0,
+                    0,
+                    0,
+                    0,
+                    src.as_resource(),
+                    0,
+                    ptr::null(),
+                );
+            }
+            return Ok(());
+        }
+
+        let dest_view = dest.create_subresource_ds_view(&self.device, 0)?;
+
+        if self.blitter.is_none() {
+            self.blitter = Some(d3d11::Blitter::new(&self.device)?);
+        }
+
+        let result = self.blitter.as_mut().unwrap().resolve_depth(
+            &self.device,
+            &self.ctx,
+            &src,
+            dest_view.as_mut(),
+        );
+
+        // Resolving overwrote some of the app's state.
+        self.restore_state_after_blit();
+
+        result
+    }
+
+    /// Synchronises D3D9's render target views and depth / stencil view with D3D11.
+    ///
+    /// Render targets are bound with their sRGB view if sRGB writes are enabled.
+    ///
+    /// D3D11 requires all of the render targets to have the same size,
+    /// so additional render targets which don't match the first one are left unbound.
+    fn update_render_targets(&mut self) {
+        let srgb = self.istate.get_render_state(D3DRS_SRGBWRITEENABLE) != 0;
+        let size = self
+            .render_targets
+            .first()
+            .and_then(Option::as_ref)
+            .map(|rt| surface_size(rt));
+
+        // Only the slots up to the last bound render target need to be set.
+        let num = self
+            .render_targets
+            .iter()
+            .rposition(Option::is_some)
+            .map_or(0, |last| last + 1);
+
+        let mut rt_views = [ptr::null_mut(); D3D11_SIMULTANEOUS_RENDER_TARGET_COUNT as usize];
+        for (i, rt) in self.render_targets.iter().enumerate() {
+            if let Some(rt) = rt {
+                // Null render targets leave their slot empty.
+                if rt.is_null_render_target() {
+                    continue;
+                }
+
+                if Some(surface_size(rt)) != size {
+                    warn!(
+                        "Render target {} does not have the same size as the first one",
+                        i
+                    );
+                    continue;
+                }
+
+                let srgb_view = if srgb {
+                    let view = rt.srgb_render_target_view();
+                    if view.is_none() {
+                        run_once!(|| warn!("sRGB writes are not supported for some formats"));
+                    }
+                    view
+                } else {
+                    None
+                };
+
+                let view = srgb_view.or_else(|| rt.render_target_view());
+                rt_views[i] = view.unwrap() as *mut _;
+            }
+        }
+
+        let ds_view = self
+            .depth_stencil
+            .as_ref()
+            .map(|ds| ds.depth_stencil_view().unwrap() as *mut _)
+            .unwrap_or(ptr::null_mut());
+
+        unsafe {
+            self.ctx
+                .OMSetRenderTargets(num as u32, rt_views.as_ptr(), ds_view);
+        }
+    }
+
+    /// Resets the viewport and the scissor rectangle to cover the whole of the first render target.
+    fn reset_viewport_and_scissor(&mut self) -> Result<(), Error> {
+        let (width, height) = self.render_target_size()?;
+
+        let vp = D3DVIEWPORT9 {
+            X: 0,
+            Y: 0,
+            Width: width,
+            Height: height,
+            MinZ: 0.0,
+            MaxZ: 1.0,
+        };
+
+        // This is not affected by state block recording, so the state is applied directly.
+        self.istate.set_viewport(&vp);
+        self.bound.depth_range = Some((vp.MinZ, vp.MaxZ));
+        self.bind_viewport(&vp, (vp.MinZ, vp.MaxZ));
+
+        let rect = RECT {
+            left: 0,
+            top: 0,
+            right: width as i32,
+            bottom: height as i32,
+        };
+
+        self.istate.set_scissor_rect(&rect);
+        self.update_scissor_rect();
+
+        Ok(())
+    }
+
+    /// Synchronises the D3D11 scissor rectangle with D3D9's scissor state.
+    fn update_scissor_rect(&self) {
+        let rect = self.istate.get_scissor_rect();
+
+        unsafe {
+            self.ctx.RSSetScissorRects(1, &rect);
+        }
+    }
+
+    /// Binds a rasterizer state object matching the current rasterization-related render states.
+    fn bind_rasterizer_state(&mut self) -> Result<(), Error> {
+        let istate = &self.istate;
+        let state = |ty| istate.get_render_state(ty);
+
+        let key = [
+            state(D3DRS_CULLMODE),
+            state(D3DRS_FILLMODE),
+            state(D3DRS_DEPTHBIAS),
+            state(D3DRS_SLOPESCALEDEPTHBIAS),
+            state(D3DRS_SCISSORTESTENABLE),
+        ];
+
+        let state = match self.rasterizer_states.get(&key) {
+            Some(state) => state.clone(),
+            None => {
+                let state = self.create_rasterizer_state(key)?;
+                self.rasterizer_states.insert(key, state.clone());
+                state
+            }
+        };
+
+        if changed(&mut self.bound.rasterizer, state.as_mut()) {
+            unsafe {
+                self.ctx.RSSetState(state.as_mut());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Creates a rasterizer state object from a set of D3D9 render states.
+    fn create_rasterizer_state(
+        &self,
+        [cull_mode, fill_mode, depth_bias, slope_scale_depth_bias, scissor_test]: [u32; 5],
+    ) -> Result<ComPtr<ID3D11RasterizerState>, Error> {
+        // D3D9 considers clockwise triangles to be front facing, just like D3D11's default.
+        // Its cull modes name the winding order of the culled triangles.
+        let cull_mode = match cull_mode {
+            D3DCULL_NONE => D3D11_CULL_NONE,
+            D3DCULL_CW => D3D11_CULL_FRONT,
+            _ => D3D11_CULL_BACK,
+        };
+
+        let fill_mode = match fill_mode {
+            D3DFILL_WIREFRAME => D3D11_FILL_WIREFRAME,
+            D3DFILL_POINT => {
+                run_once!(|| warn!("Point fill mode is not supported"));
+                D3D11_FILL_SOLID
+            }
+            _ => D3D11_FILL_SOLID,
+        };
+
+        // D3D9's depth bias is a floating point offset of the depth value, while D3D11's
+        // is expressed in units of the depth buffer's precision. Assume a 24-bit depth buffer.
+        let depth_bias = f32::from_bits(depth_bias) * (1 << 24) as f32;
+
+        let desc = D3D11_RASTERIZER_DESC {
+            FillMode: fill_mode,
+            CullMode: cull_mode,
+            FrontCounterClockwise: 0,
+            DepthBias: depth_bias as i32,
+            DepthBiasClamp: 0.0,
+            SlopeScaledDepthBias: f32::from_bits(slope_scale_depth_bias),
+            DepthClipEnable: 1,
+            ScissorEnable: (scissor_test != 0) as BOOL,
+            MultisampleEnable: 0,
+            AntialiasedLineEnable: 0,
+        };
+
+        let state = unsafe {
+            let mut ptr = ptr::null_mut();
+            let result = self.device.CreateRasterizerState(&desc, &mut ptr);
+            if_not_success_err!(check_hresult(result, "Failed to create rasterizer state"));
+            ComPtr::new(ptr)
+        };
+
+        Ok(state)
+    }
+
+    /// Binds a depth / stencil state object matching the current depth and stencil render states.
+    fn bind_depth_stencil_state(&mut self) -> Result<(), Error> {
+        let istate = &self.istate;
+        let state = |ty| istate.get_render_state(ty);
+
+        let key = [
+            state(D3DRS_ZENABLE),
+            state(D3DRS_ZWRITEENABLE),
+            state(D3DRS_ZFUNC),
+            state(D3DRS_STENCILENABLE),
+            state(D3DRS_STENCILMASK),
+            state(D3DRS_STENCILWRITEMASK),
+            state(D3DRS_STENCILFUNC),
+            state(D3DRS_STENCILFAIL),
+            state(D3DRS_STENCILZFAIL),
+            state(D3DRS_STENCILPASS),
+            state(D3DRS_TWOSIDEDSTENCILMODE),
+            state(D3DRS_CCW_STENCILFUNC),
+            state(D3DRS_CCW_STENCILFAIL),
+            state(D3DRS_CCW_STENCILZFAIL),
+            state(D3DRS_CCW_STENCILPASS),
+        ];
+
+        // The reference value is not part of the state object.
+        let stencil_ref = state(D3DRS_STENCILREF);
+
+        let state = match self.depth_stencil_states.get(&key) {
+            Some(state) => state.clone(),
+            None => {
+                let state = self.create_depth_stencil_state(key)?;
+                self.depth_stencil_states.insert(key, state.clone());
+                state
+            }
+        };
+
+        if changed(&mut self.bound.depth_stencil, (state.as_mut(), stencil_ref)) {
+            unsafe {
+                self.ctx.OMSetDepthStencilState(state.as_mut(), stencil_ref);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Creates a depth / stencil state object from a set of D3D9 render states.
+    fn create_depth_stencil_state(
+        &self,
+        key: [u32; 15],
+    ) -> Result<ComPtr<ID3D11DepthStencilState>, Error> {
+        let [z_enable, z_write_enable, z_func, stencil_enable, stencil_mask, stencil_write_mask, ..] =
+            key;
+
+        if z_enable == D3DZB_USEW {
+            run_once!(|| warn!("W-buffering is not supported, using a Z-buffer instead"));
+        }
+
+        let stencil_op = |ops: &[u32]| D3D11_DEPTH_STENCILOP_DESC {
+            StencilFunc: d3d9_to_d3d11_comparison(ops[0]),
+            StencilFailOp: d3d9_to_d3d11_stencil_op(ops[1]),
+            StencilDepthFailOp: d3d9_to_d3d11_stencil_op(ops[2]),
+            StencilPassOp: d3d9_to_d3d11_stencil_op(ops[3]),
+        };
+
+        // D3D9's front faces are the clockwise ones, like in D3D11.
+        // In two-sided mode, the CCW stencil state applies to back faces.
+        let front_face = stencil_op(&key[6..10]);
+        let back_face = if key[10] != 0 {
+            stencil_op(&key[11..15])
+        } else {
+            front_face
+        };
+
+        let desc = D3D11_DEPTH_STENCIL_DESC {
+            DepthEnable: (z_enable != D3DZB_FALSE) as BOOL,
+            DepthWriteMask: if z_write_enable != 0 {
+                D3D11_DEPTH_WRITE_MASK_ALL
+            } else {
+                D3D11_DEPTH_WRITE_MASK_ZERO
+            },
+            DepthFunc: d3d9_to_d3d11_comparison(z_func),
+            StencilEnable: (stencil_enable != 0) as BOOL,
+            // D3D11 only supports 8-bit stencil buffers.
+            StencilReadMask: stencil_mask as u8,
+            StencilWriteMask: stencil_write_mask as u8,
+            FrontFace: front_face,
+            BackFace: back_face,
+        };
+
+        let state = unsafe {
+            let mut ptr = ptr::null_mut();
+            let result = self.device.CreateDepthStencilState(&desc, &mut ptr);
+            if_not_success_err!(check_hresult(
+                result,
+                "Failed to create depth / stencil state"
+            ));
+            ComPtr::new(ptr)
+        };
+
+        Ok(state)
+    }
+}
+
+impl Drop for Device {
+    fn drop(&mut self) {
+        if let Some(annotation) = self.ctx.annotation() {
+            d3d11::perf::clear_target(annotation);
+        }
+    }
+}
+
+impl_iunknown!(struct Device: IUnknown, IDirect3DDevice9; IDirect3DDevice9Ex if ex);
+
+#[implementation(IDirect3DDevice9)]
+impl Device {
+    // -- Device status functions --
+
+    /// Resets the device, recreating the implicit swap chain and the default render targets.
+    ///
+    /// See `reset_device` for the details.
+    fn reset(&mut self, pp: *mut D3DPRESENT_PARAMETERS) -> Error {
+        let _lock = self.lock();
+        let pp = if_error!(check_mut_ref(pp));
+
+        // The newer swap effects are only available through `ResetEx`.
+        if pp.SwapEffect < D3DSWAPEFFECT_DISCARD || pp.SwapEffect > D3DSWAPEFFECT_COPY {
+            return Error::InvalidCall;
+        }
+
+        to_error_success!(self.reset_device(pp))
+    }
+
+    /// Checks that the device has not yet been lost / reset.
+    fn test_cooperative_level(&self) -> Error {
+        let _lock = self.lock();
+        match self.update_status() {
+            DeviceStatus::Ok => Error::Success,
+            DeviceStatus::Lost => Error::DeviceLost,
+            DeviceStatus::NotReset => Error::DeviceNotReset,
+        }
+    }
+
+    /// Determines how much graphics memory is available.
+    fn get_available_texture_mem(&self) -> u32 {
+        let _lock = self.lock();
+        self.adapter().available_memory()
+    }
+
+    /// Asks the driver to evict all managed resources from VRAM.
+    fn evict_managed_resources(&self) -> Error {
+        let _lock = self.lock();
+        // Do nothing. The D3D11 driver handles everything.
+        Error::Success
+    }
+
+    // -- Creation parameters functions --
+
+    /// Returns a reference to the parent interface.
+    fn get_direct_3_d(&self, ptr: *mut *mut Context) -> Error {
+        let _lock = self.lock();
+        let ptr = if_error!(check_mut_ref(ptr));
+        *ptr = com_ref(self.parent);
+        Error::Success
+    }
+
+    /// Returns the caps of this device.
+    fn get_device_caps(&self, caps: *mut D3DCAPS9) -> Error {
+        let _lock = self.lock();
+        let caps = if_error!(check_mut_ref(caps));
+        *caps = self.adapter().caps();
+        Error::Success
+    }
+
+    /// Returns the creation parameters of this device.
+    fn get_creation_parameters(&self, params: *mut D3DDEVICE_CREATION_PARAMETERS) -> Error {
+        let _lock = self.lock();
+        let params = if_error!(check_mut_ref(params));
+        *params = self.creation_params;
+        Error::Success
+    }
+
+    // -- Swap chain functions --
+
+    /// Creates new swap chains for this device.
+    fn create_additional_swap_chain(
+        &mut self,
+        pp: *mut D3DPRESENT_PARAMETERS,
+        ret: *mut *mut SwapChain,
+    ) -> Error {
+        let _lock = self.lock();
+        let factory = self.factory.as_mut();
+        let pp = if_error!(check_mut_ref(pp));
+        let window = self.window;
+
+        let ret = if_error!(check_mut_ref(ret));
+
+        *ret = if_error!(SwapChain::new(self, &self.device, factory, pp, window)).into();
+        Error::Success
+    }
+
+    /// Returns an implicit swap chain.
+    fn get_swap_chain(&self, sc: u32, ret: *mut *mut SwapChain) -> Error {
+        let _lock = self.lock();
+        let sc = if_error!(self.check_swap_chain(sc));
+        let ret = if_error!(check_mut_ref(ret));
+
+        *ret = sc.clone().into();
+        Error::Success
+    }
+
+    /// Returns the number of implicit swap chains.
+    fn get_number_of_swap_chains(&self) -> u32 {
+        let _lock = self.lock();
+        // TODO: to have more than one implicit SC, we need multi-GPU support.
+        1
+    }
+
+    // The functions below all operate on the implicit swap chains.
+
+    fn present(&self, src: usize, dest: usize, wnd: HWND, dirty: usize) -> Error {
+        let _lock = self.lock();
+        if let Some(ref info_queue) = self.info_queue {
+            info_queue.flush();
+        }
+
+        if self.update_status() != DeviceStatus::Ok {
+            return Error::DeviceLost;
+        }
+
+        self.present_swap_chains(src, dest, wnd, dirty, 0)
+    }
+
+    fn get_front_buffer_data(&self, sc: u32, fb: *mut Surface) -> Error {
+        let _lock = self.lock();
+        if_error!(self.check_swap_chain(sc)).get_front_buffer_data(fb)
+    }
+
+    fn get_back_buffer(
+        &self,
+        sc: u32,
+        bi: u32,
+        ty: D3DBACKBUFFER_TYPE,
+        ret: *mut *mut Surface,
+    ) -> Error {
+        let _lock = self.lock();
+        if_error!(self.check_swap_chain(sc)).get_back_buffer(bi, ty, ret)
+    }
+
+    fn get_raster_status(&self, sc: u32, rs: *mut D3DRASTER_STATUS) -> Error {
+        let _lock = self.lock();
+        if_error!(self.check_swap_chain(sc)).get_raster_status(rs)
+    }
+
+    fn get_display_mode(&self, sc: u32, dm: *mut D3DDISPLAYMODE) -> Error {
+        let _lock = self.lock();
+        if_error!(self.check_swap_chain(sc)).get_display_mode(dm)
+    }
+
+    // -- Gamma control functions --
+
+    /// Sets the current gamma ramp.
+    fn set_gamma_ramp(&mut self, sc: u32, flags: u32, ramp: *const D3DGAMMARAMP) {
+        let _lock = self.lock();
+        self.check_swap_chain(sc)
+            .and_then(|sc| check_ref(ramp).and_then(|ramp| sc.set_gamma_ramp(flags, ramp)))
+            .unwrap_or_else(|_| error!("Failed to set gamma ramp"));
+    }
+
+    /// Retrieves the monitor's gamma ramp.
+    fn get_gamma_ramp(&self, sc: u32, ret: *mut D3DGAMMARAMP) {
+        let _lock = self.lock();
+        self.check_swap_chain(sc)
+            .and_then(|sc| check_mut_ref(ret).and_then(|ret| sc.get_gamma_ramp(ret)))
+            .unwrap_or_else(|_| error!("Failed to retrieve gamma ramp"));
+    }
+
+    // -- Render target functions --
+
+    /// Creates a new render target.
+    fn create_render_target(
+        &mut self,
+        width: u32,
+        height: u32,
+        fmt: D3DFORMAT,
+        ms_ty: D3DMULTISAMPLE_TYPE,
+        ms_qlt: u32,
+        lockable: u32,
+        ret: *mut *mut Surface,
+        shared_handle: usize,
+    ) -> Error {
+        let _lock = self.lock();
+        let ret = if_error!(check_mut_ref(ret));
+
+        if lockable != 0 {
+            error!("Lockable render targets are not supported");
+        }
+
+        if shared_handle != 0 {
+            error!("Shared resources are not supported");
+            return Error::InvalidCall;
+        }
+
+        // First we need to create a texture we will render to.
+        let texture = if_error!(d3d11::Texture2D::new_rt(
+            &self.device,
+            (width, height),
+            fmt,
+            ms_ty,
+            ms_qlt
+        ));
+
+        // Null render targets only need a texture to describe them, they are never bound.
+        *ret = if is_null_format(fmt) {
+            let data = SurfaceData::NullRenderTarget;
+            Surface::new(
+                self,
+                texture,
+                UsageFlags::RENDER_TARGET,
+                MemoryPool::Default,
+                data,
+            )
+        } else {
+            if_error!(self.create_render_target_helper(texture))
+        }
+        .into();
+
+        Error::Success
+    }
+
+    /// Sets a new render target on this device.
+    fn set_render_target(&mut self, i: u32, rt: *mut Surface) -> Error {
+        let _lock = self.lock();
+        if i >= D3D11_SIMULTANEOUS_RENDER_TARGET_COUNT {
+            return Error::InvalidCall;
+        }
+
+        // The default render target is never allowed to be null.
+        if i == 0 && rt.is_null() {
+            return Error::InvalidCall;
+        }
+
+        let i = i as usize;
+
+        // Ensure the RT vector contains at least as many entries as we need.
+        self.render_targets.resize(i + 1, None);
+
+        self.render_targets[i] = if let Some(rt) = unsafe { rt.as_mut() } {
+            // Ensure this surface is indeed a render target.
+            let is_rt = rt.render_target_view().is_some() || rt.is_null_render_target();
+            if !is_rt || rt.is_lost() {
+                return Error::InvalidCall;
+            }
+
+            // All of the simultaneous render targets must have the same size.
+            if i != 0 && surface_size(rt) != if_error!(self.render_target_size()) {
+                error!(
+                    "Render target {} does not have the same size as the first one",
+                    i
+                );
+                return Error::InvalidCall;
+            }
+
+            Some(ComPtr::new(rt))
+        } else {
+            None
+        };
+
+        self.update_render_targets();
+
+        // Changing the main render target also resets the viewport and scissor rect to cover it.
+        if i == 0 {
+            if_error!(self.reset_viewport_and_scissor());
+        }
+
+        Error::Success
+    }
+
+    /// Retrieves a reference to a bound render target.
+    fn get_render_target(&self, i: u32, ret: *mut *mut Surface) -> Error {
+        let _lock = self.lock();
+        let rt = if_error!(self.check_render_target(i));
+        let ret = if_error!(check_mut_ref(ret));
+
+        *ret = rt.clone().into();
+
+        Error::Success
+    }
+
+    /// Copies the contents of a render target to a surface in system memory.
+    fn get_render_target_data(&self, rt: *mut Surface, dest: *mut Surface) -> Error {
+        let _lock = self.lock();
+        let rt = if_error!(check_mut_ref(rt));
+        let dest = if_error!(check_mut_ref(dest));
+
+        let (rt_desc, dest_desc) = unsafe {
+            let mut rt_desc = MaybeUninit::uninit();
+            let mut dest_desc = MaybeUninit::uninit();
+            if_not_success!(rt.get_desc(rt_desc.as_mut_ptr()));
+            if_not_success!(dest.get_desc(dest_desc.as_mut_ptr()));
+            (rt_desc.assume_init(), dest_desc.assume_init())
+        };
+
+        if dest.pool() != MemoryPool::SystemMem {
+            error!("Render target data can only be copied to system memory surfaces");
+            return Error::InvalidCall;
+        }
+
+        if (rt_desc.Width, rt_desc.Height, rt_desc.Format)
+            != (dest_desc.Width, dest_desc.Height, dest_desc.Format)
+        {
+            error!(
+                "Render target data can only be copied to a surface of the same size and format"
+            );
+            return Error::InvalidCall;
+        }
+
+        if rt_desc.MultiSampleType != D3DMULTISAMPLE_NONE {
+            error!("Cannot retrieve the data of a multisampled render target");
+            return Error::InvalidCall;
+        }
+
+        // The rendering recorded into a command list hasn't happened yet.
+        if self.immediate_ctx.is_some() {
+            error!("Cannot retrieve render target data while recording a command list");
+            return Error::InvalidCall;
+        }
+
+        let ctx = self.device_context();
+
+        // Render targets cannot be read by the CPU, so we need to copy them to a staging texture.
+        let staging = if_error!(d3d11::Texture2D::new_staging(
+            &self.device,
+            (rt_desc.Width, rt_desc.Height),
+            d3d_format_to_dxgi(rt_desc.Format),
+        ));
+
+        let (rt_res, rt_subres) = rt.subresource();
+        let staging_res = staging.as_resource();
+
+        let src = unsafe {
+            ctx.CopySubresourceRegion(staging_res, 0, 0, 0, 0, rt_res, rt_subres, ptr::null());
+
+            let mut mapped = MaybeUninit::uninit();
+            let result = ctx.Map(staging_res, 0, D3D11_MAP_READ, 0, mapped.as_mut_ptr());
+            if_not_success!(check_hresult(result, "Failed to map staging texture"));
+
+            mapped.assume_init()
+        };
+
+        let (dest_res, dest_subres) = dest.subresource();
+        let dest_rect = match ctx.map(
+            dest_res,
+            dest_subres,
+            LockFlags::empty(),
+            UsageFlags::WRITE_ONLY,
+        ) {
+            Ok(rect) => rect,
+            Err(err) => {
+                ctx.unmap(staging_res, 0);
+                return err;
+            }
+        };
+
+        // The pitches of the two textures might differ, so copy the data row by row.
+        let row_size = cmp::min(src.RowPitch, dest_rect.Pitch as u32) as usize;
+
+        for row in 0..rt_desc.Height as usize {
+            unsafe {
+                let src_row = (src.pData as *const u8).add(row * src.RowPitch as usize);
+                let dest_row = (dest_rect.pBits as *mut u8).add(row * dest_rect.Pitch as usize);
+
+                ptr::copy_nonoverlapping(src_row, dest_row, row_size);
+            }
+        }
+
+        ctx.unmap(dest_res, dest_subres);
+        ctx.unmap(staging_res, 0);
+
+        Error::Success
+    }
+
+    // -- Depth / stencil buffer functions --
+
+    /// Creates a new depth / stencil buffer.
+    fn create_depth_stencil_surface(
+        &self,
+        width: u32,
+        height: u32,
+        fmt: D3DFORMAT,
+        ms_ty: D3DMULTISAMPLE_TYPE,
+        ms_qlt: u32,
+        discard: u32,
+        ret: *mut *mut Surface,
+        shared_handle: usize,
+    ) -> Error {
+        let _lock = self.lock();
+        let ret = if_error!(check_mut_ref(ret));
+
+        if shared_handle != 0 {
+            error!("Shared resources are not supported");
+            return Error::InvalidCall;
+        }
+
+        if discard != 0 {
+            error!("Discarding depth/stencil buffer not supported");
+        }
+
+        let texture = if_error!(d3d11::Texture2D::new_ds(
+            &self.device,
+            (width, height),
+            fmt,
+            ms_ty,
+            ms_qlt
+        ));
+
+        let ds_view = if_error!(texture.create_ds_view(&self.device));
+
+        let data = SurfaceData::DepthStencil(ds_view);
+
+        *ret = Surface::new(
+            self,
+            texture,
+            UsageFlags::DEPTH_STENCIL,
+            MemoryPool::Default,
+            data,
+        )
+        .into();
+
+        Error::Success
+    }
+
+    /// Sets the current depth / stencil buffer.
+    fn set_depth_stencil_surface(&mut self, ds: *mut Surface) -> Error {
+        let _lock = self.lock();
+        self.depth_stencil = if let Some(ds) = unsafe { ds.as_mut() } {
+            if ds.depth_stencil_view().is_none() || ds.is_lost() {
+                return Error::InvalidCall;
+            }
+
+            Some(ComPtr::new(ds))
+        } else {
+            None
+        };
+
+        self.update_render_targets();
+
+        Error::Success
+    }
+
+    /// Retrieves the bound depth / stencil buffer.
+    fn get_depth_stencil_surface(&self, ret: *mut *mut Surface) -> Error {
+        let _lock = self.lock();
+        let ret = if_error!(check_mut_ref(ret));
+
+        *ret = self
+            .depth_stencil
+            .as_ref()
+            .map(|ds| ds.clone().into())
+            .unwrap_or(ptr::null_mut());
+
+        Error::Success
+    }
+
+    /// Creates an off-screen surface.
+    fn create_offscreen_plain_surface(
+        &self,
+        width: u32,
+        height: u32,
+        fmt: D3DFORMAT,
+        pool: MemoryPool,
+        ret: *mut *mut Surface,
+        shared_handle: usize,
+    ) -> Error {
+        let _lock = self.lock();
+        let ret = if_error!(check_mut_ref(ret));
+
+        if shared_handle != 0 {
+            error!("Shared resources are not supported");
+            return Error::InvalidCall;
+        }
+
+        let texture = if_error!(d3d11::Texture2D::new(
+            &self.device,
+            (width, height),
+            1,
+            UsageFlags::empty(),
+            fmt,
+            // We ignore the pool, we need this surface to always be CPU-readable
+            // (i.e. D3D11_USAGE_STAGING), since that's its intended use.
+            MemoryPool::SystemMem,
+        ));
+
+        let data = SurfaceData::None;
+
+        // We pass in the correct pool here, for storage purposes.
+        *ret = Surface::new(self, texture, UsageFlags::empty(), pool, data).into();
+
+        Error::Success
+    }
+
+    // -- Surface manipulation functions --
+
+    /// Copies a surface's region to another surface.
+    fn update_surface(
         &self,
         src: *mut Surface,
         sr: *const RECT,
         dest: *mut Surface,
         dp: *const POINT,
     ) -> Error {
+        let _lock = self.lock();
         let src = if_error!(check_mut_ref(src));
         let dest = if_error!(check_mut_ref(dest));
         let dp = if_error!(check_ref(dp));
@@ -629,7 +2591,11 @@ impl Device {
                 back: 1,
             });
 
-            let src_box = src_box.map(|b| &b as *const _).unwrap_or(ptr::null());
+            // Borrow the box, so that it outlives the copy.
+            let src_box = src_box
+                .as_ref()
+                .map(|b| b as *const _)
+                .unwrap_or(ptr::null());
 
             self.ctx.CopySubresourceRegion(
                 dest_res,
@@ -643,28 +2609,293 @@ impl Device {
             );
         }
 
-        Error::Success
-    }
+        Error::Success
+    }
+
+    /// Copies the modified regions of a system memory texture to a texture in the default pool.
+    fn update_texture(&self, src: *mut BaseTexture, dest: *mut BaseTexture) -> Error {
+        let _lock = self.lock();
+        if src.is_null() || dest.is_null() {
+            return Error::InvalidCall;
+        }
+
+        let src = ComPtr::new(com_ref(src));
+        let dest = ComPtr::new(com_ref(dest));
+
+        if BaseTexture::from_interface(&src).pool() != MemoryPool::SystemMem
+            || BaseTexture::from_interface(&dest).pool() != MemoryPool::Default
+        {
+            error!("UpdateTexture copies from system memory textures to default pool textures");
+            return Error::InvalidCall;
+        }
+
+        let (src, dest) = match (Texture::from_base(&src), Texture::from_base(&dest)) {
+            (Some(src), Some(dest)) => (src, dest),
+            _ => {
+                error!("UpdateTexture is only supported for 2D textures");
+                return Error::InvalidCall;
+            }
+        };
+
+        // If the source has more levels, its largest ones are skipped.
+        let (src_levels, dest_levels) = (src.level_count(), dest.level_count());
+        if src_levels < dest_levels {
+            error!("UpdateTexture's source has fewer levels than its destination");
+            return Error::InvalidCall;
+        }
+        let skipped = src_levels - dest_levels;
+
+        if src.level_size(skipped) != dest.level_size(0) {
+            error!("UpdateTexture's source and destination have different sizes");
+            return Error::InvalidCall;
+        }
+
+        let src_res = src.texture().as_resource();
+        let dest_res = dest.texture().as_resource();
+
+        for level in 0..dest_levels {
+            for rect in src.take_dirty_rects(skipped + level) {
+                let src_box = D3D11_BOX {
+                    left: rect.left as u32,
+                    top: rect.top as u32,
+                    front: 0,
+                    right: rect.right as u32,
+                    bottom: rect.bottom as u32,
+                    back: 1,
+                };
+
+                unsafe {
+                    self.ctx.CopySubresourceRegion(
+                        dest_res,
+                        level,
+                        rect.left as u32,
+                        rect.top as u32,
+                        0,
+                        src_res,
+                        skipped + level,
+                        &src_box,
+                    );
+                }
+            }
+        }
+
+        Error::Success
+    }
+
+    /// Copies a region of a surface to a region of another surface, possibly scaling it.
+    fn stretch_rect(
+        &mut self,
+        src: *mut Surface,
+        sr: *const RECT,
+        dest: *mut Surface,
+        dr: *const RECT,
+        filter: D3DTEXTUREFILTERTYPE,
+    ) -> Error {
+        let _lock = self.lock();
+        if ptr::eq(src, dest) {
+            error!("Cannot stretch a surface onto itself");
+            return Error::InvalidCall;
+        }
+
+        let src = if_error!(check_mut_ref(src));
+        let dest = if_error!(check_mut_ref(dest));
+
+        let (src_desc, dest_desc) = unsafe {
+            let mut src_desc = MaybeUninit::uninit();
+            let mut dest_desc = MaybeUninit::uninit();
+            if_not_success!(src.get_desc(src_desc.as_mut_ptr()));
+            if_not_success!(dest.get_desc(dest_desc.as_mut_ptr()));
+            (src_desc.assume_init(), dest_desc.assume_init())
+        };
+
+        if src.pool() != MemoryPool::Default || dest.pool() != MemoryPool::Default {
+            error!("StretchRect only works with surfaces in the default pool");
+            return Error::InvalidCall;
+        }
+
+        let sr = if_error!(surface_rect(sr, &src_desc));
+        let dr = if_error!(surface_rect(dr, &dest_desc));
+
+        let linear = match filter {
+            D3DTEXF_NONE | D3DTEXF_POINT => false,
+            D3DTEXF_LINEAR => true,
+            _ => {
+                error!("Unsupported StretchRect filter: {}", filter);
+                return Error::InvalidCall;
+            }
+        };
+
+        let same_size =
+            sr.right - sr.left == dr.right - dr.left && sr.bottom - sr.top == dr.bottom - dr.top;
+        let same_format = src_desc.Format == dest_desc.Format;
+        let multisampled = src_desc.MultiSampleType != D3DMULTISAMPLE_NONE;
+        let whole_surfaces = (src_desc.Width, src_desc.Height)
+            == (dest_desc.Width, dest_desc.Height)
+            && sr.left == 0
+            && sr.top == 0
+            && (sr.right as u32, sr.bottom as u32) == (src_desc.Width, src_desc.Height);
+
+        let is_ds = |usage: UsageFlags| usage.intersects(UsageFlags::DEPTH_STENCIL);
+        if is_ds(src.usage()) || is_ds(dest.usage()) {
+            // Depth / stencil buffers can only be copied as a whole, outside of a scene.
+            let valid = is_ds(src.usage())
+                && is_ds(dest.usage())
+                && same_format
+                && whole_surfaces
+                && !multisampled
+                && !self.in_scene;
+
+            if !valid {
+                error!("Invalid StretchRect of depth / stencil surfaces");
+                return Error::InvalidCall;
+            }
+        }
+
+        let (src_res, src_subres) = src.subresource();
+        let (dest_res, dest_subres) = dest.subresource();
+
+        // If no conversion is needed, we can simply copy the data.
+        if same_size && same_format && !multisampled {
+            let src_box = D3D11_BOX {
+                left: sr.left as u32,
+                top: sr.top as u32,
+                front: 0,
+                right: sr.right as u32,
+                bottom: sr.bottom as u32,
+                back: 1,
+            };
+
+            unsafe {
+                self.ctx.CopySubresourceRegion(
+                    dest_res,
+                    dest_subres,
+                    dr.left as u32,
+                    dr.top as u32,
+                    0,
+                    src_res,
+                    src_subres,
+                    &src_box,
+                );
+            }
+
+            return Error::Success;
+        }
+
+        // Anything else requires drawing to the destination, which must be a render target.
+        if !dest.usage().intersects(UsageFlags::RENDER_TARGET)
+            || dest_desc.MultiSampleType != D3DMULTISAMPLE_NONE
+        {
+            error!("StretchRect can only scale or convert to a render target");
+            return Error::InvalidCall;
+        }
+
+        // A multisampled surface covering the destination can be resolved directly.
+        if multisampled && same_format && whole_surfaces {
+            let format = d3d_format_to_dxgi(src_desc.Format);
+
+            unsafe {
+                self.ctx
+                    .ResolveSubresource(dest_res, dest_subres, src_res, src_subres, format);
+            }
+
+            return Error::Success;
+        }
+
+        let dest_view = if_error!(dest.create_rt_view(&self.device));
 
-    fn update_texture() {
-        unimplemented!()
-    }
-    fn stretch_rect() {
-        unimplemented!()
+        if self.blitter.is_none() {
+            self.blitter = Some(if_error!(d3d11::Blitter::new(&self.device)));
+        }
+
+        let result = self.blitter.as_mut().unwrap().blit(
+            &self.device,
+            &self.ctx,
+            (src.texture(), src_subres),
+            &sr,
+            dest_view.as_mut(),
+            &dr,
+            linear,
+        );
+
+        // Blitting overwrote some of the app's state.
+        self.restore_state_after_blit();
+
+        to_error_success!(result)
     }
+
+    /// Fills a region of a surface with a color.
     fn color_fill(&self, surface: *mut Surface, rect: *const RECT, color: D3DCOLOR) -> Error {
-        warn!(
-            "unimplemented: color_fill({:?}, {:?}, {})",
-            surface, rect, color
-        );
+        let _lock = self.lock();
         let surface = if_error!(check_mut_ref(surface));
 
-        // Basically we would need to fill a buffer with the color manually,
-        // _but_ the data layout is format dependant.
+        let desc = unsafe {
+            let mut desc = MaybeUninit::uninit();
+            if_not_success!(surface.get_desc(desc.as_mut_ptr()));
+            desc.assume_init()
+        };
+
+        if surface.usage().intersects(UsageFlags::DEPTH_STENCIL) {
+            error!("Cannot color fill a depth / stencil surface");
+            return Error::InvalidCall;
+        }
+
+        let rect = if_error!(surface_rect(rect, &desc));
+
+        // Render targets can be cleared by the GPU.
+        if surface.usage().intersects(UsageFlags::RENDER_TARGET) {
+            let view = if_error!(surface.create_rt_view(&self.device));
+            let view = view.as_mut() as *mut _;
+            let color = d3dcolor_to_rgba(color);
+
+            let full = (rect.right as u32, rect.bottom as u32) == (desc.Width, desc.Height)
+                && rect.left == 0
+                && rect.top == 0;
+
+            if full || !self.ctx.clear_rects(view, &color, &[rect]) {
+                if !full {
+                    run_once!(|| warn!(
+                        "Partial clears are not supported, filling the whole surface"
+                    ));
+                }
+
+                unsafe {
+                    self.ctx.ClearRenderTargetView(view, &color);
+                }
+            }
+
+            return Error::Success;
+        }
+
+        // Other surfaces have to be filled by the CPU, in the surface's format.
+        let pixel = match d3dcolor_to_pixel(color, desc.Format) {
+            Some(pixel) => pixel,
+            None => {
+                error!("Cannot color fill a surface of format {}", desc.Format);
+                return Error::InvalidCall;
+            }
+        };
+
+        // Surfaces are always mapped through the immediate context, even when recording.
+        let ctx = self.device_context();
+        let (res, subres) = surface.subresource();
+        let mapped = if_error!(ctx.map(res, subres, LockFlags::empty(), UsageFlags::WRITE_ONLY));
+
+        let width = (rect.right - rect.left) as usize;
+
+        for y in rect.top as usize..rect.bottom as usize {
+            let row = unsafe {
+                let start = (mapped.pBits as *mut u8)
+                    .add(y * mapped.Pitch as usize + rect.left as usize * pixel.len());
+                slice::from_raw_parts_mut(start, width * pixel.len())
+            };
+
+            for dest in row.chunks_mut(pixel.len()) {
+                dest.copy_from_slice(&pixel);
+            }
+        }
+
+        ctx.unmap(res, subres);
 
-        // We can use https://docs.microsoft.com/en-us/windows/win32/api/d3d11_1/nf-d3d11_1-id3d11devicecontext1-clearview
-        // ClearView, but that requires float[4] instead of D3DCOLOR
-        // but also ClearView is DX11.1
         Error::Success
     }
 
@@ -675,13 +2906,14 @@ impl Device {
         &self,
         width: u32,
         height: u32,
-        mut levels: u32,
+        levels: u32,
         usage: UsageFlags,
         fmt: D3DFORMAT,
         pool: MemoryPool,
         ret: *mut *mut Texture,
         shared_handle: usize,
     ) -> Error {
+        let _lock = self.lock();
         let ret = if_error!(check_mut_ref(ret));
 
         if shared_handle != 0 {
@@ -689,24 +2921,29 @@ impl Device {
             return Error::InvalidCall;
         }
 
-        if levels == 0 {
-            levels = 32 - cmp::max(width, height).leading_zeros();
-        }
+        let full_chain = 32 - cmp::max(width, height).leading_zeros();
+        let (levels, d3d11_levels) = if_error!(mip_levels(levels, full_chain, usage));
 
-        if usage.intersects(UsageFlags::AUTO_GEN_MIP_MAP) {
-            warn!("Autom mip-map generation not yet supported");
-        }
+        // Paletted textures are expanded to RGBA when they are unlocked.
+        let paletted = is_paletted_format(fmt);
+        let d3d11_fmt = if paletted { D3DFMT_A8B8G8R8 } else { fmt };
 
         let texture = if_error!(d3d11::Texture2D::new(
             &self.device,
             (width, height),
-            levels,
+            d3d11_levels,
             usage,
-            fmt,
+            d3d11_fmt,
             pool
         ));
 
-        *ret = Texture::new(self, pool, texture, levels, usage).into();
+        let mut texture = if_error!(Texture::new(self, pool, texture, levels, usage));
+
+        if paletted {
+            texture.set_paletted_format(fmt);
+        }
+
+        *ret = texture.into();
 
         Error::Success
     }
@@ -715,13 +2952,14 @@ impl Device {
     fn create_cube_texture(
         &self,
         edge_len: u32,
-        mut levels: u32,
+        levels: u32,
         usage: UsageFlags,
         fmt: D3DFORMAT,
         pool: MemoryPool,
         ret: *mut *mut CubeTexture,
         shared_handle: usize,
     ) -> Error {
+        let _lock = self.lock();
         let ret = if_error!(check_mut_ref(ret));
 
         if shared_handle != 0 {
@@ -729,113 +2967,590 @@ impl Device {
             return Error::InvalidCall;
         }
 
-        if levels == 0 {
-            levels = 32 - edge_len.leading_zeros();
-        }
-
-        if usage.intersects(UsageFlags::AUTO_GEN_MIP_MAP) {
-            warn!("Autom mip-map generation not yet supported");
-        }
+        let full_chain = 32 - edge_len.leading_zeros();
+        let (levels, d3d11_levels) = if_error!(mip_levels(levels, full_chain, usage));
 
         let texture = if_error!(d3d11::Texture2D::new_cube_texture(
             &self.device,
             edge_len,
-            levels,
+            d3d11_levels,
             usage,
             fmt,
             pool
         ));
 
-        *ret = CubeTexture::new(self, texture, levels, usage, pool).into();
+        *ret = if_error!(CubeTexture::new(self, texture, levels, usage, pool)).into();
 
         Error::Success
     }
 
-    fn create_volume_texture() {
-        unimplemented!()
+    /// Creates a new 3D texture.
+    fn create_volume_texture(
+        &self,
+        width: u32,
+        height: u32,
+        depth: u32,
+        levels: u32,
+        usage: UsageFlags,
+        fmt: D3DFORMAT,
+        pool: MemoryPool,
+        ret: *mut *mut VolumeTexture,
+        shared_handle: usize,
+    ) -> Error {
+        let _lock = self.lock();
+        let ret = if_error!(check_mut_ref(ret));
+
+        if shared_handle != 0 {
+            error!("Shared resources are not supported");
+            return Error::InvalidCall;
+        }
+
+        if usage.intersects(UsageFlags::RENDER_TARGET | UsageFlags::DEPTH_STENCIL) {
+            error!("Volume textures cannot be render targets or depth / stencil buffers");
+            return Error::InvalidCall;
+        }
+
+        let full_chain = 32 - cmp::max(width, cmp::max(height, depth)).leading_zeros();
+        let (levels, d3d11_levels) = if_error!(mip_levels(levels, full_chain, usage));
+
+        let texture = if_error!(d3d11::Texture3D::new(
+            &self.device,
+            (width, height, depth),
+            d3d11_levels,
+            usage,
+            fmt,
+            pool
+        ));
+
+        *ret = if_error!(VolumeTexture::new(self, pool, texture, levels, usage)).into();
+
+        Error::Success
     }
 
     // -- Drawing functions --
 
+    /// Clears the render targets and / or the depth / stencil buffer.
     fn clear(
-        &self,
+        &mut self,
         count: DWORD,
-        rect: *mut *const RECT,
+        rects: *const D3DRECT,
         flags: DWORD,
         color: D3DCOLOR,
         z: f32,
         stencil: DWORD,
     ) -> Error {
-        warn!("Unimplemented clear");
+        let _lock = self.lock();
+        if count != 0 && rects.is_null() {
+            return Error::InvalidCall;
+        }
+
+        if self.is_lost() {
+            return Error::Success;
+        }
+
+        let clear_ds = flags & (D3DCLEAR_ZBUFFER | D3DCLEAR_STENCIL) != 0;
+
+        if clear_ds && self.depth_stencil.is_none() {
+            return Error::InvalidCall;
+        }
+
+        // D3D9 never clears outside of the current viewport.
+        let vp = self.istate.get_viewport();
+        let coord = |pos: u32| cmp::min(pos, i32::max_value() as u32) as i32;
+        let mut bounds = RECT {
+            left: coord(vp.X),
+            top: coord(vp.Y),
+            right: coord(vp.X.saturating_add(vp.Width)),
+            bottom: coord(vp.Y.saturating_add(vp.Height)),
+        };
+
+        // Clears are also affected by the scissor test.
+        if self.istate.get_render_state(D3DRS_SCISSORTESTENABLE) != 0 {
+            let sr = self.istate.get_scissor_rect();
+            bounds = RECT {
+                left: cmp::max(bounds.left, sr.left),
+                top: cmp::max(bounds.top, sr.top),
+                right: cmp::min(bounds.right, sr.right),
+                bottom: cmp::min(bounds.bottom, sr.bottom),
+            };
+        }
+
+        // Determine which regions of the render target should be cleared.
+        let regions: Vec<RECT> = if rects.is_null() {
+            vec![bounds]
+        } else {
+            let rects = unsafe { slice::from_raw_parts(rects, count as usize) };
+
+            rects
+                .iter()
+                .map(|r| RECT {
+                    left: cmp::max(r.x1, bounds.left),
+                    top: cmp::max(r.y1, bounds.top),
+                    right: cmp::min(r.x2, bounds.right),
+                    bottom: cmp::min(r.y2, bounds.bottom),
+                })
+                .filter(|r| r.left < r.right && r.top < r.bottom)
+                .collect()
+        };
+
+        if regions.is_empty() {
+            return Error::Success;
+        }
+
+        // If we have to clear the whole target, we can use the fast path.
+        let (width, height) = if_error!(self.render_target_size());
+        let full = regions.iter().any(|r| {
+            r.left <= 0 && r.top <= 0 && r.right >= width as i32 && r.bottom >= height as i32
+        });
+
+        if flags & D3DCLEAR_TARGET != 0 {
+            let color = d3dcolor_to_rgba(color);
+
+            let views = self
+                .render_targets
+                .iter()
+                .flatten()
+                .filter_map(|rt| rt.render_target_view());
+
+            for view in views {
+                let view = view as *mut _;
+
+                if full || !self.ctx.clear_rects(view, &color, &regions) {
+                    if !full {
+                        run_once!(|| warn!(
+                            "Partial clears are not supported, clearing the whole render target"
+                        ));
+                    }
+
+                    unsafe {
+                        self.ctx.ClearRenderTargetView(view, &color);
+                    }
+                }
+            }
+        }
+
+        if clear_ds {
+            let view = self
+                .depth_stencil
+                .as_ref()
+                .unwrap()
+                .depth_stencil_view()
+                .unwrap();
+
+            let clear_depth = flags & D3DCLEAR_ZBUFFER != 0;
+            let clear_stencil = flags & D3DCLEAR_STENCIL != 0;
+
+            if full {
+                let mut ds_flags = 0;
+
+                if clear_depth {
+                    ds_flags |= D3D11_CLEAR_DEPTH;
+                }
+
+                if clear_stencil {
+                    ds_flags |= D3D11_CLEAR_STENCIL;
+                }
+
+                unsafe {
+                    self.ctx
+                        .ClearDepthStencilView(view, ds_flags, z, stencil as u8);
+                }
+            } else {
+                // D3D11 has no way of clearing only part of a depth / stencil view,
+                // so the blitter draws quads over the regions instead.
+                if self.blitter.is_none() {
+                    self.blitter = Some(if_error!(d3d11::Blitter::new(&self.device)));
+                }
+
+                let result = self.blitter.as_mut().unwrap().clear_depth_stencil(
+                    &self.device,
+                    &self.ctx,
+                    view,
+                    &regions,
+                    if clear_depth { Some(z) } else { None },
+                    if clear_stencil {
+                        Some(stencil as u8)
+                    } else {
+                        None
+                    },
+                );
+
+                // Clearing overwrote some of the app's state.
+                self.restore_state_after_blit();
+
+                if_error!(result);
+            }
+        }
+
         Error::Success
     }
 
-    fn begin_scene(&self) -> Error {
-        // TODO: BeginScene should handle if EndScene wasn't properly called.
-        // It should potentially also clear some stuff
+    /// Marks the beginning of a series of draw calls.
+    fn begin_scene(&mut self) -> Error {
+        let _lock = self.lock();
+        // Scenes cannot be nested.
+        if self.in_scene {
+            return Error::InvalidCall;
+        }
+
+        self.in_scene = true;
 
         Error::Success
     }
-    fn end_scene() {
-        unimplemented!()
+
+    /// Marks the end of a series of draw calls.
+    fn end_scene(&mut self) -> Error {
+        let _lock = self.lock();
+        if !self.in_scene {
+            return Error::InvalidCall;
+        }
+
+        self.in_scene = false;
+
+        // Submit the commands of the scene, so the GPU can start working on them
+        // before the app presents. Commands recorded into a command list are submitted
+        // when the list is run.
+        let now = Instant::now();
+        if self.immediate_ctx.is_none() && now - self.last_scene_flush >= SCENE_FLUSH_INTERVAL {
+            unsafe {
+                self.ctx.Flush();
+            }
+            self.last_scene_flush = now;
+        }
+
+        Error::Success
     }
 
-    fn draw_indexed_primitive() {
-        unimplemented!()
+    /// Draws indexed primitives using the bound index buffer.
+    fn draw_indexed_primitive(
+        &mut self,
+        ty: D3DPRIMITIVETYPE,
+        base_vertex: i32,
+        // D3D11 doesn't need to be told which range of vertices is used.
+        _min_index: u32,
+        _num_vertices: u32,
+        start_index: u32,
+        count: u32,
+    ) -> Error {
+        let _lock = self.lock();
+        if_error!(self.check_in_scene());
+        if self.is_lost() {
+            return Error::Success;
+        }
+        if_error!(self.set_primitive_topology(ty));
+        if_error!(self.flush_state(ty));
+
+        let ib = if_error!(self.istate.get_indices().ok_or(Error::InvalidCall));
+        let index_count = primitive_vertex_count(ty, count);
+
+        self.bind_vertex_buffers();
+
+        unsafe {
+            self.ctx
+                .IASetIndexBuffer(ib.get_dx11().as_buffer(), ib.dxgi_format(), 0);
+            self.ctx.DrawIndexed(index_count, start_index, base_vertex);
+        }
+
+        Error::Success
     }
-    fn draw_indexed_primitive_u_p() {
-        unimplemented!()
+
+    /// Draws indexed primitives using vertex and index data supplied by the app.
+    fn draw_indexed_primitive_u_p(
+        &mut self,
+        ty: D3DPRIMITIVETYPE,
+        min_index: u32,
+        num_vertices: u32,
+        count: u32,
+        index_data: *const c_void,
+        index_fmt: D3DFORMAT,
+        vertex_data: *const c_void,
+        stride: u32,
+    ) -> Error {
+        let _lock = self.lock();
+        if_error!(self.check_in_scene());
+
+        if index_data.is_null() || vertex_data.is_null() || stride == 0 {
+            return Error::InvalidCall;
+        }
+
+        if self.is_lost() {
+            return Error::Success;
+        }
+
+        let (index_size, dxgi_fmt) = match index_fmt {
+            D3DFMT_INDEX16 => (2, DXGI_FORMAT_R16_UINT),
+            D3DFMT_INDEX32 => (4, DXGI_FORMAT_R32_UINT),
+            _ => return Error::InvalidCall,
+        };
+
+        if_error!(self.set_primitive_topology(ty));
+        if_error!(self.flush_state(ty));
+
+        let index_count = primitive_vertex_count(ty, count);
+
+        // The indices can refer to any vertex up to the end of the used range.
+        let vertices = unsafe {
+            let len = (min_index + num_vertices) * stride;
+            slice::from_raw_parts(vertex_data as *const u8, len as usize)
+        };
+
+        let indices = unsafe {
+            let len = index_count * index_size;
+            slice::from_raw_parts(index_data as *const u8, len as usize)
+        };
+
+        let (vb, vb_offset) = if_error!(self.up_vertices.upload(&self.device, &self.ctx, vertices));
+        let (ib, ib_offset) = if_error!(self.up_indices.upload(&self.device, &self.ctx, indices));
+
+        unsafe {
+            self.ctx.IASetVertexBuffers(0, 1, &vb, &stride, &vb_offset);
+            self.ctx.IASetIndexBuffer(ib, dxgi_fmt, ib_offset);
+            self.ctx.DrawIndexed(index_count, 0, 0);
+        }
+
+        // Like D3D9, unbind the first stream and the index buffer after drawing.
+        // The remaining streams are restored by the next draw call.
+        self.istate.set_stream_source(0, StreamSource::default());
+        self.istate.set_indices(None);
+
+        Error::Success
     }
-    fn draw_primitive() {
-        unimplemented!()
+
+    /// Draws non-indexed primitives from the bound vertex streams.
+    fn draw_primitive(&mut self, ty: D3DPRIMITIVETYPE, start_vertex: u32, count: u32) -> Error {
+        let _lock = self.lock();
+        if_error!(self.check_in_scene());
+        if self.is_lost() {
+            return Error::Success;
+        }
+        if_error!(self.set_primitive_topology(ty));
+        if_error!(self.flush_state(ty));
+
+        let vertex_count = primitive_vertex_count(ty, count);
+
+        self.bind_vertex_buffers();
+
+        unsafe {
+            self.ctx.Draw(vertex_count, start_vertex);
+        }
+
+        Error::Success
     }
-    fn draw_primitive_u_p() {
-        unimplemented!()
+
+    /// Draws primitives using vertex data supplied by the app.
+    fn draw_primitive_u_p(
+        &mut self,
+        ty: D3DPRIMITIVETYPE,
+        count: u32,
+        data: *const c_void,
+        stride: u32,
+    ) -> Error {
+        let _lock = self.lock();
+        if_error!(self.check_in_scene());
+
+        if data.is_null() || stride == 0 {
+            return Error::InvalidCall;
+        }
+
+        if self.is_lost() {
+            return Error::Success;
+        }
+
+        if_error!(self.set_primitive_topology(ty));
+        if_error!(self.flush_state(ty));
+
+        let vertex_count = primitive_vertex_count(ty, count);
+
+        let vertices = unsafe {
+            let len = vertex_count * stride;
+            slice::from_raw_parts(data as *const u8, len as usize)
+        };
+
+        let (vb, offset) = if_error!(self.up_vertices.upload(&self.device, &self.ctx, vertices));
+
+        unsafe {
+            self.ctx.IASetVertexBuffers(0, 1, &vb, &stride, &offset);
+            self.ctx.Draw(vertex_count, 0);
+        }
+
+        // Like D3D9, unbind the first stream after drawing.
+        // The remaining streams are restored by the next draw call.
+        self.istate.set_stream_source(0, StreamSource::default());
+
+        Error::Success
     }
 
     // -- State block functions --
 
     /// Creates a new state block which can capture commands.
     fn create_state_block(&mut self, ty: D3DSTATEBLOCKTYPE, ret: *mut *mut StateBlock) -> Error {
+        let _lock = self.lock();
+        let ret = if_error!(check_mut_ref(ret));
+
+        *ret = if_error!(StateBlock::new(self, ty)).into();
+
+        Error::Success
+    }
+
+    /// Begins recording a new state block.
+    ///
+    /// Until the recording ends, state-setting calls only modify the state block.
+    fn begin_state_block(&mut self) -> Error {
+        let _lock = self.lock();
+        if self.recording.is_some() {
+            error!("A state block is already being recorded");
+            return Error::InvalidCall;
+        }
+
+        self.recording = Some(RecordedState::default());
+
+        Error::Success
+    }
+
+    /// Ends recording a state block, and returns a pointer to it.
+    fn end_state_block(&mut self, ret: *mut *mut StateBlock) -> Error {
+        let _lock = self.lock();
+        let ret = if_error!(check_mut_ref(ret));
+
+        let state = if_error!(self.recording.take().ok_or(Error::InvalidCall));
+        *ret = StateBlock::from_recording(self, state).into();
+
+        Error::Success
+    }
+
+    /// Validates the current state of the device, or the state of the
+    /// currently recording state block, if any.
+    fn validate_device(&self, passes: *mut u32) -> Error {
+        let _lock = self.lock();
+        let passes = if_error!(check_mut_ref(passes));
+
+        // We do not emulate anything using multiple passes.
+        *passes = 1;
+
+        Error::Success
+    }
+
+    // -- Palette functions --
+
+    /// Sets the colors of a palette.
+    ///
+    /// The flags of each entry contain the alpha value of the color.
+    fn set_palette_entries(&mut self, index: u32, entries: *const PALETTEENTRY) -> Error {
+        let _lock = self.lock();
+        let entries = if_error!(check_ref(entries as *const [PALETTEENTRY; 256]));
+        self.palettes.insert(index, *entries);
+        Error::Success
+    }
+
+    /// Retrieves the colors of a palette.
+    fn get_palette_entries(&self, index: u32, ret: *mut PALETTEENTRY) -> Error {
+        let _lock = self.lock();
+        let ret = if_error!(check_mut_ref(ret as *mut [PALETTEENTRY; 256]));
+
+        match self.palettes.get(&index) {
+            Some(entries) => *ret = *entries,
+            None => {
+                error!("Palette {} was never set", index);
+                return Error::InvalidCall;
+            }
+        }
+
+        Error::Success
+    }
+
+    /// Selects the palette used by paletted textures.
+    fn set_current_texture_palette(&mut self, index: u32) -> Error {
+        let _lock = self.lock();
+        if !self.palettes.contains_key(&index) {
+            error!("Palette {} was never set", index);
+            return Error::InvalidCall;
+        }
+
+        self.current_palette = index;
+        Error::Success
+    }
+
+    /// Retrieves the index of the palette used by paletted textures.
+    fn get_current_texture_palette(&self, ret: *mut u32) -> Error {
+        let _lock = self.lock();
         let ret = if_error!(check_mut_ref(ret));
+        *ret = self.current_palette;
+        Error::Success
+    }
+
+    // -- Hardware cursor functions --
+
+    /// Moves the cursor to a position on the screen.
+    fn set_cursor_position(&self, x: i32, y: i32, _flags: u32) {
+        let _lock = self.lock();
+        // Windows always updates the cursor immediately, so we can ignore the flags.
+        self.cursor.set_position(x, y);
+    }
+
+    /// Sets the cursor's image, from a surface containing 32-bit ARGB pixels.
+    fn set_cursor_properties(&mut self, x_hot: u32, y_hot: u32, bitmap: *mut Surface) -> Error {
+        let _lock = self.lock();
+        let bitmap = if_error!(check_ref(bitmap));
+
+        let desc = unsafe {
+            let mut desc = MaybeUninit::uninit();
+            if_not_success!(bitmap.get_desc(desc.as_mut_ptr()));
+            desc.assume_init()
+        };
+
+        if desc.Format != D3DFMT_A8R8G8B8 {
+            error!("Cursor images must use the A8R8G8B8 format");
+            return Error::InvalidCall;
+        }
+
+        if x_hot >= desc.Width || y_hot >= desc.Height {
+            error!("Cursor hotspot ({}, {}) is out of bounds", x_hot, y_hot);
+            return Error::InvalidCall;
+        }
+
+        // The surface might not be readable by the CPU, so copy it to a staging texture.
+        let staging = if_error!(d3d11::Texture2D::new_staging(
+            &self.device,
+            (desc.Width, desc.Height),
+            d3d_format_to_dxgi(desc.Format),
+        ));
+        let staging_res = staging.as_resource();
 
-        *ret = if_error!(StateBlock::new(self, ty)).into();
+        // The image is needed right away, so it is never recorded into a command list.
+        let ctx = self.device_context();
 
-        Error::Success
-    }
+        let (res, subres) = bitmap.subresource();
+        let src = unsafe {
+            ctx.CopySubresourceRegion(staging_res, 0, 0, 0, 0, res, subres, ptr::null());
 
-    /// Begins recording a new state block.
-    fn begin_state_block(&mut self) -> Error {
-        unimplemented!()
-    }
+            let mut mapped = MaybeUninit::uninit();
+            let result = ctx.Map(staging_res, 0, D3D11_MAP_READ, 0, mapped.as_mut_ptr());
+            if_not_success!(check_hresult(result, "Failed to map cursor image"));
 
-    /// Ends recording a state block, and returns a pointer to it.
-    fn end_state_block(&mut self, ret: *mut *mut StateBlock) -> Error {
-        let _ret = if_error!(check_mut_ref(ret));
-        unimplemented!()
-    }
+            mapped.assume_init()
+        };
 
-    /// Validates the current state of the device, or the state of the
-    /// currently recording state block, if any.
-    fn validate_device(&self, passes: *mut u32) -> Error {
-        let passes = if_error!(check_mut_ref(passes));
+        // Remove the padding between the rows.
+        let (width, height) = (desc.Width as usize, desc.Height as usize);
+        let mut pixels = Vec::with_capacity(width * height);
+        for row in 0..height {
+            unsafe {
+                let src_row = (src.pData as *const u8).add(row * src.RowPitch as usize);
+                pixels.extend_from_slice(slice::from_raw_parts(src_row as *const u32, width));
+            }
+        }
 
-        // We do not emulate anything using multiple passes.
-        *passes = 1;
+        ctx.unmap(staging_res, 0);
 
-        Error::Success
+        to_error_success!(self
+            .cursor
+            .set_image(&pixels, (desc.Width, desc.Height), (x_hot, y_hot)))
     }
 
-    // -- Hardware cursor functions --
-
-    fn set_cursor_position() {
-        unimplemented!()
-    }
-    fn set_cursor_properties() {
-        unimplemented!()
-    }
-    fn show_cursor() {
-        unimplemented!()
+    /// Shows or hides the cursor, returning whether it was previously visible.
+    fn show_cursor(&mut self, show: BOOL) -> BOOL {
+        let _lock = self.lock();
+        self.cursor.show(show != 0) as BOOL
     }
 
     // -- Pipeline state functions --
@@ -845,12 +3560,35 @@ impl Device {
 
     /// Sets the render state.
     fn set_render_state(&mut self, state: D3DRENDERSTATETYPE, value: u32) -> Error {
+        let _lock = self.lock();
+        if let Some(rec) = self.recording.as_mut() {
+            rec.render_states.insert(state, value);
+            return Error::Success;
+        }
+
+        // The RESZ hack is a command, not an actual point size.
+        if state == D3DRS_POINTSIZE && value == RESZ_CODE {
+            if let Err(error) = self.resolve_depth_stencil() {
+                error!("Failed to resolve the depth buffer");
+                return error;
+            }
+
+            return Error::Success;
+        }
+
         self.istate.set_render_state(state, value);
+
+        // Render targets have a separate view for sRGB writes.
+        if state == D3DRS_SRGBWRITEENABLE {
+            self.update_render_targets();
+        }
+
         Error::Success
     }
 
     /// Retrieves the value of the current render state.
     fn get_render_state(&self, state: D3DRENDERSTATETYPE, ret: *mut u32) -> Error {
+        let _lock = self.lock();
         let ret = if_error!(check_mut_ref(ret));
 
         *ret = self.istate.get_render_state(state);
@@ -866,90 +3604,209 @@ impl Device {
         elems: *const D3DVERTEXELEMENT9,
         ret: *mut *mut VertexDeclaration,
     ) -> Error {
+        let _lock = self.lock();
         let ret = if_error!(check_mut_ref(ret));
 
-        *ret = VertexDeclaration::new(self, elems).into();
+        *ret = if_error!(VertexDeclaration::new(self, elems)).into();
 
         Error::Success
     }
 
     /// Sets the current vertex declaration.
     fn set_vertex_declaration(&mut self, decl: *const VertexDeclaration) -> Error {
+        let _lock = self.lock();
+        if let Some(rec) = self.recording.as_mut() {
+            rec.vertex_decl = Some((decl, 0));
+            return Error::Success;
+        }
+
         self.istate.set_vertex_declaration(decl);
         Error::Success
     }
 
     /// Gets the current vertex declaration.
     fn get_vertex_declaration(&self, ret: *mut *const VertexDeclaration) -> Error {
+        let _lock = self.lock();
         let ret = if_error!(check_mut_ref(ret));
         *ret = com_ref(self.istate.get_vertex_declaration());
         Error::Success
     }
 
+    /// Sets the current vertex declaration from a flexible vertex format code.
+    fn set_f_v_f(&mut self, fvf: u32) -> Error {
+        let _lock = self.lock();
+        if fvf == 0 {
+            return Error::InvalidCall;
+        }
+
+        // Apps tend to use a handful of FVFs, so we keep the declarations around.
+        if !self.fvf_decls.contains_key(&fvf) {
+            let mut elems = fvf_to_vertex_elements(fvf);
+            elems.push(D3DDECL_END);
+
+            let decl = if_error!(VertexDeclaration::new(self, elems.as_ptr()));
+            self.fvf_decls.insert(fvf, decl);
+        }
+
+        let decl = self.fvf_decls[&fvf].as_mut() as *const _;
+
+        if let Some(rec) = self.recording.as_mut() {
+            rec.vertex_decl = Some((decl, fvf));
+            return Error::Success;
+        }
+
+        self.istate.set_fvf(fvf, decl);
+
+        Error::Success
+    }
+
+    /// Retrieves the current FVF code, or 0 if a vertex declaration is used instead.
+    fn get_f_v_f(&self, ret: *mut u32) -> Error {
+        let _lock = self.lock();
+        let ret = if_error!(check_mut_ref(ret));
+        *ret = self.istate.get_fvf();
+        Error::Success
+    }
+
     /// Creates a vertex shader from its bytecode.
     fn create_vertex_shader(&self, func: *const u32, ret: *mut *mut VertexShader) -> Error {
+        let _lock = self.lock();
         let ret = if_error!(check_mut_ref(ret));
+
+        if func.is_null() {
+            return Error::InvalidCall;
+        }
+
         *ret = if_error!(VertexShader::new(self, func)).into();
         Error::Success
     }
 
     /// Sets the current vertex shader.
-    fn set_vertex_shader(&mut self, vs_raw_ptr: *const VertexShader) -> Error {
-        trace!("set_vertex_shader");
-        let vs = if_error!(check_ref(vs_raw_ptr));
-        self.istate.set_vertex_shader(vs);
-        unsafe {
-            self.device_context()
-                .VSSetShader(vs.get_dx11().as_mut(), ptr::null_mut(), 0);
+    fn set_vertex_shader(&mut self, vs: *mut VertexShader) -> Error {
+        let _lock = self.lock();
+        // A null shader switches back to the fixed function pipeline.
+        let vs = unsafe { vs.as_mut() }.map(|vs| ComPtr::new(com_ref(vs)));
+
+        if let Some(rec) = self.recording.as_mut() {
+            rec.vertex_shader = Some(vs);
+            return Error::Success;
         }
+
+        self.istate.set_vertex_shader(vs);
         Error::Success
     }
 
-    /// Retrieves the current vertex shader;
-    fn get_vertex_shader(&self, ret: *mut *const VertexShader) -> Error {
+    /// Retrieves the current vertex shader.
+    fn get_vertex_shader(&self, ret: *mut *mut VertexShader) -> Error {
+        let _lock = self.lock();
         let ret = if_error!(check_mut_ref(ret));
-        *ret = self.istate.get_vertex_shader();
-        // TODO: somehow use DX11 GetVertexShader and wrap it, so _we_ aren't responsible for the state?
-        // But then how to look it up properly.
+
+        *ret = self
+            .istate
+            .get_vertex_shader()
+            .map(|vs| vs.clone().into())
+            .unwrap_or(ptr::null_mut());
+
         Error::Success
     }
 
-    fn set_vertex_shader_constant_b() {
-        unimplemented!()
+    /// Sets some of the vertex shader's boolean constants.
+    fn set_vertex_shader_constant_b(
+        &mut self,
+        start_register: UINT,
+        constant_data: *const BOOL,
+        bool_count: UINT,
+    ) -> Error {
+        let _lock = self.lock();
+        let data = if_error!(bools_to_registers(constant_data, bool_count));
+
+        if let Some(rec) = self.recording.as_mut() {
+            if_error!(self.vs_consts.bool.get(start_register, bool_count));
+            record_constants(&mut rec.vs_constants.bool, start_register, &data);
+            return Error::Success;
+        }
+
+        if_error!(self.vs_consts.bool.set(start_register, &data));
+        Error::Success
     }
-    fn get_vertex_shader_constant_b() {
-        unimplemented!()
+
+    /// Retrieves some of the vertex shader's boolean constants.
+    fn get_vertex_shader_constant_b(
+        &self,
+        start_register: UINT,
+        constant_data: *mut BOOL,
+        bool_count: UINT,
+    ) -> Error {
+        let _lock = self.lock();
+        let data = if_error!(self.vs_consts.bool.get(start_register, bool_count));
+        if_error!(registers_to_bools(data, constant_data));
+        Error::Success
     }
+
+    /// Sets some of the vertex shader's float constants.
     fn set_vertex_shader_constant_f(
-        &self,
+        &mut self,
         start_register: UINT,
         constant_data: *const f32,
         vector4f_count: UINT,
     ) -> Error {
-        warn!(
-            "TODO: Implement set_vertex_shader_constant_f({}, {:?}, {})",
-            start_register, constant_data, vector4f_count
-        );
-        // According to DX11, up to 16 registers are allowed?
+        let _lock = self.lock();
+        let data = if_error!(constants_from_ptr(constant_data, vector4f_count));
 
-        // TODO: typically we would create one buffer per shader
-        unsafe {
-            // TODO: properly create the buffer. But then also: how to reference them properly?
-            // Does it work magically when just assuming one buffer is one constant?
-            /*let buf: *mut ID3D11Buffer = ptr::null_mut();
-            self.device_context()
-            .VSSetConstantBuffers(start_register, 1, &buf);*/
+        if let Some(rec) = self.recording.as_mut() {
+            if_error!(self.vs_consts.float.get(start_register, vector4f_count));
+            record_constants(&mut rec.vs_constants.float, start_register, data);
+            return Error::Success;
         }
+
+        if_error!(self.vs_consts.float.set(start_register, data));
         Error::Success
     }
-    fn get_vertex_shader_constant_f() {
-        unimplemented!()
+
+    /// Retrieves some of the vertex shader's float constants.
+    fn get_vertex_shader_constant_f(
+        &self,
+        start_register: UINT,
+        constant_data: *mut f32,
+        vector4f_count: UINT,
+    ) -> Error {
+        let _lock = self.lock();
+        let data = if_error!(self.vs_consts.float.get(start_register, vector4f_count));
+        if_error!(constants_to_ptr(data, constant_data));
+        Error::Success
     }
-    fn set_vertex_shader_constant_i() {
-        unimplemented!()
+
+    /// Sets some of the vertex shader's integer constants.
+    fn set_vertex_shader_constant_i(
+        &mut self,
+        start_register: UINT,
+        constant_data: *const i32,
+        vector4i_count: UINT,
+    ) -> Error {
+        let _lock = self.lock();
+        let data = if_error!(constants_from_ptr(constant_data, vector4i_count));
+
+        if let Some(rec) = self.recording.as_mut() {
+            if_error!(self.vs_consts.int.get(start_register, vector4i_count));
+            record_constants(&mut rec.vs_constants.int, start_register, data);
+            return Error::Success;
+        }
+
+        if_error!(self.vs_consts.int.set(start_register, data));
+        Error::Success
     }
-    fn get_vertex_shader_constant_i() {
-        unimplemented!()
+
+    /// Retrieves some of the vertex shader's integer constants.
+    fn get_vertex_shader_constant_i(
+        &self,
+        start_register: UINT,
+        constant_data: *mut i32,
+        vector4i_count: UINT,
+    ) -> Error {
+        let _lock = self.lock();
+        let data = if_error!(self.vs_consts.int.get(start_register, vector4i_count));
+        if_error!(constants_to_ptr(data, constant_data));
+        Error::Success
     }
 
     /// Creates a new vertex buffer.
@@ -962,6 +3819,7 @@ impl Device {
         ret: *mut *mut VertexBuffer,
         shared_handle: usize,
     ) -> Error {
+        let _lock = self.lock();
         let ret = if_error!(check_mut_ref(ret));
 
         if shared_handle != 0 {
@@ -969,6 +3827,10 @@ impl Device {
             return Error::InvalidCall;
         }
 
+        if len == 0 || pool == MemoryPool::Scratch {
+            return Error::InvalidCall;
+        }
+
         let buffer = if_error!(d3d11::Buffer::new(
             &self.device,
             len,
@@ -992,6 +3854,7 @@ impl Device {
         ret: *mut *mut IndexBuffer,
         shared_handle: usize,
     ) -> Error {
+        let _lock = self.lock();
         let ret = if_error!(check_mut_ref(ret));
 
         if shared_handle != 0 {
@@ -999,6 +3862,15 @@ impl Device {
             return Error::InvalidCall;
         }
 
+        if len == 0 || pool == MemoryPool::Scratch {
+            return Error::InvalidCall;
+        }
+
+        if fmt != D3DFMT_INDEX16 && fmt != D3DFMT_INDEX32 {
+            error!("Invalid index buffer format: {}", fmt);
+            return Error::InvalidCall;
+        }
+
         let buffer = if_error!(d3d11::Buffer::new(
             &self.device,
             len,
@@ -1012,31 +3884,107 @@ impl Device {
         Error::Success
     }
 
+    /// Binds a vertex buffer to a certain input stream.
     fn set_stream_source(
+        &mut self,
+        stream: u32,
+        vb: *mut VertexBuffer,
+        offset: u32,
+        stride: u32,
+    ) -> Error {
+        let _lock = self.lock();
+        if stream as usize >= MAX_STREAMS {
+            return Error::InvalidCall;
+        }
+
+        if unsafe { vb.as_ref() }.map_or(false, |vb| vb.is_lost()) {
+            error!("Tried to bind a vertex buffer lost by a device reset");
+            return Error::InvalidCall;
+        }
+
+        // Null buffers unbind the stream.
+        // A stride of 0 is valid: every vertex then reads the same data.
+        let source = StreamSource {
+            buffer: if vb.is_null() {
+                None
+            } else {
+                Some(ComPtr::new(com_ref(vb)))
+            },
+            offset,
+            stride,
+        };
+
+        if let Some(rec) = self.recording.as_mut() {
+            rec.streams.insert(stream, source);
+            return Error::Success;
+        }
+
+        self.istate.set_stream_source(stream, source);
+
+        Error::Success
+    }
+
+    /// Retrieves the vertex buffer bound to an input stream.
+    fn get_stream_source(
         &self,
-        stream_number: UINT,
-        stream_data: *mut VertexBuffer,
-        offset_in_bytes: UINT,
-        stride: UINT,
+        stream: u32,
+        ret: *mut *mut VertexBuffer,
+        offset: *mut u32,
+        stride: *mut u32,
     ) -> Error {
-        // TODO: When grouping buffers together, we can reduce API calls, but for this we need a
-        // reliable way of knowing when we've reached the last s_s_s call, introducing state bookkeeping and related issues.
-        trace!("SetStreamSource");
-        let vb = if_error!(check_ref(stream_data));
+        let _lock = self.lock();
+        let ret = if_error!(check_mut_ref(ret));
+        let offset = if_error!(check_mut_ref(offset));
+        let stride = if_error!(check_mut_ref(stride));
 
-        unsafe {
-            self.device_context().IASetVertexBuffers(
-                stream_number,
-                1,
-                &vb.get_dx11().as_buffer(),
-                &stride,
-                &offset_in_bytes,
-            );
+        let source = if_error!(self
+            .istate
+            .get_stream_source(stream)
+            .ok_or(Error::InvalidCall));
+
+        *ret = source
+            .buffer
+            .as_ref()
+            .map(|vb| vb.clone().into())
+            .unwrap_or(ptr::null_mut());
+        *offset = source.offset;
+        *stride = source.stride;
+
+        Error::Success
+    }
+
+    /// Sets the index buffer used by indexed draw calls.
+    fn set_indices(&mut self, ib: *mut IndexBuffer) -> Error {
+        let _lock = self.lock();
+        if unsafe { ib.as_ref() }.map_or(false, |ib| ib.is_lost()) {
+            error!("Tried to bind an index buffer lost by a device reset");
+            return Error::InvalidCall;
+        }
+
+        // Setting a null buffer unbinds the previous one, releasing it.
+        let ib = unsafe { ib.as_mut() }.map(|ib| ComPtr::new(com_ref(ib)));
+
+        if let Some(rec) = self.recording.as_mut() {
+            rec.indices = Some(ib);
+            return Error::Success;
         }
+
+        self.istate.set_indices(ib);
         Error::Success
     }
-    fn get_stream_source() {
-        unimplemented!()
+
+    /// Retrieves the current index buffer.
+    fn get_indices(&self, ret: *mut *mut IndexBuffer) -> Error {
+        let _lock = self.lock();
+        let ret = if_error!(check_mut_ref(ret));
+
+        *ret = self
+            .istate
+            .get_indices()
+            .map(|ib| ib.clone().into())
+            .unwrap_or(ptr::null_mut());
+
+        Error::Success
     }
 
     fn set_stream_source_freq() {
@@ -1050,13 +3998,26 @@ impl Device {
 
     /// Sets the state of a texture sampler.
     fn set_sampler_state(&mut self, sampler: u32, ty: D3DSAMPLERSTATETYPE, value: u32) -> Error {
+        let _lock = self.lock();
+        if let Some(rec) = self.recording.as_mut() {
+            rec.sampler_states.insert((sampler, ty), value);
+            return Error::Success;
+        }
+
         self.istate.set_sampler_state(sampler, ty, value);
 
+        // Textures have a separate view for sRGB sampling.
+        // Vertex texture samplers don't support sRGB sampling.
+        if ty == D3DSAMP_SRGBTEXTURE && sampler < MAX_SAMPLERS as u32 {
+            self.bind_texture(sampler, self.istate.get_texture(sampler));
+        }
+
         Error::Success
     }
 
     /// Gets the state of a texture sampler.
     fn get_sampler_state(&self, sampler: u32, ty: D3DSAMPLERSTATETYPE, ret: *mut u32) -> Error {
+        let _lock = self.lock();
         let ret = if_error!(check_mut_ref(ret));
 
         *ret = self.istate.get_sampler_state(sampler, ty);
@@ -1066,62 +4027,189 @@ impl Device {
 
     /// Create a pixel shader from its bytecode.
     fn create_pixel_shader(&self, func: *const u32, ret: *mut *mut PixelShader) -> Error {
+        let _lock = self.lock();
         let ret = if_error!(check_mut_ref(ret));
+
+        if func.is_null() {
+            return Error::InvalidCall;
+        }
+
         *ret = if_error!(PixelShader::new(self, func)).into();
         Error::Success
     }
 
     /// Sets the current pixel shader.
-    fn set_pixel_shader(&mut self, ps: *const PixelShader) -> Error {
+    fn set_pixel_shader(&mut self, ps: *mut PixelShader) -> Error {
+        let _lock = self.lock();
+        // A null shader switches back to the fixed function pipeline.
+        let ps = unsafe { ps.as_mut() }.map(|ps| ComPtr::new(com_ref(ps)));
+
+        if let Some(rec) = self.recording.as_mut() {
+            rec.pixel_shader = Some(ps);
+            return Error::Success;
+        }
+
         self.istate.set_pixel_shader(ps);
         Error::Success
     }
 
-    /// Gets the current pixel shader.
-    fn get_pixel_shader(&self, ret: *mut *const PixelShader) -> Error {
+    /// Retrieves the current pixel shader.
+    fn get_pixel_shader(&self, ret: *mut *mut PixelShader) -> Error {
+        let _lock = self.lock();
         let ret = if_error!(check_mut_ref(ret));
-        *ret = self.istate.get_pixel_shader();
+
+        *ret = self
+            .istate
+            .get_pixel_shader()
+            .map(|ps| ps.clone().into())
+            .unwrap_or(ptr::null_mut());
+
         Error::Success
     }
 
-    fn set_pixel_shader_constant_b() {
-        unimplemented!()
+    /// Sets some of the pixel shader's boolean constants.
+    fn set_pixel_shader_constant_b(
+        &mut self,
+        start_register: UINT,
+        constant_data: *const BOOL,
+        bool_count: UINT,
+    ) -> Error {
+        let _lock = self.lock();
+        let data = if_error!(bools_to_registers(constant_data, bool_count));
+
+        if let Some(rec) = self.recording.as_mut() {
+            if_error!(self.ps_consts.bool.get(start_register, bool_count));
+            record_constants(&mut rec.ps_constants.bool, start_register, &data);
+            return Error::Success;
+        }
+
+        if_error!(self.ps_consts.bool.set(start_register, &data));
+        Error::Success
     }
-    fn get_pixel_shader_constant_b() {
-        unimplemented!()
+
+    /// Retrieves some of the pixel shader's boolean constants.
+    fn get_pixel_shader_constant_b(
+        &self,
+        start_register: UINT,
+        constant_data: *mut BOOL,
+        bool_count: UINT,
+    ) -> Error {
+        let _lock = self.lock();
+        let data = if_error!(self.ps_consts.bool.get(start_register, bool_count));
+        if_error!(registers_to_bools(data, constant_data));
+        Error::Success
     }
+
+    /// Sets some of the pixel shader's float constants.
     fn set_pixel_shader_constant_f(
-        &self,
+        &mut self,
         start_register: UINT,
         constant_data: *const f32,
         vector4f_count: UINT,
     ) -> Error {
-        warn!(
-            "TODO: Implement set_pixel_shader_constant_f({}, {:?}, {})",
-            start_register, constant_data, vector4f_count
-        );
+        let _lock = self.lock();
+        let data = if_error!(constants_from_ptr(constant_data, vector4f_count));
+
+        if let Some(rec) = self.recording.as_mut() {
+            if_error!(self.ps_consts.float.get(start_register, vector4f_count));
+            record_constants(&mut rec.ps_constants.float, start_register, data);
+            return Error::Success;
+        }
+
+        if_error!(self.ps_consts.float.set(start_register, data));
         Error::Success
     }
-    fn get_pixel_shader_constant_f() {
-        unimplemented!()
+
+    /// Retrieves some of the pixel shader's float constants.
+    fn get_pixel_shader_constant_f(
+        &self,
+        start_register: UINT,
+        constant_data: *mut f32,
+        vector4f_count: UINT,
+    ) -> Error {
+        let _lock = self.lock();
+        let data = if_error!(self.ps_consts.float.get(start_register, vector4f_count));
+        if_error!(constants_to_ptr(data, constant_data));
+        Error::Success
     }
-    fn set_pixel_shader_constant_i() {
-        unimplemented!()
+
+    /// Sets some of the pixel shader's integer constants.
+    fn set_pixel_shader_constant_i(
+        &mut self,
+        start_register: UINT,
+        constant_data: *const i32,
+        vector4i_count: UINT,
+    ) -> Error {
+        let _lock = self.lock();
+        let data = if_error!(constants_from_ptr(constant_data, vector4i_count));
+
+        if let Some(rec) = self.recording.as_mut() {
+            if_error!(self.ps_consts.int.get(start_register, vector4i_count));
+            record_constants(&mut rec.ps_constants.int, start_register, data);
+            return Error::Success;
+        }
+
+        if_error!(self.ps_consts.int.set(start_register, data));
+        Error::Success
     }
-    fn get_pixel_shader_constant_i() {
-        unimplemented!()
+
+    /// Retrieves some of the pixel shader's integer constants.
+    fn get_pixel_shader_constant_i(
+        &self,
+        start_register: UINT,
+        constant_data: *mut i32,
+        vector4i_count: UINT,
+    ) -> Error {
+        let _lock = self.lock();
+        let data = if_error!(self.ps_consts.int.get(start_register, vector4i_count));
+        if_error!(constants_to_ptr(data, constant_data));
+        Error::Success
     }
 
     /// Binds a texture to a stage.
+    ///
+    /// Binding a null texture unbinds the stage.
     fn set_texture(&mut self, stage: u32, texture: *mut BaseTexture) -> Error {
+        let _lock = self.lock();
+        let is_vertex_stage =
+            D3DVERTEXTEXTURESAMPLER0 <= stage && stage <= D3DVERTEXTEXTURESAMPLER3;
+
+        if stage >= 16 && !is_vertex_stage {
+            error!("Invalid texture stage: {}", stage);
+            return Error::InvalidCall;
+        }
+
+        let texture = unsafe { texture.as_mut() }.map(|tx| ComPtr::new(com_ref(tx)));
+
+        if let Some(tx) = texture.as_ref() {
+            if BaseTexture::from_interface(tx).is_lost() {
+                error!("Tried to bind a texture lost by a device reset");
+                return Error::InvalidCall;
+            }
+        }
+
+        if let Some(rec) = self.recording.as_mut() {
+            rec.textures.insert(stage, texture);
+            return Error::Success;
+        }
+
+        self.bind_texture(stage, texture.as_ref());
         self.istate.set_texture(stage, texture);
+
         Error::Success
     }
 
     /// Retrieves the bound texture of a certain stage.
     fn get_texture(&self, stage: u32, ret: *mut *mut BaseTexture) -> Error {
+        let _lock = self.lock();
         let ret = if_error!(check_mut_ref(ret));
-        *ret = self.istate.get_texture(stage);
+
+        *ret = self
+            .istate
+            .get_texture(stage)
+            .map(|tx| tx.clone().into())
+            .unwrap_or(ptr::null_mut());
+
         Error::Success
     }
 
@@ -1132,6 +4220,12 @@ impl Device {
         ty: D3DTEXTURESTAGESTATETYPE,
         value: u32,
     ) -> Error {
+        let _lock = self.lock();
+        if let Some(rec) = self.recording.as_mut() {
+            rec.texture_stage_states.insert((stage, ty), value);
+            return Error::Success;
+        }
+
         self.istate.set_texture_stage_state(stage, ty, value);
         Error::Success
     }
@@ -1143,6 +4237,7 @@ impl Device {
         ty: D3DTEXTURESTAGESTATETYPE,
         ret: *mut u32,
     ) -> Error {
+        let _lock = self.lock();
         let ret = if_error!(check_mut_ref(ret));
         *ret = self.istate.get_texture_stage_state(stage, ty);
         Error::Success
@@ -1152,42 +4247,102 @@ impl Device {
 
     /// Sets a device's viewport.
     fn set_viewport(&mut self, vp: *const D3DVIEWPORT9) -> Error {
+        let _lock = self.lock();
         let vp = if_error!(check_ref(vp));
+
+        // The viewport must be contained within the render target.
+        let (width, height) = if_error!(self.render_target_size());
+        let right = vp.X.checked_add(vp.Width);
+        let bottom = vp.Y.checked_add(vp.Height);
+        match (right, bottom) {
+            (Some(right), Some(bottom)) if right <= width && bottom <= height => (),
+            _ => return Error::InvalidCall,
+        }
+
+        if let Some(rec) = self.recording.as_mut() {
+            rec.viewport = Some(*vp);
+            return Error::Success;
+        }
+
         self.istate.set_viewport(vp);
+
+        self.bound.depth_range = Some((vp.MinZ, vp.MaxZ));
+        self.bind_viewport(vp, (vp.MinZ, vp.MaxZ));
+
         Error::Success
     }
 
     /// Retrieves the currently set viewport.
     fn get_viewport(&self, ret: *mut D3DVIEWPORT9) -> Error {
+        let _lock = self.lock();
         let ret = if_error!(check_mut_ref(ret));
         *ret = self.istate.get_viewport();
         Error::Success
     }
 
-    fn set_scissor_rect() {
-        unimplemented!()
+    /// Sets the rectangle used by the scissor test.
+    fn set_scissor_rect(&mut self, rect: *const RECT) -> Error {
+        let _lock = self.lock();
+        let rect = if_error!(check_ref(rect));
+
+        if let Some(rec) = self.recording.as_mut() {
+            rec.scissor_rect = Some(*rect);
+            return Error::Success;
+        }
+
+        self.istate.set_scissor_rect(rect);
+        self.update_scissor_rect();
+        Error::Success
     }
-    fn get_scissor_rect() {
-        unimplemented!()
+
+    /// Retrieves the rectangle used by the scissor test.
+    fn get_scissor_rect(&self, ret: *mut RECT) -> Error {
+        let _lock = self.lock();
+        let ret = if_error!(check_mut_ref(ret));
+        *ret = self.istate.get_scissor_rect();
+        Error::Success
     }
 
-    // -- Query creation --
+    // -- Query creation --
+
+    /// Creates a new asynchronous query.
+    ///
+    /// If `ret` is null, only checks whether the query type is supported.
+    fn create_query(&self, ty: D3DQUERYTYPE, ret: *mut *mut Query) -> Error {
+        let _lock = self.lock();
+        if ret.is_null() {
+            return if Query::is_supported(ty) {
+                Error::Success
+            } else {
+                Error::NotAvailable
+            };
+        }
+
+        let ret = if_error!(check_mut_ref(ret));
+        *ret = if_error!(Query::new(self, ty)).into();
 
-    fn create_query() {
-        unimplemented!()
+        Error::Success
     }
 
     // -- Fixed function pipeline --
 
     /// Sets the current material.
     fn set_material(&mut self, mat: *const D3DMATERIAL9) -> Error {
+        let _lock = self.lock();
         let mat = if_error!(check_ref(mat));
+
+        if let Some(rec) = self.recording.as_mut() {
+            rec.material = Some(*mat);
+            return Error::Success;
+        }
+
         self.istate.set_material(mat);
         Error::Success
     }
 
     /// Retrieves the currently set material.
     fn get_material(&self, ret: *mut D3DMATERIAL9) -> Error {
+        let _lock = self.lock();
         let ret = if_error!(check_mut_ref(ret));
         *ret = self.istate.get_material();
         Error::Success
@@ -1195,33 +4350,169 @@ impl Device {
 
     /// Sets a transformation matrix to a value.
     fn set_transform(&mut self, ty: D3DTRANSFORMSTATETYPE, mat: *const D3DMATRIX) -> Error {
-        if (D3DTS_VIEW <= ty && ty <= D3DTS_PROJECTION)
-            || (D3DTS_TEXTURE0 <= ty && ty <= D3DTS_TEXTURE7)
-            || (256 <= ty && ty <= 512)
-        {
-            let mat = if_error!(check_ref(mat));
-            self.istate
-                .set_transform(ty, unsafe { mem::transmute(*mat) });
-            Error::Success
-        } else {
-            Error::InvalidCall
+        let _lock = self.lock();
+        if_error!(check_transform_type(ty));
+        let mat = if_error!(check_ref(mat));
+        let mat: nalgebra::Matrix4<f32> = unsafe { mem::transmute(*mat) };
+
+        if let Some(rec) = self.recording.as_mut() {
+            rec.transforms.insert(ty, mat);
+            return Error::Success;
         }
+
+        self.istate.set_transform(ty, mat);
+
+        Error::Success
     }
 
     /// Retrieves a transformation matrix.
     fn get_transform(&self, ty: D3DTRANSFORMSTATETYPE, ret: *mut D3DMATRIX) -> Error {
-        if (D3DTS_VIEW <= ty && ty <= D3DTS_PROJECTION)
-            || (D3DTS_TEXTURE0 <= ty && ty <= D3DTS_TEXTURE7)
-            || (256 <= ty && ty <= 512)
-        {
-            let ret = if_error!(check_mut_ref(ret));
-            *ret = unsafe { mem::transmute(self.istate.get_transform(ty)) };
+        let _lock = self.lock();
+        if_error!(check_transform_type(ty));
+        let ret = if_error!(check_mut_ref(ret));
+
+        *ret = unsafe { mem::transmute(self.istate.get_transform(ty)) };
+
+        Error::Success
+    }
+
+    /// Multiplies a transformation matrix by another matrix.
+    fn multiply_transform(&mut self, ty: D3DTRANSFORMSTATETYPE, mat: *const D3DMATRIX) -> Error {
+        let _lock = self.lock();
+        if_error!(check_transform_type(ty));
+        let mat = if_error!(check_ref(mat));
+
+        // D3D9 computes `mat * current` with row-major matrices,
+        // which is the reverse order for our column-major matrices.
+        let mat: nalgebra::Matrix4<f32> = unsafe { mem::transmute(*mat) };
+
+        if let Some(rec) = self.recording.as_mut() {
+            // Multiply the recorded matrix, if there is one.
+            let current = match rec.transforms.get(&ty) {
+                Some(&current) => current,
+                None => self.istate.get_transform(ty),
+            };
+            rec.transforms.insert(ty, current * mat);
+            return Error::Success;
+        }
+
+        let current = self.istate.get_transform(ty);
+        self.istate.set_transform(ty, current * mat);
+
+        Error::Success
+    }
+
+    /// Sets the properties of a light.
+    fn set_light(&mut self, index: u32, light: *const D3DLIGHT9) -> Error {
+        let _lock = self.lock();
+        let light = if_error!(check_ref(light));
+
+        let valid_type = match light.Type {
+            D3DLIGHT_POINT | D3DLIGHT_SPOT | D3DLIGHT_DIRECTIONAL => true,
+            _ => false,
+        };
+
+        if !valid_type || light.Range < 0.0 {
+            error!("Invalid light: type {}, range {}", light.Type, light.Range);
+            return Error::InvalidCall;
+        }
+
+        if let Some(rec) = self.recording.as_mut() {
+            rec.lights.insert(index, *light);
+            return Error::Success;
+        }
+
+        self.istate.set_light(index, light);
+
+        Error::Success
+    }
+
+    /// Retrieves the properties of a light.
+    fn get_light(&self, index: u32, ret: *mut D3DLIGHT9) -> Error {
+        let _lock = self.lock();
+        let ret = if_error!(check_mut_ref(ret));
+
+        match self.istate.get_light(index) {
+            Some(light) => {
+                *ret = light;
+                Error::Success
+            }
+            None => Error::InvalidCall,
+        }
+    }
+
+    /// Enables or disables a light.
+    fn light_enable(&mut self, index: u32, enable: BOOL) -> Error {
+        let _lock = self.lock();
+        if let Some(rec) = self.recording.as_mut() {
+            rec.light_enables.insert(index, enable != 0);
+            return Error::Success;
+        }
+
+        if self.istate.set_light_enable(index, enable != 0) {
             Error::Success
         } else {
+            error!(
+                "Cannot enable more than {} lights at the same time",
+                shader::MAX_LIGHTS
+            );
             Error::InvalidCall
         }
     }
 
+    /// Checks if a light is enabled.
+    fn get_light_enable(&self, index: u32, ret: *mut BOOL) -> Error {
+        let _lock = self.lock();
+        let ret = if_error!(check_mut_ref(ret));
+
+        if self.istate.get_light(index).is_none() {
+            return Error::InvalidCall;
+        }
+
+        *ret = self.istate.is_light_enabled(index) as BOOL;
+
+        Error::Success
+    }
+
+    /// Sets the equation of a user clip plane.
+    ///
+    /// Fixed function planes are in world space, while shaders get them in clip space.
+    fn set_clip_plane(&mut self, index: u32, plane: *const f32) -> Error {
+        let _lock = self.lock();
+        if index as usize >= shader::MAX_CLIP_PLANES || plane.is_null() {
+            return Error::InvalidCall;
+        }
+
+        let mut value = [0.0; 4];
+        unsafe {
+            ptr::copy_nonoverlapping(plane, value.as_mut_ptr(), 4);
+        }
+
+        if let Some(rec) = self.recording.as_mut() {
+            rec.clip_planes.insert(index, value);
+            return Error::Success;
+        }
+
+        self.istate.set_clip_plane(index as usize, value);
+
+        Error::Success
+    }
+
+    /// Retrieves the equation of a user clip plane.
+    fn get_clip_plane(&self, index: u32, ret: *mut f32) -> Error {
+        let _lock = self.lock();
+        if index as usize >= shader::MAX_CLIP_PLANES || ret.is_null() {
+            return Error::InvalidCall;
+        }
+
+        let value = self.istate.get_clip_plane(index as usize);
+        unsafe {
+            ptr::copy_nonoverlapping(value.as_ptr(), ret, 4);
+        }
+
+        Error::Success
+    }
+
     fn delete_patch() {
         unimplemented!()
     }
@@ -1231,76 +4522,449 @@ impl Device {
     fn draw_tri_patch() {
         unimplemented!()
     }
-    fn get_clip_plane() {
-        unimplemented!()
-    }
     fn get_clip_status() {
         unimplemented!()
     }
-    fn get_current_texture_palette() {
+    fn get_n_patch_mode() {
         unimplemented!()
     }
-    fn get_f_v_f() {
+    fn get_software_vertex_processing() {
         unimplemented!()
     }
-    fn get_indices() {
+    fn process_vertices() {
         unimplemented!()
     }
-    fn get_light() {
+    fn set_clip_status() {
         unimplemented!()
     }
-    fn get_light_enable() {
+    fn set_dialog_box_mode() {
         unimplemented!()
     }
-    fn get_n_patch_mode() {
+    fn set_n_patch_mode() {
         unimplemented!()
     }
-    fn get_palette_entries() {
+    fn set_software_vertex_processing() {
         unimplemented!()
     }
-    fn get_software_vertex_processing() {
-        unimplemented!()
+}
+
+#[implementation(IDirect3DDevice9Ex)]
+impl Device {
+    /// Sets the kernel used for convolution filtering when stretching surfaces.
+    fn set_convolution_mono_kernel(
+        &mut self,
+        _width: u32,
+        _height: u32,
+        _rows: *mut f32,
+        _columns: *mut f32,
+    ) -> Error {
+        let _lock = self.lock();
+        error!("Convolution filtering is not supported");
+        Error::NotAvailable
     }
-    fn light_enable() {
-        unimplemented!()
+
+    /// Copies rectangles from a monochrome surface into another one.
+    #[allow(clippy::too_many_arguments)]
+    fn compose_rects(
+        &mut self,
+        _src: *mut Surface,
+        _dest: *mut Surface,
+        _src_rects: *mut VertexBuffer,
+        _count: u32,
+        _dest_rects: *mut VertexBuffer,
+        _op: D3DCOMPOSERECTSOP,
+        _x: i32,
+        _y: i32,
+    ) -> Error {
+        let _lock = self.lock();
+        error!("Composing rectangles is not supported");
+        Error::NotAvailable
     }
-    fn multiply_transform() {
-        unimplemented!()
+
+    /// Presents the back buffer, with some extra flags.
+    fn present_ex(&self, src: usize, dest: usize, wnd: HWND, dirty: usize, flags: u32) -> Error {
+        let _lock = self.lock();
+        // Extended devices aren't lost, their presents just don't show up.
+        if self.is_occluded() {
+            return Error::PresentOccluded;
+        }
+
+        self.present_swap_chains(src, dest, wnd, dirty, flags)
     }
-    fn process_vertices() {
-        unimplemented!()
+
+    /// Retrieves the priority of the GPU thread rendering for this device.
+    fn get_g_p_u_thread_priority(&self, ret: *mut i32) -> Error {
+        let _lock = self.lock();
+        let ret = if_error!(check_mut_ref(ret));
+        let device = if_error!(self.dxgi_device());
+
+        let result = unsafe { device.GetGPUThreadPriority(ret) };
+        check_hresult(result, "Failed to get GPU thread priority")
     }
-    fn set_clip_plane() {
-        unimplemented!()
+
+    /// Changes the priority of the GPU thread rendering for this device.
+    fn set_g_p_u_thread_priority(&self, priority: i32) -> Error {
+        let _lock = self.lock();
+        if priority < -7 || priority > 7 {
+            return Error::InvalidCall;
+        }
+
+        let device = if_error!(self.dxgi_device());
+
+        let result = unsafe { device.SetGPUThreadPriority(priority) };
+        check_hresult(result, "Failed to set GPU thread priority")
     }
-    fn set_clip_status() {
-        unimplemented!()
+
+    /// Waits for the next vertical blank of a swap chain's monitor.
+    fn wait_for_v_blank(&self, sc: u32) -> Error {
+        let _lock = self.lock();
+        to_error_success!(if_error!(self.check_swap_chain(sc)).wait_for_vblank())
     }
-    fn set_current_texture_palette() {
-        unimplemented!()
+
+    /// Checks if some resources are in video memory.
+    fn check_resource_residency(&self, resources: *mut *mut Resource, count: u32) -> Error {
+        let _lock = self.lock();
+        if count > 0 && resources.is_null() {
+            return Error::InvalidCall;
+        }
+
+        // D3D11 makes resources resident before they are used.
+        Error::Success
     }
-    fn set_dialog_box_mode() {
-        unimplemented!()
+
+    /// Sets the maximum number of frames which can be queued for presenting.
+    fn set_maximum_frame_latency(&self, latency: u32) -> Error {
+        let _lock = self.lock();
+        // 0 restores the default latency.
+        if latency > 16 {
+            error!("Invalid maximum frame latency: {}", latency);
+            return Error::InvalidCall;
+        }
+
+        let device = if_error!(self.dxgi_device());
+
+        let result = unsafe { device.SetMaximumFrameLatency(latency) };
+        check_hresult(result, "Failed to set maximum frame latency")
     }
-    fn set_f_v_f() {
-        unimplemented!()
+
+    /// Retrieves the maximum number of frames which can be queued for presenting.
+    fn get_maximum_frame_latency(&self, ret: *mut u32) -> Error {
+        let _lock = self.lock();
+        let ret = if_error!(check_mut_ref(ret));
+        let device = if_error!(self.dxgi_device());
+
+        let result = unsafe { device.GetMaximumFrameLatency(ret) };
+        check_hresult(result, "Failed to get maximum frame latency")
+    }
+
+    /// Checks if the device's window can be presented to.
+    fn check_device_state(&self, _wnd: HWND) -> Error {
+        let _lock = self.lock();
+        if self.is_occluded() {
+            Error::PresentOccluded
+        } else {
+            Error::Success
+        }
+    }
+
+    /// Creates a new render target, with some extra usage flags.
+    fn create_render_target_ex(
+        &mut self,
+        width: u32,
+        height: u32,
+        fmt: D3DFORMAT,
+        ms_ty: D3DMULTISAMPLE_TYPE,
+        ms_qlt: u32,
+        lockable: u32,
+        ret: *mut *mut Surface,
+        shared_handle: usize,
+        _usage: u32,
+    ) -> Error {
+        let _lock = self.lock();
+        self.create_render_target(
+            width,
+            height,
+            fmt,
+            ms_ty,
+            ms_qlt,
+            lockable,
+            ret,
+            shared_handle,
+        )
+    }
+
+    /// Creates a new surface in system memory, with some extra usage flags.
+    fn create_offscreen_plain_surface_ex(
+        &self,
+        width: u32,
+        height: u32,
+        fmt: D3DFORMAT,
+        pool: MemoryPool,
+        ret: *mut *mut Surface,
+        shared_handle: usize,
+        _usage: u32,
+    ) -> Error {
+        let _lock = self.lock();
+        self.create_offscreen_plain_surface(width, height, fmt, pool, ret, shared_handle)
+    }
+
+    /// Creates a new depth / stencil buffer, with some extra usage flags.
+    fn create_depth_stencil_surface_ex(
+        &self,
+        width: u32,
+        height: u32,
+        fmt: D3DFORMAT,
+        ms_ty: D3DMULTISAMPLE_TYPE,
+        ms_qlt: u32,
+        discard: u32,
+        ret: *mut *mut Surface,
+        shared_handle: usize,
+        _usage: u32,
+    ) -> Error {
+        let _lock = self.lock();
+        self.create_depth_stencil_surface(
+            width,
+            height,
+            fmt,
+            ms_ty,
+            ms_qlt,
+            discard,
+            ret,
+            shared_handle,
+        )
+    }
+
+    /// Resets the device, switching to a certain display mode in full-screen.
+    fn reset_ex(&mut self, pp: *mut D3DPRESENT_PARAMETERS, mode: *mut D3DDISPLAYMODEEX) -> Error {
+        let _lock = self.lock();
+        let pp = if_error!(check_mut_ref(pp));
+        if_error!(check_fullscreen_mode(pp, mode));
+
+        if pp.SwapEffect < D3DSWAPEFFECT_DISCARD || pp.SwapEffect > D3DSWAPEFFECT_FLIPEX {
+            return Error::InvalidCall;
+        }
+
+        to_error_success!(self.reset_device(pp))
     }
-    fn set_indices(&mut self, idx: *mut IDirect3DIndexBuffer9) -> Error {
-        warn!("TODO: SetIndices on unsupported Fixed Function Pipeline!");
-        if_error!(check_ref(idx));
-        self.istate.set_index(idx);
+
+    /// Retrieves the display mode of a swap chain, and the rotation of the screen.
+    fn get_display_mode_ex(
+        &self,
+        sc: u32,
+        mode: *mut D3DDISPLAYMODEEX,
+        rotation: *mut D3DDISPLAYROTATION,
+    ) -> Error {
+        let _lock = self.lock();
+        let mut dm = unsafe { mem::zeroed() };
+        if_not_success!(self.get_display_mode(sc, &mut dm));
+
+        if let Ok(mode) = check_mut_ref(mode) {
+            *mode = display_mode_ex(&dm);
+        }
+
+        if let Ok(rotation) = check_mut_ref(rotation) {
+            // DXGI rotates the images for us.
+            *rotation = D3DDISPLAYROTATION_IDENTITY;
+        }
+
         Error::Success
     }
-    fn set_light() {
-        unimplemented!()
+}
+
+/// Overwrites some constant registers, unless they already contain the same values.
+///
+/// This avoids uploading the constants again when nothing changed.
+fn set_constants_if_changed(
+    consts: &mut d3d11::ConstantBuffer<f32>,
+    start: u32,
+    data: &[d3d11::Vec4<f32>],
+) -> Result<(), Error> {
+    if consts.get(start, data.len() as u32)? != data {
+        consts.set(start, data)?;
     }
-    fn set_n_patch_mode() {
-        unimplemented!()
+
+    Ok(())
+}
+
+/// Records a new value of some bound state, returning whether it differs from the old one.
+fn changed<T: PartialEq>(bound: &mut Option<T>, value: T) -> bool {
+    if bound.as_ref() == Some(&value) {
+        false
+    } else {
+        *bound = Some(value);
+        true
     }
-    fn set_palette_entries() {
-        unimplemented!()
+}
+
+/// Checks if alpha to coverage was enabled with the ATOC hack,
+/// by setting a render state which is otherwise unused to the `ATOC` format.
+fn alpha_to_coverage(istate: &DeviceState) -> bool {
+    istate.get_render_state(D3DRS_ADAPTIVETESS_Y) == D3DFMT_ATOC
+}
+
+/// Retrieves the dimensions of a surface.
+fn surface_size(surface: &Surface) -> (u32, u32) {
+    let desc = unsafe {
+        let mut desc = MaybeUninit::uninit();
+        surface.get_desc(desc.as_mut_ptr());
+        desc.assume_init()
+    };
+
+    (desc.Width, desc.Height)
+}
+
+/// Packs a light in the layout expected by the fixed function vertex shader.
+fn light_to_registers(light: &D3DLIGHT9) -> [d3d11::Vec4<f32>; shader::LIGHT_REGISTERS] {
+    let color = |c: D3DCOLORVALUE| [c.r, c.g, c.b, c.a];
+    let (pos, dir) = (light.Position, light.Direction);
+
+    [
+        color(light.Diffuse),
+        color(light.Specular),
+        color(light.Ambient),
+        [pos.x, pos.y, pos.z, light.Range],
+        [dir.x, dir.y, dir.z, light.Type as f32],
+        [
+            light.Attenuation0,
+            light.Attenuation1,
+            light.Attenuation2,
+            light.Falloff,
+        ],
+        [(light.Theta / 2.0).cos(), (light.Phi / 2.0).cos(), 0.0, 0.0],
+    ]
+}
+
+/// Converts a matrix to the registers of a `row_major float4x4` shader constant.
+///
+/// Our matrices are column-major transposes of D3D9's matrices,
+/// so their memory layout already matches D3D9's row-major layout.
+fn matrix_to_registers(matrix: &nalgebra::Matrix4<f32>) -> [d3d11::Vec4<f32>; 4] {
+    let mut registers = [[0.0; 4]; 4];
+
+    for (register, values) in registers.iter_mut().zip(matrix.as_slice().chunks(4)) {
+        register.copy_from_slice(values);
     }
-    fn set_software_vertex_processing() {
-        unimplemented!()
+
+    registers
+}
+
+/// Interprets an app-provided pointer as an array of shader constant registers.
+fn constants_from_ptr<'a, T>(ptr: *const T, count: u32) -> Result<&'a [d3d11::Vec4<T>], Error> {
+    let ptr = check_ref(ptr)? as *const T as *const d3d11::Vec4<T>;
+    Ok(unsafe { slice::from_raw_parts(ptr, count as usize) })
+}
+
+/// Copies some shader constant registers to an app-provided pointer.
+fn constants_to_ptr<T: Copy>(data: &[d3d11::Vec4<T>], ptr: *mut T) -> Result<(), Error> {
+    let ptr = check_mut_ref(ptr)? as *mut T as *mut d3d11::Vec4<T>;
+    unsafe {
+        ptr::copy_nonoverlapping(data.as_ptr(), ptr, data.len());
+    }
+    Ok(())
+}
+
+/// Retrieves the raw pointer to an optional interface, or null.
+fn com_ptr_or_null<T: Interface>(ptr: &Option<ComPtr<T>>) -> *mut T {
+    ptr.as_ref()
+        .map(|ptr| ptr.as_mut() as *mut T)
+        .unwrap_or(ptr::null_mut())
+}
+
+/// Stores some shader constant registers in a recording state block.
+fn record_constants<T: Copy>(
+    recorded: &mut HashMap<u32, d3d11::Vec4<T>>,
+    start: u32,
+    data: &[d3d11::Vec4<T>],
+) {
+    recorded.extend((start..).zip(data.iter().cloned()));
+}
+
+/// Expands an app-provided array of booleans into shader constant registers.
+fn bools_to_registers(ptr: *const BOOL, count: u32) -> Result<Vec<d3d11::Vec4<u32>>, Error> {
+    let ptr = check_ref(ptr)? as *const BOOL;
+    let bools = unsafe { slice::from_raw_parts(ptr, count as usize) };
+    Ok(bools.iter().map(|&b| [(b != 0) as u32, 0, 0, 0]).collect())
+}
+
+/// Copies the booleans stored in some shader constant registers to an app-provided pointer.
+fn registers_to_bools(data: &[d3d11::Vec4<u32>], ptr: *mut BOOL) -> Result<(), Error> {
+    let ptr = check_mut_ref(ptr)? as *mut BOOL;
+    let bools = unsafe { slice::from_raw_parts_mut(ptr, data.len()) };
+    for (b, reg) in bools.iter_mut().zip(data) {
+        *b = reg[0] as BOOL;
+    }
+    Ok(())
+}
+
+/// Determines the number of mip levels the app sees in a texture,
+/// and the number of levels of the underlying D3D11 texture.
+///
+/// Textures with automatically generated mip maps only expose their top level,
+/// and dynamic textures are limited to a single level.
+fn mip_levels(levels: u32, full_chain: u32, usage: UsageFlags) -> Result<(u32, u32), Error> {
+    if usage.intersects(UsageFlags::AUTO_GEN_MIP_MAP) {
+        if levels > 1 {
+            error!(
+                "Textures with generated mip maps cannot have {} levels",
+                levels
+            );
+            return Err(Error::InvalidCall);
+        }
+
+        return Ok((1, full_chain));
+    }
+
+    let levels = if levels == 0 { full_chain } else { levels };
+
+    // D3D11 only allows dynamic textures to have a single subresource.
+    if usage.intersects(UsageFlags::DYNAMIC) && levels > 1 {
+        warn!("Dynamic textures cannot have mip maps, creating a single level");
+        return Ok((1, 1));
+    }
+
+    Ok((levels, levels))
+}
+
+/// Retrieves a rectangle inside of a surface, or the whole surface if `rect` is null.
+fn surface_rect(rect: *const RECT, desc: &D3DSURFACE_DESC) -> Result<RECT, Error> {
+    let whole = RECT {
+        left: 0,
+        top: 0,
+        right: desc.Width as i32,
+        bottom: desc.Height as i32,
+    };
+
+    let rect = match unsafe { rect.as_ref() } {
+        Some(rect) => *rect,
+        None => return Ok(whole),
+    };
+
+    let valid = rect.left >= 0
+        && rect.top >= 0
+        && rect.left < rect.right
+        && rect.top < rect.bottom
+        && rect.right <= whole.right
+        && rect.bottom <= whole.bottom;
+
+    if valid {
+        Ok(rect)
+    } else {
+        error!("Rectangle is empty or outside of the surface");
+        Err(Error::InvalidCall)
+    }
+}
+
+/// Checks that a transform type is one which can be set by the app.
+fn check_transform_type(ty: D3DTRANSFORMSTATETYPE) -> Result<(), Error> {
+    // The world matrices are in the range [256; 511].
+    let valid = (D3DTS_VIEW <= ty && ty <= D3DTS_PROJECTION)
+        || (D3DTS_TEXTURE0 <= ty && ty <= D3DTS_TEXTURE7)
+        || (256 <= ty && ty <= 511);
+
+    if valid {
+        Ok(())
+    } else {
+        error!("Invalid transform type: {}", ty);
+        Err(Error::InvalidCall)
     }
 }
@@ -0,0 +1,76 @@
+use std::mem;
+
+use winapi::um::minwinbase::CRITICAL_SECTION;
+use winapi::um::synchapi;
+
+/// Serializes the calls made to a device from multiple threads.
+///
+/// D3D9 only makes devices thread-safe if they were created with
+/// `D3DCREATE_MULTITHREADED`, so single-threaded apps don't pay for the locking.
+///
+/// A critical section is used since it can be entered again by the thread owning it,
+/// which happens when device functions call each other.
+pub struct DeviceLock {
+    section: Option<Box<CRITICAL_SECTION>>,
+}
+
+impl DeviceLock {
+    /// Creates a new lock, which only does anything if `enabled` is true.
+    pub fn new(enabled: bool) -> Self {
+        let section = if enabled {
+            unsafe {
+                // The critical section must not move after being initialized.
+                let mut section = Box::new(mem::zeroed());
+                synchapi::InitializeCriticalSection(&mut *section);
+                Some(section)
+            }
+        } else {
+            None
+        };
+
+        Self { section }
+    }
+
+    /// Acquires the lock, which is held until the returned guard is dropped.
+    pub fn lock(&self) -> DeviceLockGuard {
+        let section = self
+            .section
+            .as_ref()
+            .map(|section| &**section as *const _ as *mut CRITICAL_SECTION);
+
+        if let Some(section) = section {
+            unsafe {
+                synchapi::EnterCriticalSection(section);
+            }
+        }
+
+        DeviceLockGuard { section }
+    }
+}
+
+impl Drop for DeviceLock {
+    fn drop(&mut self) {
+        if let Some(ref mut section) = self.section {
+            unsafe {
+                synchapi::DeleteCriticalSection(&mut **section);
+            }
+        }
+    }
+}
+
+/// Releases a `DeviceLock` when dropped.
+///
+/// The guard doesn't borrow the device, so that the device can still be modified while it is held.
+pub struct DeviceLockGuard {
+    section: Option<*mut CRITICAL_SECTION>,
+}
+
+impl Drop for DeviceLockGuard {
+    fn drop(&mut self) {
+        if let Some(section) = self.section {
+            unsafe {
+                synchapi::LeaveCriticalSection(section);
+            }
+        }
+    }
+}
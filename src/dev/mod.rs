@@ -4,6 +4,12 @@
 mod device;
 pub use self::device::*;
 
+mod cursor;
+pub use self::cursor::*;
+
+mod lock;
+pub use self::lock::*;
+
 mod swapchain;
 pub use self::swapchain::*;
 
@@ -13,6 +19,9 @@ pub use self::resource::*;
 mod surface;
 pub use self::surface::*;
 
+mod volume;
+pub use self::volume::*;
+
 mod texture;
 pub use self::texture::*;
 
@@ -23,3 +32,6 @@ pub use self::shader::*;
 
 mod buffer;
 pub use self::buffer::*;
+
+mod query;
+pub use self::query::*;
@@ -0,0 +1,219 @@
+use std::sync::atomic::AtomicU32;
+use std::{mem, ptr};
+
+use winapi::ctypes::c_void;
+use winapi::shared::{d3d9::*, d3d9types::*, minwindef::BOOL};
+use winapi::um::d3d11::*;
+use winapi::um::unknwnbase::{IUnknown, IUnknownVtbl};
+
+use com_impl::{implementation, interface, ComInterface};
+use comptr::ComPtr;
+
+use crate::core::*;
+use crate::Error;
+
+use super::Device;
+
+/// Asynchronous query for information from the GPU.
+#[interface(IDirect3DQuery9)]
+pub struct Query {
+    refs: AtomicU32,
+    device: *const Device,
+    ty: D3DQUERYTYPE,
+    query: ComPtr<ID3D11Query>,
+    // Timestamps are only valid inside of a disjoint query,
+    // so we wrap every timestamp query in its own.
+    disjoint: Option<ComPtr<ID3D11Query>>,
+}
+
+impl Query {
+    /// Creates a new query of a certain type.
+    ///
+    /// Returns `NotAvailable` for the types which are not supported.
+    pub fn new(device: &Device, ty: D3DQUERYTYPE) -> Result<ComPtr<Self>, Error> {
+        let dx11 = device.dx11_device();
+
+        let (query, disjoint) = match ty {
+            D3DQUERYTYPE_TIMESTAMP => (
+                create_query(dx11, D3D11_QUERY_TIMESTAMP)?,
+                Some(create_query(dx11, D3D11_QUERY_TIMESTAMP_DISJOINT)?),
+            ),
+            // The frequency is also retrieved from a disjoint query.
+            D3DQUERYTYPE_TIMESTAMPDISJOINT | D3DQUERYTYPE_TIMESTAMPFREQ => {
+                (create_query(dx11, D3D11_QUERY_TIMESTAMP_DISJOINT)?, None)
+            }
+            _ => return Err(Error::NotAvailable),
+        };
+
+        let query = Self {
+            __vtable: Box::new(Self::create_vtable()),
+            refs: AtomicU32::new(1),
+            device,
+            ty,
+            query,
+            disjoint,
+        };
+
+        Ok(unsafe { new_com_interface(query) })
+    }
+
+    /// Checks if a query of a certain type can be created.
+    pub fn is_supported(ty: D3DQUERYTYPE) -> bool {
+        match ty {
+            D3DQUERYTYPE_TIMESTAMP
+            | D3DQUERYTYPE_TIMESTAMPDISJOINT
+            | D3DQUERYTYPE_TIMESTAMPFREQ => true,
+            _ => false,
+        }
+    }
+
+    fn device(&self) -> &Device {
+        unsafe { &*self.device }
+    }
+
+    /// Returns the size of the data returned by this query.
+    fn data_size(&self) -> u32 {
+        match self.ty {
+            D3DQUERYTYPE_TIMESTAMPDISJOINT => mem::size_of::<BOOL>() as u32,
+            _ => mem::size_of::<u64>() as u32,
+        }
+    }
+}
+
+impl_iunknown!(struct Query: IUnknown, IDirect3DQuery9);
+
+#[implementation(IDirect3DQuery9)]
+impl Query {
+    /// Retrieves the device which created this query.
+    fn get_device(&self, ret: *mut *mut Device) -> Error {
+        let ret = if_error!(check_mut_ref(ret));
+        *ret = com_ref(self.device);
+        Error::Success
+    }
+
+    /// Retrieves the type of this query.
+    fn get_type(&self) -> D3DQUERYTYPE {
+        self.ty
+    }
+
+    /// Retrieves the size of the data returned by this query.
+    fn get_data_size(&self) -> u32 {
+        self.data_size()
+    }
+
+    /// Marks the beginning or the end of the commands this query applies to.
+    fn issue(&self, flags: u32) -> Error {
+        let _lock = self.device().lock();
+        let ctx = self.device().device_context();
+        let query = self.query.as_mut() as *mut ID3D11Asynchronous;
+
+        unsafe {
+            match (self.ty, flags) {
+                (D3DQUERYTYPE_TIMESTAMPDISJOINT, D3DISSUE_BEGIN) => ctx.Begin(query),
+                (D3DQUERYTYPE_TIMESTAMPDISJOINT, D3DISSUE_END) => ctx.End(query),
+                (D3DQUERYTYPE_TIMESTAMPFREQ, D3DISSUE_END) => {
+                    ctx.Begin(query);
+                    ctx.End(query);
+                }
+                (D3DQUERYTYPE_TIMESTAMP, D3DISSUE_END) => {
+                    let disjoint = self.disjoint.as_ref().unwrap().as_mut();
+                    let disjoint = disjoint as *mut ID3D11Asynchronous;
+
+                    ctx.Begin(disjoint);
+                    ctx.End(query);
+                    ctx.End(disjoint);
+                }
+                _ => {
+                    error!("Invalid issue flags {} for query type {}", flags, self.ty);
+                    return Error::InvalidCall;
+                }
+            }
+        }
+
+        Error::Success
+    }
+
+    /// Retrieves the data of this query.
+    ///
+    /// Returns `False` if the data is not available yet.
+    fn get_data(&self, data: *mut c_void, size: u32, flags: u32) -> Error {
+        let _lock = self.device().lock();
+
+        if !data.is_null() && size < self.data_size() {
+            return Error::InvalidCall;
+        }
+
+        let ctx = self.device().device_context();
+
+        let get_flags = if flags & D3DGETDATA_FLUSH != 0 {
+            0
+        } else {
+            D3D11_ASYNC_GETDATA_DONOTFLUSH
+        };
+
+        let get_data = |query: &ComPtr<ID3D11Query>, ret: *mut c_void, size: usize| unsafe {
+            let query = query.as_mut() as *mut ID3D11Asynchronous;
+            ctx.GetData(query, ret, size as u32, get_flags)
+        };
+
+        let mut disjoint: D3D11_QUERY_DATA_TIMESTAMP_DISJOINT = unsafe { mem::zeroed() };
+        let mut timestamp: u64 = 0;
+
+        let result = match self.ty {
+            D3DQUERYTYPE_TIMESTAMP => {
+                let companion = self.disjoint.as_ref().unwrap();
+                match get_data(companion, ptr::null_mut(), 0) {
+                    0 => get_data(
+                        &self.query,
+                        &mut timestamp as *mut _ as *mut c_void,
+                        mem::size_of_val(&timestamp),
+                    ),
+                    result => result,
+                }
+            }
+            _ => get_data(
+                &self.query,
+                &mut disjoint as *mut _ as *mut c_void,
+                mem::size_of_val(&disjoint),
+            ),
+        };
+
+        match result {
+            0 => (),
+            // S_FALSE, the GPU has not finished yet.
+            1 => return Error::False,
+            _ => if_not_success!(check_hresult(result, "Failed to get query data")),
+        }
+
+        if data.is_null() {
+            return Error::Success;
+        }
+
+        unsafe {
+            match self.ty {
+                D3DQUERYTYPE_TIMESTAMP => *(data as *mut u64) = timestamp,
+                D3DQUERYTYPE_TIMESTAMPFREQ => *(data as *mut u64) = disjoint.Frequency,
+                _ => *(data as *mut BOOL) = disjoint.Disjoint,
+            }
+        }
+
+        Error::Success
+    }
+}
+
+/// Creates a D3D11 query of a certain type.
+fn create_query(device: &ID3D11Device, ty: D3D11_QUERY) -> Result<ComPtr<ID3D11Query>, Error> {
+    let desc = D3D11_QUERY_DESC {
+        Query: ty,
+        MiscFlags: 0,
+    };
+
+    let query = unsafe {
+        let mut ptr = ptr::null_mut();
+        let result = device.CreateQuery(&desc, &mut ptr);
+        if_not_success_err!(check_hresult(result, "Failed to create query"));
+        ComPtr::new(ptr)
+    };
+
+    Ok(query)
+}
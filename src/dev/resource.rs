@@ -1,10 +1,15 @@
 use winapi::{
-    shared::d3d9::*,
+    shared::{d3d9::*, guiddef::GUID},
     um::unknwnbase::{IUnknown, IUnknownVtbl},
 };
 
 use com_impl::implementation;
+use comptr::ComPtr;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::c_void;
 use std::sync::atomic::AtomicU32;
+use std::{mem, ptr, slice};
 
 use crate::core::*;
 use crate::d3d11;
@@ -27,6 +32,10 @@ pub struct Resource {
     /// Priority of this resource.
     /// Higher value indicates this resource should be evicted last from VRAM.
     priority: u32,
+    /// The device's generation when this resource was created.
+    generation: u32,
+    /// Data attached to this resource by the app.
+    private_data: PrivateData,
 }
 
 impl Resource {
@@ -44,6 +53,8 @@ impl Resource {
             pool,
             ty,
             priority: 0,
+            generation: unsafe { (*device).generation() },
+            private_data: PrivateData::default(),
         }
     }
 
@@ -62,10 +73,22 @@ impl Resource {
         self.usage
     }
 
+    /// Retrieves the type of this resource.
+    pub fn resource_type(&self) -> ResourceType {
+        self.ty
+    }
+
     /// Retrieves the memory pool in which this resource belongs.
     pub fn pool(&self) -> MemoryPool {
         self.pool
     }
+
+    /// Checks if this resource was lost when the device was reset.
+    ///
+    /// Only resources in the default pool are lost, the rest are recreated by the runtime.
+    pub fn is_lost(&self) -> bool {
+        self.pool == MemoryPool::Default && self.generation != self.device().generation()
+    }
 }
 
 /*impl ComInterface<IUnknownVtbl> for Resource {
@@ -110,16 +133,25 @@ impl Resource {
         Error::Success
     }
 
-    fn set_private_data(self: &Thunk) {
-        unimplemented!()
+    /// Attaches some data or an interface to this resource.
+    fn set_private_data(
+        self: &Thunk,
+        guid: &GUID,
+        data: *const c_void,
+        size: u32,
+        flags: u32,
+    ) -> Error {
+        self.private_data.set(guid, data, size, flags)
     }
 
-    fn get_private_data(self: &Thunk) {
-        unimplemented!()
+    /// Retrieves data previously attached to this resource.
+    fn get_private_data(self: &Thunk, guid: &GUID, data: *mut c_void, size: *mut u32) -> Error {
+        self.private_data.get(guid, data, size)
     }
 
-    fn free_private_data(self: &Thunk) {
-        unimplemented!()
+    /// Detaches data from this resource, releasing it if it is an interface.
+    fn free_private_data(self: &Thunk, guid: &GUID) -> Error {
+        self.private_data.free(guid)
     }
 
     // TODO: the functions below could be used to improve performance.
@@ -141,3 +173,105 @@ impl Resource {
         info!("Resource pre-loading is not yet implemented");
     }
 }
+
+/// Data attached to an object by the app.
+enum PrivateDataEntry {
+    Bytes(Box<[u8]>),
+    // Interfaces are kept alive for as long as they are attached.
+    Interface(ComPtr<IUnknown>),
+}
+
+impl PrivateDataEntry {
+    /// Returns the size of the data returned by `GetPrivateData`.
+    fn size(&self) -> usize {
+        match self {
+            PrivateDataEntry::Bytes(bytes) => bytes.len(),
+            PrivateDataEntry::Interface(_) => mem::size_of::<*mut IUnknown>(),
+        }
+    }
+}
+
+/// Stores the data attached to an object with `SetPrivateData`, identified by GUIDs.
+#[derive(Default)]
+pub struct PrivateData {
+    entries: RefCell<HashMap<(u32, u16, u16, [u8; 8]), PrivateDataEntry>>,
+}
+
+/// Converts a GUID to a key which can be hashed.
+fn guid_key(guid: &GUID) -> (u32, u16, u16, [u8; 8]) {
+    (guid.Data1, guid.Data2, guid.Data3, guid.Data4)
+}
+
+impl PrivateData {
+    /// Attaches some data, replacing the data previously attached with the same GUID.
+    ///
+    /// With `D3DSPD_IUNKNOWN`, `data` is an interface, which gets a new reference.
+    pub fn set(&self, guid: &GUID, data: *const c_void, size: u32, flags: u32) -> Error {
+        if data.is_null() {
+            return Error::InvalidCall;
+        }
+
+        let entry = if flags & D3DSPD_IUNKNOWN != 0 {
+            if size as usize != mem::size_of::<*mut IUnknown>() {
+                return Error::InvalidCall;
+            }
+
+            PrivateDataEntry::Interface(ComPtr::new(com_ref(data as *const IUnknown)))
+        } else {
+            let bytes = unsafe { slice::from_raw_parts(data as *const u8, size as usize) };
+            PrivateDataEntry::Bytes(bytes.into())
+        };
+
+        self.entries.borrow_mut().insert(guid_key(guid), entry);
+
+        Error::Success
+    }
+
+    /// Copies attached data to a buffer.
+    ///
+    /// If the buffer is null, only the size of the data is returned.
+    /// Interfaces get a new reference, which the app must release.
+    pub fn get(&self, guid: &GUID, data: *mut c_void, size: *mut u32) -> Error {
+        let size = if_error!(check_mut_ref(size));
+
+        let entries = self.entries.borrow();
+        let entry = match entries.get(&guid_key(guid)) {
+            Some(entry) => entry,
+            None => return Error::NotFound,
+        };
+
+        let needed = entry.size() as u32;
+
+        if data.is_null() {
+            *size = needed;
+            return Error::Success;
+        }
+
+        if *size < needed {
+            *size = needed;
+            return Error::MoreData;
+        }
+
+        unsafe {
+            match entry {
+                PrivateDataEntry::Bytes(bytes) => {
+                    ptr::copy_nonoverlapping(bytes.as_ptr(), data as *mut u8, bytes.len());
+                }
+                PrivateDataEntry::Interface(iface) => {
+                    *(data as *mut *mut IUnknown) = com_ref(iface.as_mut());
+                }
+            }
+        }
+
+        *size = needed;
+        Error::Success
+    }
+
+    /// Detaches data, releasing it if it is an interface.
+    pub fn free(&self, guid: &GUID) -> Error {
+        match self.entries.borrow_mut().remove(&guid_key(guid)) {
+            Some(_) => Error::Success,
+            None => Error::NotFound,
+        }
+    }
+}
@@ -1,40 +1,73 @@
-use std::slice;
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::sync::atomic::AtomicU32;
+use std::{ptr, slice};
 
-use winapi::shared::{d3d9::*, d3d9types::*};
+use winapi::shared::{d3d9::*, d3d9types::*, dxgiformat::*};
+use winapi::um::d3d11::*;
 use winapi::um::unknwnbase::{IUnknown, IUnknownVtbl};
 
 use com_impl::{implementation, interface, ComInterface};
 use comptr::ComPtr;
 
+use crate::shader::{self, d3d_decl_usage_to_semantic, PixelVariant, ShaderKind, ShaderVersion};
 use crate::{core::*, Error};
 
 use super::Device;
 use std::ffi::c_void;
-use winapi::um::d3d11::ID3D11VertexShader;
 
 /// Given a pointer to an array of tokens (forming up a shader),
-/// returns a box containing the tokens.
-fn tokens_to_box(tokens: *const u32) -> Box<[u32]> {
+/// returns a box containing the tokens, and the shader's version.
+///
+/// The end of the shader is found by skipping over each instruction and comment,
+/// since their parameters might contain the same value as the end token.
+fn tokens_to_box(
+    tokens: *const u32,
+    kind: ShaderKind,
+) -> Result<(Box<[u32]>, ShaderVersion), Error> {
+    let version = check_version(unsafe { *tokens }, kind)?;
+
     let tokens = unsafe {
-        // We don't know how long the shader will be.
-        let mut len = 0;
+        // Skip the version token.
+        let mut len = 1;
 
+        // We go until we find the end token, which is included.
         loop {
+            let token = *tokens.add(len);
             len += 1;
-            // We go until we find the end token.
-            if *tokens.offset(len) == 0x0000_FFFF {
-                break;
+
+            match shader::instruction_len(version, token)? {
+                Some(params) => len += params,
+                None => break,
             }
         }
 
-        // Ensure the end token is included.
-        len += 1;
-
-        std::slice::from_raw_parts(tokens, len as usize)
+        slice::from_raw_parts(tokens, len)
     };
 
-    tokens.into()
+    Ok((tokens.into(), version))
+}
+
+/// Checks that a shader's version token is the one of a supported shader model.
+fn check_version(token: u32, kind: ShaderKind) -> Result<ShaderVersion, Error> {
+    let version = ShaderVersion::from_token(token)
+        .filter(|version| version.kind == kind)
+        .ok_or_else(|| {
+            error!("Invalid {:?} shader version token: {:#x}", kind, token);
+            Error::InvalidCall
+        })?;
+
+    if !version.is_supported() {
+        error!("Unsupported shader model: {}", version);
+        if version.kind == ShaderKind::Pixel && version.major == 1 {
+            error!("Pixel shaders must use shader model 2.0 or above");
+        }
+        return Err(Error::InvalidCall);
+    }
+
+    info!("Creating a {} shader", version);
+
+    Ok(version)
 }
 
 macro_rules! impl_shader {
@@ -74,20 +107,29 @@ pub struct VertexShader {
     refs: AtomicU32,
     device: *const Device,
     code: Box<[u32]>,
+    version: ShaderVersion,
     dx11: ComPtr<ID3D11VertexShader>,
+    // Input signature of the translated shader, needed to create input layouts.
+    signature: Box<[u8]>,
 }
 
 impl VertexShader {
     /// Create a new vertex shader.
     pub fn new(device: &Device, func: *const u32) -> Result<ComPtr<Self>, Error> {
-        let code = tokens_to_box(func);
+        let (code, version) = tokens_to_box(func, ShaderKind::Vertex)?;
+
+        let bytecode =
+            device
+                .shader_cache()
+                .get(&code, ShaderKind::Vertex, PixelVariant::default())?;
+        let signature = shader::input_signature(&bytecode)?;
 
         let dx11 = unsafe {
-            let mut p_vs = core::ptr::null_mut();
+            let mut p_vs = ptr::null_mut();
             let result = device.dx11_device().CreateVertexShader(
-                code.as_ptr() as *const c_void,
-                code.len() * 4,
-                core::ptr::null_mut(),
+                bytecode.as_ptr() as *const c_void,
+                bytecode.len(),
+                ptr::null_mut(),
                 &mut p_vs,
             );
 
@@ -100,7 +142,9 @@ impl VertexShader {
             refs: AtomicU32::new(1),
             device,
             code,
+            version,
             dx11,
+            signature,
         };
 
         Ok(unsafe { new_com_interface(vs) })
@@ -109,6 +153,16 @@ impl VertexShader {
     pub fn get_dx11(&self) -> &ComPtr<ID3D11VertexShader> {
         &self.dx11
     }
+
+    /// Retrieves the shader model this shader was written for.
+    pub fn version(&self) -> ShaderVersion {
+        self.version
+    }
+
+    /// Retrieves the input signature of this shader.
+    pub fn input_signature(&self) -> &[u8] {
+        &self.signature
+    }
 }
 
 impl_iunknown!(struct VertexShader: IUnknown, IDirect3DVertexShader9);
@@ -121,39 +175,95 @@ pub struct PixelShader {
     refs: AtomicU32,
     device: *const Device,
     code: Box<[u32]>,
+    // The D3D11 shaders compiled for each of the variants used so far.
+    variants: RefCell<HashMap<PixelVariant, ComPtr<ID3D11PixelShader>>>,
 }
 
 impl PixelShader {
     /// Create a new pixel shader.
     pub fn new(device: &Device, func: *const u32) -> Result<ComPtr<Self>, Error> {
-        let code = tokens_to_box(func);
+        let (code, _) = tokens_to_box(func, ShaderKind::Pixel)?;
 
         let ps = Self {
             __vtable: Box::new(Self::create_vtable()),
             refs: AtomicU32::new(1),
             device,
             code,
+            variants: RefCell::new(HashMap::new()),
         };
 
+        // Translate the most common variant right away, to report invalid shaders early.
+        ps.get_dx11(PixelVariant::default())?;
+
         Ok(unsafe { new_com_interface(ps) })
     }
+
+    /// Retrieves the D3D11 shader for a certain variant, translating it if needed.
+    pub fn get_dx11(&self, variant: PixelVariant) -> Result<ComPtr<ID3D11PixelShader>, Error> {
+        if let Some(ps) = self.variants.borrow().get(&variant) {
+            return Ok(ps.clone());
+        }
+
+        let device = unsafe { &*self.device };
+
+        let bytecode = device
+            .shader_cache()
+            .get(&self.code, ShaderKind::Pixel, variant)?;
+        let ps = device.dx11_device().create_pixel_shader(&bytecode)?;
+
+        self.variants.borrow_mut().insert(variant, ps.clone());
+
+        Ok(ps)
+    }
 }
 
 impl_iunknown!(struct PixelShader: IUnknown, IDirect3DPixelShader9);
 
 impl_shader!(PixelShader, IDirect3DPixelShader9);
 
+/// Converts a vertex element's type to the equivalent DXGI format.
+fn d3d_decl_type_to_dxgi(ty: D3DDECLTYPE) -> Option<DXGI_FORMAT> {
+    Some(match ty {
+        D3DDECLTYPE_FLOAT1 => DXGI_FORMAT_R32_FLOAT,
+        D3DDECLTYPE_FLOAT2 => DXGI_FORMAT_R32G32_FLOAT,
+        D3DDECLTYPE_FLOAT3 => DXGI_FORMAT_R32G32B32_FLOAT,
+        D3DDECLTYPE_FLOAT4 => DXGI_FORMAT_R32G32B32A32_FLOAT,
+        // D3DCOLOR is stored as BGRA in memory, reading it as such
+        // swizzles it to the RGBA order shaders expect.
+        D3DDECLTYPE_D3DCOLOR => DXGI_FORMAT_B8G8R8A8_UNORM,
+        D3DDECLTYPE_UBYTE4 => DXGI_FORMAT_R8G8B8A8_UINT,
+        D3DDECLTYPE_SHORT2 => DXGI_FORMAT_R16G16_SINT,
+        D3DDECLTYPE_SHORT4 => DXGI_FORMAT_R16G16B16A16_SINT,
+        D3DDECLTYPE_UBYTE4N => DXGI_FORMAT_R8G8B8A8_UNORM,
+        D3DDECLTYPE_SHORT2N => DXGI_FORMAT_R16G16_SNORM,
+        D3DDECLTYPE_SHORT4N => DXGI_FORMAT_R16G16B16A16_SNORM,
+        D3DDECLTYPE_USHORT2N => DXGI_FORMAT_R16G16_UNORM,
+        D3DDECLTYPE_USHORT4N => DXGI_FORMAT_R16G16B16A16_UNORM,
+        // UDEC3 has the same bit layout. There is no signed equivalent for DEC3N,
+        // so vertex declarations which use it are rejected.
+        D3DDECLTYPE_UDEC3 => DXGI_FORMAT_R10G10B10A2_UINT,
+        D3DDECLTYPE_FLOAT16_2 => DXGI_FORMAT_R16G16_FLOAT,
+        D3DDECLTYPE_FLOAT16_4 => DXGI_FORMAT_R16G16B16A16_FLOAT,
+        _ => return None,
+    })
+}
+
 /// Declaration of a vertex shader's inputs.
 #[interface(IDirect3DVertexDeclaration9)]
 pub struct VertexDeclaration {
     refs: AtomicU32,
     device: *const Device,
     elems: Box<[D3DVERTEXELEMENT9]>,
+    // Input layouts created from this declaration, keyed by the input signature
+    // of the shader they were created for.
+    layouts: RefCell<HashMap<Box<[u8]>, ComPtr<ID3D11InputLayout>>>,
 }
 
 impl VertexDeclaration {
     /// Creates a new vertex declaration.
-    pub fn new(device: &Device, elems: *const D3DVERTEXELEMENT9) -> ComPtr<Self> {
+    ///
+    /// Returns an error if an element has a type which cannot be read by D3D11.
+    pub fn new(device: &Device, elems: *const D3DVERTEXELEMENT9) -> Result<ComPtr<Self>, Error> {
         let elems = unsafe {
             let mut count = 0;
             let mut ptr = elems;
@@ -185,14 +295,96 @@ impl VertexDeclaration {
             Box::from(elems)
         };
 
+        let unsupported = elems.iter().find(|elem| {
+            elem.Type as u32 != D3DDECLTYPE_UNUSED
+                && d3d_decl_type_to_dxgi(elem.Type as u32).is_none()
+        });
+        if let Some(elem) = unsupported {
+            error!("Unsupported vertex element type: {}", elem.Type);
+            return Err(Error::InvalidCall);
+        }
+
         let vd = Self {
             __vtable: Box::new(Self::create_vtable()),
             refs: AtomicU32::new(1),
             device,
             elems,
+            layouts: RefCell::new(HashMap::new()),
+        };
+
+        Ok(unsafe { new_com_interface(vd) })
+    }
+
+    /// Retrieves the elements of this declaration.
+    pub fn elements(&self) -> &[D3DVERTEXELEMENT9] {
+        &self.elems
+    }
+
+    /// Checks if this declaration contains pre-transformed positions,
+    /// which are already in screen space.
+    pub fn is_pre_transformed(&self) -> bool {
+        self.elems
+            .iter()
+            .any(|elem| elem.Usage as u32 == D3DDECLUSAGE_POSITIONT)
+    }
+
+    /// Retrieves an input layout which maps this declaration to the inputs of a vertex shader.
+    ///
+    /// `signature` must be the input signature of a compiled vertex shader.
+    pub fn input_layout(&self, signature: &[u8]) -> Result<ComPtr<ID3D11InputLayout>, Error> {
+        if let Some(layout) = self.layouts.borrow().get(signature) {
+            return Ok(layout.clone());
+        }
+
+        let descs: Vec<_> = self
+            .elems
+            .iter()
+            .filter(|elem| elem.Type as u32 != D3DDECLTYPE_UNUSED)
+            .map(|elem| {
+                let format = d3d_decl_type_to_dxgi(elem.Type as u32).ok_or_else(|| {
+                    error!("Unsupported vertex element type: {}", elem.Type);
+                    Error::InvalidCall
+                })?;
+
+                if elem.Method as u32 != D3DDECLMETHOD_DEFAULT {
+                    warn!("Vertex element tessellation methods are not supported");
+                }
+
+                Ok(D3D11_INPUT_ELEMENT_DESC {
+                    SemanticName: d3d_decl_usage_to_semantic(elem.Usage as u32).as_ptr()
+                        as *const _,
+                    SemanticIndex: elem.UsageIndex as u32,
+                    Format: format,
+                    InputSlot: elem.Stream as u32,
+                    AlignedByteOffset: elem.Offset as u32,
+                    InputSlotClass: D3D11_INPUT_PER_VERTEX_DATA,
+                    InstanceDataStepRate: 0,
+                })
+            })
+            .collect::<Result<_, Error>>()?;
+
+        let device = unsafe { &*self.device };
+
+        let layout = unsafe {
+            let mut ptr = ptr::null_mut();
+
+            let result = device.dx11_device().CreateInputLayout(
+                descs.as_ptr(),
+                descs.len() as u32,
+                signature.as_ptr() as *const c_void,
+                signature.len(),
+                &mut ptr,
+            );
+            if_not_success_err!(check_hresult(result, "Failed to create input layout"));
+
+            ComPtr::new(ptr)
         };
 
-        unsafe { new_com_interface(vd) }
+        self.layouts
+            .borrow_mut()
+            .insert(signature.into(), layout.clone());
+
+        Ok(layout)
     }
 }
 
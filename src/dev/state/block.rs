@@ -1,31 +1,202 @@
+use std::collections::HashMap;
 use std::sync::atomic::AtomicU32;
 
-use winapi::shared::{d3d9::*, d3d9types::*};
+use winapi::shared::{d3d9::*, d3d9types::*, windef::RECT};
 use winapi::um::unknwnbase::{IUnknown, IUnknownVtbl};
 
+use nalgebra::{self as na, Matrix4};
+
 use com_impl::{implementation, interface, ComInterface};
 use comptr::ComPtr;
 
-use crate::dev::Device;
+use crate::d3d11::Vec4;
+use crate::dev::shader::VertexDeclaration;
+use crate::dev::*;
+use crate::shader::{MAX_BOOL_CONSTANTS, MAX_CLIP_PLANES, MAX_FLOAT_CONSTANTS, MAX_INT_CONSTANTS};
 use crate::{core::*, Error};
 
+use super::{PixelState, StreamSource, VertexState, MAX_STREAMS};
+
+/// Number of texture stages whose state is captured by the predefined state blocks.
+const MAX_TEXTURE_STAGES: u32 = 8;
+
+/// The shader constants recorded by a state block, keyed by register.
+#[derive(Clone, Default)]
+pub struct RecordedConstants {
+    pub float: HashMap<u32, Vec4<f32>>,
+    pub int: HashMap<u32, Vec4<i32>>,
+    // Booleans are stored in the first component of each register.
+    pub bool: HashMap<u32, Vec4<u32>>,
+}
+
+impl RecordedConstants {
+    /// Creates a set containing every constant register.
+    fn all() -> Self {
+        Self {
+            float: (0..MAX_FLOAT_CONSTANTS).map(|i| (i, [0.0; 4])).collect(),
+            int: (0..MAX_INT_CONSTANTS).map(|i| (i, [0; 4])).collect(),
+            bool: (0..MAX_BOOL_CONSTANTS).map(|i| (i, [0; 4])).collect(),
+        }
+    }
+}
+
+/// The portion of a device's state recorded by a state block.
+///
+/// Only the state which was set while recording (or which belongs to a predefined
+/// state block type) is stored, everything else is left untouched when applying it.
+#[derive(Clone, Default)]
+pub struct RecordedState {
+    pub render_states: HashMap<D3DRENDERSTATETYPE, u32>,
+    pub sampler_states: HashMap<(u32, D3DSAMPLERSTATETYPE), u32>,
+    pub texture_stage_states: HashMap<(u32, D3DTEXTURESTAGESTATETYPE), u32>,
+    pub textures: HashMap<u32, Option<ComPtr<BaseTexture>>>,
+    pub transforms: HashMap<D3DTRANSFORMSTATETYPE, Matrix4<f32>>,
+    pub vertex_shader: Option<Option<ComPtr<VertexShader>>>,
+    pub pixel_shader: Option<Option<ComPtr<PixelShader>>>,
+    pub vs_constants: RecordedConstants,
+    pub ps_constants: RecordedConstants,
+    // The vertex declaration, and the FVF code it was created from, if any.
+    pub vertex_decl: Option<(*const VertexDeclaration, u32)>,
+    pub streams: HashMap<u32, StreamSource>,
+    pub indices: Option<Option<ComPtr<IndexBuffer>>>,
+    pub viewport: Option<D3DVIEWPORT9>,
+    pub scissor_rect: Option<RECT>,
+    pub material: Option<D3DMATERIAL9>,
+    pub lights: HashMap<u32, D3DLIGHT9>,
+    pub light_enables: HashMap<u32, bool>,
+    pub clip_planes: HashMap<u32, [f32; 4]>,
+}
+
+impl RecordedState {
+    /// Creates a set containing the state captured by one of the predefined state block types.
+    ///
+    /// The values are placeholders, which must then be captured from the device.
+    /// For the lists of states, see:
+    /// https://docs.microsoft.com/en-us/windows/desktop/direct3d9/state-blocks-save-and-restore-state
+    fn predefined(ty: D3DSTATEBLOCKTYPE, lights: &[u32]) -> Result<Self, Error> {
+        let mut state = Self::default();
+
+        let (vertex, pixel) = match ty {
+            D3DSBT_ALL => (true, true),
+            D3DSBT_VERTEXSTATE => (true, false),
+            D3DSBT_PIXELSTATE => (false, true),
+            _ => {
+                error!("Invalid state block type: {}", ty);
+                return Err(Error::InvalidCall);
+            }
+        };
+
+        if vertex {
+            state.add_states(
+                VertexState::RENDER_STATES,
+                (0..VertexState::MAX_SAMPLERS as u32).map(|i| i + D3DVERTEXTEXTURESAMPLER0),
+                VertexState::SAMPLER_STATES,
+                VertexState::TEXTURE_STATES,
+            );
+
+            state.vertex_shader = Some(None);
+            state.vs_constants = RecordedConstants::all();
+            state.vertex_decl = Some((std::ptr::null(), 0));
+
+            for &index in lights {
+                state.lights.insert(index, unsafe { std::mem::zeroed() });
+                state.light_enables.insert(index, false);
+            }
+        }
+
+        if pixel {
+            state.add_states(
+                PixelState::RENDER_STATES,
+                0..PixelState::MAX_SAMPLERS as u32,
+                PixelState::SAMPLER_STATES,
+                PixelState::TEXTURE_STATES,
+            );
+
+            state.pixel_shader = Some(None);
+            state.ps_constants = RecordedConstants::all();
+        }
+
+        // The remaining state is only captured by blocks capturing everything.
+        if vertex && pixel {
+            let stages = (0..16).chain(D3DVERTEXTEXTURESAMPLER0..=D3DVERTEXTEXTURESAMPLER3);
+            state.textures = stages.map(|stage| (stage, None)).collect();
+
+            let world = (0..256).map(|i| 256 + i);
+            let textures = D3DTS_TEXTURE0..=D3DTS_TEXTURE7;
+            state.transforms = [D3DTS_VIEW, D3DTS_PROJECTION]
+                .iter()
+                .cloned()
+                .chain(textures)
+                .chain(world)
+                .map(|ty| (ty, na::one()))
+                .collect();
+
+            state.streams = (0..MAX_STREAMS as u32)
+                .map(|i| (i, StreamSource::default()))
+                .collect();
+            state.indices = Some(None);
+
+            state.viewport = Some(unsafe { std::mem::zeroed() });
+            state.scissor_rect = Some(unsafe { std::mem::zeroed() });
+            state.material = Some(unsafe { std::mem::zeroed() });
+
+            state.clip_planes = (0..MAX_CLIP_PLANES as u32).map(|i| (i, [0.0; 4])).collect();
+        }
+
+        Ok(state)
+    }
+
+    /// Adds the render states, and the sampler and texture stage states of some samplers.
+    fn add_states(
+        &mut self,
+        render_states: &[D3DRENDERSTATETYPE],
+        samplers: impl Iterator<Item = u32>,
+        sampler_states: &[D3DSAMPLERSTATETYPE],
+        texture_states: &[D3DTEXTURESTAGESTATETYPE],
+    ) {
+        self.render_states
+            .extend(render_states.iter().map(|&ty| (ty, 0)));
+
+        for sampler in samplers {
+            self.sampler_states
+                .extend(sampler_states.iter().map(|&ty| ((sampler, ty), 0)));
+        }
+
+        for stage in 0..MAX_TEXTURE_STAGES {
+            self.texture_stage_states
+                .extend(texture_states.iter().map(|&ty| ((stage, ty), 0)));
+        }
+    }
+}
+
 /// Object which records some portions of a device's state.
 #[interface(IDirect3DStateBlock9)]
 pub struct StateBlock {
     refs: AtomicU32,
     device: *mut Device,
+    state: RecordedState,
 }
 
 impl StateBlock {
-    /// Creates a new empty state block.
-    pub fn new(device: &mut Device, _ty: D3DSTATEBLOCKTYPE) -> Result<ComPtr<Self>, Error> {
-        let _sb = Self {
+    /// Creates a new state block of one of the predefined types,
+    /// containing the current values of the device's state.
+    pub fn new(device: &mut Device, ty: D3DSTATEBLOCKTYPE) -> Result<ComPtr<Self>, Error> {
+        let mut state = RecordedState::predefined(ty, &device.light_indices())?;
+        device.capture_state(&mut state)?;
+
+        Ok(Self::from_recording(device, state))
+    }
+
+    /// Creates a state block containing some recorded state.
+    pub fn from_recording(device: &mut Device, state: RecordedState) -> ComPtr<Self> {
+        let sb = Self {
             __vtable: Box::new(Self::create_vtable()),
             refs: AtomicU32::new(1),
             device,
+            state,
         };
 
-        unimplemented!();
+        unsafe { new_com_interface(sb) }
     }
 }
 
@@ -42,11 +213,13 @@ impl StateBlock {
 
     /// Captures the current values for the state which is already in this block.
     fn capture(&mut self) -> Error {
-        unimplemented!()
+        let device = unsafe { &*self.device };
+        to_error_success!(device.capture_state(&mut self.state))
     }
 
     /// Applies the contained state to the parent device.
     fn apply(&self) -> Error {
-        unimplemented!()
+        let device = unsafe { &mut *self.device };
+        device.apply_state(&self.state)
     }
 }
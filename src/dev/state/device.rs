@@ -1,15 +1,32 @@
 use std::collections::HashMap;
-use std::{mem, ptr};
+use std::mem;
 
-use winapi::shared::d3d9types::*;
+use winapi::shared::{d3d9types::*, windef::RECT};
 
 use nalgebra::{self as na, Matrix4};
 
+use comptr::ComPtr;
+
 use crate::dev::shader::VertexDeclaration;
 use crate::dev::*;
+use crate::shader::{MAX_CLIP_PLANES, MAX_LIGHTS};
 
 use super::*;
 
+/// Number of texture stages: 16 for pixel shaders and 4 for vertex shaders.
+const MAX_TEXTURES: usize = 20;
+
+/// Maximum number of vertex streams which can be bound at the same time.
+pub const MAX_STREAMS: usize = 16;
+
+/// A vertex buffer bound to one of the input streams.
+#[derive(Clone, Default)]
+pub struct StreamSource {
+    pub buffer: Option<ComPtr<VertexBuffer>>,
+    pub offset: u32,
+    pub stride: u32,
+}
+
 /// Structure containing all render state.
 /// This includes pixel and vertex state.
 ///
@@ -18,10 +35,22 @@ use super::*;
 pub struct DeviceState {
     vertex: VertexState,
     pixel: PixelState,
-    textures: [*mut BaseTexture; 20],
+    textures: [Option<ComPtr<BaseTexture>>; MAX_TEXTURES],
     viewport: D3DVIEWPORT9,
+    scissor_rect: RECT,
     transforms: HashMap<D3DTRANSFORMSTATETYPE, Matrix4<f32>>,
     material: D3DMATERIAL9,
+    // Every light the app has set, keyed by index.
+    lights: HashMap<u32, D3DLIGHT9>,
+    // Indices of the enabled lights, in the order they were enabled.
+    enabled_lights: Vec<u32>,
+    // The user clip planes' equations, enabled through `D3DRS_CLIPPLANEENABLE`.
+    clip_planes: [[f32; 4]; MAX_CLIP_PLANES],
+    indices: Option<ComPtr<IndexBuffer>>,
+    // The app's shaders. If unset, the fixed function pipeline is emulated.
+    vertex_shader: Option<ComPtr<VertexShader>>,
+    pixel_shader: Option<ComPtr<PixelShader>>,
+    streams: [StreamSource; MAX_STREAMS],
 }
 
 impl DeviceState {
@@ -80,7 +109,7 @@ impl DeviceState {
         }
     }
 
-    pub fn set_texture(&mut self, mut stage: u32, texture: *mut BaseTexture) {
+    pub fn set_texture(&mut self, mut stage: u32, texture: Option<ComPtr<BaseTexture>>) {
         if D3DVERTEXTEXTURESAMPLER0 <= stage && stage <= D3DVERTEXTEXTURESAMPLER3 {
             stage = 16 + stage - D3DVERTEXTEXTURESAMPLER0;
         }
@@ -90,41 +119,70 @@ impl DeviceState {
         }
     }
 
-    pub fn get_texture(&self, mut stage: u32) -> *mut BaseTexture {
+    pub fn get_texture(&self, mut stage: u32) -> Option<&ComPtr<BaseTexture>> {
         if D3DVERTEXTEXTURESAMPLER0 <= stage && stage <= D3DVERTEXTEXTURESAMPLER3 {
             stage = 16 + stage - D3DVERTEXTEXTURESAMPLER0;
         }
 
-        self.textures
-            .get(stage as usize)
-            .cloned()
-            .unwrap_or(ptr::null_mut())
+        self.textures.get(stage as usize).and_then(Option::as_ref)
     }
 
-    pub fn set_vertex_shader(&mut self, shader: *const VertexShader) {
-        self.vertex.vertex_shader = shader;
+    pub fn set_vertex_shader(&mut self, shader: Option<ComPtr<VertexShader>>) {
+        self.vertex_shader = shader;
     }
 
-    pub fn get_vertex_shader(&self) -> *const VertexShader {
-        self.vertex.vertex_shader
+    pub fn get_vertex_shader(&self) -> Option<&ComPtr<VertexShader>> {
+        self.vertex_shader.as_ref()
     }
 
-    pub fn set_pixel_shader(&mut self, shader: *const PixelShader) {
-        self.pixel.pixel_shader = shader;
+    pub fn set_pixel_shader(&mut self, shader: Option<ComPtr<PixelShader>>) {
+        self.pixel_shader = shader;
     }
 
-    pub fn get_pixel_shader(&self) -> *const PixelShader {
-        self.pixel.pixel_shader
+    pub fn get_pixel_shader(&self) -> Option<&ComPtr<PixelShader>> {
+        self.pixel_shader.as_ref()
     }
 
     pub fn set_vertex_declaration(&mut self, decl: *const VertexDeclaration) {
         self.vertex.vertex_decl = decl;
+        self.vertex.fvf = 0;
     }
 
     pub fn get_vertex_declaration(&self) -> *const VertexDeclaration {
         self.vertex.vertex_decl
     }
 
+    pub fn set_fvf(&mut self, fvf: u32, decl: *const VertexDeclaration) {
+        self.vertex.vertex_decl = decl;
+        self.vertex.fvf = fvf;
+    }
+
+    pub fn get_fvf(&self) -> u32 {
+        self.vertex.fvf
+    }
+
+    pub fn set_indices(&mut self, ib: Option<ComPtr<IndexBuffer>>) {
+        self.indices = ib;
+    }
+
+    pub fn get_indices(&self) -> Option<&ComPtr<IndexBuffer>> {
+        self.indices.as_ref()
+    }
+
+    pub fn set_stream_source(&mut self, stream: u32, source: StreamSource) {
+        if let Some(s) = self.streams.get_mut(stream as usize) {
+            *s = source;
+        }
+    }
+
+    pub fn get_stream_source(&self, stream: u32) -> Option<&StreamSource> {
+        self.streams.get(stream as usize)
+    }
+
+    pub fn stream_sources(&self) -> &[StreamSource] {
+        &self.streams
+    }
+
     pub fn set_viewport(&mut self, vp: &D3DVIEWPORT9) {
         self.viewport = *vp;
     }
@@ -133,6 +191,14 @@ impl DeviceState {
         self.viewport
     }
 
+    pub fn set_scissor_rect(&mut self, rect: &RECT) {
+        self.scissor_rect = *rect;
+    }
+
+    pub fn get_scissor_rect(&self) -> RECT {
+        self.scissor_rect
+    }
+
     pub fn set_transform(&mut self, ty: D3DTRANSFORMSTATETYPE, value: Matrix4<f32>) {
         self.transforms.insert(ty, value);
     }
@@ -148,6 +214,74 @@ impl DeviceState {
     pub fn get_material(&self) -> D3DMATERIAL9 {
         self.material
     }
+
+    pub fn set_light(&mut self, index: u32, light: &D3DLIGHT9) {
+        self.lights.insert(index, *light);
+    }
+
+    pub fn get_light(&self, index: u32) -> Option<D3DLIGHT9> {
+        self.lights.get(&index).cloned()
+    }
+
+    /// Enables or disables a light.
+    ///
+    /// Enabling a light which was never set creates a default one.
+    /// Returns false if too many lights are already enabled.
+    pub fn set_light_enable(&mut self, index: u32, enable: bool) -> bool {
+        let enabled = self.is_light_enabled(index);
+
+        if enable && !enabled {
+            if self.enabled_lights.len() >= MAX_LIGHTS {
+                return false;
+            }
+
+            self.lights.entry(index).or_insert_with(default_light);
+            self.enabled_lights.push(index);
+        } else if !enable && enabled {
+            self.enabled_lights.retain(|&i| i != index);
+        }
+
+        true
+    }
+
+    pub fn is_light_enabled(&self, index: u32) -> bool {
+        self.enabled_lights.contains(&index)
+    }
+
+    /// Retrieves the indices of every light which was ever set.
+    pub fn light_indices(&self) -> impl Iterator<Item = u32> + '_ {
+        self.lights.keys().cloned()
+    }
+
+    /// Retrieves the lights which are currently enabled.
+    pub fn enabled_lights(&self) -> impl Iterator<Item = &D3DLIGHT9> {
+        self.enabled_lights.iter().map(move |i| &self.lights[i])
+    }
+
+    pub fn set_clip_plane(&mut self, index: usize, plane: [f32; 4]) {
+        self.clip_planes[index] = plane;
+    }
+
+    pub fn get_clip_plane(&self, index: usize) -> [f32; 4] {
+        self.clip_planes[index]
+    }
+}
+
+/// Creates the light D3D9 uses when enabling a light which was never set:
+/// a white directional light, pointing in the direction of the Z axis.
+fn default_light() -> D3DLIGHT9 {
+    let mut light: D3DLIGHT9 = unsafe { mem::zeroed() };
+
+    light.Type = D3DLIGHT_DIRECTIONAL;
+    light.Diffuse = D3DCOLORVALUE {
+        r: 1.0,
+        g: 1.0,
+        b: 1.0,
+        a: 0.0,
+    };
+    light.Direction.z = 1.0;
+
+    light
 }
 
 impl Default for DeviceState {
@@ -155,17 +289,31 @@ impl Default for DeviceState {
         let mut state = Self {
             vertex: VertexState::default(),
             pixel: PixelState::default(),
-            textures: [ptr::null_mut(); 20],
+            textures: Default::default(),
             // The default viewport depends on the default render target's size.
             viewport: unsafe { mem::zeroed() },
+            // Same for the scissor rect.
+            scissor_rect: unsafe { mem::zeroed() },
             transforms: HashMap::with_capacity(4),
             material: unsafe { mem::zeroed() },
+            lights: HashMap::new(),
+            enabled_lights: Vec::with_capacity(MAX_LIGHTS),
+            clip_planes: [[0.0; 4]; MAX_CLIP_PLANES],
+            indices: None,
+            vertex_shader: None,
+            pixel_shader: None,
+            streams: Default::default(),
         };
 
         // The first texture stage has a different default state.
         state.pixel.ts[0].color_op = D3DTOP_MODULATE;
         state.pixel.ts[0].alpha_op = D3DTOP_SELECTARG1;
 
+        // Each stage uses the texture coordinates with the same index by default.
+        for (stage, ts) in state.pixel.ts.iter_mut().enumerate() {
+            ts.tex_coord_index = stage as u32;
+        }
+
         state
     }
 }
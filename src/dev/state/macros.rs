@@ -32,6 +32,15 @@ macro_rules! impl_state {
         // even then it's simpler to use a match statement everywhere.
         #[allow(single_match)]
         impl $sname {
+            /// The render states contained in this structure.
+            pub const RENDER_STATES: &'static [D3DRENDERSTATETYPE] = &[$($rs_enum,)*];
+            /// Number of samplers whose state is contained in this structure.
+            pub const MAX_SAMPLERS: usize = $maxn;
+            /// The sampler states contained in this structure.
+            pub const SAMPLER_STATES: &'static [D3DSAMPLERSTATETYPE] = &[$($ss_enum,)*];
+            /// The texture stage states contained in this structure.
+            pub const TEXTURE_STATES: &'static [D3DTEXTURESTAGESTATETYPE] = &[$($ts_enum,)*];
+
             /// Sets a render state variable.
             pub fn set_render_state(&mut self, state: D3DRENDERSTATETYPE, value: u32) {
                 match state {
@@ -10,7 +10,7 @@ mod vertex;
 pub(self) use self::vertex::VertexState;
 
 mod device;
-pub use self::device::DeviceState;
+pub use self::device::{DeviceState, StreamSource, MAX_STREAMS};
 
 mod block;
-pub use self::block::StateBlock;
+pub use self::block::{RecordedConstants, RecordedState, StateBlock};
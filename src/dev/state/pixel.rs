@@ -1,9 +1,5 @@
-use std::ptr;
-
 use winapi::shared::d3d9types::*;
 
-use crate::dev::shader::*;
-
 impl_state! {
     /// Structure containing all state related to pixel processing.
     ///
@@ -24,9 +20,11 @@ impl_state! {
         alpha_ref: D3DRS_ALPHAREF = 0,
         alpha_func: D3DRS_ALPHAFUNC = D3DCMP_ALWAYS,
         dither_enable: D3DRS_DITHERENABLE = 0,
+        // Not part of any state block type, but it still has to be stored somewhere.
+        fog_enable: D3DRS_FOGENABLE = 0,
         fog_start: D3DRS_FOGSTART = 0,
-        fog_end: D3DRS_FOGEND = 1,
-        fog_density: D3DRS_FOGDENSITY = 1,
+        fog_end: D3DRS_FOGEND = 0x3f80_0000,
+        fog_density: D3DRS_FOGDENSITY = 0x3f80_0000,
         alpha_blend_enable: D3DRS_ALPHABLENDENABLE = 0,
         depth_bias: D3DRS_DEPTHBIAS = 0,
         stencil_enable: D3DRS_STENCILENABLE = 0,
@@ -109,8 +107,7 @@ impl_state! {
         texture_transform_flags: D3DTSS_TEXTURETRANSFORMFLAGS = D3DTTFF_DISABLE,
         color_arg0: D3DTSS_COLORARG0 = D3DTA_CURRENT,
         alpha_arg0: D3DTSS_ALPHAARG0 = D3DTA_CURRENT,
-        result_arg: D3DTSS_RESULTARG = D3DTA_CURRENT;
-        // Extra state variables
-        pixel_shader: *const PixelShader = ptr::null(),
+        result_arg: D3DTSS_RESULTARG = D3DTA_CURRENT,
+        constant: D3DTSS_CONSTANT = 0;
     }
 }
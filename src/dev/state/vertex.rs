@@ -15,8 +15,8 @@ impl_state! {
         fog_color: D3DRS_FOGCOLOR = 0,
         fog_table_mode: D3DRS_FOGTABLEMODE = D3DFOG_NONE,
         fog_start: D3DRS_FOGSTART = 0,
-        fog_end: D3DRS_FOGEND = 1,
-        fog_density: D3DRS_FOGDENSITY = 1,
+        fog_end: D3DRS_FOGEND = 0x3f80_0000,
+        fog_density: D3DRS_FOGDENSITY = 0x3f80_0000,
         range_fog_enable: D3DRS_RANGEFOGENABLE = 0,
         ambient: D3DRS_AMBIENT = 0,
         color_vertex: D3DRS_COLORVERTEX = 1,
@@ -30,18 +30,19 @@ impl_state! {
         specular_material_source: D3DRS_SPECULARMATERIALSOURCE = D3DMCS_COLOR2,
         vertex_blend: D3DRS_VERTEXBLEND = D3DVBF_DISABLE,
         clip_plane_enable: D3DRS_CLIPPLANEENABLE = 0,
-        // This one is driver dependent by default.
-        point_size: D3DRS_POINTSIZE = 1,
-        point_size_min: D3DRS_POINTSIZE_MIN = 1,
+        // Float render states store the bits of their value.
+        // The point size is driver dependent by default.
+        point_size: D3DRS_POINTSIZE = 0x3f80_0000,
+        point_size_min: D3DRS_POINTSIZE_MIN = 0x3f80_0000,
         point_sprite_enable: D3DRS_POINTSPRITEENABLE = 0,
         point_scale_enable: D3DRS_POINTSCALEENABLE = 0,
-        point_scale_a: D3DRS_POINTSCALE_A = 1,
+        point_scale_a: D3DRS_POINTSCALE_A = 0x3f80_0000,
         point_scale_b: D3DRS_POINTSCALE_B = 0,
         point_scale_c: D3DRS_POINTSCALE_C = 0,
         multisample_antialias: D3DRS_MULTISAMPLEANTIALIAS = 1,
         multisample_mask: D3DRS_MULTISAMPLEMASK = 0xffff_ffff,
         patch_edge_style: D3DRS_PATCHEDGESTYLE = D3DPATCHEDGE_DISCRETE,
-        point_size_max: D3DRS_POINTSIZE_MAX = 1,
+        point_size_max: D3DRS_POINTSIZE_MAX = 0x4280_0000,
         index_vertex_blend_enable: D3DRS_INDEXEDVERTEXBLENDENABLE = 0,
         tween_factor: D3DRS_TWEENFACTOR = 0,
         position_degree: D3DRS_POSITIONDEGREE = D3DDEGREE_CUBIC,
@@ -60,7 +61,8 @@ impl_state! {
         tex_coord_index: D3DTSS_TEXCOORDINDEX = 0,
         texture_transform_flags: D3DTSS_TEXTURETRANSFORMFLAGS = D3DTTFF_DISABLE;
         // Extra state variables
-        vertex_shader: *const VertexShader = ptr::null(),
         vertex_decl: *const VertexDeclaration = ptr::null(),
+        // The FVF code the current declaration was created from, if any.
+        fvf: u32 = 0,
     }
 }
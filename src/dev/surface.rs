@@ -1,14 +1,20 @@
 use std::sync::atomic::{AtomicU32, Ordering};
+use std::{mem, ptr};
 
-use winapi::shared::{d3d9::*, d3d9types::*, guiddef::GUID, windef::RECT};
-use winapi::um::d3d11::*;
+use winapi::shared::windef::{HBITMAP, HDC, HGDIOBJ, RECT};
+use winapi::shared::{d3d9::*, d3d9types::*, dxgi::IDXGISurface1, guiddef::GUID, minwindef::FALSE};
 use winapi::um::unknwnbase::{IUnknown, IUnknownVtbl};
+use winapi::um::{d3d11::*, wingdi};
 
 use com_impl::{implementation, interface, ComInterface};
 use comptr::ComPtr;
 
-use crate::core::{fmt::dxgi_format_to_d3d, msample::dxgi_samples_to_d3d9, *};
-use crate::d3d11;
+use crate::core::{
+    fmt::{dxgi_format_to_d3d, dxgi_typeless_depth_format, D3DFMT_NULL},
+    msample::dxgi_samples_to_d3d9,
+    *,
+};
+use crate::d3d11::{self, query_interface};
 use crate::Error;
 
 use super::{Device, Resource};
@@ -22,6 +28,10 @@ pub struct Surface {
     texture: d3d11::Texture2D,
     // Extra data required for this surface type.
     data: SurfaceData,
+    // View for rendering to this surface with sRGB conversion, if its format allows it.
+    srgb_view: Option<ComPtr<ID3D11RenderTargetView>>,
+    // The GDI device context the app is using to draw on this surface, if any.
+    dc: Option<GdiContext>,
 }
 
 /// Extra information required to fully describe a surface.
@@ -33,10 +43,124 @@ pub enum SurfaceData {
     None,
     /// This surface is owning a render target.
     RenderTarget(ComPtr<ID3D11RenderTargetView>),
+    /// This surface is a render target with the `NULL` format.
+    /// It is never bound, so that only the depth / stencil buffer is written to.
+    NullRenderTarget,
     /// This surface is owning a depth / stencil buffer.
     DepthStencil(ComPtr<ID3D11DepthStencilView>),
-    /// This surface is part of a bigger texture.
-    SubResource(u32),
+    /// This surface is a subresource of a texture,
+    /// to which a reference is kept.
+    SubResource(u32, ComPtr<IUnknown>),
+    /// This surface is a subresource of a render target texture,
+    /// and owns a view for rendering to it.
+    RenderTargetLevel(u32, ComPtr<IUnknown>, ComPtr<ID3D11RenderTargetView>),
+    /// This surface is a subresource of a depth / stencil texture,
+    /// and owns a view for using it as a depth buffer.
+    DepthStencilLevel(u32, ComPtr<IUnknown>, ComPtr<ID3D11DepthStencilView>),
+}
+
+/// A GDI device context, which is used to draw on a surface.
+enum GdiContext {
+    /// The texture supports GDI interop natively.
+    Dxgi(ComPtr<IDXGISurface1>, HDC),
+    /// The surface's pixels are copied to a bitmap,
+    /// which is copied back when the context is released.
+    Bitmap {
+        dc: HDC,
+        bitmap: HBITMAP,
+        // The bitmap selected into the DC when it was created.
+        previous: HGDIOBJ,
+        bits: *mut u8,
+    },
+}
+
+impl GdiContext {
+    /// Creates a memory DC with a 32-bit bitmap of a certain size.
+    fn new_bitmap(width: u32, height: u32) -> Result<Self, Error> {
+        unsafe {
+            let mut info: wingdi::BITMAPINFO = mem::zeroed();
+            let header = &mut info.bmiHeader;
+            header.biSize = mem::size_of::<wingdi::BITMAPINFOHEADER>() as u32;
+            header.biWidth = width as i32;
+            // Negative height means the rows are stored top-down, like in D3D.
+            header.biHeight = -(height as i32);
+            header.biPlanes = 1;
+            header.biBitCount = 32;
+            header.biCompression = wingdi::BI_RGB;
+
+            let mut bits = ptr::null_mut();
+            let bitmap = wingdi::CreateDIBSection(
+                ptr::null_mut(),
+                &info,
+                wingdi::DIB_RGB_COLORS,
+                &mut bits,
+                ptr::null_mut(),
+                0,
+            );
+
+            if bitmap.is_null() {
+                error!("Failed to create bitmap for GDI interop");
+                return Err(Error::OutOfVideoMemory);
+            }
+
+            let dc = wingdi::CreateCompatibleDC(ptr::null_mut());
+
+            if dc.is_null() {
+                error!("Failed to create device context for GDI interop");
+                wingdi::DeleteObject(bitmap as *mut _);
+                return Err(Error::InvalidCall);
+            }
+
+            let previous = wingdi::SelectObject(dc, bitmap as *mut _);
+
+            Ok(GdiContext::Bitmap {
+                dc,
+                bitmap,
+                previous,
+                bits: bits as *mut u8,
+            })
+        }
+    }
+
+    /// Retrieves the handle of this device context.
+    fn handle(&self) -> HDC {
+        match *self {
+            GdiContext::Dxgi(_, dc) => dc,
+            GdiContext::Bitmap { dc, .. } => dc,
+        }
+    }
+}
+
+impl Drop for GdiContext {
+    fn drop(&mut self) {
+        if let GdiContext::Bitmap {
+            dc,
+            bitmap,
+            previous,
+            ..
+        } = *self
+        {
+            unsafe {
+                wingdi::SelectObject(dc, previous);
+                wingdi::DeleteDC(dc);
+                wingdi::DeleteObject(bitmap as *mut _);
+            }
+        }
+    }
+}
+
+/// Copies some rows of pixels between two buffers with different pitches.
+unsafe fn copy_rows(
+    src: *const u8,
+    src_pitch: usize,
+    dest: *mut u8,
+    dest_pitch: usize,
+    row: usize,
+    rows: usize,
+) {
+    for y in 0..rows {
+        ptr::copy_nonoverlapping(src.add(y * src_pitch), dest.add(y * dest_pitch), row);
+    }
 }
 
 impl Surface {
@@ -48,24 +172,66 @@ impl Surface {
         pool: MemoryPool,
         data: SurfaceData,
     ) -> ComPtr<Self> {
+        let d3d11_device = unsafe { &*device }.dx11_device();
+
+        // Failing to create the sRGB view only means that sRGB writes cannot be emulated.
+        let srgb_view = match data {
+            SurfaceData::RenderTarget(_) => texture.create_srgb_rt_view(d3d11_device, 0),
+            SurfaceData::RenderTargetLevel(subresource, _, _) => {
+                texture.create_srgb_rt_view(d3d11_device, subresource)
+            }
+            _ => Ok(None),
+        }
+        .unwrap_or(None);
+
         let surface = Self {
             __vtable: Box::new(Self::create_vtable()),
             resource: Resource::new(device, usage, pool, ResourceType::Surface),
             refs: AtomicU32::new(1),
             texture,
             data,
+            srgb_view,
+            dc: None,
         };
 
         unsafe { new_com_interface(surface) }
     }
 
+    /// Creates a new surface representing a subresource of a texture.
+    ///
+    /// Subresources of render target and depth / stencil textures get their own view,
+    /// so that they can be rendered to.
+    pub fn new_subresource(
+        device: *const Device,
+        texture: d3d11::Texture2D,
+        usage: UsageFlags,
+        pool: MemoryPool,
+        subresource: u32,
+        parent: ComPtr<IUnknown>,
+    ) -> Result<ComPtr<Self>, Error> {
+        let d3d11_device = unsafe { &*device }.dx11_device();
+
+        let data = if usage.intersects(UsageFlags::RENDER_TARGET) {
+            let view = texture.create_subresource_rt_view(d3d11_device, subresource)?;
+            SurfaceData::RenderTargetLevel(subresource, parent, view)
+        } else if usage.intersects(UsageFlags::DEPTH_STENCIL) {
+            let view = texture.create_subresource_ds_view(d3d11_device, subresource)?;
+            SurfaceData::DepthStencilLevel(subresource, parent, view)
+        } else {
+            SurfaceData::SubResource(subresource, parent)
+        };
+
+        Ok(Self::new(device, texture, usage, pool, data))
+    }
+
     /// Retrieves a reference to the subresource this surface represents.
     pub fn subresource(&self) -> (*mut ID3D11Resource, u32) {
         let resource = self.texture.as_resource();
-        let subresource = if let SurfaceData::SubResource(sr) = self.data {
-            sr
-        } else {
-            0
+        let subresource = match self.data {
+            SurfaceData::SubResource(sr, _)
+            | SurfaceData::RenderTargetLevel(sr, _, _)
+            | SurfaceData::DepthStencilLevel(sr, _, _) => sr,
+            _ => 0,
         };
 
         (resource, subresource)
@@ -73,19 +239,61 @@ impl Surface {
 
     /// If this surface is a render target, retrieves the associated RT view.
     pub fn render_target_view(&self) -> Option<&mut ID3D11RenderTargetView> {
-        if let SurfaceData::RenderTarget(ref view) = self.data {
-            Some(view.as_mut())
-        } else {
-            None
+        match self.data {
+            SurfaceData::RenderTarget(ref view)
+            | SurfaceData::RenderTargetLevel(_, _, ref view) => Some(view.as_mut()),
+            _ => None,
+        }
+    }
+
+    /// If this surface is a render target, retrieves the RT view which writes sRGB colors.
+    ///
+    /// Returns `None` if the surface's format has no sRGB equivalent.
+    pub fn srgb_render_target_view(&self) -> Option<&mut ID3D11RenderTargetView> {
+        self.srgb_view.as_ref().map(|view| view.as_mut())
+    }
+
+    /// Checks if this surface is a render target with the `NULL` format.
+    pub fn is_null_render_target(&self) -> bool {
+        match self.data {
+            SurfaceData::NullRenderTarget => true,
+            _ => false,
+        }
+    }
+
+    /// Retrieves the texture containing this surface.
+    pub fn texture(&self) -> &d3d11::Texture2D {
+        &self.texture
+    }
+
+    /// Retrieves a view for rendering to this surface, creating a new one if it has none.
+    pub fn create_rt_view(
+        &self,
+        device: &ID3D11Device,
+    ) -> Result<ComPtr<ID3D11RenderTargetView>, Error> {
+        match self.data {
+            SurfaceData::RenderTarget(ref view)
+            | SurfaceData::RenderTargetLevel(_, _, ref view) => return Ok(view.clone()),
+            _ => (),
+        }
+
+        let (_, subresource) = self.subresource();
+        let desc = self.texture.desc();
+
+        if subresource != 0 || desc.BindFlags & D3D11_BIND_RENDER_TARGET == 0 {
+            error!("Surface cannot be used as a render target");
+            return Err(Error::InvalidCall);
         }
+
+        self.texture.create_rt_view(device)
     }
 
     /// If this surface is a depth / stencil buffer, retrieves the associated DS view.
     pub fn depth_stencil_view(&self) -> Option<&mut ID3D11DepthStencilView> {
-        if let SurfaceData::DepthStencil(ref view) = self.data {
-            Some(view.as_mut())
-        } else {
-            None
+        match self.data {
+            SurfaceData::DepthStencil(ref view)
+            | SurfaceData::DepthStencilLevel(_, _, ref view) => Some(view.as_mut()),
+            _ => None,
         }
     }
 }
@@ -110,8 +318,26 @@ impl ComInterface<IDirect3DResource9Vtbl> for Surface {
 #[implementation(IDirect3DSurface9)]
 impl Surface {
     /// Gets the container of this resource.
-    fn get_container(&self, _riid: &GUID, _ret: *mut usize) -> Error {
-        unimplemented!()
+    ///
+    /// This is the parent texture for texture levels, or the device otherwise.
+    fn get_container(&self, riid: &GUID, ret: *mut usize) -> Error {
+        let ret = if_error!(check_mut_ref(ret));
+
+        let container: *const IUnknown = match self.data {
+            SurfaceData::SubResource(_, ref parent)
+            | SurfaceData::RenderTargetLevel(_, ref parent, _)
+            | SurfaceData::DepthStencilLevel(_, ref parent, _) => parent.as_mut(),
+            _ => self.device() as *const Device as *const IUnknown,
+        };
+
+        let result = unsafe { (*container).QueryInterface(riid, ret as *mut usize as *mut _) };
+
+        if result != 0 {
+            *ret = 0;
+            return Error::InvalidCall;
+        }
+
+        Error::Success
     }
 
     /// Retrieves a description of this surface.
@@ -123,7 +349,13 @@ impl Surface {
         ret.Width = desc.Width;
         ret.Height = desc.Height;
 
-        ret.Format = dxgi_format_to_d3d(desc.Format);
+        // Depth buffers which can be sampled from have a typeless format.
+        let fmt = dxgi_typeless_depth_format(desc.Format).map_or(desc.Format, |(fmt, _)| fmt);
+        ret.Format = if self.is_null_render_target() {
+            D3DFMT_NULL
+        } else {
+            dxgi_format_to_d3d(fmt)
+        };
         ret.Type = D3DRTYPE_SURFACE;
 
         ret.Usage = self.usage().bits();
@@ -138,14 +370,24 @@ impl Surface {
 
     // -- Memory mapping functions --
 
-    fn lock_rect(&mut self, ret: *mut D3DLOCKED_RECT, _r: *const RECT, flags: LockFlags) -> Error {
+    fn lock_rect(&mut self, ret: *mut D3DLOCKED_RECT, r: *const RECT, flags: LockFlags) -> Error {
+        let _lock = self.device().lock();
         let ret = if_error!(check_mut_ref(ret));
-        let (res, subres) = self.subresource();
-        *ret = if_error!(self.device_context().map(res, subres, flags, self.usage()));
+
+        if self.dc.is_some() {
+            error!("Cannot lock a surface while its device context is in use");
+            return Error::InvalidCall;
+        }
+
+        let (_, subres) = self.subresource();
+        let ctx = self.device_context();
+        let rect = unsafe { r.as_ref() };
+        *ret = if_error!(ctx.map_rect(&self.texture, subres, rect, flags, self.usage()));
         Error::Success
     }
 
     fn unlock_rect(&self) -> Error {
+        let _lock = self.device().lock();
         let (res, subres) = self.subresource();
         self.device_context().unmap(res, subres);
         Error::Success
@@ -153,13 +395,124 @@ impl Surface {
 
     // -- GDI interop functions --
 
-    /// Retrieves the device context associated with this surface.
-    fn get_d_c() {
-        unimplemented!()
+    /// Retrieves a GDI device context which can be used to draw on this surface.
+    fn get_d_c(&mut self, ret: *mut HDC) -> Error {
+        let _lock = self.device().lock();
+        let ret = if_error!(check_mut_ref(ret));
+
+        if self.dc.is_some() {
+            error!("Surface already has a device context");
+            return Error::InvalidCall;
+        }
+
+        let desc = self.texture.desc();
+
+        match dxgi_format_to_d3d(desc.Format) {
+            D3DFMT_A8R8G8B8 | D3DFMT_X8R8G8B8 => (),
+            fmt => {
+                error!("GDI interop is not supported for surface format {}", fmt);
+                return Error::InvalidCall;
+            }
+        }
+
+        let (res, subres) = self.subresource();
+
+        // DXGI only allows using the DC of textures with a single subresource.
+        if desc.MiscFlags & D3D11_RESOURCE_MISC_GDI_COMPATIBLE != 0 && subres == 0 {
+            if let Some(surface) = query_interface::<IDXGISurface1>(unsafe { &*res }) {
+                let mut dc = ptr::null_mut();
+                let result = unsafe { surface.GetDC(FALSE, &mut dc) };
+                if_not_success!(check_hresult(
+                    result,
+                    "Failed to get surface's device context"
+                ));
+
+                *ret = dc;
+                self.dc = Some(GdiContext::Dxgi(surface, dc));
+
+                return Error::Success;
+            }
+        }
+
+        let (width, height) = (desc.Width as usize, desc.Height as usize);
+        let context = if_error!(GdiContext::new_bitmap(desc.Width, desc.Height));
+
+        // Copy the current contents of the surface to the bitmap.
+        if let GdiContext::Bitmap { bits, .. } = context {
+            let ctx = self.device_context();
+            let mapped = if_error!(ctx.map(res, subres, LockFlags::READ_ONLY, self.usage()));
+
+            unsafe {
+                let src = mapped.pBits as *const u8;
+                copy_rows(
+                    src,
+                    mapped.Pitch as usize,
+                    bits,
+                    width * 4,
+                    width * 4,
+                    height,
+                );
+            }
+
+            ctx.unmap(res, subres);
+        }
+
+        *ret = context.handle();
+        self.dc = Some(context);
+
+        Error::Success
     }
 
-    /// Releases a device context associated with this surface.
-    fn release_d_c() {
-        unimplemented!()
+    /// Releases the device context previously retrieved from this surface.
+    fn release_d_c(&mut self, dc: HDC) -> Error {
+        let _lock = self.device().lock();
+        match self.dc {
+            Some(ref context) if context.handle() == dc => (),
+            _ => {
+                error!("Device context was not retrieved from this surface");
+                return Error::InvalidCall;
+            }
+        }
+
+        let context = self.dc.take().unwrap();
+
+        match context {
+            GdiContext::Dxgi(ref surface, _) => {
+                let result = unsafe { surface.ReleaseDC(ptr::null_mut()) };
+                if_not_success!(check_hresult(
+                    result,
+                    "Failed to release surface's device context"
+                ));
+            }
+            GdiContext::Bitmap { bits, .. } => {
+                let desc = self.texture.desc();
+                let width = desc.Width as usize;
+                let (res, subres) = self.subresource();
+                let ctx = self.device_context();
+
+                // Make sure GDI finished drawing to the bitmap.
+                unsafe {
+                    wingdi::GdiFlush();
+                }
+
+                let mapped = if_error!(ctx.map(res, subres, LockFlags::empty(), self.usage()));
+
+                unsafe {
+                    let dest = mapped.pBits as *mut u8;
+                    copy_rows(
+                        bits,
+                        width * 4,
+                        dest,
+                        mapped.Pitch as usize,
+                        width * 4,
+                        desc.Height as usize,
+                    );
+                }
+
+                ctx.unmap(res, subres);
+            }
+        }
+
+        Error::Success
     }
 }
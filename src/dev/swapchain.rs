@@ -1,6 +1,16 @@
-use std::{cmp, mem, ptr, sync::atomic::AtomicU32};
-
-use winapi::shared::{d3d9::*, d3d9types::*, dxgi::*, dxgitype::*, windef::HWND, winerror};
+use std::cell::{Cell, RefCell};
+use std::mem::{self, MaybeUninit};
+use std::time::Instant;
+use std::{cmp, ptr, sync::atomic::AtomicU32};
+
+use winapi::shared::dxgiformat::{
+    DXGI_FORMAT_B8G8R8A8_UNORM, DXGI_FORMAT_R10G10B10A2_UNORM, DXGI_FORMAT_UNKNOWN,
+};
+use winapi::shared::{d3d9::*, d3d9caps::*, d3d9types::*, dxgi::*, dxgitype::*};
+use winapi::shared::{
+    windef::{HWND, POINT},
+    winerror,
+};
 use winapi::um::d3d11::*;
 use winapi::um::unknwnbase::{IUnknown, IUnknownVtbl};
 use winapi::um::winuser;
@@ -9,7 +19,9 @@ use winapi::Interface;
 use com_impl::{implementation, interface, ComInterface};
 use comptr::ComPtr;
 
-use crate::core::{fmt::d3d_display_format_to_dxgi, msample::d3d9_to_dxgi_samples, *};
+use crate::core::{
+    color::pixel_to_d3dcolor, fmt::d3d_display_format_to_dxgi, msample::d3d9_to_dxgi_samples, *,
+};
 use crate::d3d11;
 use crate::Error;
 
@@ -34,8 +46,24 @@ pub struct SwapChain {
     // 0 -> no vsync
     // 1 through 4 -> vsync, with `refresh rate = (monitor Hz / sync_interval)`.
     sync_interval: u32,
+    // The gamma ramp set by the app.
+    gamma_ramp: Cell<D3DGAMMARAMP>,
+    // DXGI cannot present multisampled buffers directly, so the app renders
+    // to this texture instead, which is resolved into the back buffer when presenting.
+    msaa_buffer: Option<d3d11::Texture2D>,
+    // Copy of the last presented image. The buffers of DISCARD swap chains
+    // have undefined contents after presenting, so they cannot be read back.
+    front_buffer: RefCell<Option<d3d11::Texture2D>>,
+    // The time of a recent vertical blank, from which the current scanline is estimated.
+    // It is updated when presenting with vsync, or when the app waits for a vertical blank.
+    vblank: Cell<Option<Instant>>,
 }
 
+/// Fraction of a refresh period which is spent in the vertical blank.
+///
+/// The exact number depends on the display's timings, but is usually close to this.
+const VBLANK_FRACTION: f64 = 0.04;
+
 impl SwapChain {
     /// Creates a new swap chain with the given parameters, which presents into a window.
     pub fn new(
@@ -45,6 +73,8 @@ impl SwapChain {
         pp: &mut D3DPRESENT_PARAMETERS,
         window: HWND,
     ) -> Result<ComPtr<Self>, Error> {
+        let sync_interval = d3d9_to_dxgi_sync_interval(pp.PresentationInterval)?;
+
         // First we need to set up the description of this swap chain.
         let mut sc_desc = {
             // Fill in the description of the back buffer.
@@ -102,18 +132,6 @@ impl SwapChain {
                 }
             };
 
-            let sample_desc = {
-                let count = if pp.SwapEffect != D3DSWAPEFFECT_DISCARD {
-                    error!("Multisampling is only supported with the DISCARD swap effect");
-                    error!("Disabling multisample antialiasing");
-                    1
-                } else {
-                    pp.MultiSampleType
-                };
-
-                d3d9_to_dxgi_samples(count, pp.MultiSampleQuality)
-            };
-
             let buffer_usage = DXGI_USAGE_BACK_BUFFER | DXGI_USAGE_RENDER_TARGET_OUTPUT;
 
             let buffer_count = {
@@ -144,14 +162,16 @@ impl SwapChain {
 
             DXGI_SWAP_CHAIN_DESC {
                 BufferDesc: buffer_desc,
-                SampleDesc: sample_desc,
+                // Multisampled buffers are resolved into the swap chain's buffers.
+                SampleDesc: d3d9_to_dxgi_samples(0, 0),
                 BufferUsage: buffer_usage,
                 BufferCount: buffer_count,
                 OutputWindow: window,
-                Windowed: pp.Windowed,
+                // The swap chain is created windowed, and then switched to full-screen.
+                Windowed: 1,
                 SwapEffect: swap_effect,
-                // We don't need any special flags.
-                Flags: 0,
+                // Let DXGI change the display mode to match the back buffer in full-screen.
+                Flags: DXGI_SWAP_CHAIN_FLAG_ALLOW_MODE_SWITCH,
             }
         };
 
@@ -168,10 +188,38 @@ impl SwapChain {
             ComPtr::new(ptr)
         };
 
-        let pp = *pp;
+        unsafe {
+            // D3D9 apps handle Alt-Enter themselves, by resetting the device.
+            // DXGI must not change the swap chain's state behind their backs.
+            let result = factory.MakeWindowAssociation(window, DXGI_MWA_NO_ALT_ENTER);
+            if_not_success_err!(check_hresult(result, "Failed to set window association"));
+        }
+
+        if pp.Windowed == 0 {
+            let output = parent.adapter().output();
+            Self::enter_fullscreen(&swap_chain, output, &sc_desc)?;
+        }
+
+        if pp.MultiSampleType > D3DMULTISAMPLE_NONMASKABLE && pp.SwapEffect != D3DSWAPEFFECT_DISCARD
+        {
+            error!("Multisampling is only supported with the DISCARD swap effect");
+            error!("Disabling multisample antialiasing");
+
+            // Let the device know it should not create multisampled buffers either.
+            pp.MultiSampleType = D3DMULTISAMPLE_NONE;
+            pp.MultiSampleQuality = 0;
+        }
+
+        let samples = d3d9_to_dxgi_samples(pp.MultiSampleType, pp.MultiSampleQuality);
 
-        // Clamp this to 4.
-        let sync_interval = cmp::min(pp.PresentationInterval, 4);
+        let msaa_buffer = if samples.Count > 1 {
+            let buffer = Self::swap_chain_buffer(&swap_chain, 0)?;
+            Some(buffer.new_multisampled(device, samples)?)
+        } else {
+            None
+        };
+
+        let pp = *pp;
 
         let swap_chain = Self {
             __vtable: Box::new(Self::create_vtable()),
@@ -180,19 +228,124 @@ impl SwapChain {
             swap_chain,
             pp,
             sync_interval,
+            gamma_ramp: Cell::new(identity_gamma_ramp()),
+            msaa_buffer,
+            front_buffer: RefCell::new(None),
+            vblank: Cell::new(None),
         };
 
         Ok(unsafe { new_com_interface(swap_chain) })
     }
 
+    /// Switches a swap chain to full-screen mode, on a certain output.
+    fn enter_fullscreen(
+        swap_chain: &IDXGISwapChain,
+        output: Option<ComPtr<IDXGIOutput>>,
+        desc: &DXGI_SWAP_CHAIN_DESC,
+    ) -> Result<(), Error> {
+        let output = output
+            .as_ref()
+            .map_or(ptr::null_mut(), |output| output.as_mut() as *mut _);
+
+        unsafe {
+            // Changing the display mode first avoids an extra mode change.
+            let result = swap_chain.ResizeTarget(&desc.BufferDesc);
+            if_not_success_err!(check_hresult(result, "Failed to change display mode"));
+
+            let result = swap_chain.SetFullscreenState(1, output);
+            if result == winerror::DXGI_STATUS_MODE_CHANGE_IN_PROGRESS
+                || result == winerror::DXGI_ERROR_NOT_CURRENTLY_AVAILABLE
+            {
+                // The app isn't in the foreground. The swap chain stays windowed,
+                // the device becomes lost, and the app will retry when resetting it.
+                warn!("Could not switch to full-screen mode");
+                return Ok(());
+            }
+            if_not_success_err!(check_hresult(
+                result,
+                "Failed to switch to full-screen mode"
+            ));
+
+            // DXGI recommends resizing the buffers after every mode change.
+            let result = swap_chain.ResizeBuffers(0, 0, 0, DXGI_FORMAT_UNKNOWN, desc.Flags);
+            if_not_success_err!(check_hresult(result, "Failed to resize swap chain buffers"));
+        }
+
+        Ok(())
+    }
+
+    /// Waits for the next vertical blank of the monitor this swap chain is on.
+    pub fn wait_for_vblank(&self) -> Result<(), Error> {
+        unsafe {
+            let mut output = ptr::null_mut();
+            let result = self.swap_chain.GetContainingOutput(&mut output);
+            if_not_success_err!(check_hresult(result, "Failed to get swap chain's output"));
+            let output = ComPtr::new(output);
+
+            let result = output.WaitForVBlank();
+            if_not_success_err!(check_hresult(result, "Failed to wait for vertical blank"));
+        }
+
+        self.vblank.set(Some(Instant::now()));
+
+        Ok(())
+    }
+
+    /// Retrieves the device which owns this swap chain.
+    fn device(&self) -> &Device {
+        unsafe { &*self.parent }
+    }
+
+    /// Checks if this swap chain presents to a window, rather than in full-screen mode.
+    pub fn is_windowed(&self) -> bool {
+        self.pp.Windowed != 0
+    }
+
+    /// Retrieves the position of the window's client area, relative to its monitor.
+    fn window_position(&self) -> (i32, i32) {
+        unsafe {
+            let mut desc = MaybeUninit::uninit();
+            if self.swap_chain.GetDesc(desc.as_mut_ptr()) != 0 {
+                return (0, 0);
+            }
+
+            let window = desc.assume_init().OutputWindow;
+
+            let mut pos = POINT { x: 0, y: 0 };
+            winuser::ClientToScreen(window, &mut pos);
+
+            let monitor = winuser::MonitorFromWindow(window, winuser::MONITOR_DEFAULTTONEAREST);
+            let mut mi: winuser::MONITORINFO = mem::zeroed();
+            mi.cbSize = mem::size_of_val(&mi) as u32;
+            winuser::GetMonitorInfoW(monitor, &mut mi);
+
+            (pos.x - mi.rcMonitor.left, pos.y - mi.rcMonitor.top)
+        }
+    }
+
     /// Retrieves a buffer in this swap chain.
     pub fn buffer(&self, id: u32) -> Result<d3d11::Texture2D, Error> {
+        Self::swap_chain_buffer(&self.swap_chain, id)
+    }
+
+    /// Retrieves the texture the app renders to for a certain back buffer.
+    ///
+    /// For multisampled swap chains, this is the texture resolved into the buffers when presenting.
+    pub fn back_buffer(&self, id: u32) -> Result<d3d11::Texture2D, Error> {
+        match self.msaa_buffer {
+            Some(ref buffer) => Ok(buffer.clone()),
+            None => self.buffer(id),
+        }
+    }
+
+    /// Retrieves a buffer of a DXGI swap chain.
+    fn swap_chain_buffer(swap_chain: &IDXGISwapChain, id: u32) -> Result<d3d11::Texture2D, Error> {
         let mut ptr: *mut ID3D11Texture2D = ptr::null_mut();
         let uuid = ID3D11Texture2D::uuidof();
 
         let ret = &mut ptr as *mut _ as *mut *mut _;
 
-        let result = unsafe { self.swap_chain.GetBuffer(id, &uuid, ret) };
+        let result = unsafe { swap_chain.GetBuffer(id, &uuid, ret) };
 
         if_not_success_err!(check_hresult(
             result,
@@ -202,6 +355,38 @@ impl SwapChain {
         Ok(ComPtr::new(ptr).into())
     }
 
+    /// Copies the buffer which is about to be presented, so that it can be read back later.
+    fn save_front_buffer(&self) -> Result<(), Error> {
+        let buffer = self.buffer(0)?;
+        let desc = buffer.desc();
+
+        let mut front_buffer = self.front_buffer.borrow_mut();
+
+        // The copy is recreated if the buffers were resized.
+        let outdated = front_buffer.as_ref().map_or(true, |front| {
+            let front = front.desc();
+            (front.Width, front.Height, front.Format) != (desc.Width, desc.Height, desc.Format)
+        });
+
+        if outdated {
+            *front_buffer = Some(d3d11::Texture2D::new_sampled(
+                &self.device().adapter().device(),
+                (desc.Width, desc.Height),
+                desc.Format,
+            )?);
+        }
+
+        let front_buffer = front_buffer.as_ref().unwrap();
+
+        unsafe {
+            self.device()
+                .device_context()
+                .CopyResource(front_buffer.as_resource(), buffer.as_resource());
+        }
+
+        Ok(())
+    }
+
     // Retrieves this swap chain's containing output.
     fn output(&self) -> Result<ComPtr<IDXGIOutput>, Error> {
         let output = unsafe {
@@ -214,36 +399,118 @@ impl SwapChain {
         Ok(output)
     }
 
-    /// Sets the associated output's gamma ramp.
-    pub fn set_gamma_ramp(&self, flags: u32, _ramp: &D3DGAMMARAMP) -> Result<(), Error> {
-        if self.pp.Windowed == 1 {
-            return Err(Error::InvalidCall);
-        }
-
+    /// Sets the gamma ramp used by this swap chain.
+    ///
+    /// The ramp can only be applied to the output in full-screen mode,
+    /// but it is always stored so that it can be retrieved later.
+    pub fn set_gamma_ramp(&self, flags: u32, ramp: &D3DGAMMARAMP) -> Result<(), Error> {
         // winapi is missing this constant.
         const D3DSGR_CALIBRATE: u32 = 1;
 
         if flags & D3DSGR_CALIBRATE != 0 {
-            warn!("Gamma calibration isn't implemented");
+            info!("Gamma calibration isn't implemented, ignoring it");
+        }
+
+        self.gamma_ramp.set(*ramp);
+
+        if self.is_windowed() {
+            run_once!(|| warn!("Gamma ramps are not applied in windowed mode"));
+            return Ok(());
         }
 
-        let _output = self.output()?;
+        let output = self.output()?;
 
-        error!("Setting gamma ramp is not supported");
+        let caps = unsafe {
+            let mut caps = MaybeUninit::uninit();
+            let result = output.GetGammaControlCapabilities(caps.as_mut_ptr());
+            if_not_success_err!(check_hresult(
+                result,
+                "Failed to get the output's gamma capabilities"
+            ));
+            caps.assume_init()
+        };
+
+        let mut control: DXGI_GAMMA_CONTROL = unsafe { mem::zeroed() };
+        control.Scale = DXGI_RGB {
+            Red: 1.0,
+            Green: 1.0,
+            Blue: 1.0,
+        };
+
+        let points = cmp::min(
+            caps.NumGammaControlPoints as usize,
+            control.GammaCurve.len(),
+        );
+
+        for (point, &pos) in control.GammaCurve[..points]
+            .iter_mut()
+            .zip(&caps.ControlPointPositions[..points])
+        {
+            *point = DXGI_RGB {
+                Red: sample_gamma_ramp(&ramp.red, pos),
+                Green: sample_gamma_ramp(&ramp.green, pos),
+                Blue: sample_gamma_ramp(&ramp.blue, pos),
+            };
+        }
+
+        let result = unsafe { output.SetGammaControl(&control) };
+        if_not_success_err!(check_hresult(result, "Failed to set the output's gamma"));
 
         Ok(())
     }
 
-    /// Gets the associated output's gamma ramp.
-    pub fn get_gamma_ramp(&self, _ramp: &mut D3DGAMMARAMP) -> Result<(), Error> {
-        let _output = self.output()?;
+    /// Gets the gamma ramp used by this swap chain.
+    pub fn get_gamma_ramp(&self, ramp: &mut D3DGAMMARAMP) -> Result<(), Error> {
+        *ramp = self.gamma_ramp.get();
+        Ok(())
+    }
+}
 
-        error!("Getting gamma ramp is not supported");
+/// Creates a gamma ramp which does not modify colors.
+fn identity_gamma_ramp() -> D3DGAMMARAMP {
+    let mut channel = [0; 256];
 
-        Ok(())
+    for (i, value) in channel.iter_mut().enumerate() {
+        // Maps 0xFF to 0xFFFF.
+        *value = (i * 0x101) as u16;
+    }
+
+    D3DGAMMARAMP {
+        red: channel,
+        green: channel,
+        blue: channel,
     }
 }
 
+/// Converts a D3D9 presentation interval to the equivalent DXGI sync interval.
+fn d3d9_to_dxgi_sync_interval(interval: u32) -> Result<u32, Error> {
+    Ok(match interval {
+        D3DPRESENT_INTERVAL_IMMEDIATE => 0,
+        // The default interval waits for the vertical blank, just like ONE.
+        D3DPRESENT_INTERVAL_DEFAULT | D3DPRESENT_INTERVAL_ONE => 1,
+        D3DPRESENT_INTERVAL_TWO => 2,
+        D3DPRESENT_INTERVAL_THREE => 3,
+        D3DPRESENT_INTERVAL_FOUR => 4,
+        _ => {
+            error!("Invalid presentation interval: {:#x}", interval);
+            return Err(Error::InvalidCall);
+        }
+    })
+}
+
+/// Interpolates a channel of a gamma ramp at a position between 0 and 1.
+fn sample_gamma_ramp(channel: &[u16; 256], pos: f32) -> f32 {
+    let pos = pos.max(0.0).min(1.0) * 255.0;
+
+    let lower = pos.floor() as usize;
+    let upper = cmp::min(lower + 1, 255);
+    let t = pos - lower as f32;
+
+    let value = f32::from(channel[lower]) * (1.0 - t) + f32::from(channel[upper]) * t;
+
+    value / 65535.0
+}
+
 impl Drop for SwapChain {
     fn drop(&mut self) {
         unsafe {
@@ -260,6 +527,8 @@ impl_iunknown!(struct SwapChain: IUnknown, IDirect3DSwapChain9);
 impl SwapChain {
     /// Presents the back buffer to the screen, and moves to the next buffer in the chain.
     pub fn present(&self, src: usize, dest: usize, wnd: HWND, dirty: usize, flags: u32) -> Error {
+        let _lock = self.device().lock();
+
         if src != 0 || dest != 0 || dirty != 0 {
             // Check if the app is even allowed to partially present.
             if self.pp.SwapEffect != D3DSWAPEFFECT_COPY {
@@ -288,22 +557,144 @@ impl SwapChain {
             warn!("sRGB / gamma correction not yet supported");
         }
 
+        if let Some(ref msaa_buffer) = self.msaa_buffer {
+            let buffer = if_error!(self.buffer(0));
+            let format = buffer.desc().Format;
+            let ctx = self.device().device_context();
+
+            unsafe {
+                ctx.ResolveSubresource(
+                    buffer.as_resource(),
+                    0,
+                    msaa_buffer.as_resource(),
+                    0,
+                    format,
+                );
+            }
+        }
+
+        if_error!(self.save_front_buffer());
+
         // Try to present.
         let result = unsafe { self.swap_chain.Present(self.sync_interval, fl) };
 
         match result {
-            0 => Error::Success,
+            0 => {
+                // With vsync, presenting returns shortly after a vertical blank.
+                if self.sync_interval != 0 {
+                    self.vblank.set(Some(Instant::now()));
+                }
+                Error::Success
+            }
             winerror::DXGI_ERROR_WAS_STILL_DRAWING => Error::WasStillDrawing,
+            winerror::DXGI_ERROR_DEVICE_REMOVED | winerror::DXGI_ERROR_DEVICE_RESET => {
+                error!("The GPU device was lost");
+                Error::DeviceLost
+            }
             hr => check_hresult(hr, "Failed to present to screen"),
         }
     }
 
     /// Copies data from the front buffer into a surface.
+    ///
+    /// The surface must be an ARGB surface in system memory. In windowed mode,
+    /// it should be as big as the monitor, and the image is placed where the window is.
     pub fn get_front_buffer_data(&self, fb: *mut Surface) -> Error {
-        let _fb = check_mut_ref(fb);
-        // TODO: we need to get the front buffer, then copy its data into the passed-in surface.
-        // We also need to ensure the format is converted to a format D3D9 supports.
-        unimplemented!()
+        let _lock = self.device().lock();
+        let fb = if_error!(check_mut_ref(fb));
+
+        let fb_desc = unsafe {
+            let mut desc = MaybeUninit::uninit();
+            if_not_success!(fb.get_desc(desc.as_mut_ptr()));
+            desc.assume_init()
+        };
+
+        if fb.pool() != MemoryPool::SystemMem || fb_desc.Format != D3DFMT_A8R8G8B8 {
+            error!("Front buffer data can only be copied to ARGB system memory surfaces");
+            return Error::InvalidCall;
+        }
+
+        // DXGI doesn't let us access the front buffer, so we read the copy made when presenting.
+        let buffer = match *self.front_buffer.borrow() {
+            Some(ref buffer) => buffer.clone(),
+            None => {
+                error!("Cannot get the front buffer's data before presenting");
+                return Error::InvalidCall;
+            }
+        };
+        let desc = buffer.desc();
+
+        let fmt = match desc.Format {
+            DXGI_FORMAT_R10G10B10A2_UNORM => D3DFMT_A2B10G10R10,
+            _ => D3DFMT_X8R8G8B8,
+        };
+
+        let parent = self.device();
+        let ctx = parent.device_context();
+
+        // The buffers cannot be read by the CPU, so we need to copy them to a staging texture.
+        let staging = if_error!(d3d11::Texture2D::new_staging(
+            &parent.adapter().device(),
+            (desc.Width, desc.Height),
+            desc.Format,
+        ));
+        let staging_res = staging.as_resource();
+
+        unsafe {
+            ctx.CopyResource(staging_res, buffer.as_resource());
+        }
+
+        let src = if_error!(ctx.map_with_type(staging_res, 0, D3D11_MAP_READ, LockFlags::empty()));
+
+        let (x, y) = if self.is_windowed() {
+            self.window_position()
+        } else {
+            (0, 0)
+        };
+
+        let (fb_res, fb_subres) = fb.subresource();
+        let dest = match ctx.map(
+            fb_res,
+            fb_subres,
+            LockFlags::empty(),
+            UsageFlags::WRITE_ONLY,
+        ) {
+            Ok(rect) => rect,
+            Err(err) => {
+                ctx.unmap(staging_res, 0);
+                return err;
+            }
+        };
+
+        // Only copy the part of the buffer which is inside the surface.
+        let clip = |pos: i32, size: u32, limit: u32| {
+            let start = cmp::max(-pos, 0) as u32;
+            let end = cmp::min(i64::from(size), i64::from(limit) - i64::from(pos));
+            (start, cmp::max(end, 0) as u32)
+        };
+
+        let (x_start, x_end) = clip(x, desc.Width, fb_desc.Width);
+        let (y_start, y_end) = clip(y, desc.Height, fb_desc.Height);
+
+        for row in y_start..y_end {
+            unsafe {
+                let src_row = (src.pData as *const u32).add((row * src.RowPitch / 4) as usize);
+                let dest_row = (dest.pBits as *mut u8)
+                    .offset((row as i32 + y) as isize * dest.Pitch as isize)
+                    as *mut u32;
+
+                for column in x_start..x_end {
+                    let pixel = *src_row.add(column as usize);
+                    *dest_row.offset((column as i32 + x) as isize) =
+                        pixel_to_d3dcolor(pixel, fmt).unwrap_or(0);
+                }
+            }
+        }
+
+        ctx.unmap(fb_res, fb_subres);
+        ctx.unmap(staging_res, 0);
+
+        Error::Success
     }
 
     /// Retrieves the the back buffer's surface.
@@ -326,7 +717,7 @@ impl SwapChain {
         }
 
         // Retrieve the 2D texture representing this back buffer.
-        let buffer = if_error!(self.buffer(idx));
+        let buffer = if_error!(self.back_buffer(idx));
 
         // Create and return a pointer to the surface.
         *surf = Surface::new(
@@ -342,23 +733,125 @@ impl SwapChain {
     }
 
     /// Gets the status of the current scanline the rasterizer is processing.
+    ///
+    /// DXGI doesn't expose the scanline, so it is estimated from the time
+    /// elapsed since a vertical blank and the refresh rate of the display.
     pub fn get_raster_status(&self, rs: *mut D3DRASTER_STATUS) -> Error {
-        if_error!(check_mut_ref(rs));
+        let _lock = self.device().lock();
+        let rs = if_error!(check_mut_ref(rs));
+
+        let output = if_error!(self.output());
+        let (height, refresh_rate) = if_error!(self.output_timings(&output));
+
+        let now = Instant::now();
+
+        // Waiting for a vertical blank would block the app, which usually polls this
+        // in a loop. Until one is known, the estimate starts from the first call.
+        let vblank = match self.vblank.get() {
+            Some(vblank) => vblank,
+            None => {
+                self.vblank.set(Some(now));
+                now
+            }
+        };
+
+        let elapsed = now.duration_since(vblank);
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) * 1e-9;
 
-        // We reported in the device caps that we don't support this.
-        Error::NotAvailable
+        // How far we are into the current refresh, from 0 to 1.
+        let position = (elapsed * refresh_rate).fract();
+        // The vertical blank starts once all the visible lines have been scanned.
+        let visible = 1.0 - VBLANK_FRACTION;
+
+        if position >= visible {
+            rs.InVBlank = 1;
+            rs.ScanLine = 0;
+        } else {
+            rs.InVBlank = 0;
+            rs.ScanLine = (position / visible * f64::from(height)) as u32;
+        }
+
+        Error::Success
+    }
+
+    /// Retrieves the vertical resolution and the refresh rate of an output.
+    fn output_timings(&self, output: &IDXGIOutput) -> Result<(u32, f64), Error> {
+        let mode = self.current_mode(output)?;
+
+        // In full-screen mode, we know the refresh rate the app asked for.
+        let refresh_rate = if !self.is_windowed() && self.pp.FullScreen_RefreshRateInHz != 0 {
+            f64::from(self.pp.FullScreen_RefreshRateInHz)
+        } else {
+            match mode.RefreshRate {
+                DXGI_RATIONAL { Denominator: 0, .. } | DXGI_RATIONAL { Numerator: 0, .. } => 60.0,
+                rf => f64::from(rf.Numerator) / f64::from(rf.Denominator),
+            }
+        };
+
+        Ok((mode.Height, refresh_rate))
     }
 
-    /// Retrieves the swap chain's display mode.
+    /// Retrieves the mode an output is currently displaying.
+    fn current_mode(&self, output: &IDXGIOutput) -> Result<DXGI_MODE_DESC, Error> {
+        let desc = unsafe {
+            let mut desc = MaybeUninit::uninit();
+            let result = output.GetDesc(desc.as_mut_ptr());
+            if_not_success_err!(check_hresult(result, "Failed to get output description"));
+            desc.assume_init()
+        };
+
+        let rc = desc.DesktopCoordinates;
+
+        // DXGI has no function which returns the current mode, but the unspecified
+        // members of the description are matched with the desktop's mode.
+        let target = DXGI_MODE_DESC {
+            Width: (rc.right - rc.left) as u32,
+            Height: (rc.bottom - rc.top) as u32,
+            RefreshRate: DXGI_RATIONAL {
+                Numerator: 0,
+                Denominator: 0,
+            },
+            Format: match self.pp.BackBufferFormat {
+                D3DFMT_A2R10G10B10 => DXGI_FORMAT_R10G10B10A2_UNORM,
+                _ => DXGI_FORMAT_B8G8R8A8_UNORM,
+            },
+            ScanlineOrdering: DXGI_MODE_SCANLINE_ORDER_UNSPECIFIED,
+            Scaling: DXGI_MODE_SCALING_UNSPECIFIED,
+        };
+
+        let mode = unsafe {
+            let mut mode = MaybeUninit::uninit();
+            let result =
+                output.FindClosestMatchingMode(&target, mode.as_mut_ptr(), ptr::null_mut());
+            if_not_success_err!(check_hresult(result, "Failed to find the output's mode"));
+            mode.assume_init()
+        };
+
+        Ok(mode)
+    }
+
+    /// Retrieves the display mode of the output this swap chain is on.
     pub fn get_display_mode(&self, dm: *mut D3DDISPLAYMODE) -> Error {
         let dm = if_error!(check_mut_ref(dm));
-        let pp = &self.pp;
+
+        let output = if_error!(self.output());
+        let mode = if_error!(self.current_mode(&output));
+
+        let rf = mode.RefreshRate;
 
         *dm = D3DDISPLAYMODE {
-            Width: pp.BackBufferWidth,
-            Height: pp.BackBufferHeight,
-            Format: pp.BackBufferFormat,
-            RefreshRate: pp.FullScreen_RefreshRateInHz,
+            Width: mode.Width,
+            Height: mode.Height,
+            RefreshRate: if rf.Denominator == 0 {
+                0
+            } else {
+                rf.Numerator / rf.Denominator
+            },
+            // Displays don't show the alpha channel.
+            Format: match mode.Format {
+                DXGI_FORMAT_R10G10B10A2_UNORM => D3DFMT_A2R10G10B10,
+                _ => D3DFMT_X8R8G8B8,
+            },
         };
 
         Error::Success
@@ -1,8 +1,16 @@
+use std::mem::{self, MaybeUninit};
+use std::{cmp, ptr};
+
 use winapi::shared::{d3d9::*, d3d9types::*};
+use winapi::um::d3d11::*;
 
 use com_impl::{implementation, ComInterface};
+use comptr::ComPtr;
 
-use crate::core::*;
+use crate::core::{
+    fmt::{dxgi_format_swizzle, TextureSwizzle},
+    *,
+};
 use crate::dev::{Device, Resource};
 use crate::Error;
 
@@ -12,6 +20,18 @@ pub struct BaseTexture {
     resource: Resource,
     // Number of subresource levels in this textures.
     levels: u32,
+    // View used to bind this texture to the pipeline.
+    // Textures which cannot be sampled from do not have one.
+    view: Option<ComPtr<ID3D11ShaderResourceView>>,
+    // View used instead when the app requests sRGB sampling.
+    // Only textures whose format has an sRGB equivalent have one.
+    srgb_view: Option<ComPtr<ID3D11ShaderResourceView>>,
+    // How the channels of this texture must be rearranged when sampling it.
+    swizzle: TextureSwizzle,
+    // Most detailed mip level used when sampling this texture.
+    lod: u32,
+    // Filter requested by the app for generating mip maps.
+    autogen_filter: D3DTEXTUREFILTERTYPE,
 }
 
 impl BaseTexture {
@@ -22,17 +42,130 @@ impl BaseTexture {
         pool: MemoryPool,
         rtype: ResourceType,
         levels: u32,
+        view: Option<ComPtr<ID3D11ShaderResourceView>>,
+        srgb_view: Option<ComPtr<ID3D11ShaderResourceView>>,
     ) -> Self {
+        let swizzle = view
+            .as_ref()
+            .map(|view| unsafe {
+                let mut desc = mem::zeroed();
+                view.GetDesc(&mut desc);
+                dxgi_format_swizzle(desc.Format)
+            })
+            .unwrap_or(TextureSwizzle::None);
+
         Self {
             resource: Resource::new(device, usage, pool, rtype),
             levels,
+            view,
+            srgb_view,
+            swizzle,
+            lod: 0,
+            autogen_filter: D3DTEXF_LINEAR,
         }
     }
 
+    /// Retrieves the common texture data from a pointer to a texture interface.
+    pub fn from_interface(texture: &ComPtr<BaseTexture>) -> &BaseTexture {
+        let thunk = texture.as_mut() as *const Thunk;
+        unsafe { &(*thunk).txt }
+    }
+
     /// Retrieves the number of mip map levels in this texture.
     pub fn level_count(&self) -> u32 {
         self.levels
     }
+
+    /// Retrieves the shader resource view of this texture, if it has one.
+    pub fn shader_resource_view(&self) -> Option<&ComPtr<ID3D11ShaderResourceView>> {
+        self.view.as_ref()
+    }
+
+    /// Retrieves the shader resource view which samples this texture as sRGB, if it has one.
+    pub fn srgb_shader_resource_view(&self) -> Option<&ComPtr<ID3D11ShaderResourceView>> {
+        self.srgb_view.as_ref()
+    }
+
+    /// Retrieves the swizzle which must be applied when sampling this texture.
+    pub fn swizzle(&self) -> TextureSwizzle {
+        self.swizzle
+    }
+
+    /// Recreates the shader resource views, so that they skip the mip levels before `lod`.
+    fn update_view_lod(&mut self, lod: u32) -> Result<(), Error> {
+        if let Some(view) = self.view.as_ref() {
+            self.view = Some(self.view_with_lod(view, lod)?);
+        }
+
+        if let Some(view) = self.srgb_view.as_ref() {
+            self.srgb_view = Some(self.view_with_lod(view, lod)?);
+        }
+
+        Ok(())
+    }
+
+    /// Creates a copy of a shader resource view, which skips the mip levels before `lod`.
+    ///
+    /// Returns the same view if its LOD cannot be changed.
+    fn view_with_lod(
+        &self,
+        old_view: &ComPtr<ID3D11ShaderResourceView>,
+        lod: u32,
+    ) -> Result<ComPtr<ID3D11ShaderResourceView>, Error> {
+        let (resource, mut desc) = unsafe {
+            let mut resource = ptr::null_mut();
+            old_view.GetResource(&mut resource);
+
+            let mut desc = MaybeUninit::<D3D11_SHADER_RESOURCE_VIEW_DESC>::uninit();
+            old_view.GetDesc(desc.as_mut_ptr());
+
+            (ComPtr::new(resource), desc.assume_init())
+        };
+
+        let levels = self.levels - lod;
+
+        unsafe {
+            match desc.ViewDimension {
+                D3D11_SRV_DIMENSION_TEXTURE2D => {
+                    let tex = desc.u.Texture2D_mut();
+                    tex.MostDetailedMip = lod;
+                    tex.MipLevels = levels;
+                }
+                D3D11_SRV_DIMENSION_TEXTURE2DARRAY => {
+                    let tex = desc.u.Texture2DArray_mut();
+                    tex.MostDetailedMip = lod;
+                    tex.MipLevels = levels;
+                }
+                D3D11_SRV_DIMENSION_TEXTURECUBE => {
+                    let tex = desc.u.TextureCube_mut();
+                    tex.MostDetailedMip = lod;
+                    tex.MipLevels = levels;
+                }
+                D3D11_SRV_DIMENSION_TEXTURE3D => {
+                    let tex = desc.u.Texture3D_mut();
+                    tex.MostDetailedMip = lod;
+                    tex.MipLevels = levels;
+                }
+                dim => {
+                    warn!("Cannot change the LOD of a view with dimension {}", dim);
+                    return Ok(old_view.clone());
+                }
+            }
+        }
+
+        let view = unsafe {
+            let mut ptr = ptr::null_mut();
+            let device = self.device().dx11_device();
+            let result = device.CreateShaderResourceView(resource.as_mut(), &desc, &mut ptr);
+            if_not_success_err!(check_hresult(
+                result,
+                "Failed to create shader resource view"
+            ));
+            ComPtr::new(ptr)
+        };
+
+        Ok(view)
+    }
 }
 
 impl std::ops::Deref for BaseTexture {
@@ -70,24 +203,75 @@ impl std::ops::DerefMut for Thunk {
 
 #[implementation(IDirect3DBaseTexture9)]
 impl BaseTexture {
-    fn set_l_o_d(self: &mut Thunk, _lod: u32) -> u32 {
-        unimplemented!()
+    /// Sets the most detailed mip level used when sampling this texture,
+    /// returning the previous one.
+    ///
+    /// The new level is used the next time the texture is bound.
+    fn set_l_o_d(self: &mut Thunk, lod: u32) -> u32 {
+        // Only managed textures support this.
+        if self.pool() != MemoryPool::Managed {
+            return 0;
+        }
+
+        let previous = self.lod;
+        let lod = cmp::min(lod, self.levels - 1);
+
+        if lod != previous {
+            match self.update_view_lod(lod) {
+                Ok(()) => self.lod = lod,
+                Err(_) => error!("Failed to set texture LOD to {}", lod),
+            }
+        }
+
+        previous
     }
+
+    /// Retrieves the most detailed mip level used when sampling this texture.
     fn get_l_o_d(self: &Thunk) -> u32 {
-        unimplemented!()
+        self.lod
     }
 
     fn get_level_count(self: &Thunk) -> u32 {
         self.level_count()
     }
 
-    fn set_auto_gen_filter_type(self: &mut Thunk, _filter: D3DTEXTUREFILTERTYPE) -> Error {
-        unimplemented!()
+    /// Sets the filter used for generating mip maps.
+    fn set_auto_gen_filter_type(self: &mut Thunk, filter: D3DTEXTUREFILTERTYPE) -> Error {
+        match filter {
+            D3DTEXF_POINT
+            | D3DTEXF_LINEAR
+            | D3DTEXF_ANISOTROPIC
+            | D3DTEXF_PYRAMIDALQUAD
+            | D3DTEXF_GAUSSIANQUAD => (),
+            _ => return Error::InvalidCall,
+        }
+
+        if filter != D3DTEXF_LINEAR {
+            warn!(
+                "Mip maps are always generated with a linear filter, not {}",
+                filter
+            );
+        }
+
+        self.autogen_filter = filter;
+
+        Error::Success
     }
+
+    /// Retrieves the filter used for generating mip maps.
     fn get_auto_gen_filter_type(self: &Thunk) -> D3DTEXTUREFILTERTYPE {
-        unimplemented!()
+        self.autogen_filter
     }
+
+    /// Generates the mip levels of a texture with automatically generated mip maps.
     fn generate_mip_sub_levels(self: &mut Thunk) {
-        unimplemented!()
+        if !self.usage().intersects(UsageFlags::AUTO_GEN_MIP_MAP) {
+            return;
+        }
+
+        match self.view.as_ref() {
+            Some(view) => unsafe { self.device_context().GenerateMips(view.as_mut()) },
+            None => warn!("Cannot generate the mip maps of a texture which cannot be sampled"),
+        }
     }
 }
@@ -31,15 +31,27 @@ impl CubeTexture {
         levels: u32,
         usage: UsageFlags,
         pool: MemoryPool,
-    ) -> ComPtr<Self> {
+    ) -> Result<ComPtr<Self>, Error> {
+        let d3d11_device = unsafe { &*device }.dx11_device();
+        let view = texture.create_sr_view(d3d11_device)?;
+        let srgb_view = texture.create_srgb_sr_view(d3d11_device)?;
+
         let tc = Self {
             __vtable: Box::new(Self::create_vtable()),
-            base: BaseTexture::new(device, usage, pool, ResourceType::CubeTexture, levels),
+            base: BaseTexture::new(
+                device,
+                usage,
+                pool,
+                ResourceType::CubeTexture,
+                levels,
+                view,
+                srgb_view,
+            ),
             refs: AtomicU32::new(1),
             texture,
         };
 
-        unsafe { new_com_interface(tc) }
+        Ok(unsafe { new_com_interface(tc) })
     }
 }
 
@@ -95,9 +107,11 @@ impl CubeTexture {
         let usage = self.usage();
         let pool = self.pool();
         let subres = self.texture.calc_subresource(level, face, levels);
-        let data = SurfaceData::SubResource(subres);
-
-        *ret = Surface::new(device, texture, usage, pool, data).into();
+        let parent = ComPtr::new(com_ref(self as *const Self as *const IUnknown));
+        *ret = if_error!(Surface::new_subresource(
+            device, texture, usage, pool, subres, parent
+        ))
+        .into();
 
         Error::Success
     }
@@ -108,23 +122,26 @@ impl CubeTexture {
         face: u32,
         level: u32,
         ret: *mut D3DLOCKED_RECT,
-        _r: *const RECT,
+        r: *const RECT,
         flags: LockFlags,
     ) -> Error {
+        let _lock = self.device().lock();
         let ret = if_error!(check_mut_ref(ret));
 
-        let resource = self.texture.as_resource();
         let levels = self.level_count();
         let subres = self.texture.calc_subresource(level, face, levels);
         let ctx = self.device_context();
 
-        *ret = if_error!(ctx.map(resource, subres, flags, self.usage()));
+        let rect = unsafe { r.as_ref() };
+
+        *ret = if_error!(ctx.map_rect(&self.texture, subres, rect, flags, self.usage()));
 
         Error::Success
     }
 
     /// Unmaps a face of this cube map.
     fn unlock_rect(&self, face: u32, level: u32) -> Error {
+        let _lock = self.device().lock();
         let resource = self.texture.as_resource();
         let levels = self.level_count();
         let subres = self.texture.calc_subresource(level, face, levels);
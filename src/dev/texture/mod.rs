@@ -10,3 +10,6 @@ pub use self::texture2d::Texture;
 
 mod cube;
 pub use self::cube::CubeTexture;
+
+mod volume;
+pub use self::volume::VolumeTexture;
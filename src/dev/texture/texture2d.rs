@@ -1,8 +1,11 @@
-use std::ptr;
+use std::cell::{Cell, RefCell};
 use std::sync::atomic::AtomicU32;
+use std::{cmp, ptr, slice};
 
 use winapi::shared::{d3d9::*, d3d9types::*, windef::RECT};
+use winapi::um::d3d11::{D3D11_USAGE_DYNAMIC, D3D11_USAGE_STAGING};
 use winapi::um::unknwnbase::{IUnknown, IUnknownVtbl};
+use winapi::um::wingdi::PALETTEENTRY;
 
 use com_impl::{implementation, interface, ComInterface};
 use comptr::ComPtr;
@@ -20,6 +23,112 @@ pub struct Texture {
     base: BaseTexture,
     refs: AtomicU32,
     texture: d3d11::Texture2D,
+    // Regions of every mip level modified since the texture was last uploaded.
+    dirty_rects: RefCell<Vec<Vec<RECT>>>,
+    // If this is a paletted texture, the indices the app writes to.
+    paletted: Option<PalettedLevels>,
+    // Copy used to lock the levels which the CPU cannot access directly.
+    readback: RefCell<Option<Readback>>,
+}
+
+/// A CPU-accessible copy of a texture, used for reading back its contents.
+///
+/// Textures in the default pool cannot be mapped for reading, so the level being locked
+/// is copied here first, and copied back when it is unlocked if the app wrote to it.
+struct Readback {
+    texture: d3d11::Texture2D,
+    // Bitmask of the levels which are currently locked through the copy.
+    locked: Cell<u32>,
+    // Bitmask of the locked levels which must be copied back when unlocked.
+    written: Cell<u32>,
+}
+
+/// Number of dirty rectangles tracked for every mip level.
+/// When more are added, they are merged into one.
+const MAX_DIRTY_RECTS: usize = 16;
+
+/// The pixels of a paletted texture.
+///
+/// D3D11 has no paletted formats, so the app locks these buffers instead,
+/// and they are expanded to RGBA through the current palette when unlocked.
+struct PalettedLevels {
+    fmt: D3DFORMAT,
+    // Size of every pixel, in bytes.
+    pixel_size: usize,
+    levels: RefCell<Vec<Box<[u8]>>>,
+}
+
+impl PalettedLevels {
+    /// Retrieves a pointer to a region of a mip level.
+    fn lock(
+        &self,
+        level: u32,
+        (width, height): (u32, u32),
+        rect: Option<&RECT>,
+    ) -> Result<D3DLOCKED_RECT, Error> {
+        let pitch = width as usize * self.pixel_size;
+
+        let offset = match rect {
+            Some(r) => {
+                let valid = r.left >= 0
+                    && r.top >= 0
+                    && r.left < r.right
+                    && r.top < r.bottom
+                    && r.right as u32 <= width
+                    && r.bottom as u32 <= height;
+
+                if !valid {
+                    error!("Invalid lock rect for paletted texture");
+                    return Err(Error::InvalidCall);
+                }
+
+                r.top as usize * pitch + r.left as usize * self.pixel_size
+            }
+            None => 0,
+        };
+
+        let mut levels = self.levels.borrow_mut();
+        let bits = unsafe { levels[level as usize].as_mut_ptr().add(offset) };
+
+        Ok(D3DLOCKED_RECT {
+            Pitch: pitch as i32,
+            pBits: bits as *mut _,
+        })
+    }
+
+    /// Converts the indices of a mip level to 32-bit RGBA colors.
+    fn expand(
+        &self,
+        level: u32,
+        (width, height): (u32, u32),
+        palette: &[PALETTEENTRY; 256],
+        dest: &D3DLOCKED_RECT,
+    ) {
+        let levels = self.levels.borrow();
+        let src = &levels[level as usize];
+        let (width, height) = (width as usize, height as usize);
+
+        for y in 0..height {
+            let row = unsafe {
+                let row = (dest.pBits as *mut u8).add(y * dest.Pitch as usize);
+                slice::from_raw_parts_mut(row as *mut [u8; 4], width)
+            };
+
+            for (x, pixel) in row.iter_mut().enumerate() {
+                let index = (y * width + x) * self.pixel_size;
+                let entry = palette[src[index] as usize];
+
+                // A8P8 stores its own alpha, otherwise it comes from the palette's flags.
+                let alpha = if self.pixel_size == 2 {
+                    src[index + 1]
+                } else {
+                    entry.peFlags
+                };
+
+                *pixel = [entry.peRed, entry.peGreen, entry.peBlue, alpha];
+            }
+        }
+    }
 }
 
 impl Texture {
@@ -30,15 +139,194 @@ impl Texture {
         texture: d3d11::Texture2D,
         levels: u32,
         usage: UsageFlags,
-    ) -> ComPtr<Self> {
+    ) -> Result<ComPtr<Self>, Error> {
+        let d3d11_device = unsafe { &*device }.dx11_device();
+        let view = texture.create_sr_view(d3d11_device)?;
+        let srgb_view = texture.create_srgb_sr_view(d3d11_device)?;
+
         let texture = Self {
             __vtable: Box::new(Self::create_vtable()),
-            base: BaseTexture::new(device, usage, pool, ResourceType::Texture, levels),
+            base: BaseTexture::new(
+                device,
+                usage,
+                pool,
+                ResourceType::Texture,
+                levels,
+                view,
+                srgb_view,
+            ),
             refs: AtomicU32::new(1),
             texture,
+            dirty_rects: RefCell::new(vec![Vec::new(); levels as usize]),
+            paletted: None,
+            readback: RefCell::new(None),
+        };
+
+        // New textures are entirely dirty.
+        for level in 0..levels {
+            texture.add_dirty_region(level, None);
+        }
+
+        Ok(unsafe { new_com_interface(texture) })
+    }
+
+    /// Retrieves the 2D texture behind a base texture interface, if it is one.
+    pub fn from_base(texture: &ComPtr<BaseTexture>) -> Option<&Texture> {
+        if BaseTexture::from_interface(texture).resource_type() != ResourceType::Texture {
+            return None;
+        }
+
+        // The interface pointer points to the start of the texture object.
+        Some(unsafe { &*(texture.as_mut() as *const BaseTexture as *const Texture) })
+    }
+
+    /// Makes this a paletted texture, whose pixels are indices into the current palette.
+    ///
+    /// The D3D11 texture must have an RGBA format.
+    pub fn set_paletted_format(&mut self, fmt: D3DFORMAT) {
+        let pixel_size = if fmt == D3DFMT_A8P8 { 2 } else { 1 };
+
+        let levels = (0..self.level_count())
+            .map(|level| {
+                let (width, height) = self.level_size(level);
+                let len = width as usize * height as usize * pixel_size;
+                vec![0; len].into_boxed_slice()
+            })
+            .collect();
+
+        self.paletted = Some(PalettedLevels {
+            fmt,
+            pixel_size,
+            levels: RefCell::new(levels),
+        });
+    }
+
+    /// Retrieves the underlying D3D11 texture.
+    pub fn texture(&self) -> &d3d11::Texture2D {
+        &self.texture
+    }
+
+    /// Retrieves the size of a mip level.
+    pub fn level_size(&self, level: u32) -> (u32, u32) {
+        let desc = self.texture.desc();
+        (
+            cmp::max(desc.Width >> level, 1),
+            cmp::max(desc.Height >> level, 1),
+        )
+    }
+
+    /// Marks a region of a mip level as modified, or the whole level if `rect` is `None`.
+    fn add_dirty_region(&self, level: u32, rect: Option<&RECT>) {
+        let (width, height) = self.level_size(level);
+
+        let rect = match rect {
+            Some(rect) => RECT {
+                left: cmp::max(rect.left, 0),
+                top: cmp::max(rect.top, 0),
+                right: cmp::min(rect.right, width as i32),
+                bottom: cmp::min(rect.bottom, height as i32),
+            },
+            None => RECT {
+                left: 0,
+                top: 0,
+                right: width as i32,
+                bottom: height as i32,
+            },
         };
 
-        unsafe { new_com_interface(texture) }
+        if rect.left >= rect.right || rect.top >= rect.bottom {
+            return;
+        }
+
+        let mut dirty_rects = self.dirty_rects.borrow_mut();
+        let rects = match dirty_rects.get_mut(level as usize) {
+            Some(rects) => rects,
+            None => return,
+        };
+
+        rects.push(rect);
+
+        if rects.len() > MAX_DIRTY_RECTS {
+            let bounds = rects.iter().fold(rect, |a, b| RECT {
+                left: cmp::min(a.left, b.left),
+                top: cmp::min(a.top, b.top),
+                right: cmp::max(a.right, b.right),
+                bottom: cmp::max(a.bottom, b.bottom),
+            });
+
+            rects.clear();
+            rects.push(bounds);
+        }
+    }
+
+    /// Checks if a lock must go through a CPU-accessible copy of this texture.
+    ///
+    /// Default textures cannot be mapped at all, and dynamic textures can only be written to.
+    fn needs_readback(&self, flags: LockFlags) -> bool {
+        let desc = self.texture.desc();
+
+        match desc.Usage {
+            D3D11_USAGE_STAGING => false,
+            D3D11_USAGE_DYNAMIC => flags.intersects(LockFlags::READ_ONLY),
+            _ => true,
+        }
+    }
+
+    /// Locks a level of this texture by copying it to a CPU-accessible texture.
+    fn lock_readback(
+        &self,
+        level: u32,
+        rect: Option<&RECT>,
+        flags: LockFlags,
+    ) -> Result<D3DLOCKED_RECT, Error> {
+        let mut readback = self.readback.borrow_mut();
+
+        // The copy is only created once the app first needs it.
+        if readback.is_none() {
+            let device = self.device().dx11_device();
+
+            *readback = Some(Readback {
+                texture: self.texture.new_staging_copy(device)?,
+                locked: Cell::new(0),
+                written: Cell::new(0),
+            });
+        }
+
+        let readback = readback.as_ref().unwrap();
+        let copy = readback.texture.as_resource();
+        let ctx = self.device_context();
+
+        unsafe {
+            ctx.CopySubresourceRegion(
+                copy,
+                level,
+                0,
+                0,
+                0,
+                self.texture.as_resource(),
+                level,
+                ptr::null(),
+            );
+        }
+
+        let mapped = ctx.map_rect(&readback.texture, level, rect, flags, UsageFlags::empty())?;
+
+        let bit = 1 << level;
+        readback.locked.set(readback.locked.get() | bit);
+        if !flags.intersects(LockFlags::READ_ONLY) {
+            readback.written.set(readback.written.get() | bit);
+        }
+
+        Ok(mapped)
+    }
+
+    /// Retrieves the regions of a mip level modified since the last call to this function.
+    pub fn take_dirty_rects(&self, level: u32) -> Vec<RECT> {
+        self.dirty_rects
+            .borrow_mut()
+            .get_mut(level as usize)
+            .map(|rects| rects.drain(..).collect())
+            .unwrap_or_default()
     }
 }
 
@@ -72,7 +360,16 @@ impl Texture {
             ComPtr::new(ptr)
         };
 
-        surface.get_desc(desc)
+        if_not_success!(surface.get_desc(desc));
+
+        // The D3D11 texture's format is not the one the app asked for.
+        if let Some(ref paletted) = self.paletted {
+            unsafe {
+                (*desc).Format = paletted.fmt;
+            }
+        }
+
+        Error::Success
     }
 
     /// Retrieves a surface representing a mip level of this texture.
@@ -87,9 +384,11 @@ impl Texture {
         let texture = self.texture.clone();
         let usage = self.usage();
         let pool = self.pool();
-        let data = SurfaceData::SubResource(level);
-
-        *ret = Surface::new(device, texture, usage, pool, data).into();
+        let parent = ComPtr::new(com_ref(self as *const Self as *const IUnknown));
+        *ret = if_error!(Surface::new_subresource(
+            device, texture, usage, pool, level, parent
+        ))
+        .into();
 
         Error::Success
     }
@@ -99,33 +398,115 @@ impl Texture {
         &self,
         level: u32,
         ret: *mut D3DLOCKED_RECT,
-        // TODO: maybe track dirty regions for efficiency.
-        _r: *const RECT,
+        r: *const RECT,
         flags: LockFlags,
     ) -> Error {
+        let _lock = self.device().lock();
         let ret = if_error!(check_mut_ref(ret));
 
-        let resource = self.texture.as_resource();
+        if level >= self.level_count() {
+            return Error::InvalidCall;
+        }
+
+        if !flags.intersects(LockFlags::READ_ONLY | LockFlags::NO_DIRTY_UPDATE) {
+            self.add_dirty_region(level, unsafe { r.as_ref() });
+        }
+
+        let rect = unsafe { r.as_ref() };
+
+        if let Some(ref paletted) = self.paletted {
+            *ret = if_error!(paletted.lock(level, self.level_size(level), rect));
+            return Error::Success;
+        }
+
+        if self.needs_readback(flags) {
+            *ret = if_error!(self.lock_readback(level, rect, flags));
+            return Error::Success;
+        }
+
         let ctx = self.device_context();
 
-        *ret = if_error!(ctx.map(resource, level, flags, self.usage()));
+        *ret = if_error!(ctx.map_rect(&self.texture, level, rect, flags, self.usage()));
 
         Error::Success
     }
 
     /// Unlocks the locked rectangle of memory.
     pub fn unlock_rect(&self, level: u32) -> Error {
+        let _lock = self.device().lock();
         let resource = self.texture.as_resource();
         let ctx = self.device_context();
 
+        if let Some(ref paletted) = self.paletted {
+            if level >= self.level_count() {
+                return Error::InvalidCall;
+            }
+
+            let palette = match self.device().current_palette() {
+                Some(palette) => palette,
+                None => {
+                    warn!("Paletted texture was unlocked without setting a palette");
+                    return Error::Success;
+                }
+            };
+
+            let mapped = if_error!(ctx.map(resource, level, LockFlags::empty(), self.usage()));
+            paletted.expand(level, self.level_size(level), palette, &mapped);
+        }
+
+        if let Some(ref readback) = *self.readback.borrow() {
+            let bit = 1 << level;
+
+            if readback.locked.get() & bit != 0 {
+                let copy = readback.texture.as_resource();
+                ctx.unmap(copy, level);
+
+                if readback.written.get() & bit != 0 {
+                    unsafe {
+                        ctx.CopySubresourceRegion(
+                            resource,
+                            level,
+                            0,
+                            0,
+                            0,
+                            copy,
+                            level,
+                            ptr::null(),
+                        );
+                    }
+                }
+
+                readback.locked.set(readback.locked.get() & !bit);
+                readback.written.set(readback.written.get() & !bit);
+
+                return Error::Success;
+            }
+        }
+
         ctx.unmap(resource, level);
 
         Error::Success
     }
 
+    /// Marks a region of the texture as modified, so that it gets copied by `UpdateTexture`.
+    ///
+    /// The region applies to the top level, and is scaled down for the other levels.
+    /// If the rect is null, the whole texture is marked as modified.
     fn add_dirty_rect(&mut self, r: *const RECT) -> Error {
-        let _r = if_error!(check_ref(r));
-        warn!("AddDirtyRect is not implemented");
+        let rect = unsafe { r.as_ref() };
+
+        for level in 0..self.level_count() {
+            let scaled = rect.map(|r| RECT {
+                left: r.left >> level,
+                top: r.top >> level,
+                // Round up, so that the region never becomes empty.
+                right: (r.right + (1 << level) - 1) >> level,
+                bottom: (r.bottom + (1 << level) - 1) >> level,
+            });
+
+            self.add_dirty_region(level, scaled.as_ref());
+        }
+
         Error::Success
     }
 }
@@ -0,0 +1,152 @@
+use std::cmp;
+use std::ffi::c_void;
+use std::sync::atomic::AtomicU32;
+
+use winapi::shared::{d3d9::*, d3d9types::*, guiddef::GUID};
+use winapi::um::unknwnbase::{IUnknown, IUnknownVtbl};
+
+use com_impl::{implementation, interface, ComInterface};
+use comptr::ComPtr;
+
+use crate::core::{fmt::dxgi_format_to_d3d, *};
+use crate::d3d11;
+use crate::Error;
+
+use super::{Device, PrivateData};
+
+/// Represents a 3D contiguous array of pixels, i.e. a mip level of a volume texture.
+#[interface(IDirect3DVolume9)]
+pub struct Volume {
+    refs: AtomicU32,
+    device: *const Device,
+    usage: UsageFlags,
+    pool: MemoryPool,
+    // Reference to our parent texture.
+    texture: d3d11::Texture3D,
+    // The volume texture containing this volume.
+    parent: ComPtr<IUnknown>,
+    // The mip level this volume represents.
+    level: u32,
+    // Data attached to this volume by the app.
+    private_data: PrivateData,
+}
+
+impl Volume {
+    /// Creates a new volume representing a mip level of a 3D texture.
+    pub fn new(
+        device: *const Device,
+        texture: d3d11::Texture3D,
+        level: u32,
+        usage: UsageFlags,
+        pool: MemoryPool,
+        parent: ComPtr<IUnknown>,
+    ) -> ComPtr<Self> {
+        let volume = Self {
+            __vtable: Box::new(Self::create_vtable()),
+            refs: AtomicU32::new(1),
+            device,
+            usage,
+            pool,
+            texture,
+            parent,
+            level,
+            private_data: PrivateData::default(),
+        };
+
+        unsafe { new_com_interface(volume) }
+    }
+
+    fn device(&self) -> &Device {
+        unsafe { &*self.device }
+    }
+}
+
+impl_iunknown!(struct Volume: IUnknown, IDirect3DVolume9);
+
+#[implementation(IDirect3DVolume9)]
+impl Volume {
+    /// Returns the parent device.
+    fn get_device(&self, ret: *mut *mut Device) -> Error {
+        let ret = if_error!(check_mut_ref(ret));
+        *ret = com_ref(self.device);
+        Error::Success
+    }
+
+    /// Attaches some data or an interface to this volume.
+    fn set_private_data(&self, guid: &GUID, data: *const c_void, size: u32, flags: u32) -> Error {
+        self.private_data.set(guid, data, size, flags)
+    }
+
+    /// Retrieves data previously attached to this volume.
+    fn get_private_data(&self, guid: &GUID, data: *mut c_void, size: *mut u32) -> Error {
+        self.private_data.get(guid, data, size)
+    }
+
+    /// Detaches data from this volume, releasing it if it is an interface.
+    fn free_private_data(&self, guid: &GUID) -> Error {
+        self.private_data.free(guid)
+    }
+
+    /// Gets the container of this volume.
+    fn get_container(&self, riid: &GUID, ret: *mut usize) -> Error {
+        let ret = if_error!(check_mut_ref(ret));
+
+        // Volumes always belong to a volume texture, but the app might ask for the device.
+        let mut result = unsafe {
+            self.parent
+                .QueryInterface(riid, ret as *mut usize as *mut _)
+        };
+
+        if result != 0 {
+            let device = self.device() as *const Device as *const IUnknown;
+            result = unsafe { (*device).QueryInterface(riid, ret as *mut usize as *mut _) };
+        }
+
+        if result != 0 {
+            *ret = 0;
+            return Error::InvalidCall;
+        }
+
+        Error::Success
+    }
+
+    /// Retrieves a description of this volume.
+    pub fn get_desc(&self, ret: *mut D3DVOLUME_DESC) -> Error {
+        let ret = if_error!(check_mut_ref(ret));
+
+        let desc = self.texture.desc();
+        let level_size = |size: u32| cmp::max(size >> self.level, 1);
+
+        ret.Format = dxgi_format_to_d3d(desc.Format);
+        ret.Type = D3DRTYPE_VOLUME;
+
+        ret.Usage = self.usage.bits();
+        ret.Pool = self.pool as u32;
+
+        ret.Width = level_size(desc.Width);
+        ret.Height = level_size(desc.Height);
+        ret.Depth = level_size(desc.Depth);
+
+        Error::Success
+    }
+
+    // -- Memory mapping functions --
+
+    /// Maps this volume's memory.
+    fn lock_box(&self, ret: *mut D3DLOCKED_BOX, _b: *const D3DBOX, flags: LockFlags) -> Error {
+        let _lock = self.device().lock();
+        let ret = if_error!(check_mut_ref(ret));
+        let resource = self.texture.as_resource();
+        let ctx = self.device().device_context();
+        *ret = if_error!(ctx.map_box(resource, self.level, flags, self.usage));
+        Error::Success
+    }
+
+    /// Unmaps this volume's memory.
+    fn unlock_box(&self) -> Error {
+        let _lock = self.device().lock();
+        let resource = self.texture.as_resource();
+        self.device().device_context().unmap(resource, self.level);
+        Error::Success
+    }
+}
@@ -1,9 +1,8 @@
-use crate::core::Context;
+use crate::core::{check_mut_ref, Context};
+use crate::d3d11;
 use crate::Error;
 use comptr::ComPtr;
 use std::sync::Once;
-use widestring::U16CStr;
-use winapi::shared::d3d9::IDirect3D9Ex;
 use winapi::shared::d3d9types::D3DCOLOR;
 use winapi::shared::minwindef::DWORD;
 use winapi::shared::ntdef::LPCWSTR;
@@ -15,10 +14,8 @@ const DX9C_DEBUG: u32 = 0x80000000 | 32;
 // had some special mingw linker flags to "fix up stdcall", maybe because "system" is cdecl there.
 // Either way, WinAPI is supposed to be stdcall, regardless of any toolchain
 
-/// # Safety
-/// This is unsafe because it's exported as DLL
-#[no_mangle]
-pub unsafe extern "stdcall" fn Direct3DCreate9(sdk_version: u32) -> Option<ComPtr<Context>> {
+/// Initializes the library, the first time one of the creation functions is called.
+fn init(sdk_version: u32) {
     // This function could be called multiple times during the lifetime of the DLL,
     // so we must protect the logger initializer.
     INIT_LOGGER.call_once(|| {
@@ -36,28 +33,45 @@ pub unsafe extern "stdcall" fn Direct3DCreate9(sdk_version: u32) -> Option<ComPt
         DX9C_DEBUG => info!("D3D9 version 9.0c Debug Mode"),
         _ => warn!("Unknown D3D9 SDK version {}", sdk_version),
     });
+}
 
-    Context::new().ok()
+/// # Safety
+/// This is unsafe because it's exported as DLL
+#[no_mangle]
+pub unsafe extern "stdcall" fn Direct3DCreate9(sdk_version: u32) -> Option<ComPtr<Context>> {
+    init(sdk_version);
+
+    Context::new(false).ok()
 }
 
 /// # Safety
 /// This is unsafe because it's exported as DLL
 #[no_mangle]
 pub unsafe extern "stdcall" fn Direct3DCreate9Ex(
-    _sdk_version: u32,
-    _ptr: *mut *mut IDirect3D9Ex,
+    sdk_version: u32,
+    ptr: *mut *mut Context,
 ) -> Error {
-    error!("D3D9Ex is not yet supported");
-    Error::NotAvailable
+    init(sdk_version);
+
+    let ret = if_error!(check_mut_ref(ptr));
+    *ret = if_error!(Context::new(true)).into();
+
+    Error::Success
+}
+
+/// # Safety
+/// This is unsafe because it's exported as DLL
+#[no_mangle]
+pub unsafe extern "stdcall" fn D3DPERF_BeginEvent(_col: D3DCOLOR, wsz_name: LPCWSTR) -> i32 {
+    // D3D11's annotations have no color, so only the name is kept.
+    d3d11::perf::begin_event(wsz_name)
 }
 
 /// # Safety
 /// This is unsafe because it's exported as DLL
 #[no_mangle]
-pub unsafe extern "stdcall" fn D3DPERF_BeginEvent(col: D3DCOLOR, wsz_name: LPCWSTR) -> i32 {
-    let name = U16CStr::from_ptr_str(wsz_name);
-    warn!("BeginEvent({}, {})", col, name.to_string_lossy());
-    0
+pub unsafe extern "stdcall" fn D3DPERF_SetMarker(_col: D3DCOLOR, wsz_name: LPCWSTR) {
+    d3d11::perf::set_marker(wsz_name);
 }
 
 /// # Safety
@@ -72,5 +86,5 @@ pub unsafe extern "stdcall" fn D3DPERF_SetOptions(dw_options: DWORD) -> i32 {
 /// This is unsafe because it's exported as DLL
 #[no_mangle]
 pub unsafe extern "stdcall" fn D3DPERF_EndEvent() -> i32 {
-    0
+    d3d11::perf::end_event()
 }
@@ -18,6 +18,9 @@ const fn make_status(code: u32) -> u32 {
 #[repr(u32)]
 pub enum Error {
     Success = 0,
+    // Not an error, but signals that an operation (e.g. getting a query's data)
+    // has not completed yet.
+    False = 1,
     NotFound = make_result(2150),
     MoreData = make_result(2151),
     NotAvailable = make_result(2154),
@@ -25,5 +25,7 @@ pub use self::dev::Device;
 
 mod d3d11;
 
+mod shader;
+
 mod entry;
 pub use self::entry::*;
@@ -9,8 +9,11 @@ macro_rules! run_once {
     }};
 }
 
+/// Implements `IUnknown` for a COM object, which supports a list of interfaces.
+///
+/// An extra interface can be supported only when a boolean field of the object is set.
 macro_rules! impl_iunknown {
-    (struct $struct_name:ty : $($ifaces:ident),*) => {
+    (struct $struct_name:ty : $($ifaces:ident),* $(; $opt_iface:ident if $flag:ident)?) => {
         #[implementation(IUnknown)]
         impl $struct_name {
             fn query_interface(&mut self, riid: &winapi::shared::guiddef::GUID, obj: &mut usize) -> i32 {
@@ -19,7 +22,9 @@ macro_rules! impl_iunknown {
 
                 *obj = 0;
 
-                if $(IsEqualGUID(riid, &$ifaces::uuidof())) || * {
+                if $(IsEqualGUID(riid, &$ifaces::uuidof())) || *
+                    $(|| (self.$flag && IsEqualGUID(riid, &$opt_iface::uuidof())))?
+                {
                     *obj = self as *mut _ as usize;
                     self.add_ref();
                     S_OK
@@ -0,0 +1,211 @@
+//! Caching of translated shaders.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use crate::Error;
+
+use super::hlsl::half_pixel_offset_enabled;
+use super::{translate, PixelVariant, ShaderKind};
+
+/// Environment variable which overrides the directory of the on-disk shader cache.
+///
+/// Setting it to an empty value disables the on-disk cache.
+const CACHE_DIR_VAR: &str = "D3D9TO11_SHADER_CACHE";
+
+/// Version of the translator's output, stored in every cache file.
+///
+/// It must be increased whenever the generated shaders change,
+/// so that shaders translated by an older version are translated again.
+const CACHE_VERSION: u32 = 1;
+
+/// Identifies the files of the on-disk shader cache.
+const CACHE_MAGIC: &[u8; 4] = b"D9SC";
+
+/// Stores the compiled bytecode of every shader translated so far.
+///
+/// Games often create the same shader more than once,
+/// and translating it again would be a waste of time.
+/// Shaders are also stored on disk, so that later runs of a game don't translate them again.
+pub struct ShaderCache {
+    entries: RefCell<HashMap<(Box<[u32]>, PixelVariant), Rc<[u8]>>>,
+    disk: Option<DiskCache>,
+}
+
+impl Default for ShaderCache {
+    fn default() -> Self {
+        Self {
+            entries: RefCell::default(),
+            disk: DiskCache::open(),
+        }
+    }
+}
+
+impl ShaderCache {
+    /// Retrieves the compiled bytecode for a D3D9 shader, translating it if needed.
+    pub fn get(
+        &self,
+        tokens: &[u32],
+        kind: ShaderKind,
+        variant: PixelVariant,
+    ) -> Result<Rc<[u8]>, Error> {
+        // Vertex shaders only have a single variant.
+        let variant = match kind {
+            ShaderKind::Vertex => PixelVariant::default(),
+            ShaderKind::Pixel => variant,
+        };
+
+        let key = (tokens.into(), variant);
+
+        if let Some(bytecode) = self.entries.borrow().get(&key) {
+            return Ok(bytecode.clone());
+        }
+
+        let cached = self
+            .disk
+            .as_ref()
+            .and_then(|disk| disk.load(tokens, variant));
+
+        let bytecode: Rc<[u8]> = match cached {
+            Some(bytecode) => bytecode.into(),
+            None => {
+                let bytecode = translate(tokens, kind, variant)?;
+
+                if let Some(ref disk) = self.disk {
+                    disk.store(tokens, variant, &bytecode);
+                }
+
+                bytecode.into()
+            }
+        };
+
+        self.entries.borrow_mut().insert(key, bytecode.clone());
+
+        Ok(bytecode)
+    }
+}
+
+/// Directory storing one file per translated shader, named after a hash of its D3D9 bytecode.
+///
+/// Every file starts with a header containing the cache version and the D3D9 bytecode,
+/// which is compared when loading the file in case of a hash collision.
+/// The compiled D3D11 bytecode follows it.
+struct DiskCache {
+    dir: PathBuf,
+}
+
+impl DiskCache {
+    /// Opens the cache directory, creating it if needed.
+    ///
+    /// By default, the cache is stored in the user's local application data directory.
+    fn open() -> Option<Self> {
+        let dir = match env::var_os(CACHE_DIR_VAR) {
+            Some(ref dir) if dir.is_empty() => return None,
+            Some(dir) => PathBuf::from(dir),
+            None => PathBuf::from(env::var_os("LOCALAPPDATA")?)
+                .join("d3d9-to-11")
+                .join("shaders"),
+        };
+
+        if let Err(err) = fs::create_dir_all(&dir) {
+            warn!(
+                "Failed to create shader cache directory {}: {}",
+                dir.display(),
+                err
+            );
+            return None;
+        }
+
+        Some(Self { dir })
+    }
+
+    /// Returns the path of the file storing a shader.
+    fn path(&self, tokens: &[u32], variant: PixelVariant) -> PathBuf {
+        let mut hasher = Fnv::default();
+        CACHE_VERSION.hash(&mut hasher);
+        tokens.hash(&mut hasher);
+        variant.hash(&mut hasher);
+        // The generated code also depends on this setting.
+        half_pixel_offset_enabled().hash(&mut hasher);
+
+        self.dir.join(format!("{:016x}.dxbc", hasher.finish()))
+    }
+
+    /// Reads the compiled bytecode of a shader, if it was stored by this version of the translator.
+    fn load(&self, tokens: &[u32], variant: PixelVariant) -> Option<Box<[u8]>> {
+        let data = fs::read(self.path(tokens, variant)).ok()?;
+
+        let header = header(tokens);
+        if !data.starts_with(&header) {
+            return None;
+        }
+
+        Some(data[header.len()..].into())
+    }
+
+    /// Writes the compiled bytecode of a shader.
+    ///
+    /// The file is written under a temporary name first, so that other processes
+    /// never read an incomplete file.
+    fn store(&self, tokens: &[u32], variant: PixelVariant, bytecode: &[u8]) {
+        let path = self.path(tokens, variant);
+        let temp = path.with_extension("tmp");
+
+        let mut data = header(tokens);
+        data.extend_from_slice(bytecode);
+
+        let result = fs::write(&temp, &data).and_then(|_| fs::rename(&temp, &path));
+        if let Err(err) = result {
+            warn!(
+                "Failed to write shader cache file {}: {}",
+                path.display(),
+                err
+            );
+            let _ = fs::remove_file(&temp);
+        }
+    }
+}
+
+/// Returns the header of the cache file storing a shader.
+fn header(tokens: &[u32]) -> Vec<u8> {
+    let mut header = Vec::with_capacity(12 + tokens.len() * 4);
+    header.extend_from_slice(CACHE_MAGIC);
+    header.extend_from_slice(&CACHE_VERSION.to_le_bytes());
+    header.extend_from_slice(&(tokens.len() as u32).to_le_bytes());
+
+    for token in tokens {
+        header.extend_from_slice(&token.to_le_bytes());
+    }
+
+    header
+}
+
+/// The 64-bit FNV-1a hash function.
+///
+/// Unlike the standard library's hasher, its results are guaranteed
+/// to be the same between runs and compiler versions.
+struct Fnv(u64);
+
+impl Default for Fnv {
+    fn default() -> Self {
+        Fnv(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= u64::from(byte);
+            self.0 = self.0.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+}
@@ -0,0 +1,85 @@
+//! Compilation of generated HLSL code using the D3D compiler.
+
+use std::{ptr, slice};
+
+use winapi::ctypes::c_void;
+use winapi::um::{d3dcommon::ID3DBlob, d3dcompiler::*};
+
+use comptr::ComPtr;
+
+use crate::core::*;
+use crate::Error;
+
+/// Copies the contents of a blob to a boxed slice.
+fn blob_to_box(blob: &ID3DBlob) -> Box<[u8]> {
+    unsafe {
+        let data = blob.GetBufferPointer() as *const u8;
+        slice::from_raw_parts(data, blob.GetBufferSize()).into()
+    }
+}
+
+/// Compiles HLSL source code to bytecode for a certain shader profile.
+pub fn compile(source: &str, profile: &'static [u8]) -> Result<Box<[u8]>, Error> {
+    let mut code = ptr::null_mut();
+    let mut errors = ptr::null_mut();
+
+    let result = unsafe {
+        D3DCompile(
+            source.as_ptr() as *const c_void,
+            source.len(),
+            ptr::null(),
+            ptr::null(),
+            ptr::null_mut(),
+            b"main\0".as_ptr() as *const _,
+            profile.as_ptr() as *const _,
+            D3DCOMPILE_OPTIMIZATION_LEVEL3,
+            0,
+            &mut code,
+            &mut errors,
+        )
+    };
+
+    if !errors.is_null() {
+        let errors: ComPtr<ID3DBlob> = ComPtr::new(errors);
+        let errors = blob_to_box(&errors);
+
+        if result != 0 {
+            error!(
+                "Failed to compile shader:\n{}\nSource code:\n{}",
+                String::from_utf8_lossy(&errors),
+                source
+            );
+        } else {
+            warn!(
+                "Shader compiler warnings:\n{}",
+                String::from_utf8_lossy(&errors)
+            );
+        }
+    }
+
+    if result != 0 || code.is_null() {
+        return Err(Error::InvalidCall);
+    }
+
+    let code: ComPtr<ID3DBlob> = ComPtr::new(code);
+
+    Ok(blob_to_box(&code))
+}
+
+/// Extracts the input signature from a compiled shader's bytecode.
+pub fn input_signature(bytecode: &[u8]) -> Result<Box<[u8]>, Error> {
+    let blob = unsafe {
+        let mut ptr = ptr::null_mut();
+
+        let result =
+            D3DGetInputSignatureBlob(bytecode.as_ptr() as *const c_void, bytecode.len(), &mut ptr);
+        if_not_success_err!(check_hresult(
+            result,
+            "Failed to get shader input signature"
+        ));
+
+        ComPtr::new(ptr)
+    };
+
+    Ok(blob_to_box(&blob))
+}
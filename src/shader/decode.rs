@@ -0,0 +1,396 @@
+//! Decoding of the D3D9 shader token stream.
+
+use std::fmt;
+
+use winapi::shared::d3d9types::*;
+
+use crate::Error;
+
+/// The pipeline stage a shader runs in.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ShaderKind {
+    Vertex,
+    Pixel,
+}
+
+/// The kind and shader model of a shader, as stored in its version token.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ShaderVersion {
+    pub kind: ShaderKind,
+    pub major: u32,
+    pub minor: u32,
+}
+
+impl ShaderVersion {
+    /// Decodes the version token at the start of every shader.
+    pub fn from_token(token: u32) -> Option<Self> {
+        let kind = match token >> 16 {
+            0xFFFE => ShaderKind::Vertex,
+            0xFFFF => ShaderKind::Pixel,
+            _ => return None,
+        };
+
+        Some(Self {
+            kind,
+            major: (token >> 8) & 0xFF,
+            minor: token & 0xFF,
+        })
+    }
+
+    /// Checks if this is one of the shader models D3D9 defines, which go up to 3.0,
+    /// and which can be translated.
+    ///
+    /// Pixel shader models 1.x use a different instruction set and register model,
+    /// which the translator doesn't implement.
+    pub fn is_supported(&self) -> bool {
+        match (self.kind, self.major, self.minor) {
+            (ShaderKind::Vertex, 1, 1) => true,
+            // Minor version 1 is used by the 2.x models.
+            (_, 2, 0..=1) | (_, 3, 0) => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for ShaderVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let prefix = match self.kind {
+            ShaderKind::Vertex => "vs",
+            ShaderKind::Pixel => "ps",
+        };
+
+        if self.major == 2 && self.minor == 1 {
+            write!(f, "{}_2_x", prefix)
+        } else {
+            write!(f, "{}_{}_{}", prefix, self.major, self.minor)
+        }
+    }
+}
+
+/// A register referenced by an instruction.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct Register {
+    pub ty: D3DSHADER_PARAM_REGISTER_TYPE,
+    pub num: u32,
+}
+
+impl Register {
+    /// Decodes the register referenced by a parameter token.
+    fn from_token(token: u32) -> Self {
+        let ty = ((token & D3DSP_REGTYPE_MASK) >> D3DSP_REGTYPE_SHIFT)
+            | ((token & D3DSP_REGTYPE_MASK2) >> D3DSP_REGTYPE_SHIFT2);
+
+        Self {
+            ty,
+            num: token & D3DSP_REGNUM_MASK,
+        }
+    }
+}
+
+/// A register an instruction writes to.
+#[derive(Debug, Copy, Clone)]
+pub struct DstParam {
+    pub reg: Register,
+    /// Which of the 4 components are written, one bit per component.
+    pub mask: u32,
+    pub saturate: bool,
+}
+
+impl DstParam {
+    fn from_token(token: u32) -> Self {
+        Self {
+            reg: Register::from_token(token),
+            mask: (token & D3DSP_WRITEMASK_ALL) >> 16,
+            saturate: token & D3DSPDM_SATURATE != 0,
+        }
+    }
+}
+
+/// A register an instruction reads from.
+#[derive(Debug, Clone)]
+pub struct SrcParam {
+    pub reg: Register,
+    /// Index of the component read into each of the 4 components.
+    pub swizzle: [u32; 4],
+    /// One of the `D3DSPSM_*` source modifiers.
+    pub modifier: u32,
+    /// The register used for relative addressing, if any.
+    pub relative: Option<Box<SrcParam>>,
+}
+
+impl SrcParam {
+    fn from_token(token: u32) -> Self {
+        let swizzle = (token & D3DVS_SWIZZLE_MASK) >> D3DVS_SWIZZLE_SHIFT;
+
+        Self {
+            reg: Register::from_token(token),
+            swizzle: [
+                swizzle & 3,
+                (swizzle >> 2) & 3,
+                (swizzle >> 4) & 3,
+                (swizzle >> 6) & 3,
+            ],
+            modifier: token & D3DSP_SRCMOD_MASK,
+            relative: None,
+        }
+    }
+}
+
+/// Declaration of an input, output or sampler register.
+#[derive(Debug, Copy, Clone)]
+pub struct Declaration {
+    pub reg: Register,
+    pub mask: u32,
+    pub usage: D3DDECLUSAGE,
+    pub usage_index: u32,
+    /// For samplers, one of the `D3DSTT_*` texture types.
+    pub texture_type: u32,
+}
+
+/// Values stored in constant registers by the shader itself.
+#[derive(Debug, Copy, Clone)]
+pub enum Definition {
+    Float(u32, [f32; 4]),
+    Int(u32, [i32; 4]),
+    Bool(u32, bool),
+}
+
+/// A decoded shader instruction.
+#[derive(Debug, Clone)]
+pub struct Instruction {
+    pub opcode: D3DSHADER_INSTRUCTION_OPCODE_TYPE,
+    /// Opcode-specific control bits, such as the comparison used by `ifc`.
+    pub control: u32,
+    pub dst: Option<DstParam>,
+    pub src: Vec<SrcParam>,
+    /// The predicate register, for predicated instructions.
+    pub predicate: Option<SrcParam>,
+}
+
+/// A fully decoded shader.
+#[derive(Debug, Clone)]
+pub struct Shader {
+    pub version: ShaderVersion,
+    pub decls: Vec<Declaration>,
+    pub defs: Vec<Definition>,
+    pub instructions: Vec<Instruction>,
+}
+
+/// Determines the number of parameter tokens of a shader model 1 instruction.
+///
+/// Newer shader models store the length in the instruction token itself.
+fn sm1_param_count(opcode: D3DSHADER_INSTRUCTION_OPCODE_TYPE) -> Option<usize> {
+    Some(match opcode {
+        D3DSIO_NOP => 0,
+        D3DSIO_MOV | D3DSIO_RCP | D3DSIO_RSQ | D3DSIO_EXP | D3DSIO_LOG | D3DSIO_LIT
+        | D3DSIO_FRC | D3DSIO_EXPP | D3DSIO_LOGP | D3DSIO_DCL => 2,
+        D3DSIO_ADD | D3DSIO_SUB | D3DSIO_MUL | D3DSIO_DP3 | D3DSIO_DP4 | D3DSIO_MIN
+        | D3DSIO_MAX | D3DSIO_SLT | D3DSIO_SGE | D3DSIO_DST | D3DSIO_M4x4 | D3DSIO_M4x3
+        | D3DSIO_M3x4 | D3DSIO_M3x3 | D3DSIO_M3x2 => 3,
+        D3DSIO_MAD => 4,
+        D3DSIO_DEF => 5,
+        _ => return None,
+    })
+}
+
+/// Determines the number of tokens following an instruction or comment token.
+///
+/// Returns `None` for the end token.
+pub fn instruction_len(version: ShaderVersion, token: u32) -> Result<Option<usize>, Error> {
+    let opcode = token & D3DSI_OPCODE_MASK;
+
+    let len = match opcode {
+        D3DSIO_END => return Ok(None),
+        D3DSIO_COMMENT => (token & D3DSI_COMMENTSIZE_MASK) >> D3DSI_COMMENTSIZE_SHIFT,
+        _ if version.major >= 2 => (token & D3DSI_INSTLENGTH_MASK) >> D3DSI_INSTLENGTH_SHIFT,
+        _ => {
+            return sm1_param_count(opcode).map(Some).ok_or_else(|| {
+                error!("Unsupported shader model 1 instruction: {}", opcode);
+                Error::InvalidCall
+            })
+        }
+    };
+
+    Ok(Some(len as usize))
+}
+
+/// Checks if an instruction's first parameter is a destination register.
+fn has_dst(opcode: D3DSHADER_INSTRUCTION_OPCODE_TYPE) -> bool {
+    match opcode {
+        D3DSIO_NOP | D3DSIO_CALL | D3DSIO_CALLNZ | D3DSIO_LOOP | D3DSIO_RET | D3DSIO_ENDLOOP
+        | D3DSIO_LABEL | D3DSIO_REP | D3DSIO_ENDREP | D3DSIO_IF | D3DSIO_IFC | D3DSIO_ELSE
+        | D3DSIO_ENDIF | D3DSIO_BREAK | D3DSIO_BREAKC | D3DSIO_BREAKP => false,
+        _ => true,
+    }
+}
+
+impl Shader {
+    /// Decodes a shader from its tokens, which must include the version and end tokens.
+    pub fn decode(tokens: &[u32]) -> Result<Self, Error> {
+        let version = tokens
+            .first()
+            .and_then(|&token| ShaderVersion::from_token(token))
+            .ok_or_else(|| {
+                error!("Invalid shader version token");
+                Error::InvalidCall
+            })?;
+
+        let mut shader = Self {
+            version,
+            decls: Vec::new(),
+            defs: Vec::new(),
+            instructions: Vec::new(),
+        };
+
+        let mut pos = 1;
+
+        loop {
+            let token = *tokens.get(pos).ok_or_else(|| {
+                error!("Shader is missing its end token");
+                Error::InvalidCall
+            })?;
+            pos += 1;
+
+            let len = match instruction_len(version, token)? {
+                Some(len) => len,
+                None => break,
+            };
+
+            let params = tokens.get(pos..pos + len).ok_or_else(|| {
+                error!("Shader instruction is truncated");
+                Error::InvalidCall
+            })?;
+            pos += len;
+
+            // Comments only contain data for tools, such as the constant table.
+            if token & D3DSI_OPCODE_MASK == D3DSIO_COMMENT {
+                continue;
+            }
+
+            shader.decode_instruction(token, params)?;
+        }
+
+        Ok(shader)
+    }
+
+    fn decode_instruction(&mut self, token: u32, params: &[u32]) -> Result<(), Error> {
+        let opcode = token & D3DSI_OPCODE_MASK;
+
+        let invalid = || {
+            error!("Invalid parameters for shader instruction {}", opcode);
+            Error::InvalidCall
+        };
+
+        match opcode {
+            D3DSIO_DCL => {
+                if params.len() != 2 {
+                    return Err(invalid());
+                }
+
+                let (dcl, dst) = (params[0], DstParam::from_token(params[1]));
+
+                self.decls.push(Declaration {
+                    reg: dst.reg,
+                    mask: dst.mask,
+                    usage: dcl & D3DSP_DCL_USAGE_MASK,
+                    usage_index: (dcl & D3DSP_DCL_USAGEINDEX_MASK) >> D3DSP_DCL_USAGEINDEX_SHIFT,
+                    texture_type: dcl & D3DSP_TEXTURETYPE_MASK,
+                });
+            }
+            D3DSIO_DEF => {
+                if params.len() != 5 {
+                    return Err(invalid());
+                }
+
+                let num = Register::from_token(params[0]).num;
+                let mut value = [0.0; 4];
+                for (v, &p) in value.iter_mut().zip(&params[1..]) {
+                    *v = f32::from_bits(p);
+                }
+
+                self.defs.push(Definition::Float(num, value));
+            }
+            D3DSIO_DEFI => {
+                if params.len() != 5 {
+                    return Err(invalid());
+                }
+
+                let num = Register::from_token(params[0]).num;
+                let mut value = [0; 4];
+                for (v, &p) in value.iter_mut().zip(&params[1..]) {
+                    *v = p as i32;
+                }
+
+                self.defs.push(Definition::Int(num, value));
+            }
+            D3DSIO_DEFB => {
+                if params.len() != 2 {
+                    return Err(invalid());
+                }
+
+                let num = Register::from_token(params[0]).num;
+                self.defs.push(Definition::Bool(num, params[1] != 0));
+            }
+            _ => {
+                let mut params = params.iter().cloned();
+                let sm1 = self.version.major < 2;
+
+                // Reads a source parameter, including its relative addressing token.
+                let read_src = |token: u32,
+                                params: &mut dyn Iterator<Item = u32>|
+                 -> Result<SrcParam, Error> {
+                    let mut src = SrcParam::from_token(token);
+
+                    if token & D3DSHADER_ADDRMODE_RELATIVE != 0 {
+                        let relative = if sm1 {
+                            // Shader model 1 can only use `a0.x` for relative addressing.
+                            SrcParam::from_token(D3DSPR_ADDR << D3DSP_REGTYPE_SHIFT)
+                        } else {
+                            SrcParam::from_token(params.next().ok_or_else(invalid)?)
+                        };
+
+                        src.relative = Some(Box::new(relative));
+                    }
+
+                    Ok(src)
+                };
+
+                let dst = if has_dst(opcode) {
+                    let token = params.next().ok_or_else(invalid)?;
+
+                    if token & D3DSHADER_ADDRMODE_RELATIVE != 0 && !sm1 {
+                        error!("Relative addressing of output registers is not supported");
+                        return Err(Error::InvalidCall);
+                    }
+
+                    Some(DstParam::from_token(token))
+                } else {
+                    None
+                };
+
+                let predicate = if token & D3DSHADER_INSTRUCTION_PREDICATED != 0 {
+                    let token = params.next().ok_or_else(invalid)?;
+                    Some(read_src(token, &mut params)?)
+                } else {
+                    None
+                };
+
+                let mut src = Vec::with_capacity(4);
+                while let Some(token) = params.next() {
+                    src.push(read_src(token, &mut params)?);
+                }
+
+                self.instructions.push(Instruction {
+                    opcode,
+                    control: (token & D3DSP_OPCODESPECIFICCONTROL_MASK)
+                        >> D3DSP_OPCODESPECIFICCONTROL_SHIFT,
+                    dst,
+                    src,
+                    predicate,
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
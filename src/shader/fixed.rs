@@ -0,0 +1,728 @@
+//! Generation of shaders emulating D3D9's fixed function pipeline.
+//!
+//! D3D11 has no fixed function pipeline, so when the app does not set
+//! its own shaders, we have to generate equivalent ones.
+
+use winapi::shared::d3d9types::*;
+
+use super::fog::{apply_fog, fog_decls, fog_factor};
+use super::hlsl::{
+    alpha_test, alpha_test_decls, clip_distances, clip_planes_decls, swizzle_sample,
+    varyings_struct,
+};
+use super::sprite::{point_size, point_sprite_decls};
+use super::{d3d_decl_usage_to_semantic, PixelVariant};
+
+/// Constant buffer slot of the fixed function vertex shader's constants.
+///
+/// The fixed function pipeline never uses the app's shader constants,
+/// so this slot cannot conflict with them.
+pub const FIXED_FUNCTION_SLOT: u32 = 3;
+
+/// Number of registers in the fixed function constant buffer.
+pub const FIXED_FUNCTION_REGISTERS: usize =
+    TEXTURE_TRANSFORMS_REGISTER as usize + MAX_TEXTURE_STAGES * 4;
+
+/// Register containing the combined world, view and projection matrix.
+pub const WORLD_VIEW_PROJ_REGISTER: u32 = 0;
+
+/// Register containing the scale and offset which map screen coordinates to clip space.
+///
+/// These are used for pre-transformed vertices, which skip the matrices.
+pub const SCREEN_TO_CLIP_REGISTER: u32 = 4;
+
+/// First register of the material, stored in the same order as `D3DMATERIAL9`'s fields:
+/// diffuse, ambient, specular and emissive colors, then the specular power in `x`.
+pub const MATERIAL_REGISTER: u32 = 5;
+
+/// Register containing the global ambient light color, from `D3DRS_AMBIENT`.
+pub const AMBIENT_REGISTER: u32 = 10;
+
+/// Register containing the world matrix, used to light vertices in world space.
+pub const WORLD_REGISTER: u32 = 11;
+
+/// Register containing the position of the camera in world space.
+pub const EYE_POSITION_REGISTER: u32 = 15;
+
+/// First register of the enabled lights.
+///
+/// Every light is stored in `LIGHT_REGISTERS` registers:
+/// - its diffuse, specular and ambient colors
+/// - its position, and its range in `w`
+/// - its direction, and its type in `w`
+/// - its three attenuation factors, and its spot light falloff in `w`
+/// - the cosines of half of its inner and outer spot light cone angles
+pub const LIGHTS_REGISTER: u32 = 16;
+
+/// Number of registers used by every light.
+pub const LIGHT_REGISTERS: usize = 7;
+
+/// Maximum number of lights which can be enabled at the same time.
+pub const MAX_LIGHTS: usize = 8;
+
+/// First register of the texture coordinate transforms, with one matrix per texture stage.
+pub const TEXTURE_TRANSFORMS_REGISTER: u32 =
+    LIGHTS_REGISTER + (MAX_LIGHTS * LIGHT_REGISTERS) as u32;
+
+/// Number of texture stages in the fixed function pipeline.
+pub const MAX_TEXTURE_STAGES: usize = 8;
+
+/// Returns the declaration of the fixed function constants, matching the registers above.
+fn fixed_function_decls() -> String {
+    format!(
+        "cbuffer FixedFunction : register(b{}) {{
+    row_major float4x4 world_view_proj;
+    float4 screen_to_clip;
+    float4 material_diffuse;
+    float4 material_ambient;
+    float4 material_specular;
+    float4 material_emissive;
+    float material_power;
+    float4 global_ambient;
+    row_major float4x4 world;
+    float4 eye_position;
+    Light lights[{}];
+    row_major float4x4 texture_transforms[{}];
+}};
+",
+        FIXED_FUNCTION_SLOT, MAX_LIGHTS, MAX_TEXTURE_STAGES
+    )
+}
+
+/// Returns the declaration of the structure describing a light.
+fn light_struct() -> &'static str {
+    "struct Light {
+    float4 diffuse;
+    float4 specular;
+    float4 ambient;
+    float4 position;
+    float4 direction;
+    float4 attenuation;
+    float4 spot;
+};
+"
+}
+
+/// Returns a function which adds the contribution of a light to the lighting of a vertex.
+fn light_function() -> String {
+    format!(
+        "void add_light(Light light, float3 pos, float3 normal, float3 view_dir,
+               inout float3 ambient, inout float3 diffuse, inout float3 specular) {{
+    float3 dir;
+    float att = 1.0;
+    if (light.direction.w == {directional}) {{
+        dir = -normalize(light.direction.xyz);
+    }} else {{
+        float3 to_light = light.position.xyz - pos;
+        float dist = length(to_light);
+        dir = to_light / dist;
+        float3 factors = light.attenuation.xyz;
+        att = dist > light.position.w ? 0.0 : 1.0 / dot(factors, float3(1.0, dist, dist * dist));
+        if (light.direction.w == {spot}) {{
+            float rho = dot(-dir, normalize(light.direction.xyz));
+            float cone = saturate((rho - light.spot.y) / max(light.spot.x - light.spot.y, 1e-6));
+            att *= rho > light.spot.x ? 1.0 : pow(cone, light.attenuation.w);
+        }}
+    }}
+    float n_dot_l = saturate(dot(normal, dir));
+    ambient += light.ambient.rgb * att;
+    diffuse += light.diffuse.rgb * n_dot_l * att;
+    if (n_dot_l > 0.0) {{
+        float n_dot_h = saturate(dot(normal, normalize(dir + view_dir)));
+        specular += light.specular.rgb * pow(n_dot_h, material_power) * att;
+    }}
+}}
+",
+        directional = D3DLIGHT_DIRECTIONAL,
+        spot = D3DLIGHT_SPOT,
+    )
+}
+
+/// The state of the fixed function vertex pipeline which affects the generated shader.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FixedVertexKey {
+    /// Usage and usage index of every element of the vertex declaration.
+    pub inputs: Vec<(D3DDECLUSAGE, u32)>,
+    /// Whether vertex colors are computed by lighting.
+    pub lighting: bool,
+    /// Where the diffuse, ambient, specular and emissive material colors come from.
+    pub material_sources: [D3DMATERIALCOLORSOURCE; 4],
+    /// Number of enabled lights.
+    pub light_count: usize,
+    /// Whether the specular color is computed by lighting.
+    pub specular: bool,
+    /// Equation of the fog computed for every vertex, or `D3DFOG_NONE`
+    /// if the fog factor comes from the specular color's alpha.
+    pub fog: D3DFOGMODE,
+    /// Whether the vertex fog uses the distance to the camera, rather than the depth.
+    pub range_fog: bool,
+    /// Index of the input texture coordinates used by every texture stage.
+    pub texcoord_indices: [u32; MAX_TEXTURE_STAGES],
+    /// The `D3DTTFF_*` flags of every stage's texture coordinate transform,
+    /// and the number of components of the transformed coordinates.
+    pub texture_transforms: [(u32, u32); MAX_TEXTURE_STAGES],
+}
+
+impl FixedVertexKey {
+    /// Builds the key for the elements of a vertex declaration and the lighting state.
+    pub fn new(
+        elems: &[D3DVERTEXELEMENT9],
+        lighting: bool,
+        material_sources: [D3DMATERIALCOLORSOURCE; 4],
+        light_count: usize,
+        specular: bool,
+        fog: D3DFOGMODE,
+        range_fog: bool,
+        texcoord_indices: [u32; MAX_TEXTURE_STAGES],
+        transform_flags: [u32; MAX_TEXTURE_STAGES],
+    ) -> Self {
+        let inputs: Vec<_> = elems
+            .iter()
+            .filter(|elem| elem.Type as u32 != D3DDECLTYPE_UNUSED)
+            .map(|elem| (elem.Usage as u32, elem.UsageIndex as u32))
+            .collect();
+
+        // Pre-transformed vertices are never lit.
+        let has_usage = |usage| inputs.iter().any(|&(u, _)| u == usage);
+        let lighting =
+            lighting && has_usage(D3DDECLUSAGE_POSITION) && !has_usage(D3DDECLUSAGE_POSITIONT);
+
+        // The lighting state is irrelevant without lighting.
+        let (material_sources, light_count, specular) = if lighting {
+            (material_sources, light_count, specular)
+        } else {
+            ([D3DMCS_MATERIAL; 4], 0, false)
+        };
+
+        // Pre-transformed vertices never compute their fog.
+        let (fog, range_fog) =
+            if has_usage(D3DDECLUSAGE_POSITION) && !has_usage(D3DDECLUSAGE_POSITIONT) {
+                (fog, range_fog && fog != D3DFOG_NONE)
+            } else {
+                (D3DFOG_NONE, false)
+            };
+
+        // Only the transformed coordinates need to know their number of components.
+        let mut texture_transforms = [(D3DTTFF_DISABLE, 0); MAX_TEXTURE_STAGES];
+        for (stage, &flags) in transform_flags.iter().enumerate() {
+            let elem = elems.iter().find(|elem| {
+                elem.Usage as u32 == D3DDECLUSAGE_TEXCOORD
+                    && elem.UsageIndex as u32 == texcoord_indices[stage]
+            });
+
+            if let Some(elem) = elem {
+                if flags != D3DTTFF_DISABLE {
+                    texture_transforms[stage] = (flags, decl_type_components(elem.Type as u32));
+                }
+            }
+        }
+
+        Self {
+            inputs,
+            lighting,
+            material_sources,
+            light_count,
+            specular,
+            fog,
+            range_fog,
+            texcoord_indices,
+            texture_transforms,
+        }
+    }
+
+    /// Returns the expression of a material color, which might come from a vertex color.
+    fn material_color(&self, index: usize, name: &str) -> String {
+        let input = match self.material_sources[index] {
+            D3DMCS_COLOR1 => self.input(D3DDECLUSAGE_COLOR, 0),
+            D3DMCS_COLOR2 => self.input(D3DDECLUSAGE_COLOR, 1),
+            _ => None,
+        };
+
+        match input {
+            Some(i) => format!("input.v{}", i),
+            None => format!("material_{}", name),
+        }
+    }
+
+    /// Finds the input with a certain usage.
+    fn input(&self, usage: D3DDECLUSAGE, index: u32) -> Option<usize> {
+        self.inputs
+            .iter()
+            .position(|&input| input == (usage, index))
+    }
+}
+
+/// Returns the number of components of a vertex element type.
+fn decl_type_components(ty: D3DDECLTYPE) -> u32 {
+    match ty {
+        D3DDECLTYPE_FLOAT1 => 1,
+        D3DDECLTYPE_FLOAT2
+        | D3DDECLTYPE_SHORT2
+        | D3DDECLTYPE_SHORT2N
+        | D3DDECLTYPE_USHORT2N
+        | D3DDECLTYPE_FLOAT16_2 => 2,
+        D3DDECLTYPE_FLOAT3 | D3DDECLTYPE_UDEC3 | D3DDECLTYPE_DEC3N => 3,
+        _ => 4,
+    }
+}
+
+/// Extends the input of a texture coordinate transform to 4 components.
+///
+/// D3D9 puts a 1 after the coordinates' last component,
+/// so that 2D coordinates can be translated with the matrix's third row.
+fn texture_transform_input(input: &str, components: u32) -> String {
+    match components {
+        1 => format!("float4({}.x, 1.0, 0.0, 0.0)", input),
+        2 => format!("float4({}.xy, 1.0, 0.0)", input),
+        3 => format!("float4({}.xyz, 1.0)", input),
+        _ => input.to_string(),
+    }
+}
+
+/// Returns the expression which keeps the components of a transformed texture coordinate
+/// that are passed to the rasterizer.
+///
+/// Unused components are zeroed, except for `w` which is set to 1.
+/// For projected coordinates, the component to divide by is moved to `w`.
+fn texture_transform_output(coord: &str, flags: u32) -> String {
+    let count = match flags & !D3DTTFF_PROJECTED {
+        count @ 1..=3 => count as usize,
+        _ => return coord.to_string(),
+    };
+
+    let projected = flags & D3DTTFF_PROJECTED != 0;
+    let kept = if projected { count - 1 } else { count };
+
+    let component = |i: usize| format!("{}.{}", coord, &"xyzw"[i..=i]);
+
+    let mut values: Vec<_> = (0..3)
+        .map(|i| {
+            if i < kept {
+                component(i)
+            } else {
+                "0.0".to_string()
+            }
+        })
+        .collect();
+
+    values.push(if projected {
+        component(count - 1)
+    } else {
+        "1.0".to_string()
+    });
+
+    format!("float4({})", values.join(", "))
+}
+
+/// Generates the source code of a fixed function vertex shader.
+pub fn vertex_shader_source(key: &FixedVertexKey) -> String {
+    let mut s = varyings_struct();
+    s.push_str(light_struct());
+    s.push_str(&fixed_function_decls());
+    s.push_str(&clip_planes_decls());
+    s.push_str(&point_sprite_decls());
+    s.push_str(&fog_decls());
+
+    if key.lighting {
+        s.push_str(&light_function());
+    }
+
+    s.push_str("\nstruct VertexInput {\n");
+    for (i, &(usage, index)) in key.inputs.iter().enumerate() {
+        let semantic = d3d_decl_usage_to_semantic(usage);
+        let semantic = String::from_utf8_lossy(&semantic[..semantic.len() - 1]);
+        s.push_str(&format!("    float4 v{} : {}{};\n", i, semantic, index));
+    }
+    s.push_str("};\n\n");
+
+    s.push_str("Varyings main(VertexInput input) {\n");
+    s.push_str("    Varyings output = (Varyings)0;\n");
+
+    match key.input(D3DDECLUSAGE_PSIZE, 0) {
+        Some(i) => s.push_str(&format!("    float size = input.v{}.x;\n", i)),
+        None => s.push_str("    float size = point_size;\n"),
+    }
+
+    if let Some(i) = key.input(D3DDECLUSAGE_POSITIONT, 0) {
+        // Pre-transformed vertices are in screen space, with the reciprocal of W in `w`.
+        s.push_str(&format!("    float4 pos = input.v{};\n", i));
+        s.push_str("    float w = 1.0 / pos.w;\n");
+        s.push_str("    float2 xy = pos.xy * screen_to_clip.xy + screen_to_clip.zw;\n");
+        s.push_str("    output.pos = float4(xy, pos.z, 1.0) * w;\n");
+    } else if let Some(i) = key.input(D3DDECLUSAGE_POSITION, 0) {
+        s.push_str(&format!(
+            "    float4 pos = float4(input.v{}.xyz, 1.0);\n",
+            i
+        ));
+        s.push_str("    output.pos = mul(pos, world_view_proj);\n");
+
+        // The fixed function pipeline gets the planes in world space.
+        // Pre-transformed vertices are never clipped.
+        s.push_str("    float4 clip_pos = mul(pos, world);\n");
+        for line in &clip_distances("clip_pos") {
+            s.push_str(&format!("    {}\n", line));
+        }
+
+        // Only untransformed points are scaled by their distance to the camera.
+        s.push_str("    if (point_scale_enable != 0.0) {\n");
+        s.push_str("        float dist = distance(clip_pos.xyz, eye_position.xyz);\n");
+        s.push_str("        float3 factors = float3(1.0, dist, dist * dist);\n");
+        s.push_str("        size *= viewport_size.y * rsqrt(dot(point_scale, factors));\n");
+        s.push_str("    }\n");
+    }
+
+    s.push_str(&format!("    {}\n", point_size("size")));
+
+    let dist = if key.range_fog {
+        "distance(clip_pos.xyz, eye_position.xyz)"
+    } else {
+        // This is the depth in view space, for the usual projection matrices.
+        "output.pos.w"
+    };
+    // Without vertex fog, the app can supply its own fog factor in the specular alpha.
+    let specular_alpha = key
+        .input(D3DDECLUSAGE_COLOR, 1)
+        .map(|i| format!("input.v{}.a", i))
+        .unwrap_or_else(|| "1.0".to_string());
+    let fog = fog_factor(key.fog, dist).unwrap_or(specular_alpha);
+    s.push_str(&format!("    output.fog.x = {};\n", fog));
+
+    if key.lighting {
+        for (i, name) in ["diffuse", "ambient", "specular", "emissive"]
+            .iter()
+            .enumerate()
+        {
+            let color = key.material_color(i, name);
+            s.push_str(&format!("    float4 {} = {};\n", name, color));
+        }
+
+        // Lighting is computed in world space.
+        s.push_str("    float3 world_pos = mul(pos, world).xyz;\n");
+        match key.input(D3DDECLUSAGE_NORMAL, 0) {
+            Some(i) => s.push_str(&format!(
+                "    float3 normal = normalize(mul(input.v{}.xyz, (float3x3)world));\n",
+                i
+            )),
+            None => s.push_str("    float3 normal = float3(0.0, 0.0, 0.0);\n"),
+        }
+        s.push_str("    float3 view_dir = normalize(eye_position.xyz - world_pos);\n");
+
+        s.push_str("    float3 ambient_light = float3(0.0, 0.0, 0.0);\n");
+        s.push_str("    float3 diffuse_light = float3(0.0, 0.0, 0.0);\n");
+        s.push_str("    float3 specular_light = float3(0.0, 0.0, 0.0);\n");
+
+        for i in 0..key.light_count {
+            s.push_str(&format!(
+                "    add_light(lights[{}], world_pos, normal, view_dir, \
+                 ambient_light, diffuse_light, specular_light);\n",
+                i
+            ));
+        }
+
+        s.push_str("    float3 color = emissive.rgb\n");
+        s.push_str("        + ambient.rgb * (global_ambient.rgb + ambient_light)\n");
+        s.push_str("        + diffuse.rgb * diffuse_light;\n");
+        s.push_str("    output.color0 = float4(saturate(color), diffuse.a);\n");
+
+        if key.specular {
+            s.push_str("    float3 spec = specular.rgb * specular_light;\n");
+            s.push_str("    output.color1 = float4(saturate(spec), specular.a);\n");
+        }
+    } else {
+        // Vertices without a diffuse color are white.
+        match key.input(D3DDECLUSAGE_COLOR, 0) {
+            Some(i) => s.push_str(&format!("    output.color0 = input.v{};\n", i)),
+            None => s.push_str("    output.color0 = float4(1.0, 1.0, 1.0, 1.0);\n"),
+        }
+
+        if let Some(i) = key.input(D3DDECLUSAGE_COLOR, 1) {
+            s.push_str(&format!("    output.color1 = input.v{};\n", i));
+        }
+    }
+
+    for (n, &(flags, components)) in key.texture_transforms.iter().enumerate() {
+        if let Some(i) = key.input(D3DDECLUSAGE_TEXCOORD, key.texcoord_indices[n]) {
+            let input = format!("input.v{}", i);
+            if flags == D3DTTFF_DISABLE {
+                s.push_str(&format!("    output.texcoord{} = {};\n", n, input));
+            } else {
+                s.push_str(&format!(
+                    "    float4 tc{} = mul({}, texture_transforms[{}]);\n",
+                    n,
+                    texture_transform_input(&input, components),
+                    n
+                ));
+                s.push_str(&format!(
+                    "    output.texcoord{} = {};\n",
+                    n,
+                    texture_transform_output(&format!("tc{}", n), flags)
+                ));
+            }
+        }
+    }
+
+    s.push_str("    return output;\n}\n");
+    s
+}
+
+/// Constant buffer slot of the fixed function pixel shader's constants.
+pub const TEXTURE_STAGES_SLOT: u32 = 4;
+
+/// Number of registers in the texture stages constant buffer:
+/// - the texture factor
+/// - the constant of every stage
+/// - the bump mapping matrix of every stage, in the order of the `D3DTSS_BUMPENVMAT*` states
+/// - the bump mapping luminance scale and offset of every stage
+pub const TEXTURE_STAGES_REGISTERS: usize = 1 + 3 * MAX_TEXTURE_STAGES;
+
+/// The state of a texture stage which affects the fixed function pixel shader.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct TextureStage {
+    /// Operation combining the color arguments.
+    pub color_op: D3DTEXTUREOP,
+    /// The `D3DTA_*` color arguments, in the order of `D3DTSS_COLORARG0`, `1` and `2`.
+    pub color_args: [u32; 3],
+    /// Operation combining the alpha arguments.
+    pub alpha_op: D3DTEXTUREOP,
+    /// The `D3DTA_*` alpha arguments, in the same order as the color arguments.
+    pub alpha_args: [u32; 3],
+    /// Register which receives the result, either `D3DTA_CURRENT` or `D3DTA_TEMP`.
+    pub result_arg: u32,
+    /// Type of the bound texture, or `D3DSTT_UNKNOWN` if there is none.
+    pub texture_type: D3DSAMPLER_TEXTURE_TYPE,
+    /// Whether the texture coordinates must be divided by their `w` component.
+    pub projected: bool,
+}
+
+/// The state of the fixed function pixel pipeline which affects the generated shader.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FixedPixelKey {
+    /// State emulated by every pixel shader.
+    pub variant: PixelVariant,
+    /// The texture stages, up to the first disabled one.
+    pub stages: Vec<TextureStage>,
+    /// Whether the specular color is added after the last stage.
+    pub specular: bool,
+}
+
+/// Returns the declaration of the texture stage constants, matching the registers above.
+fn texture_stage_decls() -> String {
+    format!(
+        "cbuffer TextureStages : register(b{}) {{
+    float4 texture_factor;
+    float4 stage_constants[{stages}];
+    float4 bump_env_matrices[{stages}];
+    float4 bump_env_luminance[{stages}];
+}};
+",
+        TEXTURE_STAGES_SLOT,
+        stages = MAX_TEXTURE_STAGES
+    )
+}
+
+/// Returns the expression of a texture stage argument.
+fn stage_arg(arg: u32, stage: usize) -> String {
+    let value = match arg & D3DTA_SELECTMASK {
+        D3DTA_DIFFUSE => "diffuse".to_string(),
+        D3DTA_CURRENT => "current".to_string(),
+        D3DTA_TEXTURE => format!("texel{}", stage),
+        D3DTA_TFACTOR => "texture_factor".to_string(),
+        D3DTA_SPECULAR => "specular".to_string(),
+        D3DTA_TEMP => "temp".to_string(),
+        D3DTA_CONSTANT => format!("stage_constants[{}]", stage),
+        _ => {
+            warn!("Unknown texture stage argument: {}", arg);
+            "current".to_string()
+        }
+    };
+
+    let value = if arg & D3DTA_ALPHAREPLICATE != 0 {
+        format!("{}.aaaa", value)
+    } else {
+        value
+    };
+
+    if arg & D3DTA_COMPLEMENT != 0 {
+        format!("(1.0 - {})", value)
+    } else {
+        value
+    }
+}
+
+/// Returns the expression of a texture stage operation applied to its arguments.
+///
+/// The operation is computed on all 4 channels, the caller selects the ones it needs.
+fn stage_op(op: D3DTEXTUREOP, args: &[u32; 3], stage: usize) -> String {
+    let [a0, a1, a2] = [
+        stage_arg(args[0], stage),
+        stage_arg(args[1], stage),
+        stage_arg(args[2], stage),
+    ];
+
+    match op {
+        D3DTOP_SELECTARG1 => a1,
+        D3DTOP_SELECTARG2 => a2,
+        D3DTOP_MODULATE => format!("{} * {}", a1, a2),
+        D3DTOP_MODULATE2X => format!("{} * {} * 2.0", a1, a2),
+        D3DTOP_MODULATE4X => format!("{} * {} * 4.0", a1, a2),
+        D3DTOP_ADD => format!("{} + {}", a1, a2),
+        D3DTOP_ADDSIGNED => format!("{} + {} - 0.5", a1, a2),
+        D3DTOP_ADDSIGNED2X => format!("({} + {} - 0.5) * 2.0", a1, a2),
+        D3DTOP_SUBTRACT => format!("{} - {}", a1, a2),
+        D3DTOP_ADDSMOOTH => format!("{} + {} * (1.0 - {})", a1, a2, a1),
+        D3DTOP_BLENDDIFFUSEALPHA => format!("lerp({}, {}, diffuse.a)", a2, a1),
+        D3DTOP_BLENDTEXTUREALPHA => format!("lerp({}, {}, texel{}.a)", a2, a1, stage),
+        D3DTOP_BLENDFACTORALPHA => format!("lerp({}, {}, texture_factor.a)", a2, a1),
+        D3DTOP_BLENDTEXTUREALPHAPM => format!("{} + {} * (1.0 - texel{}.a)", a1, a2, stage),
+        D3DTOP_BLENDCURRENTALPHA => format!("lerp({}, {}, current.a)", a2, a1),
+        D3DTOP_MODULATEALPHA_ADDCOLOR => format!("{} + {}.aaaa * {}", a1, a1, a2),
+        D3DTOP_MODULATECOLOR_ADDALPHA => format!("{} * {} + {}.aaaa", a1, a2, a1),
+        D3DTOP_MODULATEINVALPHA_ADDCOLOR => format!("(1.0 - {}.aaaa) * {} + {}", a1, a2, a1),
+        D3DTOP_MODULATEINVCOLOR_ADDALPHA => format!("(1.0 - {}) * {} + {}.aaaa", a1, a2, a1),
+        D3DTOP_DOTPRODUCT3 => format!("(4.0 * dot({}.rgb - 0.5, {}.rgb - 0.5)).xxxx", a1, a2),
+        D3DTOP_MULTIPLYADD => format!("{} + {} * {}", a0, a1, a2),
+        D3DTOP_LERP => format!("lerp({}, {}, {})", a2, a1, a0),
+        _ => {
+            warn!("Unsupported texture stage operation: {}", op);
+            a1
+        }
+    }
+}
+
+/// Returns whether a texture stage operation bump maps the next stage.
+fn is_bump_env_op(op: D3DTEXTUREOP) -> bool {
+    op == D3DTOP_BUMPENVMAP || op == D3DTOP_BUMPENVMAPLUMINANCE
+}
+
+/// Returns the expression sampling the texture of a stage.
+///
+/// The coordinates can be perturbed by an offset, for bump mapping.
+/// Stages without a texture read opaque white.
+fn stage_sample(
+    stage: &TextureStage,
+    n: usize,
+    variant: &PixelVariant,
+    offset: Option<&str>,
+) -> String {
+    let coord = format!("input.texcoord{}", n);
+    let coord = if stage.projected {
+        format!("({}.xyz / {}.w)", coord, coord)
+    } else {
+        coord
+    };
+
+    let coord = match (stage.texture_type, offset) {
+        (D3DSTT_UNKNOWN, _) => return "float4(1.0, 1.0, 1.0, 1.0)".to_string(),
+        (D3DSTT_CUBE, Some(offset)) | (D3DSTT_VOLUME, Some(offset)) => {
+            format!("({}.xyz + float3({}, 0.0))", coord, offset)
+        }
+        (D3DSTT_CUBE, None) | (D3DSTT_VOLUME, None) => format!("{}.xyz", coord),
+        (_, Some(offset)) => format!("({}.xy + {})", coord, offset),
+        (_, None) => format!("{}.xy", coord),
+    };
+
+    let sample = format!("tex{}.Sample(samp{}, {})", n, n, coord);
+
+    swizzle_sample(variant.swizzles[n], sample)
+}
+
+/// Generates the source code of a fixed function pixel shader.
+pub fn pixel_shader_source(key: &FixedPixelKey) -> String {
+    let variant = key.variant;
+
+    let mut s = varyings_struct();
+    s.push_str(&alpha_test_decls());
+    s.push_str(&fog_decls());
+    s.push_str(&texture_stage_decls());
+
+    for (n, stage) in key.stages.iter().enumerate() {
+        let ty = match stage.texture_type {
+            D3DSTT_UNKNOWN => continue,
+            D3DSTT_CUBE => "TextureCube",
+            D3DSTT_VOLUME => "Texture3D",
+            _ => "Texture2D",
+        };
+        s.push_str(&format!("{} tex{} : register(t{});\n", ty, n, n));
+        s.push_str(&format!("SamplerState samp{} : register(s{});\n", n, n));
+    }
+
+    s.push_str("\nfloat4 main(Varyings input) : SV_Target0 {\n");
+    s.push_str("    float4 diffuse = input.color0;\n");
+    s.push_str("    float4 specular = input.color1;\n");
+    s.push_str("    float4 current = diffuse;\n");
+    s.push_str("    float4 temp = 0.0;\n");
+
+    for (n, stage) in key.stages.iter().enumerate() {
+        // A bump mapping stage offsets the coordinates of the next one by its transformed texel.
+        let bump = n
+            .checked_sub(1)
+            .map(|prev| (prev, key.stages[prev].color_op))
+            .filter(|&(_, op)| is_bump_env_op(op));
+
+        if let Some((prev, _)) = bump {
+            s.push_str(&format!(
+                "    float2 bump{} = mul(texel{}.rg, float2x2(bump_env_matrices[{}]));\n",
+                n, prev, prev
+            ));
+        }
+
+        let offset = bump.map(|_| format!("bump{}", n));
+        s.push_str(&format!(
+            "    float4 texel{} = {};\n",
+            n,
+            stage_sample(stage, n, &variant, offset.as_ref().map(String::as_str))
+        ));
+
+        // The luminance is stored in the third channel of the bump map.
+        if let Some((prev, D3DTOP_BUMPENVMAPLUMINANCE)) = bump {
+            s.push_str(&format!(
+                "    float2 luminance{} = bump_env_luminance[{}].xy;\n",
+                n, prev
+            ));
+            s.push_str(&format!(
+                "    texel{}.rgb *= saturate(texel{}.b * luminance{}.x + luminance{}.y);\n",
+                n, prev, n, n
+            ));
+        }
+
+        // The result of a bump mapping stage is only used by the next stage.
+        if is_bump_env_op(stage.color_op) {
+            continue;
+        }
+
+        let color = stage_op(stage.color_op, &stage.color_args, n);
+
+        // The dot product is written to all channels, ignoring the alpha operation.
+        let alpha = match (stage.color_op, stage.alpha_op) {
+            (D3DTOP_DOTPRODUCT3, _) => format!("({}).a", color),
+            // The alpha operation can't really be disabled while the color operation isn't.
+            (_, D3DTOP_DISABLE) => "current.a".to_string(),
+            (_, op) => format!("({}).a", stage_op(op, &stage.alpha_args, n)),
+        };
+
+        let result = match stage.result_arg & D3DTA_SELECTMASK {
+            D3DTA_TEMP => "temp",
+            _ => "current",
+        };
+
+        s.push_str(&format!(
+            "    {} = saturate(float4(({}).rgb, {}));\n",
+            result, color, alpha
+        ));
+    }
+
+    if key.specular {
+        s.push_str("    current.rgb = saturate(current.rgb + specular.rgb);\n");
+    }
+
+    if let Some(test) = alpha_test(variant.alpha_func, "current.a") {
+        s.push_str(&format!("    {}\n", test));
+    }
+
+    for line in apply_fog(variant.fog, "current") {
+        s.push_str(&format!("    {}\n", line));
+    }
+
+    s.push_str("    return current;\n}\n");
+    s
+}
@@ -0,0 +1,84 @@
+//! Emulation of D3D9's fog.
+//!
+//! The fog factor is stored in the `x` component of the fog varying,
+//! where 1 means no fog and 0 means the color is entirely replaced by the fog color.
+//! It is either computed by the vertex shader, or for table fog, by the pixel shader.
+
+use winapi::shared::d3d9types::*;
+
+/// Constant buffer slot of the fog parameters.
+///
+/// It is bound to both the vertex shader and the pixel shader.
+pub const FOG_SLOT: u32 = 6;
+
+/// Number of registers in the fog constant buffer:
+/// - the fog color
+/// - the start and end distances of linear fog, and the density of exponential fog
+pub const FOG_REGISTERS: usize = 2;
+
+/// How fog is applied by pixel shaders.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum FogMode {
+    /// Fog is disabled.
+    None,
+    /// The fog factor is interpolated from the vertices.
+    Vertex,
+    /// The fog factor is computed for every pixel from its depth,
+    /// using one of the `D3DFOGMODE` equations.
+    Table(D3DFOGMODE),
+}
+
+/// Returns the declaration of the fog constants, matching the registers above.
+pub fn fog_decls() -> String {
+    format!(
+        "cbuffer Fog : register(b{}) {{
+    float4 fog_color;
+    float fog_start;
+    float fog_end;
+    float fog_density;
+}};
+",
+        FOG_SLOT
+    )
+}
+
+/// Returns the expression computing the fog factor at a certain distance from the camera.
+///
+/// Returns `None` if the mode doesn't compute any fog.
+pub fn fog_factor(mode: D3DFOGMODE, dist: &str) -> Option<String> {
+    Some(match mode {
+        D3DFOG_LINEAR => format!("saturate((fog_end - {}) / (fog_end - fog_start))", dist),
+        D3DFOG_EXP => format!("saturate(exp(-{} * fog_density))", dist),
+        D3DFOG_EXP2 => format!(
+            "saturate(exp(-({d} * fog_density) * ({d} * fog_density)))",
+            d = dist
+        ),
+        D3DFOG_NONE => return None,
+        _ => {
+            warn!("Unknown fog mode: {}", mode);
+            return None;
+        }
+    })
+}
+
+/// Returns the statements which blend a pixel shader's color output with the fog color.
+///
+/// Only the color is fogged, the alpha is left as is.
+pub fn apply_fog(mode: FogMode, color: &str) -> Vec<String> {
+    // The eye-space depth is in the `w` component of the pixel's position,
+    // like the W-based fog D3D9 uses with perspective projections.
+    let factor = match mode {
+        FogMode::None => return Vec::new(),
+        FogMode::Vertex => Some("saturate(input.fog.x)".to_string()),
+        FogMode::Table(mode) => fog_factor(mode, "input.pos.w"),
+    };
+
+    factor
+        .map(|factor| {
+            vec![
+                format!("float fog = {};", factor),
+                format!("{}.rgb = lerp(fog_color.rgb, {}.rgb, fog);", color, color),
+            ]
+        })
+        .unwrap_or_default()
+}
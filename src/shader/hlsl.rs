@@ -0,0 +1,1242 @@
+//! Generation of HLSL code from decoded D3D9 shaders.
+//!
+//! Every D3D9 register becomes a local `float4` variable (or an array, for constants),
+//! and every instruction computes a `float4` value which is then masked
+//! and stored into its destination register.
+
+use std::collections::BTreeSet;
+use std::env;
+
+use winapi::shared::d3d9types::*;
+
+use crate::core::fmt::TextureSwizzle;
+use crate::Error;
+
+use super::decode::*;
+use super::fog::{apply_fog, fog_decls};
+use super::sprite::{point_size, point_sprite_decls};
+use super::{d3d_decl_usage_to_semantic, PixelVariant};
+
+/// Environment variable which makes vertex shaders move their positions by half a pixel
+/// when set to `1`, to match the pixel centers of D3D9.
+///
+/// Apps which already work around the difference would end up shifted the other way,
+/// so this is only applied if the user asks for it.
+const HALF_PIXEL_OFFSET_VAR: &str = "D3D9TO11_HALF_PIXEL_OFFSET";
+
+/// Checks if the user asked for vertex shaders to apply D3D9's half pixel offset.
+pub fn half_pixel_offset_enabled() -> bool {
+    env::var_os(HALF_PIXEL_OFFSET_VAR).map_or(false, |value| value == "1")
+}
+
+/// Number of float constant registers exposed to shaders.
+pub const MAX_FLOAT_CONSTANTS: u32 = 256;
+/// Number of integer constant registers exposed to shaders.
+pub const MAX_INT_CONSTANTS: u32 = 16;
+/// Number of boolean constant registers exposed to shaders.
+pub const MAX_BOOL_CONSTANTS: u32 = 16;
+/// Range of the values stored in the registers of shader model 1 pixel shaders,
+/// which use fixed point numbers.
+pub const PS_1X_MAX_VALUE: f32 = 8.0;
+/// Constant buffer slot of the alpha test's reference value.
+pub const ALPHA_TEST_SLOT: u32 = 3;
+/// Constant buffer slot of the user clip planes.
+pub const CLIP_PLANES_SLOT: u32 = 4;
+/// Number of user clip planes supported by D3D9.
+pub const MAX_CLIP_PLANES: usize = 6;
+
+/// The values passed from vertex shaders to pixel shaders, besides the position.
+///
+/// Both stages always declare all of them in this order,
+/// so that any vertex shader can be linked with any pixel shader.
+///
+/// The fog varying also stores the point size in `y`, see the `sprite` module.
+const VARYINGS: &[(&str, &str)] = &[
+    ("color0", "COLOR0"),
+    ("color1", "COLOR1"),
+    ("texcoord0", "TEXCOORD0"),
+    ("texcoord1", "TEXCOORD1"),
+    ("texcoord2", "TEXCOORD2"),
+    ("texcoord3", "TEXCOORD3"),
+    ("texcoord4", "TEXCOORD4"),
+    ("texcoord5", "TEXCOORD5"),
+    ("texcoord6", "TEXCOORD6"),
+    ("texcoord7", "TEXCOORD7"),
+    ("texcoord8", "TEXCOORD8"),
+    ("texcoord9", "TEXCOORD9"),
+    ("fog", "FOG"),
+];
+
+/// Returns the declaration of the structure linking vertex shaders to pixel shaders.
+///
+/// Every generated shader must use this, including the fixed function ones.
+pub fn varyings_struct() -> String {
+    let mut s = String::from("struct Varyings {\n    float4 pos : SV_Position;\n");
+
+    for (name, semantic) in VARYINGS {
+        s.push_str(&format!("    float4 {} : {};\n", name, semantic));
+    }
+
+    // One distance per user clip plane, split in two since registers only hold 4 of them.
+    s.push_str("    float4 clip0 : SV_ClipDistance0;\n");
+    s.push_str("    float2 clip1 : SV_ClipDistance1;\n");
+
+    s.push_str("};\n");
+    s
+}
+
+/// Returns the names of the varyings storing texture coordinates.
+pub fn texcoord_varyings() -> impl Iterator<Item = &'static str> {
+    VARYINGS
+        .iter()
+        .map(|&(name, _)| name)
+        .filter(|name| name.starts_with("texcoord"))
+}
+
+/// Returns the declaration of the constant buffer holding the user clip planes.
+///
+/// Disabled planes are set to zero, which never clips anything.
+pub fn clip_planes_decls() -> String {
+    format!(
+        "cbuffer ClipPlanes : register(b{}) {{ float4 clip_planes[{}]; }};\n",
+        CLIP_PLANES_SLOT, MAX_CLIP_PLANES
+    )
+}
+
+/// Returns the statements which compute the distances of a position to the user clip planes.
+///
+/// The position must be in the same space as the planes.
+pub fn clip_distances(pos: &str) -> [String; 2] {
+    let dist = |i: usize| format!("dot({}, clip_planes[{}])", pos, i);
+    [
+        format!(
+            "output.clip0 = float4({}, {}, {}, {});",
+            dist(0),
+            dist(1),
+            dist(2),
+            dist(3)
+        ),
+        format!("output.clip1 = float2({}, {});", dist(4), dist(5)),
+    ]
+}
+
+/// Returns the declaration of the constant buffer holding the alpha test's reference value.
+pub fn alpha_test_decls() -> String {
+    format!(
+        "cbuffer AlphaTest : register(b{}) {{ float alpha_ref; }};\n",
+        ALPHA_TEST_SLOT
+    )
+}
+
+/// Returns the code which discards pixels failing the alpha test.
+///
+/// Returns nothing if the test always passes.
+pub fn alpha_test(func: D3DCMPFUNC, alpha: &str) -> Option<String> {
+    let op = match func {
+        D3DCMP_ALWAYS => return None,
+        D3DCMP_NEVER => return Some("discard;".to_string()),
+        D3DCMP_LESS => "<",
+        D3DCMP_EQUAL => "==",
+        D3DCMP_LESSEQUAL => "<=",
+        D3DCMP_GREATER => ">",
+        D3DCMP_NOTEQUAL => "!=",
+        D3DCMP_GREATEREQUAL => ">=",
+        _ => {
+            warn!("Unknown alpha test function: {}", func);
+            return None;
+        }
+    };
+
+    Some(format!("if (!({} {} alpha_ref)) discard;", alpha, op))
+}
+
+/// Rearranges the channels of a value sampled from a texture, for formats DXGI lacks.
+pub fn swizzle_sample(swizzle: TextureSwizzle, sample: String) -> String {
+    match swizzle {
+        TextureSwizzle::Luminance => format!("({}).rrra", sample),
+        TextureSwizzle::LuminanceAlpha => format!("({}).rrrg", sample),
+        _ => sample,
+    }
+}
+
+/// Determines which varying stores the values with a certain usage.
+fn varying_name(usage: D3DDECLUSAGE, index: u32) -> Option<&'static str> {
+    let name = match usage {
+        D3DDECLUSAGE_COLOR => format!("color{}", index),
+        D3DDECLUSAGE_TEXCOORD => format!("texcoord{}", index),
+        D3DDECLUSAGE_FOG => "fog".to_string(),
+        _ => return None,
+    };
+
+    VARYINGS.iter().find(|(n, _)| *n == name).map(|(n, _)| *n)
+}
+
+/// Returns the swizzle string selecting the components enabled in a write mask.
+fn mask_str(mask: u32) -> String {
+    "xyzw"
+        .chars()
+        .enumerate()
+        .filter(|(i, _)| mask & (1 << i) != 0)
+        .map(|(_, c)| c)
+        .collect()
+}
+
+/// Returns the string representation of a swizzle.
+fn swizzle_str(swizzle: &[u32; 4]) -> String {
+    swizzle
+        .iter()
+        .map(|&c| "xyzw".as_bytes()[c as usize] as char)
+        .collect()
+}
+
+/// Formats a float so it can be used as a literal in HLSL.
+fn float_literal(value: f32) -> String {
+    if value.is_finite() {
+        format!("{:?}", value)
+    } else {
+        format!("asfloat({}u)", value.to_bits())
+    }
+}
+
+/// Returns the HLSL operator for an instruction's comparison control bits.
+fn comparison(control: u32) -> Result<&'static str, Error> {
+    Ok(match control {
+        D3DSPC_GT => ">",
+        D3DSPC_EQ => "==",
+        D3DSPC_GE => ">=",
+        D3DSPC_LT => "<",
+        D3DSPC_NE => "!=",
+        D3DSPC_LE => "<=",
+        _ => {
+            error!("Invalid shader comparison: {}", control);
+            return Err(Error::InvalidCall);
+        }
+    })
+}
+
+/// Converts a decoded shader to HLSL code.
+pub fn generate(shader: &Shader, variant: PixelVariant) -> Result<String, Error> {
+    let mut gen = Generator {
+        shader,
+        variant,
+        out: String::new(),
+        indent: 0,
+        blocks: 0,
+        loops: 0,
+    };
+
+    gen.write_shader()?;
+
+    Ok(gen.out)
+}
+
+struct Generator<'a> {
+    shader: &'a Shader,
+    variant: PixelVariant,
+    out: String,
+    indent: usize,
+    // Number of flow control blocks which are currently open.
+    blocks: usize,
+    // Number of loops opened so far, used to give each loop counter a unique name.
+    loops: usize,
+}
+
+impl<'a> Generator<'a> {
+    /// Writes a line of code at the current indentation.
+    fn line(&mut self, line: &str) {
+        for _ in 0..self.indent {
+            self.out.push_str("    ");
+        }
+        self.out.push_str(line);
+        self.out.push('\n');
+    }
+
+    fn kind(&self) -> ShaderKind {
+        self.shader.version.kind
+    }
+
+    fn write_shader(&mut self) -> Result<(), Error> {
+        self.write_resources();
+        self.write_varyings();
+
+        match self.kind() {
+            ShaderKind::Vertex => {
+                self.write_vertex_input();
+
+                self.line("Varyings main(VertexInput input) {");
+                self.indent += 1;
+                self.line("Varyings output = (Varyings)0;");
+            }
+            ShaderKind::Pixel => {
+                self.write_pixel_output();
+
+                self.line("PixelOutput main(Varyings input, bool vface : SV_IsFrontFace) {");
+                self.indent += 1;
+                self.line("PixelOutput output = (PixelOutput)0;");
+            }
+        }
+
+        self.write_locals();
+
+        if self.kind() == ShaderKind::Pixel {
+            self.write_pixel_input();
+        }
+
+        let shader = self.shader;
+        for inst in &shader.instructions {
+            self.write_instruction(inst)?;
+        }
+
+        match self.kind() {
+            ShaderKind::Vertex => {
+                self.write_vertex_output()?;
+
+                // Shaders get the planes in clip space.
+                for line in &clip_distances("output.pos") {
+                    self.line(line);
+                }
+            }
+            ShaderKind::Pixel => self.copy_pixel_output(),
+        }
+
+        self.line("return output;");
+        self.indent -= 1;
+        self.line("}");
+
+        Ok(())
+    }
+
+    /// Retrieves the registers of a certain type written by the shader.
+    fn written_registers(&self, ty: D3DSHADER_PARAM_REGISTER_TYPE) -> BTreeSet<u32> {
+        self.shader
+            .instructions
+            .iter()
+            .filter_map(|inst| inst.dst)
+            .filter(|dst| dst.reg.ty == ty)
+            .map(|dst| dst.reg.num)
+            .collect()
+    }
+
+    /// Declares the render targets written by a pixel shader.
+    fn write_pixel_output(&mut self) {
+        self.line("struct PixelOutput {");
+        self.indent += 1;
+
+        for n in self.written_registers(D3DSPR_COLOROUT) {
+            self.line(&format!("float4 oC{} : SV_Target{};", n, n));
+        }
+
+        if !self.written_registers(D3DSPR_DEPTHOUT).is_empty() {
+            self.line("float oDepth : SV_Depth;");
+        }
+
+        self.indent -= 1;
+        self.line("};");
+        self.line("");
+    }
+
+    /// Copies the varyings to the input registers of a shader model 3 pixel shader.
+    fn write_pixel_input(&mut self) {
+        if self.shader.version.major < 3 {
+            // Older shaders read the varyings directly, see `reg_name`.
+            return;
+        }
+
+        let shader = self.shader;
+        for decl in &shader.decls {
+            if decl.reg.ty != D3DSPR_INPUT {
+                continue;
+            }
+
+            let varying = match varying_name(decl.usage, decl.usage_index) {
+                Some(varying) => varying,
+                None => {
+                    warn!(
+                        "Pixel shader input with usage {} and index {} is always zero",
+                        decl.usage, decl.usage_index
+                    );
+                    continue;
+                }
+            };
+
+            // The vertex shader stores each packed output starting at `x`.
+            let mask = mask_str(decl.mask);
+            self.line(&format!(
+                "v{}.{} = input.{}.{};",
+                decl.reg.num,
+                mask,
+                varying,
+                &"xyzw"[..mask.len()]
+            ));
+        }
+    }
+
+    /// Copies a pixel shader's output registers to its render targets.
+    fn copy_pixel_output(&mut self) {
+        let color_outputs = self.written_registers(D3DSPR_COLOROUT);
+
+        // The alpha test only applies to the first render target.
+        if color_outputs.contains(&0) {
+            if let Some(test) = alpha_test(self.variant.alpha_func, "oC0.a") {
+                self.line(&test);
+            }
+
+            if self.shader.version.major < 3 {
+                for line in apply_fog(self.variant.fog, "oC0") {
+                    self.line(&line);
+                }
+            }
+        }
+
+        for n in color_outputs {
+            self.line(&format!("output.oC{} = oC{};", n, n));
+        }
+
+        if !self.written_registers(D3DSPR_DEPTHOUT).is_empty() {
+            self.line("output.oDepth = oDepth.x;");
+        }
+    }
+
+    /// Declares the constant buffers and textures used by the shader.
+    fn write_resources(&mut self) {
+        self.line(&format!(
+            "cbuffer FloatConstants : register(b0) {{ float4 c[{}]; }};",
+            MAX_FLOAT_CONSTANTS
+        ));
+        self.line(&format!(
+            "cbuffer IntConstants : register(b1) {{ int4 i[{}]; }};",
+            MAX_INT_CONSTANTS
+        ));
+        // Booleans are stored in the first component of each register.
+        self.line(&format!(
+            "cbuffer BoolConstants : register(b2) {{ uint4 b[{}]; }};",
+            MAX_BOOL_CONSTANTS
+        ));
+        if self.uses_relative_constants() {
+            self.write_relative_constants();
+        }
+        match self.kind() {
+            ShaderKind::Vertex => {
+                self.out.push_str(&clip_planes_decls());
+                self.out.push_str(&point_sprite_decls());
+            }
+            ShaderKind::Pixel => {
+                self.out.push_str(&alpha_test_decls());
+                self.out.push_str(&fog_decls());
+            }
+        }
+        self.line("");
+
+        let shader = self.shader;
+        for decl in &shader.decls {
+            if decl.reg.ty != D3DSPR_SAMPLER {
+                continue;
+            }
+
+            let ty = match decl.texture_type {
+                D3DSTT_CUBE => "TextureCube",
+                D3DSTT_VOLUME => "Texture3D",
+                _ => "Texture2D",
+            };
+
+            let n = decl.reg.num;
+            self.line(&format!("{} tex{} : register(t{});", ty, n, n));
+            self.line(&format!("SamplerState samp{} : register(s{});", n, n));
+        }
+
+        self.line("");
+    }
+
+    /// Checks if the shader indexes the float constants with a register.
+    fn uses_relative_constants(&self) -> bool {
+        self.shader
+            .instructions
+            .iter()
+            .flat_map(|inst| &inst.src)
+            .any(|src| src.reg.ty == D3DSPR_CONST && src.relative.is_some())
+    }
+
+    /// Declares the function reading a float constant at an index only known at runtime.
+    ///
+    /// Constants defined by the shader aren't stored in the constant buffer,
+    /// so they have to be checked for before indexing it.
+    fn write_relative_constants(&mut self) {
+        self.line("float4 c_relative(int n) {");
+        self.indent += 1;
+
+        let shader = self.shader;
+        for def in &shader.defs {
+            if let Definition::Float(n, v) = *def {
+                self.line(&format!(
+                    "if (n == {}) return float4({}, {}, {}, {});",
+                    n,
+                    float_literal(v[0]),
+                    float_literal(v[1]),
+                    float_literal(v[2]),
+                    float_literal(v[3])
+                ));
+            }
+        }
+
+        self.line("return c[n];");
+        self.indent -= 1;
+        self.line("}");
+    }
+
+    /// Declares the structure linking vertex shaders to pixel shaders.
+    fn write_varyings(&mut self) {
+        self.out.push_str(&varyings_struct());
+        self.line("");
+    }
+
+    /// Declares the vertex attributes read by a vertex shader.
+    fn write_vertex_input(&mut self) {
+        self.line("struct VertexInput {");
+        self.indent += 1;
+
+        let shader = self.shader;
+        for decl in &shader.decls {
+            if decl.reg.ty != D3DSPR_INPUT {
+                continue;
+            }
+
+            // These must match the semantics used for the vertex declaration's input layout.
+            let semantic = d3d_decl_usage_to_semantic(decl.usage);
+            let semantic = String::from_utf8_lossy(&semantic[..semantic.len() - 1]);
+
+            self.line(&format!(
+                "float4 v{} : {}{};",
+                decl.reg.num, semantic, decl.usage_index
+            ));
+        }
+
+        self.indent -= 1;
+        self.line("};");
+        self.line("");
+    }
+
+    /// Declares a local variable for every register the shader reads or writes.
+    fn write_locals(&mut self) {
+        let mut regs = BTreeSet::new();
+
+        for inst in &self.shader.instructions {
+            let srcs = inst.src.iter().chain(inst.predicate.iter());
+            let relatives = srcs.clone().filter_map(|src| src.relative.as_ref());
+
+            for src in srcs.chain(relatives.map(|r| &**r)) {
+                regs.insert((src.reg.ty, src.reg.num));
+            }
+
+            if let Some(dst) = inst.dst {
+                regs.insert((dst.reg.ty, dst.reg.num));
+            }
+        }
+
+        // Every declared input is written by `write_pixel_input`, even if it is never read.
+        for decl in &self.shader.decls {
+            if decl.reg.ty == D3DSPR_INPUT {
+                regs.insert((decl.reg.ty, decl.reg.num));
+            }
+        }
+
+        for (ty, num) in regs {
+            let reg = Register { ty, num };
+
+            let vertex = self.kind() == ShaderKind::Vertex;
+            let sm3 = self.shader.version.major >= 3;
+
+            let decl = match ty {
+                D3DSPR_TEMP | D3DSPR_COLOROUT | D3DSPR_DEPTHOUT => {
+                    format!("float4 {} = 0;", self.reg_name(reg).unwrap())
+                }
+                D3DSPR_RASTOUT | D3DSPR_ATTROUT | D3DSPR_OUTPUT if vertex => {
+                    format!("float4 {} = 0;", self.reg_name(reg).unwrap())
+                }
+                D3DSPR_INPUT if !vertex && sm3 => format!("float4 v{} = 0;", num),
+                D3DSPR_ADDR if vertex => "int4 a0 = 0;".to_string(),
+                D3DSPR_LOOP => "int aL = 0;".to_string(),
+                D3DSPR_PREDICATE => "bool4 p0 = false;".to_string(),
+                _ => continue,
+            };
+
+            self.line(&decl);
+        }
+    }
+
+    /// Copies a vertex shader's outputs to the varyings.
+    fn write_vertex_output(&mut self) -> Result<(), Error> {
+        let written: BTreeSet<_> = self
+            .shader
+            .instructions
+            .iter()
+            .filter_map(|inst| inst.dst)
+            .map(|dst| dst.reg)
+            .filter(|reg| match reg.ty {
+                D3DSPR_RASTOUT | D3DSPR_ATTROUT | D3DSPR_OUTPUT => true,
+                _ => false,
+            })
+            .map(|reg| (reg.ty, reg.num))
+            .collect();
+
+        let shader = self.shader;
+
+        // Points use the size from the render state, unless the shader writes its own.
+        let mut size = None;
+
+        if shader.version.major >= 3 {
+            // Outputs are linked to pixel shader inputs through their declared usage.
+            for decl in &shader.decls {
+                if decl.reg.ty != D3DSPR_OUTPUT || !written.contains(&(D3DSPR_OUTPUT, decl.reg.num))
+                {
+                    continue;
+                }
+
+                let varying = match decl.usage {
+                    D3DDECLUSAGE_POSITION | D3DDECLUSAGE_POSITIONT => Some("pos"),
+                    // Stored after the other outputs, since it shares the fog varying.
+                    D3DDECLUSAGE_PSIZE => {
+                        let component = mask_str(decl.mask).chars().next().unwrap_or('x');
+                        size = Some(format!("o{}.{}", decl.reg.num, component));
+                        continue;
+                    }
+                    usage => varying_name(usage, decl.usage_index),
+                };
+
+                let varying = match varying {
+                    Some(varying) => varying,
+                    None => {
+                        warn!(
+                            "Vertex shader output with usage {} and index {} is ignored",
+                            decl.usage, decl.usage_index
+                        );
+                        continue;
+                    }
+                };
+
+                // Registers can pack multiple outputs, but each varying starts at `x`.
+                let mask = mask_str(decl.mask);
+                self.line(&format!(
+                    "output.{}.{} = o{}.{};",
+                    varying,
+                    &"xyzw"[..mask.len()],
+                    decl.reg.num,
+                    mask
+                ));
+            }
+        } else {
+            for &(ty, num) in &written {
+                let varying = match (ty, num) {
+                    (D3DSPR_RASTOUT, D3DSRO_POSITION) => "pos".to_string(),
+                    (D3DSPR_RASTOUT, D3DSRO_FOG) => "fog".to_string(),
+                    (D3DSPR_ATTROUT, n) => format!("color{}", n),
+                    (D3DSPR_TEXCRDOUT, n) => format!("texcoord{}", n),
+                    (D3DSPR_RASTOUT, D3DSRO_POINT_SIZE) => {
+                        size = Some("oPts.x".to_string());
+                        continue;
+                    }
+                    _ => continue,
+                };
+
+                let reg = self.reg_name(Register { ty, num })?;
+                self.line(&format!("output.{} = {};", varying, reg));
+            }
+        }
+
+        let size = size.unwrap_or_else(|| "point_size".to_string());
+        self.line(&point_size(&size));
+
+        // Half a pixel in clip space, which is scaled by `w` before the perspective divide.
+        if half_pixel_offset_enabled() {
+            self.line("output.pos.xy += float2(1.0, -1.0) / viewport_size * output.pos.w;");
+        }
+
+        Ok(())
+    }
+
+    /// Retrieves the name of the variable storing a register.
+    fn reg_name(&self, reg: Register) -> Result<String, Error> {
+        let n = reg.num;
+        let sm3 = self.shader.version.major >= 3;
+
+        let vertex = self.kind() == ShaderKind::Vertex;
+
+        Ok(match reg.ty {
+            D3DSPR_TEMP => format!("r{}", n),
+            D3DSPR_INPUT if vertex => format!("input.v{}", n),
+            D3DSPR_INPUT if sm3 => format!("v{}", n),
+            D3DSPR_INPUT => format!("input.color{}", n),
+            D3DSPR_CONST => format!("c[{}]", n),
+            D3DSPR_ADDR if vertex => "a0".to_string(),
+            // In pixel shaders, this is the texture coordinate register type.
+            D3DSPR_TEXTURE => format!("input.texcoord{}", n),
+            D3DSPR_RASTOUT => match n {
+                D3DSRO_POSITION => "oPos".to_string(),
+                D3DSRO_FOG => "oFog".to_string(),
+                _ => "oPts".to_string(),
+            },
+            D3DSPR_ATTROUT => format!("oD{}", n),
+            D3DSPR_OUTPUT if sm3 => format!("o{}", n),
+            D3DSPR_TEXCRDOUT => format!("oT{}", n),
+            D3DSPR_CONSTINT => format!("i[{}]", n),
+            D3DSPR_COLOROUT => format!("oC{}", n),
+            D3DSPR_DEPTHOUT => "oDepth".to_string(),
+            D3DSPR_CONSTBOOL => format!("b[{}].x", n),
+            D3DSPR_LOOP => "aL".to_string(),
+            // D3D9 places pixel centers at integer coordinates, while D3D11 offsets them by half a pixel.
+            D3DSPR_MISCTYPE if n == D3DSMO_POSITION => {
+                "float4(input.pos.xy - 0.5, 0.0, 0.0)".to_string()
+            }
+            D3DSPR_MISCTYPE if n == D3DSMO_FACE => {
+                "(vface ? float4(1.0, 1.0, 1.0, 1.0) : float4(-1.0, -1.0, -1.0, -1.0))".to_string()
+            }
+            D3DSPR_PREDICATE => "p0".to_string(),
+            ty => {
+                error!("Unsupported shader register type: {}", ty);
+                return Err(Error::InvalidCall);
+            }
+        })
+    }
+
+    /// Finds the value a shader assigned to a constant register, if any.
+    fn definition(&self, reg: Register) -> Option<Definition> {
+        self.shader
+            .defs
+            .iter()
+            .cloned()
+            .find(|def| match (*def, reg.ty) {
+                (Definition::Float(n, _), D3DSPR_CONST)
+                | (Definition::Int(n, _), D3DSPR_CONSTINT)
+                | (Definition::Bool(n, _), D3DSPR_CONSTBOOL) => n == reg.num,
+                _ => false,
+            })
+    }
+
+    /// Returns the expression used to read an integer constant register.
+    fn int_src(&self, src: &SrcParam) -> Result<String, Error> {
+        match self.definition(src.reg) {
+            Some(Definition::Int(_, v)) => {
+                Ok(format!("int4({}, {}, {}, {})", v[0], v[1], v[2], v[3]))
+            }
+            _ => self.reg_name(src.reg),
+        }
+    }
+
+    /// Returns the expression used to read a boolean condition.
+    fn bool_src(&self, src: &SrcParam) -> Result<String, Error> {
+        let expr = match src.reg.ty {
+            D3DSPR_PREDICATE => format!("p0.{}", &"xyzw"[src.swizzle[0] as usize..][..1]),
+            D3DSPR_CONSTBOOL => match self.definition(src.reg) {
+                Some(Definition::Bool(_, v)) => v.to_string(),
+                _ => format!("({} != 0)", self.reg_name(src.reg)?),
+            },
+            ty => {
+                error!("Unsupported condition register type: {}", ty);
+                return Err(Error::InvalidCall);
+            }
+        };
+
+        Ok(if src.modifier == D3DSPSM_NOT {
+            format!("!{}", expr)
+        } else {
+            expr
+        })
+    }
+
+    /// Returns the `float4` expression used to read a source parameter.
+    fn src(&self, src: &SrcParam) -> Result<String, Error> {
+        let reg = src.reg;
+
+        let base = match (reg.ty, self.definition(reg)) {
+            (D3DSPR_CONST, _) if src.relative.is_some() => {
+                let index = self.relative_index(src.relative.as_ref().unwrap())?;
+                format!("c_relative({} + {})", reg.num, index)
+            }
+            _ if src.relative.is_some() => {
+                error!(
+                    "Relative addressing of register type {} is not supported",
+                    reg.ty
+                );
+                return Err(Error::InvalidCall);
+            }
+            (D3DSPR_CONST, Some(Definition::Float(_, v))) => format!(
+                "float4({}, {}, {}, {})",
+                float_literal(v[0]),
+                float_literal(v[1]),
+                float_literal(v[2]),
+                float_literal(v[3])
+            ),
+            (D3DSPR_CONSTINT, _) => format!("float4({})", self.int_src(src)?),
+            (D3DSPR_ADDR, _) if self.kind() == ShaderKind::Vertex => {
+                format!("float4({})", self.reg_name(reg)?)
+            }
+            (D3DSPR_PREDICATE, _) => format!("float4({})", self.reg_name(reg)?),
+            (D3DSPR_LOOP, _) => "float4(aL, aL, aL, aL)".to_string(),
+            _ => self.reg_name(reg)?,
+        };
+
+        let value = if src.swizzle == [0, 1, 2, 3] {
+            base
+        } else {
+            format!("{}.{}", base, swizzle_str(&src.swizzle))
+        };
+
+        Ok(match src.modifier {
+            D3DSPSM_NONE => value,
+            D3DSPSM_NEG => format!("-{}", value),
+            D3DSPSM_BIAS => format!("({} - 0.5)", value),
+            D3DSPSM_BIASNEG => format!("-({} - 0.5)", value),
+            D3DSPSM_SIGN => format!("({} * 2.0 - 1.0)", value),
+            D3DSPSM_SIGNNEG => format!("-({} * 2.0 - 1.0)", value),
+            D3DSPSM_COMP => format!("(1.0 - {})", value),
+            D3DSPSM_X2 => format!("({} * 2.0)", value),
+            D3DSPSM_X2NEG => format!("-({} * 2.0)", value),
+            D3DSPSM_ABS => format!("abs({})", value),
+            D3DSPSM_ABSNEG => format!("-abs({})", value),
+            modifier => {
+                error!("Unsupported source modifier: {:#x}", modifier);
+                return Err(Error::InvalidCall);
+            }
+        })
+    }
+
+    /// Returns the integer expression offsetting a relatively addressed register.
+    fn relative_index(&self, relative: &SrcParam) -> Result<String, Error> {
+        match relative.reg.ty {
+            D3DSPR_ADDR => Ok(format!(
+                "{}.{}",
+                self.reg_name(relative.reg)?,
+                &"xyzw"[relative.swizzle[0] as usize..][..1]
+            )),
+            D3DSPR_LOOP => self.reg_name(relative.reg),
+            ty => {
+                error!("Unsupported relative addressing register type: {}", ty);
+                Err(Error::InvalidCall)
+            }
+        }
+    }
+
+    /// Returns the expression reading one of a source parameter's registers as a scalar.
+    ///
+    /// Scalar instructions use the last component if no replicate swizzle is specified.
+    fn scalar_src(&self, src: &SrcParam) -> Result<String, Error> {
+        Ok(format!("({}).w", self.src(src)?))
+    }
+
+    /// Returns the expression for a matrix multiplication, using `rows` consecutive
+    /// registers starting at `matrix`, and `cols` components of `vector`.
+    fn matrix_mul(
+        &self,
+        vector: &SrcParam,
+        matrix: &SrcParam,
+        rows: u32,
+        cols: usize,
+    ) -> Result<String, Error> {
+        let v = format!("({}).{}", self.src(vector)?, &"xyzw"[..cols]);
+
+        let mut dots = Vec::with_capacity(4);
+        for row in 0..4 {
+            if row < rows {
+                let mut matrix = matrix.clone();
+                matrix.reg.num += row;
+                dots.push(format!(
+                    "dot({}, ({}).{})",
+                    v,
+                    self.src(&matrix)?,
+                    &"xyzw"[..cols]
+                ));
+            } else {
+                dots.push("0.0".to_string());
+            }
+        }
+
+        Ok(format!("float4({})", dots.join(", ")))
+    }
+
+    /// Stores the result of an instruction into its destination register.
+    fn write_dst(&mut self, inst: &Instruction, value: String) -> Result<(), Error> {
+        let dst = inst.dst.ok_or_else(|| {
+            error!(
+                "Shader instruction {} is missing its destination",
+                inst.opcode
+            );
+            Error::InvalidCall
+        })?;
+
+        let name = self.reg_name(dst.reg)?;
+
+        let mut value = if dst.saturate {
+            format!("saturate({})", value)
+        } else if self.kind() == ShaderKind::Pixel && self.shader.version.major < 2 {
+            format!(
+                "clamp({}, -{m}, {m})",
+                value,
+                m = float_literal(PS_1X_MAX_VALUE)
+            )
+        } else {
+            value
+        };
+
+        if dst.reg.ty == D3DSPR_ADDR {
+            // Shader model 1 truncates values written to the address register,
+            // while `mova` rounds them to the nearest integer.
+            value = if inst.opcode == D3DSIO_MOVA {
+                format!("(int4)round({})", value)
+            } else {
+                format!("(int4)floor({})", value)
+            };
+        }
+
+        let mask = mask_str(dst.mask);
+
+        let line = match inst.predicate {
+            Some(ref pred) => {
+                // Only the components for which the predicate is true are written.
+                let not = if pred.modifier == D3DSPSM_NOT {
+                    "!"
+                } else {
+                    ""
+                };
+                let cond = format!("{}p0.{}", not, swizzle_str(&pred.swizzle));
+
+                format!(
+                    "{name}.{mask} = ({cond}).{mask} ? ({value}).{mask} : {name}.{mask};",
+                    name = name,
+                    mask = mask,
+                    cond = cond,
+                    value = value
+                )
+            }
+            None if dst.mask == 0xF => format!("{} = {};", name, value),
+            None => format!("{}.{} = ({}).{};", name, mask, value, mask),
+        };
+
+        self.line(&line);
+
+        Ok(())
+    }
+
+    fn write_instruction(&mut self, inst: &Instruction) -> Result<(), Error> {
+        let src = |i: usize| -> Result<&SrcParam, Error> {
+            inst.src.get(i).ok_or_else(|| {
+                error!("Shader instruction {} is missing a source", inst.opcode);
+                Error::InvalidCall
+            })
+        };
+
+        let sm1 = self.shader.version.major < 2;
+
+        let value = match inst.opcode {
+            D3DSIO_NOP => return Ok(()),
+            D3DSIO_MOV | D3DSIO_MOVA => self.src(src(0)?)?,
+            D3DSIO_ADD => format!("{} + {}", self.src(src(0)?)?, self.src(src(1)?)?),
+            D3DSIO_SUB => format!("{} - {}", self.src(src(0)?)?, self.src(src(1)?)?),
+            D3DSIO_MUL => format!("{} * {}", self.src(src(0)?)?, self.src(src(1)?)?),
+            D3DSIO_MAD => format!(
+                "{} * {} + {}",
+                self.src(src(0)?)?,
+                self.src(src(1)?)?,
+                self.src(src(2)?)?
+            ),
+            D3DSIO_RCP => format!("(float4)(1.0 / {})", self.scalar_src(src(0)?)?),
+            // D3D9 takes the absolute value of the operand.
+            D3DSIO_RSQ => format!("(float4)rsqrt(abs({}))", self.scalar_src(src(0)?)?),
+            D3DSIO_DP3 => format!(
+                "(float4)dot(({}).xyz, ({}).xyz)",
+                self.src(src(0)?)?,
+                self.src(src(1)?)?
+            ),
+            D3DSIO_DP4 => format!(
+                "(float4)dot({}, {})",
+                self.src(src(0)?)?,
+                self.src(src(1)?)?
+            ),
+            D3DSIO_MIN => format!("min({}, {})", self.src(src(0)?)?, self.src(src(1)?)?),
+            D3DSIO_MAX => format!("max({}, {})", self.src(src(0)?)?, self.src(src(1)?)?),
+            D3DSIO_SLT => format!("float4({} < {})", self.src(src(0)?)?, self.src(src(1)?)?),
+            D3DSIO_SGE => format!("float4({} >= {})", self.src(src(0)?)?, self.src(src(1)?)?),
+            D3DSIO_EXP => format!("(float4)exp2({})", self.scalar_src(src(0)?)?),
+            D3DSIO_LOG => format!("(float4)log2(abs({}))", self.scalar_src(src(0)?)?),
+            // Shader model 1's partial precision versions also return intermediate results.
+            D3DSIO_EXPP if sm1 => {
+                let s = self.scalar_src(src(0)?)?;
+                format!("float4(exp2(floor({s})), frac({s}), exp2({s}), 1.0)", s = s)
+            }
+            D3DSIO_LOGP if sm1 => {
+                let s = self.scalar_src(src(0)?)?;
+                format!(
+                    "float4(floor(log2(abs({s}))), abs({s}) / exp2(floor(log2(abs({s})))), \
+                     log2(abs({s})), 1.0)",
+                    s = s
+                )
+            }
+            D3DSIO_EXPP => format!("(float4)exp2({})", self.scalar_src(src(0)?)?),
+            D3DSIO_LOGP => format!("(float4)log2(abs({}))", self.scalar_src(src(0)?)?),
+            D3DSIO_POW => format!(
+                "(float4)pow(abs({}), {})",
+                self.scalar_src(src(0)?)?,
+                self.scalar_src(src(1)?)?
+            ),
+            // Only `x` and `y` can be written. Shader model 2 passes two extra constants,
+            // which are only needed to compute the result with a series.
+            D3DSIO_SINCOS => {
+                let s = self.scalar_src(src(0)?)?;
+                format!("float4(cos({s}), sin({s}), 0.0, 0.0)", s = s)
+            }
+            // D3D9 clamps the specular power to [-128, 128].
+            D3DSIO_LIT => {
+                let s = self.src(src(0)?)?;
+                format!(
+                    "float4(1.0, max(({s}).x, 0.0), \
+                     ({s}).x > 0.0 && ({s}).y > 0.0 \
+                     ? pow(({s}).y, clamp(({s}).w, -128.0, 128.0)) : 0.0, 1.0)",
+                    s = s
+                )
+            }
+            D3DSIO_DST => {
+                let (a, b) = (self.src(src(0)?)?, self.src(src(1)?)?);
+                format!(
+                    "float4(1.0, ({a}).y * ({b}).y, ({a}).z, ({b}).w)",
+                    a = a,
+                    b = b
+                )
+            }
+            D3DSIO_FRC => format!("frac({})", self.src(src(0)?)?),
+            D3DSIO_ABS => format!("abs({})", self.src(src(0)?)?),
+            D3DSIO_SGN => format!("sign({})", self.src(src(0)?)?),
+            D3DSIO_NRM => {
+                let s = self.src(src(0)?)?;
+                format!("{} * rsqrt(dot(({}).xyz, ({}).xyz))", s, s, s)
+            }
+            D3DSIO_CRS => format!(
+                "float4(cross(({}).xyz, ({}).xyz), 0.0)",
+                self.src(src(0)?)?,
+                self.src(src(1)?)?
+            ),
+            D3DSIO_LRP => format!(
+                "lerp({}, {}, {})",
+                self.src(src(2)?)?,
+                self.src(src(1)?)?,
+                self.src(src(0)?)?
+            ),
+            D3DSIO_M4x4 => self.matrix_mul(src(0)?, src(1)?, 4, 4)?,
+            D3DSIO_M4x3 => self.matrix_mul(src(0)?, src(1)?, 3, 4)?,
+            D3DSIO_M3x4 => self.matrix_mul(src(0)?, src(1)?, 4, 3)?,
+            D3DSIO_M3x3 => self.matrix_mul(src(0)?, src(1)?, 3, 3)?,
+            D3DSIO_M3x2 => self.matrix_mul(src(0)?, src(1)?, 2, 3)?,
+            D3DSIO_TEXLDL => {
+                let coord = self.src(src(0)?)?;
+                let n = src(1)?.reg.num;
+                let dim = self.texture_dimensions(n);
+                let sample = format!(
+                    "tex{n}.SampleLevel(samp{n}, ({c}).{d}, ({c}).w)",
+                    n = n,
+                    c = coord,
+                    d = &"xyz"[..dim]
+                );
+                self.swizzle_sample(n, sample)
+            }
+            D3DSIO_CMP => format!(
+                "({} >= 0.0 ? {} : {})",
+                self.src(src(0)?)?,
+                self.src(src(1)?)?,
+                self.src(src(2)?)?
+            ),
+            D3DSIO_DP2ADD => format!(
+                "(float4)(dot(({}).xy, ({}).xy) + ({}).x)",
+                self.src(src(0)?)?,
+                self.src(src(1)?)?,
+                self.src(src(2)?)?
+            ),
+            D3DSIO_DSX => format!("ddx({})", self.src(src(0)?)?),
+            D3DSIO_DSY => format!("ddy({})", self.src(src(0)?)?),
+            D3DSIO_TEX => {
+                let coord = self.src(src(0)?)?;
+                let n = src(1)?.reg.num;
+                let d = &"xyz"[..self.texture_dimensions(n)];
+
+                let sample = if inst.control & (D3DSI_TEXLD_PROJECT >> 16) != 0 {
+                    format!(
+                        "tex{n}.Sample(samp{n}, ({c}).{d} / ({c}).w)",
+                        n = n,
+                        c = coord,
+                        d = d
+                    )
+                } else if inst.control & (D3DSI_TEXLD_BIAS >> 16) != 0 {
+                    format!(
+                        "tex{n}.SampleBias(samp{n}, ({c}).{d}, ({c}).w)",
+                        n = n,
+                        c = coord,
+                        d = d
+                    )
+                } else {
+                    format!("tex{n}.Sample(samp{n}, ({c}).{d})", n = n, c = coord, d = d)
+                };
+                self.swizzle_sample(n, sample)
+            }
+            D3DSIO_TEXLDD => {
+                let n = src(1)?.reg.num;
+                let d = &"xyz"[..self.texture_dimensions(n)];
+                let sample = format!(
+                    "tex{n}.SampleGrad(samp{n}, ({}).{d}, ({}).{d}, ({}).{d})",
+                    self.src(src(0)?)?,
+                    self.src(src(2)?)?,
+                    self.src(src(3)?)?,
+                    n = n,
+                    d = d
+                );
+                self.swizzle_sample(n, sample)
+            }
+            D3DSIO_TEXKILL => {
+                // The register to test is stored as a destination parameter.
+                let dst = inst.dst.ok_or(Error::InvalidCall)?;
+                let line = format!(
+                    "if (any(({}).{} < 0.0)) discard;",
+                    self.reg_name(dst.reg)?,
+                    mask_str(dst.mask)
+                );
+                self.line(&line);
+                return Ok(());
+            }
+            D3DSIO_SETP => {
+                let dst = inst.dst.ok_or(Error::InvalidCall)?;
+                let mask = mask_str(dst.mask);
+                let line = format!(
+                    "p0.{m} = ({} {} {}).{m};",
+                    self.src(src(0)?)?,
+                    comparison(inst.control)?,
+                    self.src(src(1)?)?,
+                    m = mask
+                );
+                self.line(&line);
+                return Ok(());
+            }
+            _ => return self.write_flow_control(inst),
+        };
+
+        self.write_dst(inst, value)
+    }
+
+    /// Rearranges the channels sampled from a texture, for formats DXGI lacks.
+    fn swizzle_sample(&self, sampler: u32, sample: String) -> String {
+        // Vertex shaders are not compiled with a variant.
+        if self.kind() != ShaderKind::Pixel {
+            return sample;
+        }
+
+        match self.variant.swizzles.get(sampler as usize) {
+            Some(&swizzle) => swizzle_sample(swizzle, sample),
+            None => sample,
+        }
+    }
+
+    /// Retrieves the number of coordinates needed to sample a texture.
+    fn texture_dimensions(&self, sampler: u32) -> usize {
+        let decl = self
+            .shader
+            .decls
+            .iter()
+            .find(|decl| decl.reg.ty == D3DSPR_SAMPLER && decl.reg.num == sampler);
+
+        match decl.map(|decl| decl.texture_type) {
+            Some(D3DSTT_CUBE) | Some(D3DSTT_VOLUME) => 3,
+            _ => 2,
+        }
+    }
+
+    /// Indents the code of a flow control block.
+    fn open_block(&mut self) {
+        self.blocks += 1;
+        self.indent += 1;
+    }
+
+    /// Ends the innermost flow control block, checking that the shader opened one.
+    fn close_block(&mut self) -> Result<(), Error> {
+        if self.blocks == 0 {
+            error!("Shader ends a flow control block which it didn't start");
+            return Err(Error::InvalidCall);
+        }
+
+        self.blocks -= 1;
+        self.indent -= 1;
+
+        Ok(())
+    }
+
+    fn write_flow_control(&mut self, inst: &Instruction) -> Result<(), Error> {
+        let src =
+            |i: usize| -> Result<&SrcParam, Error> { inst.src.get(i).ok_or(Error::InvalidCall) };
+
+        match inst.opcode {
+            D3DSIO_IF => {
+                let cond = self.bool_src(src(0)?)?;
+                self.line(&format!("if ({}) {{", cond));
+                self.open_block();
+            }
+            D3DSIO_IFC => {
+                let line = format!(
+                    "if (({}).x {} ({}).x) {{",
+                    self.src(src(0)?)?,
+                    comparison(inst.control)?,
+                    self.src(src(1)?)?
+                );
+                self.line(&line);
+                self.open_block();
+            }
+            D3DSIO_ELSE => {
+                self.close_block()?;
+                self.line("} else {");
+                self.open_block();
+            }
+            D3DSIO_REP => {
+                let count = self.int_src(src(0)?)?;
+                let n = self.loops;
+                self.loops += 1;
+
+                self.line(&format!(
+                    "for (int rep{n} = 0; rep{n} < {}.x; rep{n}++) {{",
+                    count,
+                    n = n
+                ));
+                self.open_block();
+            }
+            D3DSIO_LOOP => {
+                // The integer constant stores the iteration count, start and step.
+                let params = self.int_src(src(1)?)?;
+                let n = self.loops;
+                self.loops += 1;
+
+                self.line(&format!("aL = {}.y;", params));
+                self.line(&format!(
+                    "for (int loop{n} = 0; loop{n} < {p}.x; loop{n}++, aL += {p}.z) {{",
+                    n = n,
+                    p = params
+                ));
+                self.open_block();
+            }
+            D3DSIO_ENDIF | D3DSIO_ENDREP | D3DSIO_ENDLOOP => {
+                self.close_block()?;
+                self.line("}");
+            }
+            D3DSIO_BREAK => self.line("break;"),
+            D3DSIO_BREAKC => {
+                let line = format!(
+                    "if (({}).x {} ({}).x) break;",
+                    self.src(src(0)?)?,
+                    comparison(inst.control)?,
+                    self.src(src(1)?)?
+                );
+                self.line(&line);
+            }
+            D3DSIO_BREAKP => {
+                let cond = self.bool_src(src(0)?)?;
+                self.line(&format!("if ({}) break;", cond));
+            }
+            opcode => {
+                error!("Unsupported shader instruction: {}", opcode);
+                return Err(Error::InvalidCall);
+            }
+        }
+
+        Ok(())
+    }
+}
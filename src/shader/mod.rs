@@ -0,0 +1,117 @@
+//! Translation of D3D9 shaders to D3D11 shaders.
+//!
+//! D3D9 shader bytecode is decoded into a list of instructions, from which equivalent
+//! HLSL code is generated. The HLSL code is then compiled using the D3D compiler.
+
+use winapi::shared::d3d9types::*;
+
+use crate::core::fmt::TextureSwizzle;
+use crate::Error;
+
+mod decode;
+pub use self::decode::*;
+
+mod hlsl;
+pub use self::hlsl::{
+    ALPHA_TEST_SLOT, CLIP_PLANES_SLOT, MAX_BOOL_CONSTANTS, MAX_CLIP_PLANES, MAX_FLOAT_CONSTANTS,
+    MAX_INT_CONSTANTS, PS_1X_MAX_VALUE,
+};
+
+mod compile;
+pub use self::compile::{compile, input_signature};
+
+mod fixed;
+pub use self::fixed::*;
+
+mod sprite;
+pub use self::sprite::*;
+
+mod fog;
+pub use self::fog::{FogMode, FOG_REGISTERS, FOG_SLOT};
+
+mod cache;
+pub use self::cache::ShaderCache;
+
+/// Converts a vertex element's usage to the name of the matching shader semantic.
+///
+/// The shader translator uses the same names for the inputs of vertex shaders.
+pub fn d3d_decl_usage_to_semantic(usage: D3DDECLUSAGE) -> &'static [u8] {
+    match usage {
+        D3DDECLUSAGE_POSITION => b"POSITION\0",
+        D3DDECLUSAGE_BLENDWEIGHT => b"BLENDWEIGHT\0",
+        D3DDECLUSAGE_BLENDINDICES => b"BLENDINDICES\0",
+        D3DDECLUSAGE_NORMAL => b"NORMAL\0",
+        D3DDECLUSAGE_PSIZE => b"PSIZE\0",
+        D3DDECLUSAGE_TEXCOORD => b"TEXCOORD\0",
+        D3DDECLUSAGE_TANGENT => b"TANGENT\0",
+        D3DDECLUSAGE_BINORMAL => b"BINORMAL\0",
+        D3DDECLUSAGE_TESSFACTOR => b"TESSFACTOR\0",
+        D3DDECLUSAGE_POSITIONT => b"POSITIONT\0",
+        D3DDECLUSAGE_COLOR => b"COLOR\0",
+        D3DDECLUSAGE_FOG => b"FOG\0",
+        D3DDECLUSAGE_DEPTH => b"DEPTH\0",
+        D3DDECLUSAGE_SAMPLE => b"SAMPLE\0",
+        _ => b"UNKNOWN\0",
+    }
+}
+
+/// State of the pipeline which D3D11 lacks, and which is emulated in pixel shaders.
+///
+/// A separate D3D11 shader is compiled for every combination of these values.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct PixelVariant {
+    /// Comparison function of the alpha test, or `D3DCMP_ALWAYS` if it is disabled.
+    pub alpha_func: D3DCMPFUNC,
+    /// Swizzles applied to the values sampled from each texture.
+    pub swizzles: [TextureSwizzle; 16],
+    /// How fog is applied to the color output.
+    ///
+    /// Shader model 3 pixel shaders must compute fog themselves, so this is ignored for them.
+    pub fog: FogMode,
+}
+
+impl Default for PixelVariant {
+    fn default() -> Self {
+        Self {
+            alpha_func: D3DCMP_ALWAYS,
+            swizzles: [TextureSwizzle::None; 16],
+            fog: FogMode::None,
+        }
+    }
+}
+
+/// Returns the profile which shaders of a certain kind are compiled for.
+pub fn profile(kind: ShaderKind) -> &'static [u8] {
+    match kind {
+        ShaderKind::Vertex => b"vs_4_0\0",
+        ShaderKind::Pixel => b"ps_4_0\0",
+    }
+}
+
+/// The profile which the generated geometry shaders are compiled for.
+///
+/// D3D9 has no geometry shaders, they are only used to emulate some of its features.
+pub const GEOMETRY_PROFILE: &[u8] = b"gs_4_0\0";
+
+/// Translates a D3D9 shader to HLSL, and compiles it to D3D11 bytecode.
+///
+/// The variant is ignored for vertex shaders.
+pub fn translate(
+    tokens: &[u32],
+    kind: ShaderKind,
+    variant: PixelVariant,
+) -> Result<Box<[u8]>, Error> {
+    let shader = Shader::decode(tokens)?;
+
+    if shader.version.kind != kind {
+        error!(
+            "Expected a {:?} shader, got {:?}",
+            kind, shader.version.kind
+        );
+        return Err(Error::InvalidCall);
+    }
+
+    let source = hlsl::generate(&shader, variant)?;
+
+    compile::compile(&source, profile(kind))
+}
@@ -0,0 +1,77 @@
+//! Emulation of D3D9's point sizes and point sprites.
+//!
+//! D3D11 always rasterizes points as single pixels, so point lists are drawn
+//! with a geometry shader which expands every point into a screen-aligned quad.
+//!
+//! Vertex shaders pass the size of each point, in pixels, in the `y` component
+//! of the fog varying, which is otherwise unused.
+
+use super::hlsl::{texcoord_varyings, varyings_struct};
+
+/// Constant buffer slot of the point size state.
+///
+/// It is bound to both the vertex shader and the geometry shader.
+pub const POINT_SPRITE_SLOT: u32 = 5;
+
+/// Number of registers in the point sprite constant buffer:
+/// - the point size, its minimum and maximum, and whether it is scaled by distance
+/// - the three scale factors, and whether point sprites are enabled in `w`
+/// - the size of the viewport
+pub const POINT_SPRITE_REGISTERS: usize = 3;
+
+/// Returns the declaration of the point size constants, matching the registers above.
+pub fn point_sprite_decls() -> String {
+    format!(
+        "cbuffer PointSprite : register(b{}) {{
+    float point_size;
+    float point_size_min;
+    float point_size_max;
+    float point_scale_enable;
+    float3 point_scale;
+    float point_sprite_enable;
+    float2 viewport_size;
+}};
+",
+        POINT_SPRITE_SLOT
+    )
+}
+
+/// Returns the statement which stores the size of a point, clamped to the allowed range.
+pub fn point_size(size: &str) -> String {
+    format!(
+        "output.fog.y = clamp({}, point_size_min, point_size_max);",
+        size
+    )
+}
+
+/// Generates the source code of the geometry shader which expands points into quads.
+///
+/// If point sprites are enabled, the texture coordinates of the quad's corners
+/// are replaced so that each point is covered by a whole texture.
+pub fn geometry_shader_source() -> String {
+    let mut s = varyings_struct();
+    s.push_str(&point_sprite_decls());
+
+    s.push_str("\n[maxvertexcount(4)]\n");
+    s.push_str("void main(point Varyings input[1], inout TriangleStream<Varyings> stream) {\n");
+    s.push_str("    Varyings output = input[0];\n");
+
+    // Half of the size in clip space, which is scaled by `w` before the perspective divide.
+    s.push_str("    float2 extent = input[0].fog.y / viewport_size * input[0].pos.w;\n");
+
+    // The corners are emitted in triangle strip order, starting from the top left.
+    s.push_str("    for (uint i = 0; i < 4; ++i) {\n");
+    s.push_str("        float2 corner = float2(i & 1, i >> 1);\n");
+    s.push_str("        float2 offset = (corner * 2.0 - 1.0) * float2(1.0, -1.0);\n");
+    s.push_str("        output.pos.xy = input[0].pos.xy + offset * extent;\n");
+    s.push_str("        if (point_sprite_enable != 0.0) {\n");
+    for texcoord in texcoord_varyings() {
+        s.push_str(&format!("            output.{}.xy = corner;\n", texcoord));
+    }
+    s.push_str("        }\n");
+    s.push_str("        stream.Append(output);\n");
+    s.push_str("    }\n");
+
+    s.push_str("}\n");
+    s
+}
@@ -2,6 +2,9 @@ use comptr::ComPtr;
 use std::{mem, ptr, slice};
 use winapi::shared::{d3d9::*, d3d9types::*, windef::*};
 
+/// Depth format which can be sampled from, defined by a FourCC code.
+const D3DFMT_INTZ: D3DFORMAT = 0x5A54_4E49;
+
 pub struct Device {
     device: ComPtr<IDirect3DDevice9>,
 }
@@ -64,6 +67,10 @@ impl Device {
     /// Runs the device tests.
     pub fn run_tests(&mut self) {
         self.check_auto_rt_ds();
+        self.check_depth_range();
+        crate::fvf::run_tests(&self.device);
+        crate::shader::run_tests(&self.device);
+        crate::texture::run_tests(&self.device);
         self.fill_default_render_target();
     }
 
@@ -112,6 +119,181 @@ impl Device {
         assert_eq!(rt_desc.Height, ds_desc.Height);
     }
 
+    // Draws quads at known depths with a viewport which doesn't cover the whole depth range,
+    // then reads back the depth buffer to check it was mapped like D3D9 does.
+    fn check_depth_range(&self) {
+        const SIZE: usize = 16;
+
+        let rt = self.get_render_target(0);
+        let ds = self.get_depth_stencil();
+
+        let depth = Texture::new(
+            &self.device,
+            SIZE,
+            SIZE,
+            1,
+            D3DUSAGE_DEPTHSTENCIL,
+            D3DFMT_INTZ,
+            D3DPOOL_DEFAULT,
+        );
+        let color = Texture::new(
+            &self.device,
+            SIZE,
+            SIZE,
+            1,
+            D3DUSAGE_RENDERTARGET,
+            D3DFMT_A8R8G8B8,
+            D3DPOOL_DEFAULT,
+        );
+
+        let vp = D3DVIEWPORT9 {
+            X: 0,
+            Y: 0,
+            Width: SIZE as u32,
+            Height: SIZE as u32,
+            MinZ: 0.5,
+            MaxZ: 1.0,
+        };
+
+        // Pre-transformed vertices are not affected by the viewport's depth range.
+        let size = SIZE as f32;
+        let pre_transformed = [
+            [0.0, 0.0, 0.25, 1.0],
+            [size, 0.0, 0.25, 1.0],
+            [0.0, size, 0.25, 1.0],
+            [size, size, 0.25, 1.0f32],
+        ];
+        self.draw_depth(&depth, &color, &vp, D3DFVF_XYZRHW, &pre_transformed);
+        assert_eq!(
+            self.read_depth(&depth, &color),
+            64,
+            "Pre-transformed vertices were mapped to the viewport's depth range"
+        );
+
+        // Transformed vertices are mapped from [0, 1] to the viewport's depth range.
+        let transformed = [
+            [-1.0, 1.0, 0.5],
+            [1.0, 1.0, 0.5],
+            [-1.0, -1.0, 0.5],
+            [1.0, -1.0, 0.5f32],
+        ];
+        self.draw_depth(&depth, &color, &vp, D3DFVF_XYZ, &transformed);
+        assert_eq!(
+            self.read_depth(&depth, &color),
+            191,
+            "Transformed vertices were not mapped to the viewport's depth range"
+        );
+
+        unsafe {
+            self.device.SetRenderTarget(0, rt.surface.as_mut());
+            self.device.SetDepthStencilSurface(ds.surface.as_mut());
+            self.device.SetTexture(0, ptr::null_mut());
+            self.device.SetRenderState(D3DRS_LIGHTING, 1);
+        }
+    }
+
+    /// Draws a quad as a triangle strip, only writing to a depth texture.
+    fn draw_depth<V>(
+        &self,
+        depth: &Texture,
+        color: &Texture,
+        vp: &D3DVIEWPORT9,
+        fvf: u32,
+        vertices: &[V; 4],
+    ) {
+        let depth = depth.get_level(0);
+        let color = color.get_level(0);
+
+        unsafe {
+            self.device.SetRenderTarget(0, color.surface.as_mut());
+            self.device.SetDepthStencilSurface(depth.surface.as_mut());
+            self.device.SetViewport(vp);
+            self.device
+                .Clear(0, ptr::null(), D3DCLEAR_ZBUFFER, 0, 1.0, 0);
+
+            self.device.SetRenderState(D3DRS_LIGHTING, 0);
+            self.device.SetRenderState(D3DRS_ZENABLE, D3DZB_TRUE);
+            self.device.SetRenderState(D3DRS_ZFUNC, D3DCMP_ALWAYS);
+            self.device.SetFVF(fvf);
+
+            self.device.BeginScene();
+            let result = self.device.DrawPrimitiveUP(
+                D3DPT_TRIANGLESTRIP,
+                2,
+                vertices.as_ptr() as *const _,
+                mem::size_of::<V>() as u32,
+            );
+            assert_eq!(result, 0, "Failed to draw quad");
+            self.device.EndScene();
+
+            self.device.SetRenderState(D3DRS_ZFUNC, D3DCMP_LESSEQUAL);
+        }
+    }
+
+    /// Reads back the depth at the center of a depth texture, as an 8-bit value.
+    ///
+    /// The depth is sampled into a color render target, which is then copied to system memory.
+    fn read_depth(&self, depth: &Texture, color: &Texture) -> u8 {
+        let color_surface = color.get_level(0);
+        let desc = color_surface.desc();
+
+        let vertices = [
+            [0.0, 0.0, 0.0, 1.0, 0.0, 0.0],
+            [desc.Width as f32, 0.0, 0.0, 1.0, 1.0, 0.0],
+            [0.0, desc.Height as f32, 0.0, 1.0, 0.0, 1.0],
+            [desc.Width as f32, desc.Height as f32, 0.0, 1.0, 1.0, 1.0f32],
+        ];
+
+        unsafe {
+            self.device.SetDepthStencilSurface(ptr::null_mut());
+            self.device.SetRenderState(D3DRS_ZENABLE, D3DZB_FALSE);
+            self.device.SetTexture(
+                0,
+                depth.texture.as_mut() as *mut IDirect3DTexture9 as *mut _,
+            );
+            self.device.SetFVF(D3DFVF_XYZRHW | D3DFVF_TEX1);
+
+            self.device.BeginScene();
+            let result = self.device.DrawPrimitiveUP(
+                D3DPT_TRIANGLESTRIP,
+                2,
+                vertices.as_ptr() as *const _,
+                mem::size_of_val(&vertices[0]) as u32,
+            );
+            assert_eq!(result, 0, "Failed to draw quad");
+            self.device.EndScene();
+
+            self.device.SetTexture(0, ptr::null_mut());
+            self.device.SetRenderState(D3DRS_ZENABLE, D3DZB_TRUE);
+        }
+
+        let readback = Texture::new(
+            &self.device,
+            desc.Width as usize,
+            desc.Height as usize,
+            1,
+            0,
+            D3DFMT_A8R8G8B8,
+            D3DPOOL_SYSTEMMEM,
+        );
+        let readback = readback.get_level(0);
+
+        unsafe {
+            let result = self
+                .device
+                .GetRenderTargetData(color_surface.surface.as_mut(), readback.surface.as_mut());
+            assert_eq!(result, 0, "Failed to read back render target");
+        }
+
+        let (ptr, stride) = readback.map::<u32>(D3DLOCK_READONLY);
+        let (x, y) = (desc.Width as usize / 2, desc.Height as usize / 2);
+        let pixel = unsafe { *ptr.add(y * stride + x) };
+        readback.unmap();
+
+        // INTZ textures return the depth in every channel, so any of them will do.
+        (pixel >> 16) as u8
+    }
+
     // Creates a CPU-mappable texture, maps it, fills it with color manually,
     // then copies it onto the back buffer.
     pub fn fill_default_render_target(&self) {
@@ -0,0 +1,109 @@
+//! Tests for the conversion of flexible vertex formats to vertex declarations.
+
+use comptr::ComPtr;
+use std::ptr;
+use winapi::shared::{d3d9::*, d3d9types::*};
+
+/// Expected element, as its type, offset, usage and usage index.
+type Element = (D3DDECLTYPE, u16, D3DDECLUSAGE, u8);
+
+const POSITION: Element = (D3DDECLTYPE_FLOAT3, 0, D3DDECLUSAGE_POSITION, 0);
+
+/// Runs the FVF tests.
+pub fn run_tests(device: &IDirect3DDevice9) {
+    let weights = |ty, index, offset| (ty, offset, D3DDECLUSAGE_BLENDWEIGHT, index);
+    let indices = |ty, offset| (ty, offset, D3DDECLUSAGE_BLENDINDICES, 0);
+
+    check(
+        device,
+        D3DFVF_XYZB1,
+        &[POSITION, weights(D3DDECLTYPE_FLOAT1, 0, 12)],
+    );
+    check(
+        device,
+        D3DFVF_XYZB2,
+        &[POSITION, weights(D3DDECLTYPE_FLOAT2, 0, 12)],
+    );
+    check(
+        device,
+        D3DFVF_XYZB3,
+        &[POSITION, weights(D3DDECLTYPE_FLOAT3, 0, 12)],
+    );
+    check(
+        device,
+        D3DFVF_XYZB4,
+        &[POSITION, weights(D3DDECLTYPE_FLOAT4, 0, 12)],
+    );
+    // The fifth weight does not fit in a single element.
+    check(
+        device,
+        D3DFVF_XYZB5,
+        &[
+            POSITION,
+            weights(D3DDECLTYPE_FLOAT4, 0, 12),
+            weights(D3DDECLTYPE_FLOAT1, 1, 28),
+        ],
+    );
+
+    // The last beta stores the blending indices instead of a weight.
+    check(
+        device,
+        D3DFVF_XYZB1 | D3DFVF_LASTBETA_D3DCOLOR,
+        &[POSITION, indices(D3DDECLTYPE_D3DCOLOR, 12)],
+    );
+    check(
+        device,
+        D3DFVF_XYZB3 | D3DFVF_LASTBETA_UBYTE4 | D3DFVF_NORMAL,
+        &[
+            POSITION,
+            weights(D3DDECLTYPE_FLOAT2, 0, 12),
+            indices(D3DDECLTYPE_UBYTE4, 20),
+            (D3DDECLTYPE_FLOAT3, 24, D3DDECLUSAGE_NORMAL, 0),
+        ],
+    );
+    check(
+        device,
+        D3DFVF_XYZB5 | D3DFVF_LASTBETA_UBYTE4,
+        &[
+            POSITION,
+            weights(D3DDECLTYPE_FLOAT4, 0, 12),
+            indices(D3DDECLTYPE_UBYTE4, 28),
+        ],
+    );
+}
+
+/// Sets an FVF, and compares the elements of the resulting vertex declaration to the expected ones.
+fn check(device: &IDirect3DDevice9, fvf: u32, expected: &[Element]) {
+    unsafe {
+        let result = device.SetFVF(fvf);
+        assert_eq!(result, 0, "Failed to set FVF {:#x}", fvf);
+
+        let mut ptr = ptr::null_mut();
+        let result = device.GetVertexDeclaration(&mut ptr);
+        assert_eq!(result, 0, "Failed to get vertex declaration");
+        let decl = ComPtr::new(ptr);
+
+        let mut num = 0;
+        decl.GetDeclaration(ptr::null_mut(), &mut num);
+
+        let mut elems = vec![D3DDECL_END; num as usize];
+        let result = decl.GetDeclaration(elems.as_mut_ptr(), &mut num);
+        assert_eq!(result, 0, "Failed to get vertex elements");
+
+        // The end marker is not interesting.
+        let elems: Vec<Element> = elems
+            .iter()
+            .filter(|e| e.Stream != 0xFF)
+            .map(|e| {
+                (
+                    e.Type as D3DDECLTYPE,
+                    e.Offset,
+                    e.Usage as D3DDECLUSAGE,
+                    e.UsageIndex,
+                )
+            })
+            .collect();
+
+        assert_eq!(elems, expected, "Wrong vertex elements for FVF {:#x}", fvf);
+    }
+}
@@ -12,6 +12,9 @@ use winit::{EventsLoop, Window};
 
 mod context;
 mod device;
+mod fvf;
+mod shader;
+mod texture;
 
 fn main() {
     let mut events_loop = EventsLoop::new();
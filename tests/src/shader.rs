@@ -0,0 +1,296 @@
+//! Tests for the translation of shader instructions.
+//!
+//! Every test runs a single instruction in a vertex shader, on values from the float constants,
+//! then renders the result into a floating point render target which is read back.
+
+use comptr::ComPtr;
+use std::mem::{self, MaybeUninit};
+use std::{ptr, slice};
+use winapi::shared::{d3d9::*, d3d9types::*};
+
+pub const VS_3_0: u32 = 0xFFFE_0300;
+pub const PS_3_0: u32 = 0xFFFF_0300;
+pub const END: u32 = 0x0000_FFFF;
+
+/// Swizzle which reads every component in order.
+pub const XYZW: u32 = 0xE4;
+/// Swizzle which replicates the `x` component.
+const XXXX: u32 = 0x00;
+
+/// Maximum difference from the reference values.
+///
+/// This is loose enough for the partial precision instructions.
+const TOLERANCE: f32 = 1e-2;
+
+/// Runs the shader instruction tests.
+pub fn run_tests(device: &IDirect3DDevice9) {
+    let tests = ShaderTests::new(device);
+
+    let (cos, sin) = (0.5f32.cos(), 0.5f32.sin());
+    tests.check(
+        "sincos",
+        D3DSIO_SINCOS,
+        0b0011,
+        &[src(D3DSPR_CONST, 0, XXXX)],
+        &[[0.5, 0.0, 0.0, 0.0]],
+        [cos, sin, 0.0, 0.0],
+    );
+
+    tests.check(
+        "lit",
+        D3DSIO_LIT,
+        0b1111,
+        &[src(D3DSPR_CONST, 0, XYZW)],
+        &[[0.5, 0.8, 0.0, 2.0]],
+        [1.0, 0.5, 0.64, 1.0],
+    );
+    // There is no specular light for surfaces facing away from the light.
+    tests.check(
+        "lit",
+        D3DSIO_LIT,
+        0b1111,
+        &[src(D3DSPR_CONST, 0, XYZW)],
+        &[[-0.5, 0.8, 0.0, 2.0]],
+        [1.0, 0.0, 0.0, 1.0],
+    );
+
+    tests.check(
+        "dst",
+        D3DSIO_DST,
+        0b1111,
+        &[src(D3DSPR_CONST, 0, XYZW), src(D3DSPR_CONST, 1, XYZW)],
+        &[[0.0, 2.0, 3.0, 0.0], [0.0, 0.5, 0.0, 4.0]],
+        [1.0, 1.0, 3.0, 4.0],
+    );
+
+    tests.check(
+        "expp",
+        D3DSIO_EXPP,
+        0b0001,
+        &[src(D3DSPR_CONST, 0, XXXX)],
+        &[[3.0, 0.0, 0.0, 0.0]],
+        [8.0, 0.0, 0.0, 0.0],
+    );
+
+    tests.check(
+        "logp",
+        D3DSIO_LOGP,
+        0b0001,
+        &[src(D3DSPR_CONST, 0, XXXX)],
+        &[[8.0, 0.0, 0.0, 0.0]],
+        [3.0, 0.0, 0.0, 0.0],
+    );
+
+    // The base's absolute value is used.
+    tests.check(
+        "pow",
+        D3DSIO_POW,
+        0b0001,
+        &[src(D3DSPR_CONST, 0, XXXX), src(D3DSPR_CONST, 1, XXXX)],
+        &[[-2.0, 0.0, 0.0, 0.0], [3.0, 0.0, 0.0, 0.0]],
+        [8.0, 0.0, 0.0, 0.0],
+    );
+
+    tests.restore();
+}
+
+/// Encodes an instruction token for shader model 2 and above, which stores the length.
+pub fn instruction(opcode: u32, len: usize) -> u32 {
+    opcode | ((len as u32) << D3DSI_INSTLENGTH_SHIFT)
+}
+
+/// Encodes the register type and number of a parameter token.
+fn register(ty: u32, num: u32) -> u32 {
+    (1 << 31)
+        | ((ty << D3DSP_REGTYPE_SHIFT) & D3DSP_REGTYPE_MASK)
+        | ((ty << D3DSP_REGTYPE_SHIFT2) & D3DSP_REGTYPE_MASK2)
+        | num
+}
+
+/// Encodes a destination parameter, writing the components in `mask`.
+pub fn dst(ty: u32, num: u32, mask: u32) -> u32 {
+    register(ty, num) | (mask << 16)
+}
+
+/// Encodes a source parameter.
+pub fn src(ty: u32, num: u32, swizzle: u32) -> u32 {
+    register(ty, num) | (swizzle << D3DVS_SWIZZLE_SHIFT)
+}
+
+/// Encodes the declaration of a register with a usage, or of a sampler with its texture type.
+pub fn dcl(usage: u32, reg: u32) -> [u32; 3] {
+    [instruction(D3DSIO_DCL, 2), (1 << 31) | usage, reg]
+}
+
+struct ShaderTests<'a> {
+    device: &'a IDirect3DDevice9,
+    rt: ComPtr<IDirect3DSurface9>,
+    readback: ComPtr<IDirect3DSurface9>,
+    // The render target to restore after the tests.
+    old_rt: ComPtr<IDirect3DSurface9>,
+}
+
+impl<'a> ShaderTests<'a> {
+    fn new(device: &'a IDirect3DDevice9) -> Self {
+        let fmt = D3DFMT_A32B32G32R32F;
+
+        unsafe {
+            let mut ptr = ptr::null_mut();
+            let result = device.CreateRenderTarget(1, 1, fmt, 0, 0, 0, &mut ptr, ptr::null_mut());
+            assert_eq!(result, 0, "Failed to create render target");
+            let rt = ComPtr::new(ptr);
+
+            let mut ptr = ptr::null_mut();
+            let result = device.CreateOffscreenPlainSurface(
+                1,
+                1,
+                fmt,
+                D3DPOOL_SYSTEMMEM,
+                &mut ptr,
+                ptr::null_mut(),
+            );
+            assert_eq!(result, 0, "Failed to create readback surface");
+            let readback = ComPtr::new(ptr);
+
+            // The pixel shader outputs the texture coordinate written by the vertex shader.
+            let mut tokens = vec![PS_3_0];
+            tokens.extend_from_slice(&dcl(D3DDECLUSAGE_TEXCOORD, dst(D3DSPR_INPUT, 0, 0xF)));
+            tokens.extend_from_slice(&[
+                instruction(D3DSIO_MOV, 2),
+                dst(D3DSPR_COLOROUT, 0, 0xF),
+                src(D3DSPR_INPUT, 0, XYZW),
+                END,
+            ]);
+
+            let mut ptr = ptr::null_mut();
+            let result = device.CreatePixelShader(tokens.as_ptr(), &mut ptr);
+            assert_eq!(result, 0, "Failed to create pixel shader");
+            let ps = ComPtr::new(ptr);
+
+            let mut ptr = ptr::null_mut();
+            let result = device.GetRenderTarget(0, &mut ptr);
+            assert_eq!(result, 0, "Failed to get render target");
+            let old_rt = ComPtr::new(ptr);
+
+            device.SetRenderTarget(0, rt.as_mut());
+            device.SetRenderState(D3DRS_ZENABLE, D3DZB_FALSE);
+            device.SetFVF(D3DFVF_XYZ);
+            device.SetPixelShader(ps.as_mut());
+
+            Self {
+                device,
+                rt,
+                readback,
+                old_rt,
+            }
+        }
+    }
+
+    /// Runs an instruction on the given constants, and compares the written components
+    /// of the result to the expected values.
+    fn check(
+        &self,
+        name: &str,
+        opcode: u32,
+        mask: u32,
+        srcs: &[u32],
+        consts: &[[f32; 4]],
+        expected: [f32; 4],
+    ) {
+        let mut tokens = vec![VS_3_0];
+        tokens.extend_from_slice(&dcl(D3DDECLUSAGE_POSITION, dst(D3DSPR_INPUT, 0, 0xF)));
+        tokens.extend_from_slice(&dcl(D3DDECLUSAGE_POSITION, dst(D3DSPR_OUTPUT, 0, 0xF)));
+        tokens.extend_from_slice(&dcl(D3DDECLUSAGE_TEXCOORD, dst(D3DSPR_OUTPUT, 1, 0xF)));
+
+        tokens.push(instruction(opcode, 1 + srcs.len()));
+        tokens.push(dst(D3DSPR_TEMP, 0, mask));
+        tokens.extend_from_slice(srcs);
+
+        tokens.extend_from_slice(&[
+            instruction(D3DSIO_MOV, 2),
+            dst(D3DSPR_OUTPUT, 0, 0xF),
+            src(D3DSPR_INPUT, 0, XYZW),
+            instruction(D3DSIO_MOV, 2),
+            dst(D3DSPR_OUTPUT, 1, 0xF),
+            src(D3DSPR_TEMP, 0, XYZW),
+            END,
+        ]);
+
+        // A quad covering the whole render target.
+        let vertices = [
+            [-1.0, 1.0, 0.0],
+            [1.0, 1.0, 0.0],
+            [-1.0, -1.0, 0.0],
+            [1.0, -1.0, 0.0f32],
+        ];
+
+        let result = unsafe {
+            let mut ptr = ptr::null_mut();
+            let result = self.device.CreateVertexShader(tokens.as_ptr(), &mut ptr);
+            assert_eq!(result, 0, "Failed to create vertex shader for {}", name);
+            let vs = ComPtr::new(ptr);
+
+            self.device.SetVertexShader(vs.as_mut());
+            self.device.SetVertexShaderConstantF(
+                0,
+                consts.as_ptr() as *const f32,
+                consts.len() as u32,
+            );
+
+            self.device.BeginScene();
+            let result = self.device.DrawPrimitiveUP(
+                D3DPT_TRIANGLESTRIP,
+                2,
+                vertices.as_ptr() as *const _,
+                mem::size_of_val(&vertices[0]) as u32,
+            );
+            assert_eq!(result, 0, "Failed to draw with {}", name);
+            self.device.EndScene();
+
+            self.device.SetVertexShader(ptr::null_mut());
+
+            let result = self
+                .device
+                .GetRenderTargetData(self.rt.as_mut(), self.readback.as_mut());
+            assert_eq!(result, 0, "Failed to read back the result of {}", name);
+
+            let mut lr = MaybeUninit::uninit();
+            let result = self
+                .readback
+                .LockRect(lr.as_mut_ptr(), ptr::null(), D3DLOCK_READONLY);
+            assert_eq!(result, 0, "Failed to map readback surface");
+            let lr: D3DLOCKED_RECT = lr.assume_init();
+
+            let pixel = slice::from_raw_parts(lr.pBits as *const f32, 4);
+            let mut result = [0.0; 4];
+            result.copy_from_slice(pixel);
+
+            self.readback.UnlockRect();
+
+            result
+        };
+
+        for i in 0..4 {
+            if mask & (1 << i) == 0 {
+                continue;
+            }
+
+            assert!(
+                (result[i] - expected[i]).abs() <= TOLERANCE,
+                "Wrong result for {}: expected {:?}, got {:?}",
+                name,
+                expected,
+                result
+            );
+        }
+    }
+
+    /// Restores the state changed by the tests.
+    fn restore(&self) {
+        unsafe {
+            self.device.SetRenderTarget(0, self.old_rt.as_mut());
+            self.device.SetRenderState(D3DRS_ZENABLE, D3DZB_TRUE);
+            self.device.SetPixelShader(ptr::null_mut());
+        }
+    }
+}
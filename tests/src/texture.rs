@@ -0,0 +1,172 @@
+//! Tests for sampling textures from shaders.
+
+use comptr::ComPtr;
+use std::mem::{self, MaybeUninit};
+use std::ptr;
+use winapi::shared::{d3d9::*, d3d9types::*};
+
+use crate::shader::{dcl, dst, instruction, src, END, PS_3_0, VS_3_0, XYZW};
+
+/// Runs the texture sampling tests.
+pub fn run_tests(device: &IDirect3DDevice9) {
+    check_cube_texture(device);
+}
+
+/// Clears every face of a cube texture to a different color, then samples it
+/// in the direction of each face's axis.
+fn check_cube_texture(device: &IDirect3DDevice9) {
+    // The colors of the faces, in the order of `D3DCUBEMAP_FACES`.
+    const COLORS: [D3DCOLOR; 6] = [
+        0xFFFF_0000,
+        0xFF00_FF00,
+        0xFF00_00FF,
+        0xFFFF_FF00,
+        0xFF00_FFFF,
+        0xFFFF_00FF,
+    ];
+    const DIRECTIONS: [[f32; 4]; 6] = [
+        [1.0, 0.0, 0.0, 0.0],
+        [-1.0, 0.0, 0.0, 0.0],
+        [0.0, 1.0, 0.0, 0.0],
+        [0.0, -1.0, 0.0, 0.0],
+        [0.0, 0.0, 1.0, 0.0],
+        [0.0, 0.0, -1.0, 0.0],
+    ];
+
+    unsafe {
+        let mut ptr = ptr::null_mut();
+        let result = device.GetRenderTarget(0, &mut ptr);
+        assert_eq!(result, 0, "Failed to get render target");
+        let old_rt = ComPtr::new(ptr);
+
+        let mut ptr = ptr::null_mut();
+        let result = device.CreateCubeTexture(
+            1,
+            1,
+            D3DUSAGE_RENDERTARGET,
+            D3DFMT_A8R8G8B8,
+            D3DPOOL_DEFAULT,
+            &mut ptr,
+            ptr::null_mut(),
+        );
+        assert_eq!(result, 0, "Failed to create cube texture");
+        let cube = ComPtr::new(ptr);
+
+        for (face, &color) in COLORS.iter().enumerate() {
+            let mut ptr = ptr::null_mut();
+            let result = cube.GetCubeMapSurface(face as u32, 0, &mut ptr);
+            assert_eq!(result, 0, "Failed to get cube map face");
+            let surface = ComPtr::new(ptr);
+
+            device.SetRenderTarget(0, surface.as_mut());
+            device.Clear(0, ptr::null(), D3DCLEAR_TARGET, color, 1.0, 0);
+        }
+
+        let mut ptr = ptr::null_mut();
+        let result =
+            device.CreateRenderTarget(1, 1, D3DFMT_A8R8G8B8, 0, 0, 0, &mut ptr, ptr::null_mut());
+        assert_eq!(result, 0, "Failed to create render target");
+        let rt = ComPtr::new(ptr);
+
+        let mut ptr = ptr::null_mut();
+        let result = device.CreateOffscreenPlainSurface(
+            1,
+            1,
+            D3DFMT_A8R8G8B8,
+            D3DPOOL_SYSTEMMEM,
+            &mut ptr,
+            ptr::null_mut(),
+        );
+        assert_eq!(result, 0, "Failed to create readback surface");
+        let readback = ComPtr::new(ptr);
+
+        // The vertex shader passes the position through.
+        let mut tokens = vec![VS_3_0];
+        tokens.extend_from_slice(&dcl(D3DDECLUSAGE_POSITION, dst(D3DSPR_INPUT, 0, 0xF)));
+        tokens.extend_from_slice(&dcl(D3DDECLUSAGE_POSITION, dst(D3DSPR_OUTPUT, 0, 0xF)));
+        tokens.extend_from_slice(&[
+            instruction(D3DSIO_MOV, 2),
+            dst(D3DSPR_OUTPUT, 0, 0xF),
+            src(D3DSPR_INPUT, 0, XYZW),
+            END,
+        ]);
+
+        let mut ptr = ptr::null_mut();
+        let result = device.CreateVertexShader(tokens.as_ptr(), &mut ptr);
+        assert_eq!(result, 0, "Failed to create vertex shader");
+        let vs = ComPtr::new(ptr);
+
+        // The pixel shader samples the cube map in the direction stored in the first constant.
+        let mut tokens = vec![PS_3_0];
+        tokens.extend_from_slice(&dcl(D3DSTT_CUBE, dst(D3DSPR_SAMPLER, 0, 0xF)));
+        tokens.extend_from_slice(&[
+            instruction(D3DSIO_MOV, 2),
+            dst(D3DSPR_TEMP, 1, 0xF),
+            src(D3DSPR_CONST, 0, XYZW),
+            instruction(D3DSIO_TEX, 3),
+            dst(D3DSPR_TEMP, 0, 0xF),
+            src(D3DSPR_TEMP, 1, XYZW),
+            src(D3DSPR_SAMPLER, 0, XYZW),
+            instruction(D3DSIO_MOV, 2),
+            dst(D3DSPR_COLOROUT, 0, 0xF),
+            src(D3DSPR_TEMP, 0, XYZW),
+            END,
+        ]);
+
+        let mut ptr = ptr::null_mut();
+        let result = device.CreatePixelShader(tokens.as_ptr(), &mut ptr);
+        assert_eq!(result, 0, "Failed to create pixel shader");
+        let ps = ComPtr::new(ptr);
+
+        device.SetRenderTarget(0, rt.as_mut());
+        device.SetRenderState(D3DRS_ZENABLE, D3DZB_FALSE);
+        device.SetFVF(D3DFVF_XYZ);
+        device.SetVertexShader(vs.as_mut());
+        device.SetPixelShader(ps.as_mut());
+        device.SetTexture(0, cube.as_mut() as *mut IDirect3DCubeTexture9 as *mut _);
+
+        // A quad covering the whole render target.
+        let vertices = [
+            [-1.0, 1.0, 0.0],
+            [1.0, 1.0, 0.0],
+            [-1.0, -1.0, 0.0],
+            [1.0, -1.0, 0.0f32],
+        ];
+
+        for (face, direction) in DIRECTIONS.iter().enumerate() {
+            device.SetPixelShaderConstantF(0, direction.as_ptr(), 1);
+
+            device.BeginScene();
+            let result = device.DrawPrimitiveUP(
+                D3DPT_TRIANGLESTRIP,
+                2,
+                vertices.as_ptr() as *const _,
+                mem::size_of_val(&vertices[0]) as u32,
+            );
+            assert_eq!(result, 0, "Failed to draw quad");
+            device.EndScene();
+
+            let result = device.GetRenderTargetData(rt.as_mut(), readback.as_mut());
+            assert_eq!(result, 0, "Failed to read back render target");
+
+            let mut lr = MaybeUninit::uninit();
+            let result = readback.LockRect(lr.as_mut_ptr(), ptr::null(), D3DLOCK_READONLY);
+            assert_eq!(result, 0, "Failed to map readback surface");
+            let lr: D3DLOCKED_RECT = lr.assume_init();
+            let pixel = *(lr.pBits as *const u32);
+            readback.UnlockRect();
+
+            assert_eq!(
+                pixel, COLORS[face],
+                "Sampled the wrong color in the direction of face {}",
+                face
+            );
+        }
+
+        device.SetRenderTarget(0, old_rt.as_mut());
+        device.SetRenderState(D3DRS_ZENABLE, D3DZB_TRUE);
+        device.SetVertexShader(ptr::null_mut());
+        device.SetPixelShader(ptr::null_mut());
+        device.SetTexture(0, ptr::null_mut());
+    }
+}